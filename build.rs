@@ -0,0 +1,35 @@
+//! Generates the gRPC service stubs for `proto/tiles.proto`. The messages are
+//! written by hand in `src/grpc.rs`, so building doesn't need `protoc`.
+
+use tonic_build::manual::{Builder, Method, Service};
+
+const CODEC: &str = "tonic_prost::ProstCodec";
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let method = |name: &str, route: &str, input: &str, output: &str| {
+        Method::builder()
+            .name(name)
+            .route_name(route)
+            .input_type(format!("crate::grpc::{}", input))
+            .output_type(format!("crate::grpc::{}", output))
+            .codec_path(CODEC)
+    };
+    let tiles = Service::builder()
+        .name("Tiles")
+        .package("maptile")
+        .method(method("get_tile", "GetTile", "TileRequest", "TileReply").build())
+        .method(
+            method("get_tile_batch", "GetTileBatch", "BatchRequest", "TileReply")
+                .server_streaming()
+                .build(),
+        )
+        .method(
+            method("seed_region", "SeedRegion", "SeedRequest", "SeedProgress")
+                .server_streaming()
+                .build(),
+        )
+        .build();
+    Builder::new().build_client(false).compile(&[tiles]);
+}
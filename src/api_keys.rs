@@ -0,0 +1,351 @@
+use crate::config::{ApiKeyConfig, Config};
+use crate::error::AppError;
+use crate::handlers::AppState;
+use axum::extract::{Query, RawPathParams, Request, State};
+use axum::http::{header, HeaderMap, HeaderValue, Uri};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::RequestExt;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Keys allowed to fetch tiles, with their daily quotas and usage so far
+pub struct ApiKeys {
+    keys: HashMap<String, ApiKey>,
+    tenants: BTreeMap<String, Arc<Tenant>>,
+}
+
+struct ApiKey {
+    name: String,
+    usage: Usage,
+    tenant: Option<Arc<Tenant>>,
+}
+
+/// Customer whose keys share a quota
+struct Tenant {
+    usage: Usage,
+    /// Layers its keys may fetch, all when unset
+    layers: Option<HashSet<String>>,
+}
+
+/// Tiles served against a key or tenant
+struct Usage {
+    /// Tiles per UTC day, unlimited when 0
+    daily_quota: u64,
+    /// Day number since the epoch and tiles served on it
+    today: Mutex<(u64, u64)>,
+    total: AtomicU64,
+}
+
+/// Usage of one key, as reported by `/admin/stats`
+#[derive(Debug, Serialize)]
+pub struct KeyUsage {
+    pub today: u64,
+    pub total: u64,
+    pub daily_quota: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+}
+
+/// Combined usage of a tenant's keys, as reported by `/admin/stats`
+#[derive(Debug, Serialize)]
+pub struct TenantUsage {
+    pub today: u64,
+    pub total: u64,
+    pub daily_quota: Option<u64>,
+    /// Names of its keys
+    pub keys: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub layers: Option<Vec<String>>,
+}
+
+impl Usage {
+    fn new(daily_quota: u64) -> Self {
+        Self {
+            daily_quota,
+            today: Mutex::new((0, 0)),
+            total: AtomicU64::new(0),
+        }
+    }
+
+    /// Day and tiles served on it, reset when `day` has begun
+    fn day(&self, day: u64) -> MutexGuard<'_, (u64, u64)> {
+        let mut today = self.today.lock().expect("api key usage lock poisoned");
+        if today.0 != day {
+            *today = (day, 0);
+        }
+        today
+    }
+
+    fn allows(&self, today: &(u64, u64), tiles: u64) -> bool {
+        self.daily_quota == 0 || today.1 + tiles <= self.daily_quota
+    }
+
+    fn record(&self, today: &mut (u64, u64), tiles: u64) {
+        today.1 += tiles;
+        self.total.fetch_add(tiles, Ordering::Relaxed);
+    }
+
+    /// Tiles served today and in total
+    fn counts(&self) -> (u64, u64) {
+        let today = *self.day(current_day());
+        (today.1, self.total.load(Ordering::Relaxed))
+    }
+}
+
+impl ApiKeys {
+    /// Keys from the config and key file, or None when authentication is off
+    pub fn load(config: &Config) -> anyhow::Result<Option<Self>> {
+        let mut entries = config.api_keys.clone();
+        if let Some(path) = &config.api_keys_file {
+            entries.extend(read_key_file(path)?);
+        }
+        if entries.is_empty() {
+            return Ok(None);
+        }
+
+        let tenants: BTreeMap<_, _> = config
+            .tenants
+            .iter()
+            .map(|tenant| {
+                let layers = tenant.layers.as_ref().map(|layers| layers.iter().cloned().collect());
+                let usage = Usage::new(tenant.daily_quota.unwrap_or_default());
+                (tenant.name.clone(), Arc::new(Tenant { usage, layers }))
+            })
+            .collect();
+        let keys = entries
+            .into_iter()
+            .map(|entry| {
+                let tenant = match &entry.tenant {
+                    Some(name) => Some(tenants.get(name).cloned().ok_or_else(|| {
+                        let key = masked(&entry.key);
+                        anyhow::anyhow!("API key {} names unknown tenant {:?}", key, name)
+                    })?),
+                    None => None,
+                };
+                // The tenant's quota covers keys that don't set their own
+                let default_quota = match tenant {
+                    Some(_) => 0,
+                    None => config.api_key_daily_quota,
+                };
+                let key = ApiKey {
+                    name: entry.name.unwrap_or_else(|| masked(&entry.key)),
+                    usage: Usage::new(entry.daily_quota.unwrap_or(default_quota)),
+                    tenant,
+                };
+                Ok((entry.key, key))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Some(Self { keys, tenants }))
+    }
+
+    /// Name of the valid key a request carries, if any
+    pub fn identify(&self, uri: &Uri, headers: &HeaderMap) -> Option<&str> {
+        let key = request_key(uri, headers)?;
+        self.keys.get(key).map(|key| key.name.as_str())
+    }
+
+    /// Count `tiles` of `layer` against the request's key and its tenant,
+    /// all or none of them
+    pub fn consume(
+        &self,
+        uri: &Uri,
+        headers: &HeaderMap,
+        layer: &str,
+        tiles: u64,
+    ) -> Result<(), AppError> {
+        let key = request_key(uri, headers)
+            .and_then(|key| self.keys.get(key))
+            .ok_or(AppError::Unauthorized)?;
+        let tenant = key.tenant.as_deref();
+        if tenant.and_then(|t| t.layers.as_ref()).is_some_and(|layers| !layers.contains(layer)) {
+            return Err(AppError::LayerNotAllowed(layer.to_string()));
+        }
+
+        // Always the key before its tenant, so concurrent requests can't deadlock
+        let day = current_day();
+        let mut key_today = key.usage.day(day);
+        let mut tenant_today = tenant.map(|tenant| tenant.usage.day(day));
+        let tenant_allows = match (tenant, &tenant_today) {
+            (Some(tenant), Some(today)) => tenant.usage.allows(today, tiles),
+            _ => true,
+        };
+        if !key.usage.allows(&key_today, tiles) || !tenant_allows {
+            return Err(AppError::QuotaExceeded);
+        }
+        key.usage.record(&mut key_today, tiles);
+        if let (Some(tenant), Some(today)) = (tenant, &mut tenant_today) {
+            tenant.usage.record(today, tiles);
+        }
+        Ok(())
+    }
+
+    /// Usage of every key, by name
+    pub fn usage(&self) -> BTreeMap<String, KeyUsage> {
+        self.keys
+            .values()
+            .map(|key| {
+                let (today, total) = key.usage.counts();
+                let usage = KeyUsage {
+                    today,
+                    total,
+                    daily_quota: quota(&key.usage),
+                    tenant: key.tenant.as_ref().and_then(|tenant| self.tenant_name(tenant)),
+                };
+                (key.name.clone(), usage)
+            })
+            .collect()
+    }
+
+    /// Combined usage of each tenant's keys, or None when there are no tenants
+    pub fn tenant_usage(&self) -> Option<BTreeMap<String, TenantUsage>> {
+        if self.tenants.is_empty() {
+            return None;
+        }
+        let usage = self
+            .tenants
+            .iter()
+            .map(|(name, tenant)| {
+                let (today, total) = tenant.usage.counts();
+                let mut keys: Vec<_> = self
+                    .keys
+                    .values()
+                    .filter(|key| key.tenant.as_ref().is_some_and(|t| Arc::ptr_eq(t, tenant)))
+                    .map(|key| key.name.clone())
+                    .collect();
+                keys.sort();
+                let mut layers: Option<Vec<_>> =
+                    tenant.layers.as_ref().map(|layers| layers.iter().cloned().collect());
+                if let Some(layers) = &mut layers {
+                    layers.sort();
+                }
+                let usage = TenantUsage {
+                    today,
+                    total,
+                    daily_quota: quota(&tenant.usage),
+                    keys,
+                    layers,
+                };
+                (name.clone(), usage)
+            })
+            .collect();
+        Some(usage)
+    }
+
+    fn tenant_name(&self, tenant: &Arc<Tenant>) -> Option<String> {
+        self.tenants
+            .iter()
+            .find(|(_, t)| Arc::ptr_eq(t, tenant))
+            .map(|(name, _)| name.clone())
+    }
+}
+
+fn quota(usage: &Usage) -> Option<u64> {
+    (usage.daily_quota > 0).then_some(usage.daily_quota)
+}
+
+/// Middleware rejecting tile requests without a valid key or over quota
+pub async fn require_api_key(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(keys) = &state.api_keys else {
+        return next.run(request).await;
+    };
+    let layer = requested_layer(&state, &mut request).await;
+    match keys.consume(request.uri(), request.headers(), &layer, 1) {
+        Ok(()) => next.run(request).await,
+        Err(e) => {
+            let challenge = matches!(e, AppError::Unauthorized);
+            let mut response = e.into_response();
+            if challenge {
+                response
+                    .headers_mut()
+                    .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            }
+            response
+        }
+    }
+}
+
+/// Layer a tile request is for: its `{name}` or `{layer}` path segment, the
+/// WMTS `LAYER` parameter, or else the base tiles named by `wmts_layer`
+async fn requested_layer(state: &AppState, request: &mut Request) -> String {
+    if let Ok(params) = request.extract_parts::<RawPathParams>().await {
+        let layer = params.iter().find(|(name, _)| *name == "name" || *name == "layer");
+        if let Some((_, layer)) = layer {
+            return layer.to_string();
+        }
+    }
+    let query = Query::<HashMap<String, String>>::try_from_uri(request.uri());
+    query
+        .ok()
+        .and_then(|Query(params)| {
+            params.into_iter().find(|(name, _)| name.eq_ignore_ascii_case("layer"))
+        })
+        .map(|(_, layer)| layer)
+        .unwrap_or_else(|| state.wmts_layer.clone())
+}
+
+/// Key from `?key=` or an `Authorization: Bearer` header
+fn request_key<'a>(uri: &'a Uri, headers: &'a HeaderMap) -> Option<&'a str> {
+    let from_query = uri.query().and_then(|query| {
+        query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("key="))
+            .filter(|key| !key.is_empty())
+    });
+    from_query.or_else(|| {
+        headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(str::trim)
+    })
+}
+
+/// One key per line, optionally followed by its daily quota (`-` for none) and
+/// tenant; `#` starts a comment
+fn read_key_file(path: &Path) -> anyhow::Result<Vec<ApiKeyConfig>> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read API key file {:?}: {}", path, e))?;
+    contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let mut fields = line.split_whitespace();
+            let key = fields.next().unwrap_or_default().to_string();
+            let daily_quota = match fields.next() {
+                Some("-") | None => None,
+                Some(quota) => Some(quota.parse().map_err(|_| {
+                    anyhow::anyhow!("Invalid quota {:?} in API key file {:?}", quota, path)
+                })?),
+            };
+            Ok(ApiKeyConfig {
+                key,
+                name: None,
+                daily_quota,
+                tenant: fields.next().map(str::to_string),
+            })
+        })
+        .collect()
+}
+
+/// Identify an unnamed key in stats without revealing it
+fn masked(key: &str) -> String {
+    let prefix: String = key.chars().take(4).collect();
+    format!("{}…", prefix)
+}
+
+fn current_day() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs() / 86_400)
+        .unwrap_or_default()
+}
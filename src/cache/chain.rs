@@ -0,0 +1,109 @@
+use crate::cache::TileStore;
+use crate::error::Result;
+use crate::types::{TileData, TileKey};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Cache tiers and local sources, consulted in order before upstream
+pub struct TierChain {
+    tiers: Vec<Arc<dyn TileStore>>,
+}
+
+/// A tile found in the chain
+pub struct TierHit {
+    pub tile: Arc<TileData>,
+    /// Position of the tier it came from
+    pub tier: usize,
+}
+
+impl TierChain {
+    pub fn new(tiers: Vec<Arc<dyn TileStore>>) -> Self {
+        Self { tiers }
+    }
+
+    pub fn name(&self, tier: usize) -> &'static str {
+        self.tiers[tier].name()
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.tiers.iter().map(|store| store.name()).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tiers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+
+    /// First tier at or after `from` holding the tile; failing tiers count as misses
+    pub async fn lookup(&self, key: &TileKey, from: usize) -> Option<TierHit> {
+        for (tier, store) in self.tiers.iter().enumerate().skip(from) {
+            match store.get(key).await {
+                Ok(Some(tile)) => return Some(TierHit { tile, tier }),
+                Ok(None) => {}
+                Err(e) => {
+                    let tier = store.name();
+                    tracing::warn!(key = %key, tier, error = %e, "Tier lookup failed");
+                }
+            }
+        }
+        None
+    }
+
+    /// What every tier holds for a tile, failures included, without stopping
+    /// at the first hit
+    pub async fn probe(
+        &self,
+        key: &TileKey,
+    ) -> Vec<(&'static str, Result<Option<Arc<TileData>>>)> {
+        let mut results = Vec::with_capacity(self.tiers.len());
+        for store in &self.tiers {
+            results.push((store.name(), store.get(key).await));
+        }
+        results
+    }
+
+    /// Copy a hit into the writable tiers ahead of the one it was found in
+    pub async fn promote(&self, key: &TileKey, hit: &TierHit) {
+        self.put_all(key, &hit.tile, &self.tiers[..hit.tier]).await;
+    }
+
+    /// Write a tile through to every writable tier
+    pub async fn store(&self, key: &TileKey, tile: &Arc<TileData>) {
+        self.put_all(key, tile, &self.tiers).await;
+    }
+
+    async fn put_all(&self, key: &TileKey, tile: &Arc<TileData>, tiers: &[Arc<dyn TileStore>]) {
+        for store in tiers.iter().filter(|store| store.writable()) {
+            if let Err(e) = store.put(key, tile.clone()).await {
+                tracing::warn!(key = %key, tier = store.name(), error = %e, "Tier write failed");
+            }
+        }
+    }
+
+    /// Remove a tile from every writable tier, returning the tiers known to
+    /// have held it
+    pub async fn remove(&self, key: &TileKey) -> Result<Vec<&'static str>> {
+        let mut removed = Vec::new();
+        for store in self.tiers.iter().filter(|store| store.writable()) {
+            if store.delete(key).await? {
+                removed.push(store.name());
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Wait for every tier's queued writes, up to `timeout` each
+    pub async fn flush(&self, timeout: Duration) -> bool {
+        let mut flushed = true;
+        for store in &self.tiers {
+            if !store.flush(timeout).await {
+                tracing::warn!(tier = store.name(), "Writes still pending");
+                flushed = false;
+            }
+        }
+        flushed
+    }
+}
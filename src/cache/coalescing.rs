@@ -1,11 +1,34 @@
-use crate::types::TileKey;
+use crate::error::{AppError, Result};
+use crate::types::{TileData, TileKey};
 use dashmap::DashMap;
-use std::sync::Arc;
-use tokio::sync::Notify;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// Outcome of a fetch as seen by every request coalesced onto it
+type SharedResult = std::result::Result<Arc<TileData>, Arc<AppError>>;
+
+type Sender = watch::Sender<Option<SharedResult>>;
+
+/// A fetch in progress. It can have two leaders racing once a waiter has taken
+/// over from a stalled one; the first to finish answers everyone.
+struct Flight {
+    receiver: watch::Receiver<Option<SharedResult>>,
+    /// Held strongly only by the leaders' guards
+    sender: Weak<Sender>,
+    taken_over: bool,
+}
+
+impl Flight {
+    fn is_live(&self) -> bool {
+        self.sender.strong_count() > 0
+    }
+}
 
 /// Request coalescing to deduplicate concurrent requests for the same tile
 pub struct RequestCoalescer {
-    in_flight: DashMap<TileKey, Arc<Notify>>,
+    in_flight: DashMap<TileKey, Flight>,
 }
 
 impl RequestCoalescer {
@@ -16,48 +39,162 @@ impl RequestCoalescer {
     }
 
     /// Try to acquire a lock for fetching a tile.
-    /// Returns Ok(guard) if this is the first request for this tile.
-    /// Returns Err(notify) if another request is already fetching this tile.
+    /// Returns Acquired(guard) if this is the first request for this tile.
+    /// Returns Wait(waiter) if another request is already fetching this tile.
     pub fn try_acquire(&self, key: TileKey) -> CoalesceResult<'_> {
-        let notify = Arc::new(Notify::new());
+        let mut entry = self.in_flight.entry(key).or_insert_with(|| Flight {
+            receiver: watch::channel(None).1,
+            sender: Weak::new(),
+            taken_over: false,
+        });
+        // Also replaces a flight whose leaders all went away without cleaning up
+        if entry.is_live() {
+            return CoalesceResult::Wait(Waiter(entry.receiver.clone()));
+        }
+        let (sender, receiver) = watch::channel(None);
+        let sender = Arc::new(sender);
+        *entry = Flight {
+            receiver,
+            sender: Arc::downgrade(&sender),
+            taken_over: false,
+        };
+        CoalesceResult::Acquired(CoalesceGuard {
+            key,
+            in_flight: &self.in_flight,
+            sender,
+        })
+    }
 
-        match self.in_flight.entry(key) {
-            dashmap::Entry::Occupied(entry) => {
-                CoalesceResult::Wait(entry.get().clone())
-            }
-            dashmap::Entry::Vacant(entry) => {
-                entry.insert(notify);
-                CoalesceResult::Acquired(CoalesceGuard {
-                    key,
-                    in_flight: &self.in_flight,
-                })
+    /// Wait for the leader's result, for at most `timeout` unless it is zero.
+    /// With `takeover`, the first waiter to time out becomes a second leader
+    /// while the others keep waiting for whichever finishes first.
+    pub async fn wait(
+        &self,
+        key: TileKey,
+        waiter: Waiter,
+        timeout: Duration,
+        takeover: bool,
+    ) -> WaitOutcome<'_> {
+        let waiter = match waiter.result(timeout).await {
+            Ok(outcome) => return outcome,
+            Err(waiter) => waiter,
+        };
+        if !takeover {
+            return WaitOutcome::TimedOut;
+        }
+        if let Some(guard) = self.take_over(key, &waiter) {
+            return WaitOutcome::TookOver(guard);
+        }
+        match waiter.result(Duration::ZERO).await {
+            Ok(outcome) => outcome,
+            Err(_) => WaitOutcome::Abandoned,
+        }
+    }
+
+    /// Join a stalled flight as its second leader, unless one already has
+    fn take_over(&self, key: TileKey, stalled: &Waiter) -> Option<CoalesceGuard<'_>> {
+        let mut flight = self.in_flight.get_mut(&key)?;
+        if flight.taken_over || !flight.receiver.same_channel(&stalled.0) {
+            return None;
+        }
+        let sender = flight.sender.upgrade()?;
+        flight.taken_over = true;
+        Some(CoalesceGuard {
+            key,
+            in_flight: &self.in_flight,
+            sender,
+        })
+    }
+
+    /// Number of tiles currently being fetched
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.iter().filter(|flight| flight.is_live()).count()
+    }
+
+    /// Wait until no fetch is in flight, giving up after `timeout`.
+    /// Returns whether the coalescer drained.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.in_flight() > 0 {
+            if Instant::now() >= deadline {
+                return false;
             }
+            tokio::time::sleep(Duration::from_millis(50)).await;
         }
+        true
     }
 }
 
 pub enum CoalesceResult<'a> {
     Acquired(CoalesceGuard<'a>),
-    Wait(Arc<Notify>),
+    Wait(Waiter),
+}
+
+/// Handle on another request's fetch
+pub struct Waiter(watch::Receiver<Option<SharedResult>>);
+
+pub enum WaitOutcome<'a> {
+    /// The leader's tile or error
+    Done(Result<Arc<TileData>>),
+    /// The leader gave up without a result, e.g. because its client disconnected
+    Abandoned,
+    /// The leader took longer than the wait timeout
+    TimedOut,
+    /// The leader took longer than the wait timeout and this request now fetches too
+    TookOver(CoalesceGuard<'a>),
+}
+
+impl Waiter {
+    async fn result<'a>(mut self, timeout: Duration) -> std::result::Result<WaitOutcome<'a>, Self> {
+        let wait = async { self.0.wait_for(Option::is_some).await.map(|result| result.clone()) };
+        let waited = if timeout.is_zero() {
+            Ok(wait.await)
+        } else {
+            tokio::time::timeout(timeout, wait).await
+        };
+        match waited {
+            Ok(Ok(Some(result))) => Ok(WaitOutcome::Done(result.map_err(AppError::Shared))),
+            Ok(_) => Ok(WaitOutcome::Abandoned),
+            Err(_) => Err(self),
+        }
+    }
 }
 
 pub struct CoalesceGuard<'a> {
     key: TileKey,
-    in_flight: &'a DashMap<TileKey, Arc<Notify>>,
+    in_flight: &'a DashMap<TileKey, Flight>,
+    sender: Arc<Sender>,
 }
 
 impl<'a> CoalesceGuard<'a> {
-    pub fn complete(self) {
-        if let Some((_, notify)) = self.in_flight.remove(&self.key) {
-            notify.notify_waiters();
-        }
+    /// Hand the fetch result to every waiter, unless a racing leader already
+    /// has, and release the key
+    pub fn complete(self, result: Result<Arc<TileData>>) -> Result<Arc<TileData>> {
+        let result = result.map_err(Arc::new);
+        self.sender.send_if_modified(|current| {
+            if current.is_some() {
+                return false;
+            }
+            *current = Some(result.clone());
+            true
+        });
+        self.release();
+        result.map_err(AppError::Shared)
+    }
+
+    fn release(&self) {
+        let receiver = self.sender.subscribe();
+        self.in_flight
+            .remove_if(&self.key, |_, flight| flight.receiver.same_channel(&receiver));
     }
 }
 
 impl<'a> Drop for CoalesceGuard<'a> {
     fn drop(&mut self) {
-        if let Some((_, notify)) = self.in_flight.remove(&self.key) {
-            notify.notify_waiters();
+        // A racing leader may still be fetching; waiters see the channel close
+        // once the last guard is gone
+        if Arc::strong_count(&self.sender) == 1 {
+            self.release();
         }
     }
 }
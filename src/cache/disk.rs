@@ -1,85 +1,1358 @@
+use crate::cache::eviction::{Candidate, Evictor};
 use crate::config::Config;
-use crate::error::Result;
-use crate::types::{TileData, TileKey};
+use crate::error::{AppError, Result};
+use crate::types::{
+    generation_dir, is_generated_etag, is_path_component, TileData, TileFormat, TileKey, TilePath,
+    Validators,
+};
 use bytes::Bytes;
 use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::{self, File};
-use std::io::Write;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
-/// Disk cache with zero-copy reads via mmap
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct DiskUsage {
+    pub tiles: u64,
+    /// Shared blobs when deduplication is on
+    pub blobs: u64,
+    pub bytes: u64,
+    /// Tiles by zoom level, not counting the blobs they point at
+    pub zooms: BTreeMap<u8, ZoomUsage>,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct ZoomUsage {
+    pub tiles: u64,
+    pub bytes: u64,
+}
+
+impl DiskUsage {
+    fn add_tile(&mut self, z: u8, bytes: u64) {
+        let zoom = self.zooms.entry(z).or_default();
+        zoom.tiles += 1;
+        zoom.bytes += bytes;
+        self.tiles += 1;
+        self.bytes += bytes;
+    }
+
+    fn remove_tile(&mut self, z: u8, bytes: u64) {
+        if let Some(zoom) = self.zooms.get_mut(&z) {
+            zoom.tiles = zoom.tiles.saturating_sub(1);
+            zoom.bytes = zoom.bytes.saturating_sub(bytes);
+        }
+        self.tiles = self.tiles.saturating_sub(1);
+        self.bytes = self.bytes.saturating_sub(bytes);
+    }
+
+    fn add_blob(&mut self, bytes: u64) {
+        self.blobs += 1;
+        self.bytes += bytes;
+    }
+
+    fn remove_blob(&mut self, bytes: u64) {
+        self.blobs = self.blobs.saturating_sub(1);
+        self.bytes = self.bytes.saturating_sub(bytes);
+    }
+
+    /// Forget every tile, keeping the blobs
+    fn clear_tiles(&mut self) {
+        let tile_bytes: u64 = self.zooms.values().map(|zoom| zoom.bytes).sum();
+        self.bytes = self.bytes.saturating_sub(tile_bytes);
+        self.tiles = 0;
+        self.zooms.clear();
+    }
+}
+
+/// What a disk cache holds, taken from its first sweep and then kept current
+/// as tiles are stored and removed, so sizes don't take a walk of the cache.
+/// Each sweep recounts, settling any drift from files changed while it ran.
+#[derive(Default)]
+struct DiskIndex(Mutex<Option<DiskUsage>>);
+
+impl DiskIndex {
+    /// Usage as last counted, None before the first sweep
+    fn usage(&self) -> Option<DiskUsage> {
+        self.0.lock().expect("disk index lock poisoned").clone()
+    }
+
+    fn reset(&self, usage: DiskUsage) {
+        *self.0.lock().expect("disk index lock poisoned") = Some(usage);
+    }
+
+    /// Apply a change, once there's a count to apply it to
+    fn update(&self, change: impl FnOnce(&mut DiskUsage)) {
+        if let Some(usage) = self.0.lock().expect("disk index lock poisoned").as_mut() {
+            change(usage);
+        }
+    }
+}
+
+/// Smaller tiles are read into memory; a mapping per tile costs more than the copy
+const MMAP_MIN_LEN: u64 = 64 * 1024;
+
+/// Files removed by a cache sweep
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SweepSummary {
+    pub tmp_files: u64,
+    pub invalid_tiles: u64,
+    pub orphaned_blobs: u64,
+    /// Directories of earlier cache generations
+    pub old_generations: u64,
+    /// Tile and blob directories left empty by removed files
+    pub empty_dirs: u64,
+}
+
+/// Tiles removed to bring the cache back under its size limit
+#[derive(Debug, Default, Clone, Copy)]
+pub struct EvictionSummary {
+    pub tiles: u64,
+    pub bytes: u64,
+}
+
+/// A tile's files on disk; times are Unix seconds, and tile fields are absent
+/// when there is no tile file
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct DiskTileInfo {
+    pub path: PathBuf,
+    /// Size of the tile file, excluding any shared blob
+    pub bytes: Option<u64>,
+    /// When the tile was first written, from its header
+    pub stored_at: Option<u64>,
+    /// When the tile was last fetched or revalidated
+    pub refreshed_at: Option<u64>,
+    /// Last read, where the filesystem records it
+    pub accessed_at: Option<u64>,
+    pub compression: Option<String>,
+    /// Content hash of the shared blob holding the data when deduplicated
+    pub blob: Option<String>,
+    /// When upstream last reported the tile missing
+    pub tombstoned_at: Option<u64>,
+}
+
+/// Eviction stops once the cache is down to this share of its limit, so it
+/// doesn't run again on the next few stored tiles
+const EVICTION_TARGET: f64 = 0.9;
+
+/// Coordinates per directory level of the sharded layout
+const SHARD: u32 = 1000;
+
+/// How tile files are arranged under the cache directory
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiskLayout {
+    /// `z/x/y.png`, one directory per column
+    #[default]
+    Flat,
+    /// `z/xxx/xxx/xxx/yyy/yyy/y.png`, coordinates split into groups of three
+    /// digits so no directory holds more than a thousand entries
+    Sharded,
+}
+
+impl DiskLayout {
+    /// Directory holding a tile, relative to the cache directory
+    fn tile_dir(self, key: &TileKey) -> PathBuf {
+        match self {
+            DiskLayout::Flat => PathBuf::from(format!("{}/{}", key.z, key.x)),
+            DiskLayout::Sharded => PathBuf::from(format!(
+                "{}/{:03}/{:03}/{:03}/{:03}/{:03}",
+                key.z,
+                key.x / (SHARD * SHARD),
+                key.x / SHARD % SHARD,
+                key.x % SHARD,
+                key.y / (SHARD * SHARD),
+                key.y / SHARD % SHARD,
+            )),
+        }
+    }
+
+    /// Key as named within its directory, with only the row's last group
+    fn local_key(self, key: &TileKey) -> TileKey {
+        match self {
+            DiskLayout::Flat => *key,
+            DiskLayout::Sharded => TileKey {
+                y: key.y % SHARD,
+                ..*key
+            },
+        }
+    }
+}
+
+impl FromStr for DiskLayout {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "flat" => Ok(DiskLayout::Flat),
+            "sharded" => Ok(DiskLayout::Sharded),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A directory of tile files: zoom, column, and the row its file names count from
+struct TileDir {
+    z: u8,
+    x: u32,
+    y_base: u32,
+    generation: u32,
+    path: PathBuf,
+}
+
+/// Disk cache, serving large tiles zero-copy from mmap
 #[derive(Clone)]
 pub struct DiskCache {
     base_dir: PathBuf,
+    layout: DiskLayout,
+    /// zstd level for stored tiles, 0 stores them as received
+    compression_level: i32,
+    /// Store tile data in shared content-addressed blobs
+    dedup: bool,
+    /// Generation new keys are stamped with and walks look at, shared by clones
+    generation: Arc<AtomicU32>,
+    /// Running totals of the current generation, shared by clones
+    index: Arc<DiskIndex>,
 }
 
 impl DiskCache {
     pub fn new(config: &Config) -> Result<Self> {
         fs::create_dir_all(&config.cache_dir)?;
+        // Bumps through the admin API outlive a restart unless the config
+        // has since moved past them
+        let bumped = fs::read_to_string(config.cache_dir.join(GENERATION_FILE))
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(0);
         Ok(Self {
             base_dir: config.cache_dir.clone(),
+            layout: config.disk_layout,
+            compression_level: config.disk_compression_level,
+            dedup: config.disk_dedup,
+            generation: Arc::new(AtomicU32::new(config.cache_generation.max(bumped))),
+            index: Arc::default(),
         })
     }
 
-    fn tile_path(&self, key: &TileKey) -> PathBuf {
-        self.base_dir.join(key.to_path())
+    /// A cache in a subdirectory, with this one's settings and generation.
+    /// `name` must be a single plain path component, keeping the cache inside
+    /// this one's directory.
+    pub fn subdirectory(&self, name: &str) -> Result<Self> {
+        if !is_path_component(name) {
+            return Err(AppError::InvalidPath(name.to_string()));
+        }
+        Ok(Self {
+            base_dir: self.base_dir.join(name),
+            index: Arc::default(),
+            ..self.clone()
+        })
+    }
+
+    /// Caches in each existing subdirectory
+    pub fn subdirectories(&self) -> Vec<Self> {
+        fs::read_dir(&self.base_dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter(|entry| entry.file_type().is_ok_and(|t| t.is_dir()))
+            .filter_map(|entry| self.subdirectory(entry.file_name().to_str()?).ok())
+            .collect()
+    }
+
+    /// Current cache generation, which every tile key should carry
+    pub fn generation(&self) -> u32 {
+        self.generation.load(Ordering::Relaxed)
+    }
+
+    /// Move to a new generation, persisting it in the cache directory, and
+    /// return it. Earlier generations' files stay until `prune_generations`.
+    pub fn bump_generation(&self) -> Result<u32> {
+        let next = self.generation() + 1;
+        write_atomic(&self.base_dir.join(GENERATION_FILE), &[next.to_string().as_bytes()])?;
+        self.generation.store(next, Ordering::Relaxed);
+        self.index.update(DiskUsage::clear_tiles);
+        Ok(next)
+    }
+
+    /// Directory holding a generation's tiles
+    fn generation_root(&self, generation: u32) -> PathBuf {
+        match generation_dir(generation) {
+            Some(dir) => self.base_dir.join(dir),
+            None => self.base_dir.clone(),
+        }
+    }
+
+    /// Where a tile is stored, an error for a key outside the tile grid or a
+    /// path that would leave the cache directory
+    fn tile_path(&self, key: &TileKey) -> Result<PathBuf> {
+        let name = self.layout.local_key(&tile_path(key)?.key()).file_name();
+        self.tile_file(key, &name)
+    }
+
+    /// Sidecar file stored next to a tile. Standard PNG tiles keep the
+    /// original `{y}.{suffix}` naming so existing caches stay valid.
+    fn sidecar_path(&self, key: &TileKey, suffix: &str) -> Result<PathBuf> {
+        let local = self.layout.local_key(&tile_path(key)?.key());
+        let name = if key.format == TileFormat::Png && key.scale == 1 {
+            format!("{}.{}", local.y, suffix)
+        } else {
+            format!("{}.{}", local.file_name(), suffix)
+        };
+        self.tile_file(key, &name)
     }
 
-    fn etag_path(&self, key: &TileKey) -> PathBuf {
-        self.base_dir
-            .join(format!("{}/{}/{}.etag", key.z, key.x, key.y))
+    /// File `name` in a tile's directory, checked to lie below the cache
+    /// directory through plain names only
+    fn tile_file(&self, key: &TileKey, name: &str) -> Result<PathBuf> {
+        let dir = self.layout.tile_dir(key);
+        let relative = match generation_dir(key.generation) {
+            Some(generation) => Path::new(&generation).join(dir).join(name),
+            None => dir.join(name),
+        };
+        let contained = relative
+            .components()
+            .all(|part| part.as_os_str().to_str().is_some_and(is_path_component));
+        if !contained {
+            return Err(AppError::InvalidPath(relative.display().to_string()));
+        }
+        Ok(self.base_dir.join(relative))
+    }
+
+    /// Pre-header caches kept the etag and content headers in these sidecars
+    fn etag_path(&self, key: &TileKey) -> Result<PathBuf> {
+        self.sidecar_path(key, "etag")
+    }
+
+    fn meta_path(&self, key: &TileKey) -> Result<PathBuf> {
+        self.sidecar_path(key, "meta")
+    }
+
+    fn tombstone_path(&self, key: &TileKey) -> Result<PathBuf> {
+        self.sidecar_path(key, "404")
+    }
+
+    /// Shared blob holding the data of every tile with this content hash
+    fn blob_path(&self, hash: &str) -> PathBuf {
+        self.base_dir.join(BLOB_DIR).join(&hash[..2]).join(hash)
     }
 
-    /// Get tile from disk using mmap for zero-copy
+    /// Get tile from disk, mapping large files instead of copying them
     pub fn get(&self, key: &TileKey) -> Option<Arc<TileData>> {
-        let path = self.tile_path(key);
-        let file = File::open(&path).ok()?;
+        let path = self.tile_path(key).ok()?;
+        let raw = read_file(&path)?;
 
-        // Use mmap for zero-copy read
-        let mmap = unsafe { Mmap::map(&file).ok()? };
-        let data = Bytes::copy_from_slice(&mmap);
+        let mut checksummed = false;
+        let mut tile = match split_header(&raw) {
+            Some((header, offset)) => {
+                let mut data = match header_field(header, "blob") {
+                    Some(hash) if is_hash(hash) => read_file(&self.blob_path(hash))?,
+                    Some(_) => return self.discard_corrupt(key, "invalid blob reference"),
+                    None => raw.slice(offset..),
+                };
+                // Tiles written before compression was enabled are read as-is
+                if header_field(header, "compression") == Some("zstd")
+                    && data.starts_with(&ZSTD_MAGIC)
+                {
+                    match zstd::decode_all(&data[..]) {
+                        Ok(decoded) => data = Bytes::from(decoded),
+                        Err(_) => return self.discard_corrupt(key, "undecodable zstd data"),
+                    }
+                }
+                if !checksum_matches(header, &data) {
+                    return self.discard_corrupt(key, "checksum mismatch");
+                }
+                checksummed = header_field(header, "crc32").is_some();
+                let mut tile = TileData::new(data, None);
+                apply_header(&mut tile, header);
+                tile
+            }
+            None => self.legacy_tile(key, raw),
+        };
+        // Tiles from before checksums were stored still get their magic bytes checked
+        if !checksummed
+            && tile.content_encoding.is_none()
+            && !key.format.plausible_header(&tile.data)
+        {
+            return self.discard_corrupt(key, "unrecognized image data");
+        }
+        // Revalidation touches the file rather than rewriting its header
+        tile.fetched_at = fs::metadata(&path).and_then(|meta| meta.modified()).ok();
+        Some(Arc::new(tile))
+    }
 
-        // Try to read etag
-        let etag = fs::read_to_string(self.etag_path(key)).ok();
+    /// Delete a damaged tile, left by a crash or disk error, so it's fetched again
+    fn discard_corrupt(&self, key: &TileKey, reason: &str) -> Option<Arc<TileData>> {
+        tracing::warn!(key = %key, reason, "Discarding corrupted disk cache tile");
+        if let Err(e) = self.remove(key) {
+            tracing::warn!(key = %key, error = %e, "Failed to remove corrupted tile");
+        }
+        None
+    }
 
-        Some(Arc::new(TileData::new(data, etag)))
+    /// Tile written before metadata was embedded, with `.etag`/`.meta` sidecars
+    fn legacy_tile(&self, key: &TileKey, data: Bytes) -> TileData {
+        let etag = self.etag_path(key).and_then(|path| Ok(fs::read_to_string(path)?));
+        let mut tile = TileData::new(data, etag.ok());
+        if let Ok(meta) = self.meta_path(key).and_then(|path| Ok(fs::read_to_string(path)?)) {
+            apply_header(&mut tile, &meta);
+        }
+        tile
+    }
+
+    /// Run `task` against this cache on the blocking pool, keeping the
+    /// filesystem off async workers
+    async fn blocking<T, F>(&self, key: &TileKey, task: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce(&DiskCache, &TileKey) -> T + Send + 'static,
+    {
+        let cache = self.clone();
+        let key = *key;
+        tokio::task::spawn_blocking(move || task(&cache, &key))
+            .await
+            .expect("disk cache task panicked")
+    }
+
+    /// Read a tile on the blocking pool
+    pub async fn load(&self, key: &TileKey) -> Option<Arc<TileData>> {
+        self.blocking(key, Self::get).await
     }
 
     /// Store tile to disk
-    pub fn store(&self, key: &TileKey, data: &Bytes, etag: Option<&str>) -> Result<()> {
-        let path = self.tile_path(key);
+    pub fn store(&self, key: &TileKey, tile: &TileData) -> Result<()> {
+        let path = self.tile_path(key)?;
+        let replaced = fs::metadata(&path).map(|meta| meta.len()).ok();
+        let compressed = self.compress(key, tile);
+        let mut storage = Vec::new();
+        if compressed.is_some() {
+            storage.push(("compression", "zstd".to_string()));
+        }
+        let payload = compressed.as_deref().unwrap_or(&tile.data);
 
-        // Ensure directory exists
-        if let Some(parent) = path.parent() {
-            fs::create_dir_all(parent)?;
+        // Identical tiles share one blob, the tile file only points at it
+        let payload = if self.dedup {
+            let hash = hex(&Sha256::digest(payload));
+            let blob_path = self.blob_path(&hash);
+            if !blob_path.exists() {
+                write_atomic(&blob_path, &[payload])?;
+                self.index.update(|usage| usage.add_blob(payload.len() as u64));
+            }
+            storage.push(("blob", hash));
+            &[][..]
+        } else {
+            payload
+        };
+
+        // Header and data land together, readers never see half a tile
+        let header = encode_header(tile, &storage);
+        write_atomic(&path, &[&header, payload])?;
+        self.track(key, |usage| {
+            if let Some(replaced) = replaced {
+                usage.remove_tile(key.z, replaced);
+            }
+            usage.add_tile(key.z, (header.len() + payload.len()) as u64);
+        });
+        // A tile copied from another tier keeps its age
+        if let Some(fetched_at) = tile.fetched_at {
+            File::options().write(true).open(&path)?.set_modified(fetched_at)?;
         }
 
-        // Write tile data atomically
-        let tmp_path = path.with_extension("tmp");
+        // Sidecars from older versions would now be stale
+        remove_if_exists(&self.etag_path(key)?)?;
+        remove_if_exists(&self.meta_path(key)?)?;
+
+        Ok(())
+    }
+
+    /// zstd-compress a tile for storage when enabled and worthwhile. Already
+    /// compressed formats and encodings are left alone.
+    fn compress(&self, key: &TileKey, tile: &TileData) -> Option<Vec<u8>> {
+        if self.compression_level == 0
+            || tile.content_encoding.is_some()
+            || matches!(key.format, TileFormat::Jpeg | TileFormat::Webp)
         {
-            let mut file = File::create(&tmp_path)?;
-            file.write_all(data)?;
-            file.sync_all()?;
+            return None;
+        }
+        let compressed = zstd::encode_all(&tile.data[..], self.compression_level).ok()?;
+        (compressed.len() < tile.data.len()).then_some(compressed)
+    }
+
+    /// Get stored validators for conditional requests
+    pub fn validators(&self, key: &TileKey) -> Validators {
+        let Ok(mut file) = self.tile_path(key).and_then(|path| Ok(File::open(path)?)) else {
+            return Validators::default();
+        };
+        match read_header(&mut file) {
+            Some(header) => {
+                let mut tile = TileData::new(Bytes::new(), None);
+                apply_header(&mut tile, &header);
+                Validators {
+                    // A locally generated etag would stop the upstream from
+                    // honoring If-Modified-Since
+                    etag: tile.etag.filter(|etag| !is_generated_etag(etag)),
+                    last_modified: tile.last_modified,
+                }
+            }
+            None => Validators {
+                etag: self.etag_path(key).and_then(|path| Ok(fs::read_to_string(path)?)).ok(),
+                last_modified: None,
+            },
+        }
+    }
+
+    /// A tile's metadata, with no data, and the length of the data it would
+    /// serve, read from its header alone. None when it isn't stored or its
+    /// length can't be told without reading it, as for legacy tiles.
+    pub fn head(&self, key: &TileKey) -> Option<(TileData, u64)> {
+        let mut file = File::open(self.tile_path(key).ok()?).ok()?;
+        let header = read_header(&mut file)?;
+        let meta = file.metadata().ok()?;
+        let stored_as_is = header_field(&header, "compression").is_none()
+            && header_field(&header, "blob").is_none();
+        let len = match header_field(&header, "length") {
+            Some(len) => len.parse().ok()?,
+            // Tiles written before lengths were stored keep their data after the header
+            None if stored_as_is => {
+                meta.len().checked_sub((HEADER_MAGIC.len() + 4 + header.len()) as u64)?
+            }
+            None => return None,
+        };
+        let mut tile = TileData::new(Bytes::new(), None);
+        apply_header(&mut tile, &header);
+        tile.fetched_at = meta.modified().ok();
+        Some((tile, len))
+    }
+
+    /// Stored validators, read on the blocking pool
+    pub async fn load_validators(&self, key: &TileKey) -> Validators {
+        self.blocking(key, Self::validators).await
+    }
+
+    /// Remove a tile and any sidecars, returning whether the tile was present
+    pub fn remove(&self, key: &TileKey) -> Result<bool> {
+        let path = self.tile_path(key)?;
+        let bytes = fs::metadata(&path).map(|meta| meta.len()).unwrap_or(0);
+        let removed = remove_if_exists(&path)?;
+        if removed {
+            self.track(key, |usage| usage.remove_tile(key.z, bytes));
         }
-        fs::rename(&tmp_path, &path)?;
+        remove_if_exists(&self.etag_path(key)?)?;
+        remove_if_exists(&self.meta_path(key)?)?;
+        remove_if_exists(&self.tombstone_path(key)?)?;
+        self.remove_empty_parents(key);
+        Ok(removed)
+    }
 
-        // Store etag if present
-        if let Some(etag) = etag {
-            let etag_path = self.etag_path(key);
-            fs::write(etag_path, etag)?;
+    /// Count a change to a tile in the index, if it's of the generation the
+    /// index covers
+    fn track(&self, key: &TileKey, change: impl FnOnce(&mut DiskUsage)) {
+        if key.generation == self.generation() {
+            self.index.update(change);
         }
+    }
+
+    /// Drop the directories above a tile that its removal left empty, up to
+    /// its generation's root; `remove_dir` fails on the rest
+    fn remove_empty_parents(&self, key: &TileKey) {
+        let root = self.generation_root(key.generation);
+        let Ok(mut dir) = self.tile_path(key) else {
+            return;
+        };
+        while dir.pop() && dir != root && fs::remove_dir(&dir).is_ok() {}
+    }
+
+    /// Time since the tile was last fetched or revalidated
+    pub fn age(&self, key: &TileKey) -> Option<Duration> {
+        let modified = fs::metadata(self.tile_path(key).ok()?).ok()?.modified().ok()?;
+        modified.elapsed().ok()
+    }
+
+    /// Time since the tile was last fetched or revalidated, read on the blocking pool
+    pub async fn tile_age(&self, key: &TileKey) -> Option<Duration> {
+        self.blocking(key, Self::age).await
+    }
+
+    /// Mark a tile as freshly revalidated
+    pub fn touch(&self, key: &TileKey) -> Result<()> {
+        let file = File::options().write(true).open(self.tile_path(key)?)?;
+        file.set_modified(SystemTime::now())?;
+        Ok(())
+    }
+
+    /// Mark a tile as freshly revalidated, on the blocking pool
+    pub async fn mark_revalidated(&self, key: &TileKey) -> Result<()> {
+        self.blocking(key, Self::touch).await
+    }
 
+    /// Record that upstream has no tile for this key
+    pub fn store_tombstone(&self, key: &TileKey) -> Result<()> {
+        create_file(&self.tombstone_path(key)?)?;
         Ok(())
     }
 
-    /// Get stored etag for conditional requests
-    pub fn get_etag(&self, key: &TileKey) -> Option<String> {
-        fs::read_to_string(self.etag_path(key)).ok()
+    /// Record that upstream has no tile for this key, on the blocking pool
+    pub async fn save_tombstone(&self, key: &TileKey) -> Result<()> {
+        self.blocking(key, Self::store_tombstone).await
+    }
+
+    /// Time since upstream last reported the tile missing, read on the blocking pool
+    pub async fn load_tombstone_age(&self, key: &TileKey) -> Option<Duration> {
+        self.blocking(key, Self::tombstone_age).await
+    }
+
+    /// Time since upstream last reported the tile missing
+    pub fn tombstone_age(&self, key: &TileKey) -> Option<Duration> {
+        let modified = fs::metadata(self.tombstone_path(key).ok()?).ok()?.modified().ok()?;
+        modified.elapsed().ok()
+    }
+
+    /// Where a tile is kept and what its file says about it, for debugging
+    pub fn inspect(&self, key: &TileKey) -> Result<DiskTileInfo> {
+        let path = self.tile_path(key)?;
+        let mut info = DiskTileInfo {
+            tombstoned_at: fs::metadata(self.tombstone_path(key)?)
+                .and_then(|meta| meta.modified())
+                .ok()
+                .and_then(unix_secs),
+            ..DiskTileInfo::default()
+        };
+        if let Ok(mut file) = File::open(&path) {
+            if let Ok(meta) = file.metadata() {
+                info.bytes = Some(meta.len());
+                info.refreshed_at = meta.modified().ok().and_then(unix_secs);
+                info.accessed_at = meta.accessed().ok().and_then(unix_secs);
+            }
+            if let Some(header) = read_header(&mut file) {
+                let field = |name| header_field(&header, name).map(str::to_string);
+                info.stored_at = field("stored-at").and_then(|secs| secs.parse().ok());
+                info.compression = field("compression");
+                info.blob = field("blob");
+            }
+        }
+        info.path = path;
+        Ok(info)
     }
 
     /// Check if tile exists on disk
     pub fn exists(&self, key: &TileKey) -> bool {
-        self.tile_path(key).exists()
+        self.tile_path(key).is_ok_and(|path| path.exists())
+    }
+
+    /// Check if tile exists on disk, on the blocking pool
+    pub async fn contains(&self, key: &TileKey) -> bool {
+        self.blocking(key, Self::exists).await
+    }
+
+    /// Verify the cache directory accepts writes
+    pub fn check_writable(&self) -> Result<()> {
+        let path = self.base_dir.join(".write-check");
+        fs::write(&path, b"ok")?;
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    /// Remove temp files left behind by interrupted writes once they are older
+    /// than `min_tmp_age`, along with empty or truncated tiles, and recount
+    /// the index from what's left
+    pub fn sweep(&self, min_tmp_age: Duration) -> SweepSummary {
+        let mut summary = SweepSummary {
+            old_generations: self.prune_generations(),
+            ..SweepSummary::default()
+        };
+        let mut referenced = HashSet::new();
+        let mut usage = DiskUsage::default();
+        for dir in self.tile_dirs() {
+            for entry in fs::read_dir(&dir.path).into_iter().flatten().flatten() {
+                let path = entry.path();
+                let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                    continue;
+                };
+
+                let tile_key = TilePath::from_filename(dir.z, dir.x, &name).map(TilePath::key);
+                let remove = if name.ends_with(".tmp") {
+                    let age = entry
+                        .metadata()
+                        .and_then(|m| m.modified())
+                        .ok()
+                        .and_then(|modified| modified.elapsed().ok());
+                    age.is_some_and(|age| age >= min_tmp_age)
+                } else if let Some(key) = tile_key {
+                    let (header, head) = read_head(&path);
+                    let header = header.unwrap_or_default();
+                    if let Some(hash) = header_field(&header, "blob") {
+                        let present = is_hash(hash) && self.blob_path(hash).exists();
+                        if present {
+                            referenced.insert(hash.to_string());
+                        }
+                        !present
+                    } else if header_field(&header, "compression") == Some("zstd") {
+                        !head.starts_with(&ZSTD_MAGIC)
+                    } else {
+                        !key.format.plausible_header(&head)
+                    }
+                } else {
+                    continue;
+                };
+                if !remove {
+                    if let (Some(_), Ok(meta)) = (tile_key, entry.metadata()) {
+                        usage.add_tile(dir.z, meta.len());
+                    }
+                    continue;
+                }
+
+                match fs::remove_file(&path) {
+                    Ok(()) if name.ends_with(".tmp") => summary.tmp_files += 1,
+                    Ok(()) => summary.invalid_tiles += 1,
+                    Err(e) => tracing::warn!(path = ?path, error = %e, "Failed to remove file"),
+                }
+            }
+        }
+        self.collect_blobs(&referenced, min_tmp_age, &mut summary);
+        self.count_blobs(&mut usage);
+        self.index.reset(usage);
+        summary.empty_dirs = self.prune_empty_dirs();
+        summary
+    }
+
+    /// Remove every empty tile and blob directory of the current generation
+    /// in one walk, returning how many went
+    pub fn prune_empty_dirs(&self) -> u64 {
+        let mut removed = 0;
+        for (_, z_dir) in numeric_dirs::<u8>(&self.generation_root(self.generation())) {
+            remove_empty_dirs(&z_dir, &mut removed);
+        }
+        for entry in fs::read_dir(self.base_dir.join(BLOB_DIR)).into_iter().flatten().flatten() {
+            if fs::remove_dir(entry.path()).is_ok() {
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    /// Delete the directories of every generation but the current one,
+    /// returning how many were removed
+    pub fn prune_generations(&self) -> u64 {
+        let current = self.generation();
+        let mut removed = 0;
+        if current > 0 {
+            // The first generation's tiles sit directly in the cache directory
+            let zooms: Vec<_> = numeric_dirs::<u8>(&self.base_dir).collect();
+            for (_, path) in &zooms {
+                if let Err(e) = fs::remove_dir_all(path) {
+                    tracing::warn!(path = ?path, error = %e, "Failed to remove old generation");
+                }
+            }
+            removed += u64::from(!zooms.is_empty());
+        }
+        for entry in fs::read_dir(&self.base_dir).into_iter().flatten().flatten() {
+            let name = entry.file_name();
+            let old = name
+                .to_str()
+                .and_then(|name| name.strip_prefix("gen-"))
+                .and_then(|generation| generation.parse::<u32>().ok())
+                .is_some_and(|generation| generation != current);
+            if !old {
+                continue;
+            }
+            match fs::remove_dir_all(entry.path()) {
+                Ok(()) => removed += 1,
+                Err(e) => {
+                    tracing::warn!(path = ?entry.path(), error = %e, "Failed to remove old generation")
+                }
+            }
+        }
+        removed
+    }
+
+    /// Remove blobs no tile points at any more. Young blobs are kept, their
+    /// tile file may not have been written yet.
+    fn collect_blobs(
+        &self,
+        referenced: &HashSet<String>,
+        min_age: Duration,
+        summary: &mut SweepSummary,
+    ) {
+        for entry in self.blob_files() {
+            let old_enough = entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .is_some_and(|age| age >= min_age);
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if !old_enough || referenced.contains(name.as_ref()) {
+                continue;
+            }
+            match fs::remove_file(entry.path()) {
+                Ok(()) if name.ends_with(".tmp") => summary.tmp_files += 1,
+                Ok(()) => summary.orphaned_blobs += 1,
+                Err(e) => tracing::warn!(path = ?entry.path(), error = %e, "Failed to remove blob"),
+            }
+        }
+    }
+
+    fn blob_files(&self) -> impl Iterator<Item = fs::DirEntry> {
+        fs::read_dir(self.base_dir.join(BLOB_DIR))
+            .into_iter()
+            .flatten()
+            .flatten()
+            .flat_map(|prefix| fs::read_dir(prefix.path()).into_iter().flatten().flatten())
+    }
+
+    /// Remove tiles in the order `evictor` ranks them until the cache is back
+    /// under 90% of `max_bytes`, if it is over it. A blob goes with the last
+    /// tile pointing at it.
+    pub fn evict(&self, max_bytes: u64, evictor: &dyn Evictor) -> EvictionSummary {
+        let mut summary = EvictionSummary::default();
+        let mut bytes = self.usage().bytes;
+        if bytes <= max_bytes {
+            return summary;
+        }
+        let target = (max_bytes as f64 * EVICTION_TARGET) as u64;
+
+        let mut candidates: Vec<Candidate> = self
+            .keys()
+            .filter_map(|key| {
+                let path = self.tile_path(&key).ok()?;
+                let metadata = fs::metadata(&path).ok()?;
+                let blob = read_head(&path).0.and_then(|header| {
+                    header_field(&header, "blob").filter(|hash| is_hash(hash)).map(str::to_string)
+                });
+                Some(Candidate {
+                    key,
+                    bytes: metadata.len(),
+                    refreshed: metadata.modified().ok()?,
+                    blob,
+                })
+            })
+            .collect();
+        // Blob sizes and how many tiles share each
+        let mut blobs: HashMap<String, (u64, usize)> = HashMap::new();
+        for hash in candidates.iter().filter_map(|c| c.blob.as_ref()) {
+            let entry = blobs.entry(hash.clone()).or_insert_with(|| {
+                let size = fs::metadata(self.blob_path(hash)).map_or(0, |m| m.len());
+                (size, 0)
+            });
+            entry.1 += 1;
+        }
+        // A blob only one tile uses counts towards that tile's size
+        let mut counted = HashSet::new();
+        for candidate in &mut candidates {
+            let Some(hash) = &candidate.blob else {
+                continue;
+            };
+            if let Some((size, 1)) = blobs.get(hash) {
+                candidate.bytes += size;
+                counted.insert(hash.clone());
+            }
+        }
+
+        evictor.rank(&mut candidates);
+        for candidate in candidates {
+            if bytes <= target {
+                break;
+            }
+            match self.remove(&candidate.key) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(e) => {
+                    tracing::warn!(key = %candidate.key, error = %e, "Failed to evict tile");
+                    continue;
+                }
+            }
+            let mut freed = candidate.bytes;
+            if let Some(hash) = &candidate.blob {
+                if let Some((size, refs)) = blobs.get_mut(hash) {
+                    *refs -= 1;
+                    let removed = *refs == 0 && remove_if_exists(&self.blob_path(hash)).is_ok();
+                    if removed {
+                        self.index.update(|usage| usage.remove_blob(*size));
+                    }
+                    if removed && !counted.contains(hash) {
+                        freed += *size;
+                    }
+                }
+            }
+            summary.tiles += 1;
+            summary.bytes += freed;
+            bytes = bytes.saturating_sub(freed);
+        }
+        summary
+    }
+
+    /// Stored tiles and their total size, from the index once a sweep has
+    /// counted them and by walking the cache directory until then
+    pub fn usage(&self) -> DiskUsage {
+        if let Some(usage) = self.index.usage() {
+            return usage;
+        }
+        let mut usage = DiskUsage::default();
+        for key in self.keys() {
+            if let Ok(metadata) = self.tile_path(&key).and_then(|path| Ok(fs::metadata(path)?)) {
+                usage.add_tile(key.z, metadata.len());
+            }
+        }
+        self.count_blobs(&mut usage);
+        usage
+    }
+
+    /// Add the stored blobs to `usage`, leaving out unfinished writes
+    fn count_blobs(&self, usage: &mut DiskUsage) {
+        for entry in self.blob_files() {
+            let finished = !entry.file_name().to_string_lossy().ends_with(".tmp");
+            if let (true, Ok(metadata)) = (finished, entry.metadata()) {
+                usage.add_blob(metadata.len());
+            }
+        }
+    }
+
+    /// Lazily walk the cache directory, yielding every stored tile
+    pub fn keys(&self) -> impl Iterator<Item = TileKey> + '_ {
+        self.tile_dirs().flat_map(|dir| {
+            fs::read_dir(&dir.path)
+                .into_iter()
+                .flatten()
+                .flatten()
+                .filter_map(move |entry| {
+                    let name = entry.file_name();
+                    let key = TilePath::from_filename(dir.z, dir.x, name.to_str()?)?.key();
+                    Some(TileKey {
+                        y: dir.y_base + key.y,
+                        generation: dir.generation,
+                        ..key
+                    })
+                })
+        })
+    }
+
+    /// Lazily walk the directories that hold the current generation's tile
+    /// files in this cache's layout
+    fn tile_dirs(&self) -> Box<dyn Iterator<Item = TileDir> + '_> {
+        let generation = self.generation();
+        let zooms = numeric_dirs::<u8>(&self.generation_root(generation));
+        match self.layout {
+            DiskLayout::Flat => Box::new(zooms.flat_map(move |(z, z_dir)| {
+                numeric_dirs::<u32>(&z_dir).map(move |(x, path)| TileDir {
+                    z,
+                    x,
+                    y_base: 0,
+                    generation,
+                    path,
+                })
+            })),
+            DiskLayout::Sharded => Box::new(zooms.flat_map(move |(z, z_dir)| {
+                shards(&z_dir, 3).flat_map(move |(x, x_dir)| {
+                    shards(&x_dir, 2).map(move |(y, path)| TileDir {
+                        z,
+                        x,
+                        y_base: y * SHARD,
+                        generation,
+                        path,
+                    })
+                })
+            })),
+        }
+    }
+
+    /// Move every tile stored in `from` layout into this cache's layout,
+    /// returning how many were moved. Tombstones without a tile are dropped.
+    pub fn migrate_from(&self, from: DiskLayout) -> Result<u64> {
+        if from == self.layout {
+            return Ok(0);
+        }
+        let old = Self {
+            layout: from,
+            ..self.clone()
+        };
+
+        // Collect first, the walk would otherwise see directories being emptied
+        let keys: Vec<TileKey> = old.keys().collect();
+        let mut moved = 0;
+        for key in keys {
+            let target = self.tile_path(&key)?;
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::rename(old.tile_path(&key)?, &target)?;
+            for suffix in ["etag", "meta", "404"] {
+                let sidecar = old.sidecar_path(&key, suffix)?;
+                if sidecar.exists() {
+                    fs::rename(sidecar, self.sidecar_path(&key, suffix)?)?;
+                }
+            }
+            moved += 1;
+            old.remove_empty_parents(&key);
+        }
+        Ok(moved)
+    }
+}
+
+/// Marks a tile file that starts with an embedded metadata header
+const HEADER_MAGIC: &[u8; 4] = b"MTC\x01";
+
+/// Headers are small; anything larger means the file isn't ours
+const MAX_HEADER_LEN: usize = 16 * 1024;
+
+/// Start of every zstd frame
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Subdirectory of the cache holding deduplicated tile data, shared by all
+/// generations
+const BLOB_DIR: &str = "blobs";
+
+/// File in the cache directory recording the generation last bumped to
+const GENERATION_FILE: &str = "generation";
+
+/// Metadata header: magic, little-endian u32 length, then `name: value` lines.
+/// `storage` describes how the data is kept, e.g. its compression.
+fn encode_header(tile: &TileData, storage: &[(&str, String)]) -> Vec<u8> {
+    let mut lines = String::new();
+    for (name, value) in storage {
+        lines.push_str(&format!("{}: {}\n", name, value));
+    }
+    if let Some(etag) = &tile.etag {
+        lines.push_str(&format!("etag: {}\n", etag));
+    }
+    if let Some(content_type) = &tile.content_type {
+        lines.push_str(&format!("content-type: {}\n", content_type));
+    }
+    if let Some(content_encoding) = &tile.content_encoding {
+        lines.push_str(&format!("content-encoding: {}\n", content_encoding));
+    }
+    if let Some(last_modified) = &tile.last_modified {
+        lines.push_str(&format!("last-modified: {}\n", last_modified));
+    }
+    if let Some(max_age) = tile.max_age {
+        lines.push_str(&format!("max-age: {}\n", max_age.as_secs()));
+    }
+    if let Some(stored) = unix_secs(SystemTime::now()) {
+        lines.push_str(&format!("stored-at: {}\n", stored));
+    }
+    if let Some(fetched) = tile.fetched_at.and_then(unix_secs) {
+        lines.push_str(&format!("fetched-at: {}\n", fetched));
+    }
+    for (name, value) in &tile.upstream_headers {
+        lines.push_str(&format!("upstream-header: {}: {}\n", name, value));
+    }
+    // Of the tile data as served, before any compression for storage
+    lines.push_str(&format!("length: {}\n", tile.data.len()));
+    lines.push_str(&format!("crc32: {:08x}\n", crc32fast::hash(&tile.data)));
+
+    let mut header = Vec::with_capacity(8 + lines.len());
+    header.extend_from_slice(HEADER_MAGIC);
+    header.extend_from_slice(&(lines.len() as u32).to_le_bytes());
+    header.extend_from_slice(lines.as_bytes());
+    header
+}
+
+/// A tile in the on-disk format, uncompressed, for stores outside the cache
+/// directory
+pub(crate) fn encode_tile(tile: &TileData) -> Vec<u8> {
+    let mut raw = encode_header(tile, &[]);
+    raw.extend_from_slice(&tile.data);
+    raw
+}
+
+/// Parse a tile written by `encode_tile`; ones stored before fetch times
+/// were recorded count from when they were stored
+pub(crate) fn decode_tile(raw: Bytes) -> Option<TileData> {
+    let (header, offset) = split_header(&raw)?;
+    let data = raw.slice(offset..);
+    if !checksum_matches(header, &data) {
+        return None;
+    }
+    let mut tile = TileData::new(data, None);
+    apply_header(&mut tile, header);
+    if tile.fetched_at.is_none() {
+        tile.fetched_at = header_field(header, "stored-at")
+            .and_then(|secs| secs.parse().ok())
+            .map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+    }
+    Some(tile)
+}
+
+/// Split a tile file into its header text and the offset of the tile data
+fn split_header(raw: &[u8]) -> Option<(&str, usize)> {
+    let len = header_len(raw.get(..8)?)?;
+    let text = std::str::from_utf8(raw.get(8..8 + len)?).ok()?;
+    Some((text, 8 + len))
+}
+
+/// Length of the header text following the 8-byte preamble, if present
+fn header_len(preamble: &[u8]) -> Option<usize> {
+    let (magic, len) = preamble.split_at_checked(4)?;
+    if magic != HEADER_MAGIC {
+        return None;
+    }
+    let len = u32::from_le_bytes(len.try_into().ok()?) as usize;
+    (len <= MAX_HEADER_LEN).then_some(len)
+}
+
+/// Read just the header text from the start of a tile file
+fn read_header(file: &mut File) -> Option<String> {
+    let mut preamble = [0; 8];
+    file.read_exact(&mut preamble).ok()?;
+    let mut text = vec![0; header_len(&preamble)?];
+    file.read_exact(&mut text).ok()?;
+    String::from_utf8(text).ok()
+}
+
+/// Value of a header line, e.g. how the stored bytes are compressed on disk
+fn header_field<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+    header.lines().find_map(|line| {
+        let (field, value) = line.split_once(": ")?;
+        (field == name).then_some(value)
+    })
+}
+
+/// Whether the data matches the header's checksum; tiles written before
+/// checksums were stored have none and pass
+fn checksum_matches(header: &str, data: &[u8]) -> bool {
+    header_field(header, "crc32").is_none_or(|expected| {
+        u32::from_str_radix(expected, 16).is_ok_and(|crc| crc == crc32fast::hash(data))
+    })
+}
+
+/// Whole file contents, mapping large files instead of copying them
+fn read_file(path: &Path) -> Option<Bytes> {
+    let mut file = File::open(path).ok()?;
+    let len = file.metadata().ok()?.len();
+    if len >= MMAP_MIN_LEN {
+        // Safety: tiles and blobs are replaced by renaming a new file over them,
+        // never rewritten in place, so the mapped inode doesn't change under us
+        let mmap = unsafe { Mmap::map(&file).ok()? };
+        Some(Bytes::from_owner(mmap))
+    } else {
+        let mut buf = Vec::with_capacity(len as usize);
+        file.read_to_end(&mut buf).ok()?;
+        Some(Bytes::from(buf))
+    }
+}
+
+/// Write `parts` to a temp file next to `path`, then rename it into place
+pub(crate) fn write_atomic(path: &Path, parts: &[&[u8]]) -> Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    {
+        let mut file = create_file(&tmp_path)?;
+        for part in parts {
+            file.write_all(part)?;
+        }
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Blob names are lowercase hex SHA-256 digests
+fn is_hash(value: &str) -> bool {
+    value.len() == 64 && value.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+fn unix_secs(time: SystemTime) -> Option<u64> {
+    time.duration_since(SystemTime::UNIX_EPOCH).ok().map(|since| since.as_secs())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn apply_header(tile: &mut TileData, header: &str) {
+    for line in header.lines() {
+        match line.split_once(": ") {
+            Some(("etag", value)) => tile.etag = Some(value.to_string()),
+            Some(("content-type", value)) => tile.content_type = Some(value.to_string()),
+            Some(("content-encoding", value)) => tile.content_encoding = Some(value.to_string()),
+            Some(("last-modified", value)) => tile.last_modified = Some(value.to_string()),
+            Some(("max-age", value)) => {
+                tile.max_age = value.parse().ok().map(Duration::from_secs);
+            }
+            Some(("fetched-at", value)) => {
+                tile.fetched_at = value
+                    .parse()
+                    .ok()
+                    .map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+            }
+            Some(("upstream-header", header)) => {
+                if let Some((name, value)) = header.split_once(": ") {
+                    tile.upstream_headers.push((name.to_string(), value.to_string()));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A tile file's header, if any, and the first bytes of its data, enough to
+/// recognize the format
+fn read_head(path: &Path) -> (Option<String>, Vec<u8>) {
+    let mut head = Vec::with_capacity(12);
+    let Ok(mut file) = File::open(path) else {
+        return (None, head);
+    };
+    let header = read_header(&mut file);
+    if header.is_none() {
+        // Legacy tile without a header, start over
+        let _ = file.seek(SeekFrom::Start(0));
+    }
+    let _ = file.take(12).read_to_end(&mut head);
+    (header, head)
+}
+
+/// Create a file along with its directory. A directory pruned as empty
+/// between the two is created again.
+fn create_file(path: &Path) -> std::io::Result<File> {
+    let Some(parent) = path.parent() else {
+        return File::create(path);
+    };
+    fs::create_dir_all(parent)?;
+    match File::create(path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            fs::create_dir_all(parent)?;
+            File::create(path)
+        }
+        result => result,
+    }
+}
+
+/// Remove the empty directories under and including `dir`, deepest first,
+/// counting them in `removed`
+fn remove_empty_dirs(dir: &Path, removed: &mut u64) {
+    for (_, child) in numeric_dirs::<u32>(dir) {
+        remove_empty_dirs(&child, removed);
+    }
+    if fs::remove_dir(dir).is_ok() {
+        *removed += 1;
+    }
+}
+
+fn remove_if_exists(path: &Path) -> Result<bool> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Walk `levels` nested shard directories, combining their names into one
+/// number, e.g. `001/234/567` into 1234567
+fn shards(dir: &Path, levels: u32) -> Box<dyn Iterator<Item = (u32, PathBuf)>> {
+    let groups = numeric_dirs::<u32>(dir);
+    if levels == 1 {
+        return Box::new(groups.filter(|(group, _)| *group < SHARD));
+    }
+    Box::new(groups.flat_map(move |(high, path)| {
+        shards(&path, levels - 1).filter_map(move |(low, path)| {
+            let value = high.checked_mul(SHARD.pow(levels - 1))?.checked_add(low)?;
+            Some((value, path))
+        })
+    }))
+}
+
+/// Subdirectories whose names parse as numbers, e.g. zoom or column levels
+fn numeric_dirs<T: FromStr>(dir: &Path) -> impl Iterator<Item = (T, PathBuf)> {
+    fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let value = entry.file_name().to_str()?.parse().ok()?;
+            entry.file_type().ok()?.is_dir().then(|| (value, entry.path()))
+        })
+}
+
+/// A key as a tile path, an error when it falls outside the tile grid
+fn tile_path(key: &TileKey) -> Result<TilePath> {
+    TilePath::new(*key).ok_or_else(|| AppError::InvalidPath(key.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(dir: &Path) -> DiskCache {
+        let config = Config {
+            cache_dir: dir.to_path_buf(),
+            ..Config::builtin()
+        };
+        DiskCache::new(&config).expect("disk cache")
+    }
+
+    #[test]
+    fn subdirectory_takes_only_plain_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(dir.path());
+        for name in ["", ".", "..", "../x", "a/b", "/abs", "a\\b", "a b"] {
+            assert!(
+                matches!(cache.subdirectory(name), Err(AppError::InvalidPath(_))),
+                "{:?} accepted",
+                name
+            );
+        }
+        let layer = cache.subdirectory("grayscale-s0.5").unwrap();
+        assert_eq!(layer.base_dir, dir.path().join("grayscale-s0.5"));
+    }
+
+    #[test]
+    fn tile_paths_stay_inside_the_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let flat = cache(dir.path());
+        let sharded = DiskCache {
+            layout: DiskLayout::Sharded,
+            ..flat.clone()
+        };
+        let keys = [
+            TileKey::new(0, 0, 0),
+            TileKey::new(31, u32::MAX >> 1, u32::MAX >> 1).with_scale(2),
+            TileKey::new(12, 2048, 1361).with_format(TileFormat::Grid).with_generation(7),
+        ];
+        for cache in [&flat, &sharded] {
+            for key in keys {
+                let path = cache.tile_path(&key).unwrap();
+                assert!(path.starts_with(dir.path()), "{} stored at {:?}", key, path);
+                assert!(cache.tombstone_path(&key).unwrap().starts_with(dir.path()));
+            }
+        }
+    }
+
+    #[test]
+    fn tile_path_rejects_keys_outside_the_grid() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(dir.path());
+        for key in [
+            TileKey::new(1, 2, 0),
+            TileKey::new(32, 0, 0),
+            TileKey::new(1, 0, 0).with_scale(3),
+        ] {
+            assert!(matches!(cache.tile_path(&key), Err(AppError::InvalidPath(_))));
+            assert!(matches!(cache.store_tombstone(&key), Err(AppError::InvalidPath(_))));
+            assert!(!cache.exists(&key));
+        }
+    }
+
+    #[test]
+    fn head_reads_metadata_without_the_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(dir.path());
+        let key = TileKey::new(3, 2, 1);
+        let mut tile = TileData::new(Bytes::from_static(b"\x89PNG\r\n\x1a\ntile"), None);
+        tile.etag = Some("\"v1\"".to_string());
+        tile.content_type = Some("image/png".to_string());
+        cache.store(&key, &tile).unwrap();
+
+        let (head, len) = cache.head(&key).unwrap();
+        assert_eq!(len, tile.data.len() as u64);
+        assert!(head.data.is_empty());
+        assert_eq!(head.etag, tile.etag);
+        assert_eq!(head.content_type, tile.content_type);
+        assert!(cache.head(&TileKey::new(3, 2, 2)).is_none());
     }
 }
@@ -0,0 +1,161 @@
+use crate::popularity::Popularity;
+use crate::types::TileKey;
+use serde::Deserialize;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Which tiles go first once the disk cache is over `disk_cache_max_bytes`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EvictionPolicy {
+    /// Least recently used
+    #[default]
+    Lru,
+    /// Fewest recent requests per byte, from the popularity counters
+    Lfu,
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "lru" => Ok(EvictionPolicy::Lru),
+            "lfu" => Ok(EvictionPolicy::Lfu),
+            _ => Err(()),
+        }
+    }
+}
+
+impl EvictionPolicy {
+    /// Ranking for the policy; LFU needs popularity tracking for its counts
+    pub(crate) fn evictor(
+        self,
+        popularity: Option<&Arc<Popularity>>,
+    ) -> anyhow::Result<Arc<dyn Evictor>> {
+        match (self, popularity) {
+            (EvictionPolicy::Lru, _) => Ok(Arc::new(Lru)),
+            (EvictionPolicy::Lfu, Some(popularity)) => Ok(Arc::new(SizeWeightedLfu {
+                popularity: popularity.clone(),
+            })),
+            (EvictionPolicy::Lfu, None) => {
+                anyhow::bail!("disk_eviction_policy \"lfu\" needs popularity_tracking")
+            }
+        }
+    }
+}
+
+/// A stored tile that could be evicted
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub key: TileKey,
+    /// Size of the tile file, plus its blob's if no other tile shares it
+    pub bytes: u64,
+    /// When the tile was last fetched or revalidated, which a requested tile
+    /// is once it goes stale
+    pub refreshed: SystemTime,
+    /// Blob holding the tile's data when deduplicated
+    pub(crate) blob: Option<String>,
+}
+
+/// Orders tiles for eviction, so other policies can be plugged in
+pub trait Evictor: Send + Sync {
+    /// Sort `candidates` so the ones to evict first come first
+    fn rank(&self, candidates: &mut [Candidate]);
+}
+
+/// Longest since last refreshed first, as close to least recently used as
+/// the files tell; read times are unreliable, cache sweeps read every tile
+pub struct Lru;
+
+impl Evictor for Lru {
+    fn rank(&self, candidates: &mut [Candidate]) {
+        candidates.sort_unstable_by_key(|candidate| candidate.refreshed);
+    }
+}
+
+/// Fewest requests per byte first, so a large tile nobody asks for goes
+/// before a small popular one; refresh times break ties, such as between
+/// tiles with no recent requests at all
+pub struct SizeWeightedLfu {
+    popularity: Arc<Popularity>,
+}
+
+impl Evictor for SizeWeightedLfu {
+    fn rank(&self, candidates: &mut [Candidate]) {
+        candidates.sort_by_cached_key(|candidate| {
+            let requests = u128::from(self.popularity.requests(&candidate.key));
+            let density = (requests << 20) / u128::from(candidate.bytes.max(1));
+            (density, candidate.refreshed)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::disk::DiskCache;
+    use crate::config::Config;
+    use crate::types::TileData;
+    use bytes::Bytes;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    const TILES: u32 = 200;
+    /// Tiles 0..HOT get four requests in five
+    const HOT: u32 = 20;
+    const REQUESTS: u32 = 3000;
+    const MAX_BYTES: u64 = 60_000;
+
+    /// Tiles of 1-2 KB, the hot ones no smaller than the rest
+    fn tile(index: u32) -> TileData {
+        let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+        data.extend(index.to_be_bytes());
+        data.resize(1024 + (index as usize * 389) % 1024, 0);
+        TileData::new(Bytes::from(data), None)
+    }
+
+    /// Serve a skewed request stream from a disk cache capped at `MAX_BYTES`,
+    /// checking the cap after every miss, and count the hot tiles kept
+    fn hot_tiles_kept(policy: EvictionPolicy) -> usize {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache_dir: dir.path().to_path_buf(),
+            popularity_tracking: true,
+            ..Config::builtin()
+        };
+        let cache = DiskCache::new(&config).unwrap();
+        let popularity = Arc::new(Popularity::from_config(&config).unwrap());
+        let evictor = policy.evictor(Some(&popularity)).unwrap();
+
+        // Fixed-seed LCG, so both policies see the same stream
+        let mut seed = 0x2545_f491_u64;
+        let mut next = move |below: u32| {
+            seed = seed.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+            ((seed >> 33) % u64::from(below)) as u32
+        };
+        for tick in 0..REQUESTS {
+            let index = if next(5) < 4 { next(HOT) } else { HOT + next(TILES - HOT) };
+            let key = TileKey::new(8, index, 0);
+            popularity.record(&key);
+            if cache.exists(&key) {
+                continue;
+            }
+            let mut data = tile(index);
+            data.fetched_at = Some(UNIX_EPOCH + Duration::from_secs(u64::from(tick)));
+            cache.store(&key, &data).unwrap();
+            cache.evict(MAX_BYTES, evictor.as_ref());
+            let bytes = cache.usage().bytes;
+            assert!(bytes <= MAX_BYTES, "{:?} left {} bytes over the cap", policy, bytes);
+        }
+        (0..HOT).filter(|&x| cache.exists(&TileKey::new(8, x, 0))).count()
+    }
+
+    #[test]
+    fn lfu_keeps_more_of_the_hot_set_than_lru() {
+        let lru = hot_tiles_kept(EvictionPolicy::Lru);
+        let lfu = hot_tiles_kept(EvictionPolicy::Lfu);
+        assert!(lfu > lru, "LFU kept {} hot tiles, LRU {}", lfu, lru);
+        assert!(lfu >= HOT as usize * 9 / 10, "LFU kept only {} of {} hot tiles", lfu, HOT);
+    }
+}
@@ -1,40 +1,94 @@
+use crate::cache::TileStore;
+use crate::config::Config;
+use crate::error::Result;
 use crate::types::{TileData, TileKey};
-use bytes::Bytes;
+use async_trait::async_trait;
 use moka::future::Cache;
 use std::sync::Arc;
 
 #[derive(Clone)]
 pub struct MemoryCache {
     cache: Cache<TileKey, Arc<TileData>>,
+    /// Tiles larger than this are left to the slower tiers, 0 for no limit
+    max_tile_bytes: usize,
 }
 
 impl MemoryCache {
-    pub fn new(max_capacity: u64) -> Self {
-        let cache = Cache::builder()
-            .max_capacity(max_capacity)
+    pub fn new(config: &Config) -> Self {
+        let mut builder = Cache::builder()
+            .max_capacity(config.memory_cache_size)
             .weigher(|_key: &TileKey, value: &Arc<TileData>| -> u32 {
-                let size = value.data.len() + value.etag.as_ref().map_or(0, |e| e.len()) + 64;
+                let size = value.data.len()
+                    + value.etag.as_ref().map_or(0, |e| e.len())
+                    + value.content_type.as_ref().map_or(0, |t| t.len())
+                    + value.content_encoding.as_ref().map_or(0, |e| e.len())
+                    + 64;
                 size.min(u32::MAX as usize) as u32
-            })
-            .build();
+            });
+        if !config.memory_ttl.is_zero() {
+            builder = builder.time_to_live(config.memory_ttl);
+        }
+        if !config.memory_tti.is_zero() {
+            builder = builder.time_to_idle(config.memory_tti);
+        }
 
-        Self { cache }
+        Self {
+            cache: builder.build(),
+            max_tile_bytes: config.memory_max_tile_bytes,
+        }
     }
 
     pub async fn get(&self, key: &TileKey) -> Option<Arc<TileData>> {
         self.cache.get(key).await
     }
 
-    pub async fn insert(&self, key: TileKey, data: Bytes, etag: Option<String>) {
-        let tile_data = Arc::new(TileData::new(data, etag));
-        self.cache.insert(key, tile_data).await;
+    /// Cache a tile, unless it's too large, in which case any older copy is
+    /// dropped instead. Returns whether the tile was cached.
+    pub async fn insert_tile(&self, key: TileKey, tile: Arc<TileData>) -> bool {
+        if self.max_tile_bytes > 0 && tile.data.len() > self.max_tile_bytes {
+            self.cache.invalidate(&key).await;
+            return false;
+        }
+        self.cache.insert(key, tile).await;
+        true
     }
 
-    pub async fn insert_tile(&self, key: TileKey, tile: Arc<TileData>) {
-        self.cache.insert(key, tile).await;
+    /// Remove a tile, returning whether it was cached
+    pub async fn remove(&self, key: &TileKey) -> bool {
+        self.cache.remove(key).await.is_some()
+    }
+
+    /// Keys of every cached tile, in no particular order
+    pub fn keys(&self) -> Vec<TileKey> {
+        self.cache.iter().map(|(key, _)| *key).collect()
+    }
+
+    /// Drop every entry, e.g. once a new cache generation makes them unreachable
+    pub fn clear(&self) {
+        self.cache.invalidate_all();
     }
 
     pub fn entry_count(&self) -> u64 {
         self.cache.entry_count()
     }
 }
+
+#[async_trait]
+impl TileStore for MemoryCache {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    async fn get(&self, key: &TileKey) -> Result<Option<Arc<TileData>>> {
+        Ok(MemoryCache::get(self, key).await)
+    }
+
+    async fn put(&self, key: &TileKey, tile: Arc<TileData>) -> Result<()> {
+        self.insert_tile(*key, tile).await;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &TileKey) -> Result<bool> {
+        Ok(self.remove(key).await)
+    }
+}
@@ -1,7 +1,25 @@
+pub mod chain;
 pub mod coalescing;
 pub mod disk;
+pub mod eviction;
 pub mod memory;
+pub mod negative;
+pub mod optimizer;
+pub mod redis;
+pub mod s3;
+pub mod snapshot;
+pub mod store;
+pub mod writer;
 
+pub use chain::{TierChain, TierHit};
 pub use coalescing::RequestCoalescer;
-pub use disk::DiskCache;
+pub use disk::{DiskCache, DiskLayout, DiskTileInfo, DiskUsage, EvictionSummary, ZoomUsage};
+pub use eviction::{EvictionPolicy, Evictor};
 pub use memory::MemoryCache;
+pub use negative::NegativeCache;
+pub use optimizer::PngOptimizer;
+pub use redis::RedisCache;
+pub use s3::S3Store;
+pub use snapshot::MemorySnapshot;
+pub use store::TileStore;
+pub use writer::{BackgroundWrites, DiskWriter};
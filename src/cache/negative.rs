@@ -0,0 +1,48 @@
+use crate::cache::DiskCache;
+use crate::types::TileKey;
+use moka::future::Cache;
+use std::time::Duration;
+
+/// Remembers tiles upstream reported missing so repeated requests don't refetch them
+#[derive(Clone)]
+pub struct NegativeCache {
+    cache: Cache<TileKey, ()>,
+    /// Persist tombstones next to disk tiles when set
+    disk: Option<DiskCache>,
+    ttl: Duration,
+}
+
+impl NegativeCache {
+    pub fn new(max_capacity: u64, ttl: Duration, disk: Option<DiskCache>) -> Self {
+        let cache = Cache::builder()
+            .max_capacity(max_capacity)
+            .time_to_live(ttl)
+            .build();
+
+        Self { cache, disk, ttl }
+    }
+
+    pub async fn contains(&self, key: &TileKey) -> bool {
+        if self.cache.contains_key(key) {
+            return true;
+        }
+        let Some(disk) = &self.disk else {
+            return false;
+        };
+        disk.load_tombstone_age(key).await.is_some_and(|age| age < self.ttl)
+    }
+
+    pub async fn insert(&self, key: TileKey) {
+        self.cache.insert(key, ()).await;
+        if let Some(disk) = &self.disk {
+            if let Err(e) = disk.save_tombstone(&key).await {
+                tracing::warn!(key = %key, error = %e, "Failed to store disk tombstone");
+            }
+        }
+    }
+
+    /// Forget a missing tile, returning whether it was cached in memory
+    pub async fn remove(&self, key: &TileKey) -> bool {
+        self.cache.remove(key).await.is_some()
+    }
+}
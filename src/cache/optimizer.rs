@@ -0,0 +1,93 @@
+use crate::cache::DiskCache;
+use crate::processing::optimize::{self, PngOptimization};
+use crate::types::{TileData, TileFormat, TileKey};
+use prometheus::{IntCounter, IntCounterVec};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+/// Bounded queue of freshly stored PNG tiles, recompressed one at a time by a
+/// background task and written back when that shrinks them. Tiles arriving
+/// with the queue full stay as fetched.
+#[derive(Clone)]
+pub struct PngOptimizer {
+    sender: mpsc::Sender<(TileKey, Arc<TileData>)>,
+    /// Tiles by outcome
+    outcomes: IntCounterVec,
+}
+
+impl PngOptimizer {
+    pub fn new(
+        disk_cache: DiskCache,
+        optimization: PngOptimization,
+        capacity: usize,
+        outcomes: IntCounterVec,
+        bytes_saved: IntCounter,
+    ) -> Self {
+        let (sender, mut receiver) = mpsc::channel::<(TileKey, Arc<TileData>)>(capacity.max(1));
+
+        let worker_outcomes = outcomes.clone();
+        tokio::spawn(async move {
+            while let Some((key, tile)) = receiver.recv().await {
+                let cache = disk_cache.clone();
+                let result = tokio::task::spawn_blocking(move || {
+                    optimize_stored(&cache, &key, &tile, optimization)
+                })
+                .await;
+                let outcome = match result {
+                    Ok(Ok(Some(saved))) => {
+                        bytes_saved.inc_by(saved as u64);
+                        "optimized"
+                    }
+                    Ok(Ok(None)) => "unchanged",
+                    Ok(Err(e)) => {
+                        tracing::debug!(key = %key, error = %e, "Failed to optimize PNG tile");
+                        "failed"
+                    }
+                    Err(e) => {
+                        tracing::error!(key = %key, error = %e, "PNG optimization task panicked");
+                        "failed"
+                    }
+                };
+                worker_outcomes.with_label_values(&[outcome]).inc();
+            }
+        });
+
+        Self { sender, outcomes }
+    }
+
+    /// Queue a tile just written to disk, unless it isn't a PNG
+    pub fn enqueue(&self, key: TileKey, tile: Arc<TileData>) {
+        if key.format != TileFormat::Png || tile.synthesized {
+            return;
+        }
+        if self.sender.try_send((key, tile)).is_err() {
+            self.outcomes.with_label_values(&["dropped"]).inc();
+        }
+    }
+}
+
+/// Recompress a stored tile and write it back, returning the bytes saved.
+/// Left alone if it was replaced on disk in the meantime.
+fn optimize_stored(
+    cache: &DiskCache,
+    key: &TileKey,
+    tile: &TileData,
+    optimization: PngOptimization,
+) -> anyhow::Result<Option<usize>> {
+    let Some(smaller) = optimize::optimize(&tile.data, optimization)? else {
+        return Ok(None);
+    };
+    let unchanged = cache.get(key).is_some_and(|stored| stored.data == tile.data);
+    if !unchanged {
+        return Ok(None);
+    }
+    let saved = tile.data.len() - smaller.len();
+    cache.store(
+        key,
+        &TileData {
+            data: smaller.into(),
+            ..tile.clone()
+        },
+    )?;
+    Ok(Some(saved))
+}
@@ -0,0 +1,99 @@
+use crate::cache::disk::{decode_tile, encode_tile};
+use crate::cache::store::TileStore;
+use crate::cache::writer::BackgroundWrites;
+use crate::config::Config;
+use crate::error::{AppError, Result};
+use crate::types::{TileData, TileKey};
+use async_trait::async_trait;
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Writes running at once; more are dropped rather than queued
+const MAX_WRITES: usize = 64;
+
+/// Recently fetched tiles shared between instances through Redis, each
+/// stored with an expiry in the disk cache's format
+#[derive(Clone)]
+pub struct RedisCache {
+    connection: ConnectionManager,
+    prefix: String,
+    ttl: Duration,
+    /// Larger tiles are left to the other tiers
+    max_value_bytes: usize,
+    writes: BackgroundWrites,
+}
+
+impl RedisCache {
+    /// Connect to the configured server, None when no URL is set
+    pub async fn connect(config: &Config) -> anyhow::Result<Option<Self>> {
+        let Some(url) = &config.redis_url else {
+            return Ok(None);
+        };
+        let client = redis::Client::open(url.as_str())?;
+        let connection = ConnectionManager::new(client)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to connect to Redis at {}: {}", url, e))?;
+        Ok(Some(Self {
+            connection,
+            prefix: config.redis_key_prefix.clone(),
+            ttl: config.redis_ttl,
+            max_value_bytes: config.redis_max_value_bytes,
+            writes: BackgroundWrites::new(MAX_WRITES),
+        }))
+    }
+
+    fn redis_key(&self, key: &TileKey) -> String {
+        format!("{}{}", self.prefix, key.store_path())
+    }
+}
+
+#[async_trait]
+impl TileStore for RedisCache {
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+
+    async fn get(&self, key: &TileKey) -> Result<Option<Arc<TileData>>> {
+        // The manager is a cheap handle onto one multiplexed connection
+        let mut connection = self.connection.clone();
+        let raw: Option<Vec<u8>> = connection.get(self.redis_key(key)).await.map_err(redis_error)?;
+        Ok(raw.and_then(|raw| decode_tile(raw.into())).map(Arc::new))
+    }
+
+    async fn put(&self, key: &TileKey, tile: Arc<TileData>) -> Result<()> {
+        if tile.data.len() > self.max_value_bytes {
+            return Ok(());
+        }
+        let cache = self.clone();
+        let key = *key;
+        let queued = self.writes.spawn(async move {
+            let mut connection = cache.connection.clone();
+            let ttl = cache.ttl.as_secs().max(1);
+            let result: redis::RedisResult<()> =
+                connection.set_ex(cache.redis_key(&key), encode_tile(&tile), ttl).await;
+            if let Err(e) = result {
+                tracing::debug!(key = %key, error = %e, "Redis write failed");
+            }
+        });
+        if !queued {
+            return Err(AppError::Cache("too many Redis writes in flight".to_string()));
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, key: &TileKey) -> Result<bool> {
+        let mut connection = self.connection.clone();
+        let removed: u64 = connection.del(self.redis_key(key)).await.map_err(redis_error)?;
+        Ok(removed > 0)
+    }
+
+    async fn flush(&self, timeout: Duration) -> bool {
+        self.writes.flush(timeout).await
+    }
+}
+
+fn redis_error(e: redis::RedisError) -> AppError {
+    AppError::Cache(e.to_string())
+}
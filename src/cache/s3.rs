@@ -0,0 +1,230 @@
+use crate::cache::disk::{decode_tile, encode_tile};
+use crate::cache::store::TileStore;
+use crate::cache::writer::BackgroundWrites;
+use crate::config::Config;
+use crate::error::{AppError, Result};
+use crate::types::{TileData, TileKey};
+use crate::upstream::client_builder;
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use reqwest::{Client, Method, StatusCode};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Uploads running at once; more are dropped rather than queued
+const MAX_UPLOADS: usize = 16;
+
+/// Tiles kept as objects in an S3-compatible bucket, one per tile, in the
+/// same header-plus-data format as the disk cache
+#[derive(Clone)]
+pub struct S3Store {
+    client: Client,
+    /// Scheme and host, e.g. `https://s3.eu-central-1.amazonaws.com`
+    endpoint: String,
+    bucket: String,
+    region: String,
+    /// Prepended to every object key
+    prefix: String,
+    /// Address the bucket in the path rather than as a subdomain
+    path_style: bool,
+    access_key_id: String,
+    secret_access_key: String,
+    uploads: BackgroundWrites,
+}
+
+impl S3Store {
+    /// Store for the configured bucket, None when no bucket is set
+    pub fn new(config: &Config) -> anyhow::Result<Option<Self>> {
+        let Some(bucket) = &config.s3_bucket else {
+            return Ok(None);
+        };
+        let (Some(access_key_id), Some(secret_access_key)) =
+            (&config.s3_access_key_id, &config.s3_secret_access_key)
+        else {
+            anyhow::bail!("s3_bucket needs s3_access_key_id and s3_secret_access_key");
+        };
+        let client = client_builder(config)?.build()?;
+        Ok(Some(Self {
+            client,
+            endpoint: config.s3_endpoint.trim_end_matches('/').to_string(),
+            bucket: bucket.clone(),
+            region: config.s3_region.clone(),
+            prefix: config.s3_prefix.clone(),
+            path_style: config.s3_path_style,
+            access_key_id: access_key_id.clone(),
+            secret_access_key: secret_access_key.clone(),
+            uploads: BackgroundWrites::new(MAX_UPLOADS),
+        }))
+    }
+
+    fn object_key(&self, key: &TileKey) -> String {
+        format!("{}{}", self.prefix, key.store_path())
+    }
+
+    /// Host and path of an object, per the addressing style
+    fn locate(&self, object_key: &str) -> (String, String) {
+        let (scheme, host) = self.endpoint.split_once("://").unwrap_or(("https", &self.endpoint));
+        let path = uri_encode(object_key);
+        if self.path_style {
+            (format!("{}://{}", scheme, host), format!("/{}/{}", self.bucket, path))
+        } else {
+            (format!("{}://{}.{}", scheme, self.bucket, host), format!("/{}", path))
+        }
+    }
+
+    /// Send a request signed with AWS Signature Version 4
+    async fn send(
+        &self,
+        method: Method,
+        key: &TileKey,
+        body: Vec<u8>,
+    ) -> Result<reqwest::Response> {
+        let (base, path) = self.locate(&self.object_key(key));
+        let host = base.split_once("://").map_or(base.as_str(), |(_, host)| host);
+        let payload_hash = hex(&Sha256::digest(&body));
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let amz_date = amz_date(now);
+        let date = &amz_date[..8];
+
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, path, host, payload_hash, amz_date, signed_headers, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let signing_key = [date, &self.region, "s3", "aws4_request"]
+            .iter()
+            .fold(format!("AWS4{}", self.secret_access_key).into_bytes(), |key, part| {
+                hmac(&key, part.as_bytes())
+            });
+        let signature = hex(&hmac(&signing_key, string_to_sign.as_bytes()));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key_id, scope, signed_headers, signature
+        );
+
+        let response = self
+            .client
+            .request(method, format!("{}{}", base, path))
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", amz_date)
+            .header("authorization", authorization)
+            .body(body)
+            .send()
+            .await?;
+        Ok(response)
+    }
+
+    async fn upload(&self, key: &TileKey, tile: &TileData) -> Result<()> {
+        let response = self.send(Method::PUT, key, encode_tile(tile)).await?;
+        if !response.status().is_success() {
+            return Err(AppError::UpstreamStatus(response.status().as_u16()));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TileStore for S3Store {
+    fn name(&self) -> &'static str {
+        "s3"
+    }
+
+    async fn get(&self, key: &TileKey) -> Result<Option<Arc<TileData>>> {
+        let response = self.send(Method::GET, key, Vec::new()).await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => return Ok(None),
+            status if !status.is_success() => {
+                return Err(AppError::UpstreamStatus(status.as_u16()))
+            }
+            _ => {}
+        }
+        let raw = response.bytes().await?;
+        let Some(tile) = decode_tile(raw) else {
+            tracing::warn!(key = %key, "Ignoring malformed tile object");
+            return Ok(None);
+        };
+        Ok(Some(Arc::new(tile)))
+    }
+
+    async fn put(&self, key: &TileKey, tile: Arc<TileData>) -> Result<()> {
+        let store = self.clone();
+        let key = *key;
+        let queued = self.uploads.spawn(async move {
+            if let Err(e) = store.upload(&key, &tile).await {
+                tracing::warn!(key = %key, error = %e, "Failed to upload tile");
+            }
+        });
+        if !queued {
+            return Err(AppError::Cache("too many uploads in flight".to_string()));
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, key: &TileKey) -> Result<bool> {
+        let response = self.send(Method::DELETE, key, Vec::new()).await?;
+        // Deleting a missing object succeeds with 204, so presence is unknown
+        if !response.status().is_success() {
+            return Err(AppError::UpstreamStatus(response.status().as_u16()));
+        }
+        Ok(false)
+    }
+
+    async fn flush(&self, timeout: Duration) -> bool {
+        self.uploads.flush(timeout).await
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Percent-encode an object key for the request path, keeping `/` separators
+fn uri_encode(key: &str) -> String {
+    key.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// `YYYYMMDDTHHMMSSZ` for a Unix timestamp
+fn amz_date(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+    // Civil date from days since the epoch (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3_600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
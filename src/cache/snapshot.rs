@@ -0,0 +1,87 @@
+use crate::cache::disk::write_atomic;
+use crate::cache::{DiskCache, MemoryCache};
+use crate::error::Result;
+use crate::types::TilePath;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// File in the cache directory listing the tiles held in memory
+const SNAPSHOT_FILE: &str = "memory-snapshot";
+
+/// Keys of the memory cache's tiles, saved so a restart can load the same
+/// tiles back from disk before clients ask for them
+pub struct MemorySnapshot {
+    path: PathBuf,
+}
+
+impl MemorySnapshot {
+    pub fn new(cache_dir: &Path) -> Self {
+        Self {
+            path: cache_dir.join(SNAPSHOT_FILE),
+        }
+    }
+
+    /// Write the current generation's memory cache keys as `z/x/file` lines,
+    /// returning how many were saved
+    pub fn save(&self, memory_cache: &MemoryCache, generation: u32) -> Result<usize> {
+        let mut lines = String::new();
+        let mut saved = 0;
+        for key in memory_cache.keys() {
+            if key.generation == generation {
+                lines.push_str(&format!("{}\n", key));
+                saved += 1;
+            }
+        }
+        write_atomic(&self.path, &[lines.as_bytes()])?;
+        Ok(saved)
+    }
+
+    /// Load the tiles of the last snapshot from disk into memory, skipping
+    /// ones no longer on disk, and return how many were loaded
+    pub async fn restore(&self, memory_cache: &MemoryCache, disk_cache: &DiskCache) -> usize {
+        let Ok(contents) = fs::read_to_string(&self.path) else {
+            return 0;
+        };
+        let generation = disk_cache.generation();
+        let mut restored = 0;
+        for line in contents.lines() {
+            let Some(path) = TilePath::parse(line.trim()) else {
+                continue;
+            };
+            let key = path.key().with_generation(generation);
+            if memory_cache.get(&key).await.is_some() {
+                continue;
+            }
+            if let Some(tile) = disk_cache.load(&key).await {
+                if memory_cache.insert_tile(key, tile).await {
+                    restored += 1;
+                }
+            }
+        }
+        restored
+    }
+}
+
+/// Reload the last snapshot, then save a new one every `interval`
+pub async fn warm_start(
+    snapshot: MemorySnapshot,
+    memory_cache: MemoryCache,
+    disk_cache: DiskCache,
+    interval: Duration,
+) {
+    let restored = snapshot.restore(&memory_cache, &disk_cache).await;
+    tracing::info!(restored, "Warmed memory cache from snapshot");
+
+    if interval.is_zero() {
+        return;
+    }
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        if let Err(e) = snapshot.save(&memory_cache, disk_cache.generation()) {
+            tracing::warn!(error = %e, "Failed to save memory cache snapshot");
+        }
+    }
+}
@@ -0,0 +1,31 @@
+use crate::error::Result;
+use crate::types::{TileData, TileKey};
+use async_trait::async_trait;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A cache tier or tile source in the chain consulted before upstream
+#[async_trait]
+pub trait TileStore: Send + Sync {
+    /// Label used in config, metrics and logs
+    fn name(&self) -> &'static str;
+
+    /// A stored tile, carrying when it was fetched if the store knows
+    async fn get(&self, key: &TileKey) -> Result<Option<Arc<TileData>>>;
+
+    /// Keep a tile; slow stores queue the write and return
+    async fn put(&self, key: &TileKey, tile: Arc<TileData>) -> Result<()>;
+
+    /// Remove a tile, returning whether it was there when that's known
+    async fn delete(&self, key: &TileKey) -> Result<bool>;
+
+    /// Whether tiles are written here; archives are read-only sources
+    fn writable(&self) -> bool {
+        true
+    }
+
+    /// Wait up to `timeout` for queued writes, returning whether they finished
+    async fn flush(&self, _timeout: Duration) -> bool {
+        true
+    }
+}
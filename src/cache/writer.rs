@@ -0,0 +1,157 @@
+use crate::cache::{DiskCache, PngOptimizer, TileStore};
+use crate::error::{AppError, Result};
+use crate::types::{TileData, TileKey};
+use async_trait::async_trait;
+use prometheus::IntCounter;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::time::Instant;
+
+/// Bounded queue of disk writes, applied in order by a background task so
+/// responses don't wait on the filesystem. This is the disk cache's tier.
+#[derive(Clone)]
+pub struct DiskWriter {
+    cache: DiskCache,
+    sender: mpsc::Sender<(TileKey, Arc<TileData>)>,
+    /// Writes queued or in progress
+    pending: Arc<AtomicUsize>,
+    /// Writes dropped because the queue was full
+    dropped: IntCounter,
+}
+
+impl DiskWriter {
+    /// Written PNG tiles are passed on to `optimizer`, if any
+    pub fn new(
+        disk_cache: DiskCache,
+        capacity: usize,
+        dropped: IntCounter,
+        optimizer: Option<PngOptimizer>,
+    ) -> Self {
+        let (sender, mut receiver) = mpsc::channel::<(TileKey, Arc<TileData>)>(capacity.max(1));
+        let pending = Arc::new(AtomicUsize::new(0));
+
+        let worker_pending = pending.clone();
+        let cache = disk_cache.clone();
+        tokio::spawn(async move {
+            while let Some((key, tile)) = receiver.recv().await {
+                let cache = cache.clone();
+                let stored = tile.clone();
+                let result = tokio::task::spawn_blocking(move || cache.store(&key, &stored)).await;
+                match result {
+                    Ok(Err(e)) => {
+                        tracing::warn!(key = %key, error = %e, "Failed to store to disk cache")
+                    }
+                    Err(e) => tracing::error!(key = %key, error = %e, "Disk write task panicked"),
+                    Ok(Ok(())) => {
+                        if let Some(optimizer) = &optimizer {
+                            optimizer.enqueue(key, tile);
+                        }
+                    }
+                }
+                worker_pending.fetch_sub(1, Ordering::AcqRel);
+            }
+        });
+
+        Self {
+            cache: disk_cache,
+            sender,
+            pending,
+            dropped,
+        }
+    }
+
+    /// Queue a tile for writing, returning false if the queue is full and the
+    /// write was dropped
+    pub fn enqueue(&self, key: TileKey, tile: Arc<TileData>) -> bool {
+        self.pending.fetch_add(1, Ordering::AcqRel);
+        if self.sender.try_send((key, tile)).is_err() {
+            self.pending.fetch_sub(1, Ordering::AcqRel);
+            self.dropped.inc();
+            return false;
+        }
+        true
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::Acquire)
+    }
+}
+
+#[async_trait]
+impl TileStore for DiskWriter {
+    fn name(&self) -> &'static str {
+        "disk"
+    }
+
+    async fn get(&self, key: &TileKey) -> Result<Option<Arc<TileData>>> {
+        Ok(self.cache.load(key).await)
+    }
+
+    async fn put(&self, key: &TileKey, tile: Arc<TileData>) -> Result<()> {
+        if !self.enqueue(*key, tile) {
+            return Err(AppError::Cache("disk write queue full".to_string()));
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, key: &TileKey) -> Result<bool> {
+        self.cache.remove(key)
+    }
+
+    /// Wait for queued writes to reach disk, giving up after `timeout`.
+    /// Returns whether the queue emptied.
+    async fn flush(&self, timeout: Duration) -> bool {
+        wait_for_zero(&self.pending, timeout).await
+    }
+}
+
+/// Writes to a remote store running in the background, at most a fixed
+/// number at once
+#[derive(Clone)]
+pub struct BackgroundWrites {
+    slots: Arc<Semaphore>,
+    pending: Arc<AtomicUsize>,
+}
+
+impl BackgroundWrites {
+    pub fn new(max_in_flight: usize) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(max_in_flight.max(1))),
+            pending: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Start a write, returning false when every slot is busy and it was dropped
+    pub fn spawn(&self, write: impl Future<Output = ()> + Send + 'static) -> bool {
+        let Ok(permit) = self.slots.clone().try_acquire_owned() else {
+            return false;
+        };
+        self.pending.fetch_add(1, Ordering::AcqRel);
+        let pending = self.pending.clone();
+        tokio::spawn(async move {
+            write.await;
+            drop(permit);
+            pending.fetch_sub(1, Ordering::AcqRel);
+        });
+        true
+    }
+
+    /// Wait for running writes, giving up after `timeout`
+    pub async fn flush(&self, timeout: Duration) -> bool {
+        wait_for_zero(&self.pending, timeout).await
+    }
+}
+
+async fn wait_for_zero(pending: &AtomicUsize, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while pending.load(Ordering::Acquire) > 0 {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    true
+}
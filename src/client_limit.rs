@@ -0,0 +1,119 @@
+use crate::config::Config;
+use crate::error::AppError;
+use crate::forwarded::client_ip;
+use crate::handlers::AppState;
+use crate::upstream::rate_limit::TokenBucket;
+use axum::extract::{Request, State};
+use axum::http::{HeaderMap, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+use dashmap::DashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Token bucket per client (API key or IP), so one noisy client can't use up the upstream quota
+pub struct ClientRateLimiter {
+    rate: f64,
+    burst: f64,
+    /// Keyed by API key name when the request has one, else by IP
+    buckets: DashMap<String, Arc<TokenBucket>>,
+}
+
+/// Outcome of a rate limit check, for the `RateLimit-*` response headers
+struct Quota {
+    limit: u64,
+    remaining: u64,
+    reset: Duration,
+}
+
+impl ClientRateLimiter {
+    /// Limiter for the configured rate, None when limiting is off
+    pub fn from_config(config: &Config) -> Option<Self> {
+        (config.client_rate_limit > 0.0).then(|| Self {
+            rate: config.client_rate_limit,
+            burst: config.client_rate_burst,
+            buckets: DashMap::new(),
+        })
+    }
+
+    fn check(&self, client: &str) -> Result<Quota, Quota> {
+        let bucket = self
+            .buckets
+            .entry(client.to_string())
+            .or_insert_with(|| Arc::new(TokenBucket::with_burst(self.rate, self.burst)))
+            .clone();
+        let limit = bucket.capacity();
+        match bucket.try_acquire() {
+            Ok(remaining) => Ok(Quota {
+                limit,
+                remaining,
+                reset: bucket.time_to_full(),
+            }),
+            Err(wait) => Err(Quota {
+                limit,
+                remaining: 0,
+                reset: wait,
+            }),
+        }
+    }
+
+    /// Forget clients whose buckets have refilled, as they are back to the default
+    pub fn prune(&self) {
+        self.buckets.retain(|_, bucket| !bucket.time_to_full().is_zero());
+    }
+}
+
+/// Middleware answering 429 to clients over their rate limit
+pub async fn limit_clients(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(limiter) = state.client_limiter.load_full() else {
+        return next.run(request).await;
+    };
+
+    let api_key = state
+        .api_keys
+        .as_ref()
+        .and_then(|keys| keys.identify(request.uri(), request.headers()));
+    let client = match api_key {
+        Some(name) => format!("key:{}", name),
+        None => match client_ip(&request) {
+            Some(ip) => ip.to_string(),
+            // Every request over a Unix socket shares one bucket
+            None => "unix".to_string(),
+        },
+    };
+    match limiter.check(&client) {
+        Ok(quota) => {
+            let mut response = next.run(request).await;
+            quota.apply(response.headers_mut());
+            response
+        }
+        Err(quota) => {
+            tracing::debug!(client = %client, "Client rate limited");
+            state.metrics.requests_rate_limited.inc();
+            let mut response = AppError::RateLimited.problem();
+            quota.apply(response.headers_mut());
+            response.headers_mut().insert(
+                axum::http::header::RETRY_AFTER,
+                HeaderValue::from(quota.reset_secs()),
+            );
+            response
+        }
+    }
+}
+
+impl Quota {
+    /// Whole seconds, rounded up so clients don't retry too early
+    fn reset_secs(&self) -> u64 {
+        self.reset.as_secs_f64().ceil() as u64
+    }
+
+    fn apply(&self, headers: &mut HeaderMap) {
+        headers.insert("ratelimit-limit", HeaderValue::from(self.limit));
+        headers.insert("ratelimit-remaining", HeaderValue::from(self.remaining));
+        headers.insert("ratelimit-reset", HeaderValue::from(self.reset_secs()));
+    }
+}
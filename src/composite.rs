@@ -0,0 +1,203 @@
+use crate::cache::coalescing::{CoalesceResult, WaitOutcome};
+use crate::cache::{DiskCache, RequestCoalescer};
+use crate::config::{CompositeLayerConfig, Config};
+use crate::error::{AppError, Result};
+use crate::handlers::tile::{load_tile, LoadedTile};
+use crate::handlers::AppState;
+use crate::processing::composite;
+use crate::types::{TileData, TileFormat, TileKey, Validators};
+use crate::upstream::{FetchResult, OsmFetcher};
+use futures_util::future::join_all;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Cache subdirectory holding every layer's tiles
+pub(crate) const LAYERS_DIR: &str = "layers";
+
+/// A raster layer whose tiles are this proxy's tiles with overlays from other
+/// upstreams alpha-blended on top, cached on their own
+pub struct CompositeLayer {
+    name: String,
+    base: bool,
+    overlays: Vec<OsmFetcher>,
+    cache: DiskCache,
+    /// Deduplicates compositing of the same tile
+    coalescer: RequestCoalescer,
+}
+
+impl CompositeLayer {
+    /// Layers of the config, by name
+    pub fn from_config(
+        config: &Config,
+        disk_cache: &DiskCache,
+    ) -> anyhow::Result<HashMap<String, Self>> {
+        let layers_cache = disk_cache.subdirectory(LAYERS_DIR)?;
+        let mut layers = HashMap::new();
+        for layer in &config.composite_layers {
+            check_layer_name(&layer.name)?;
+            if usize::from(layer.base) + layer.overlays.len() < 2 {
+                anyhow::bail!("Composite layer {:?} needs at least two sources", layer.name);
+            }
+            let composite = Self::new(config, layer, layers_cache.subdirectory(&layer.name)?)?;
+            if layers.insert(layer.name.clone(), composite).is_some() {
+                anyhow::bail!("Composite layer {:?} is configured more than once", layer.name);
+            }
+        }
+        Ok(layers)
+    }
+
+    fn new(
+        config: &Config,
+        layer: &CompositeLayerConfig,
+        cache: DiskCache,
+    ) -> anyhow::Result<Self> {
+        // Each overlay is its own provider, with its own limits and mirror health
+        let overlays = layer
+            .overlays
+            .iter()
+            .map(|overlay| {
+                OsmFetcher::new(&Config {
+                    upstreams: vec![overlay.clone()],
+                    // Composed tiles carry no upstream's headers
+                    passthrough_headers: Vec::new(),
+                    ..config.clone()
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            name: layer.name.clone(),
+            base: layer.base,
+            overlays,
+            cache,
+            coalescer: RequestCoalescer::new(),
+        })
+    }
+
+    /// Disk cache of the layer's composed tiles
+    pub fn cache(&self) -> &DiskCache {
+        &self.cache
+    }
+
+    /// A composed tile from the layer's cache, composing it again once older
+    /// than the freshness window. A stale copy stands in if that fails.
+    pub async fn load(&self, state: &Arc<AppState>, key: TileKey) -> Result<LoadedTile> {
+        let compose = || self.compose(state, key);
+        load_layer_tile(state, &self.cache, &self.coalescer, key, "composed", compose).await
+    }
+
+    /// Load every source, blend them and store the result
+    async fn compose(&self, state: &Arc<AppState>, key: TileKey) -> Result<Arc<TileData>> {
+        if state.is_offline() {
+            return Err(AppError::NotFound);
+        }
+        let source_key = key.with_format(TileFormat::Png);
+        let base = async {
+            if !self.base {
+                return Ok(None);
+            }
+            load_tile(state, source_key).await.map(|loaded| Some(loaded.tile))
+        };
+        let overlays = join_all(
+            self.overlays
+                .iter()
+                .map(|overlay| fetch_overlay(state, &self.name, overlay, source_key)),
+        );
+        let (base, overlays) = tokio::join!(base, overlays);
+
+        let mut layers: Vec<_> = base?.into_iter().collect();
+        for overlay in overlays {
+            layers.extend(overlay?);
+        }
+        if layers.is_empty() {
+            return Err(AppError::NotFound);
+        }
+
+        let cache = self.cache.clone();
+        let tile = tokio::task::spawn_blocking(move || -> Result<TileData> {
+            let mut tile = composite::blend(&layers, key.format)?;
+            tile.ensure_etag();
+            cache.store(&key, &tile)?;
+            Ok(tile)
+        })
+        .await
+        .expect("composite task panicked")?;
+        tracing::debug!(key = %key, size = tile.data.len(), "Composed tile");
+        Ok(Arc::new(tile))
+    }
+}
+
+/// An overlay's tile, None where it has none
+async fn fetch_overlay(
+    state: &AppState,
+    layer: &str,
+    fetcher: &OsmFetcher,
+    key: TileKey,
+) -> Result<Option<Arc<TileData>>> {
+    let started = Instant::now();
+    let result = fetcher.fetch(&key, &Validators::default()).await;
+    state.metrics.observe_upstream(layer, key.z, started.elapsed());
+    match result {
+        Ok(FetchResult::Data(tile)) => Ok(Some(Arc::new(tile))),
+        // Overlays such as trails or labels are often sparse
+        Ok(FetchResult::NotModified) | Err(AppError::NotFound) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Check a layer name is usable as a path segment and directory
+pub(crate) fn check_layer_name(name: &str) -> anyhow::Result<()> {
+    let valid = !name.is_empty()
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        anyhow::bail!("Layer name {:?} may only hold letters, digits, - and _", name);
+    }
+    Ok(())
+}
+
+/// A layer's tile from its cache, producing it again once older than the
+/// freshness window. A stale copy stands in if that fails. Produced tiles are
+/// attributed to the `produced` tier.
+pub(crate) async fn load_layer_tile<F, Fut>(
+    state: &AppState,
+    cache: &DiskCache,
+    coalescer: &RequestCoalescer,
+    key: TileKey,
+    produced: &'static str,
+    produce: F,
+) -> Result<LoadedTile>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<Arc<TileData>>>,
+{
+    let cached = cache.load(&key).await;
+    let window = state.cache_policy.load().freshness_window(key.z, None);
+    if let Some(tile) = &cached {
+        if cache.tile_age(&key).await.is_some_and(|age| age <= window) {
+            return Ok(LoadedTile::hit(tile.clone(), "disk"));
+        }
+    }
+
+    let result = match coalescer.try_acquire(key) {
+        CoalesceResult::Acquired(guard) => guard.complete(produce().await),
+        CoalesceResult::Wait(waiter) => {
+            let outcome = coalescer
+                .wait(key, waiter, state.coalescer_wait_timeout, false)
+                .await;
+            match outcome {
+                WaitOutcome::Done(result) => result,
+                // The other request went away or stalled, so produce our own
+                _ => produce().await,
+            }
+        }
+    };
+    match (result, cached) {
+        (Ok(tile), _) => Ok(LoadedTile::hit(tile, produced)),
+        (Err(e), Some(tile)) if e.is_transient() || state.is_offline() => {
+            tracing::warn!(key = %key, error = %e, "Layer tile failed, serving stale tile");
+            Ok(LoadedTile::stale(tile))
+        }
+        (Err(e), _) => Err(e),
+    }
+}
@@ -1,11 +1,34 @@
-use axum::http::StatusCode;
+use axum::http::{header, HeaderValue, StatusCode};
 use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use std::error::Error as _;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum AppError {
+    /// Network failure not told apart below
     #[error("Upstream error: {0}")]
-    Upstream(#[from] reqwest::Error),
+    Upstream(reqwest::Error),
+
+    #[error("Upstream timed out: {0}")]
+    UpstreamTimeout(String),
+
+    #[error("Upstream DNS lookup failed: {0}")]
+    UpstreamDns(String),
+
+    #[error("Upstream refused the connection: {0}")]
+    UpstreamRefused(String),
+
+    #[error("Upstream TLS handshake failed: {0}")]
+    UpstreamTls(String),
+
+    /// Upstream answered 429, or 503 with `Retry-After`, asking to wait
+    /// `retry_after` if it said
+    #[error("Upstream asked to slow down")]
+    UpstreamRateLimited { retry_after: Option<Duration> },
 
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
@@ -18,21 +41,277 @@ pub enum AppError {
 
     #[error("Upstream returned {0}")]
     UpstreamStatus(u16),
+
+    #[error("Upstream tile exceeds the {0} byte limit")]
+    TileTooLarge(u64),
+
+    #[error("Bad request: {0}")]
+    BadRequest(String),
+
+    #[error("Job {0} not found")]
+    JobNotFound(u64),
+
+    #[error("No upstream servers configured")]
+    NoUpstreams,
+
+    #[error("Invalid upstream_proxy: {0}")]
+    InvalidProxy(String),
+
+    #[error("Invalid passthrough_headers entry {0:?}")]
+    InvalidPassthroughHeader(String),
+
+    #[error("Invalid upstream {url}: {reason}")]
+    InvalidUpstream { url: String, reason: String },
+
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+
+    #[error("Invalid tile archive: {0}")]
+    Archive(String),
+
+    #[error("Image processing error: {0}")]
+    Image(#[from] image::ImageError),
+
+    #[error("Cache backend error: {0}")]
+    Cache(String),
+
+    #[error("Invalid cache path {0:?}")]
+    InvalidPath(String),
+
+    #[error("Missing or invalid API key")]
+    Unauthorized,
+
+    #[error("Requests from this site are not allowed")]
+    Forbidden,
+
+    #[error("This API key may not use layer {0}")]
+    LayerNotAllowed(String),
+
+    #[error("Daily tile quota exceeded")]
+    QuotaExceeded,
+
+    #[error("Timed out waiting for another request's upstream fetch")]
+    GatewayTimeout,
+
+    #[error("Missing or invalid admin token")]
+    AdminUnauthorized,
+
+    #[error("Rate limit exceeded")]
+    RateLimited,
+
+    #[error("Server overloaded")]
+    Overloaded,
+
+    #[error("Request timed out")]
+    RequestTimeout,
+
+    /// Failure of another request's fetch, handed to requests coalesced onto it
+    #[error(transparent)]
+    Shared(Arc<AppError>),
 }
 
-impl IntoResponse for AppError {
-    fn into_response(self) -> Response {
-        let status = match &self {
-            AppError::NotFound => StatusCode::NOT_FOUND,
-            AppError::InvalidCoordinates => StatusCode::BAD_REQUEST,
+impl AppError {
+    /// Whether the failure is a network error or upstream 5xx that may clear
+    /// up on its own, counting against the mirror's health and letting a
+    /// stale or fallback tile stand in
+    pub fn is_transient(&self) -> bool {
+        match self {
+            AppError::Upstream(_)
+            | AppError::UpstreamTimeout(_)
+            | AppError::UpstreamDns(_)
+            | AppError::UpstreamRefused(_)
+            | AppError::UpstreamTls(_)
+            | AppError::UpstreamRateLimited { .. } => true,
+            AppError::UpstreamStatus(code) => *code >= 500,
+            AppError::GatewayTimeout => true,
+            AppError::Shared(e) => e.is_transient(),
+            _ => false,
+        }
+    }
+
+    /// Whether another attempt, maybe at another mirror, could succeed. A
+    /// failed TLS handshake won't.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            AppError::UpstreamTls(_) => false,
+            AppError::Shared(e) => e.is_retryable(),
+            e => e.is_transient(),
+        }
+    }
+
+    /// Wait upstream asked for before the next attempt
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            AppError::UpstreamRateLimited { retry_after } => *retry_after,
+            AppError::Shared(e) => e.retry_after(),
+            _ => None,
+        }
+    }
+
+    /// Stable identifier of the kind of failure, for clients to match on
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::Upstream(_) => "upstream_error",
+            AppError::UpstreamTimeout(_) => "upstream_timeout",
+            AppError::UpstreamDns(_) => "upstream_dns",
+            AppError::UpstreamRefused(_) => "upstream_connection_refused",
+            AppError::UpstreamTls(_) => "upstream_tls",
+            AppError::UpstreamRateLimited { .. } => "upstream_rate_limited",
+            AppError::Io(_) => "io_error",
+            AppError::NotFound => "not_found",
+            AppError::InvalidCoordinates => "invalid_coordinates",
+            AppError::UpstreamStatus(_) => "upstream_status",
+            AppError::TileTooLarge(_) => "tile_too_large",
+            AppError::BadRequest(_) => "bad_request",
+            AppError::JobNotFound(_) => "job_not_found",
+            AppError::NoUpstreams => "no_upstreams",
+            AppError::InvalidProxy(_) => "invalid_proxy",
+            AppError::InvalidPassthroughHeader(_) => "invalid_passthrough_header",
+            AppError::InvalidUpstream { .. } => "invalid_upstream",
+            AppError::Sqlite(_) => "sqlite_error",
+            AppError::Archive(_) => "invalid_archive",
+            AppError::Image(_) => "image_error",
+            AppError::Cache(_) => "cache_error",
+            AppError::InvalidPath(_) => "invalid_path",
+            AppError::Unauthorized => "invalid_api_key",
+            AppError::Forbidden => "referer_not_allowed",
+            AppError::LayerNotAllowed(_) => "layer_not_allowed",
+            AppError::QuotaExceeded => "quota_exceeded",
+            AppError::GatewayTimeout => "coalesced_fetch_timeout",
+            AppError::AdminUnauthorized => "invalid_admin_token",
+            AppError::RateLimited => "rate_limited",
+            AppError::Overloaded => "overloaded",
+            AppError::RequestTimeout => "request_timeout",
+            AppError::Shared(e) => e.code(),
+        }
+    }
+
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            AppError::NotFound | AppError::JobNotFound(_) => StatusCode::NOT_FOUND,
+            AppError::InvalidCoordinates | AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
             AppError::UpstreamStatus(code) => {
                 StatusCode::from_u16(*code).unwrap_or(StatusCode::BAD_GATEWAY)
             }
-            AppError::Upstream(_) | AppError::Io(_) => StatusCode::BAD_GATEWAY,
+            AppError::Upstream(_)
+            | AppError::UpstreamDns(_)
+            | AppError::UpstreamRefused(_)
+            | AppError::UpstreamTls(_)
+            | AppError::Io(_)
+            | AppError::TileTooLarge(_)
+            | AppError::NoUpstreams
+            | AppError::InvalidProxy(_)
+            | AppError::InvalidPassthroughHeader(_)
+            | AppError::InvalidUpstream { .. } => StatusCode::BAD_GATEWAY,
+            AppError::Sqlite(_)
+            | AppError::Archive(_)
+            | AppError::Image(_)
+            | AppError::Cache(_)
+            | AppError::InvalidPath(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden | AppError::LayerNotAllowed(_) => StatusCode::FORBIDDEN,
+            AppError::QuotaExceeded | AppError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            AppError::UpstreamRateLimited { .. } | AppError::Overloaded => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            AppError::GatewayTimeout
+            | AppError::UpstreamTimeout(_)
+            | AppError::RequestTimeout => StatusCode::GATEWAY_TIMEOUT,
+            AppError::AdminUnauthorized => StatusCode::UNAUTHORIZED,
+            AppError::Shared(e) => e.status(),
+        }
+    }
+
+    /// RFC 7807 `application/problem+json` response, without logging it
+    pub(crate) fn problem(&self) -> Response {
+        let status = self.status();
+        let problem = Problem {
+            kind: "about:blank",
+            title: status.canonical_reason().unwrap_or_default(),
+            status: status.as_u16(),
+            detail: self.to_string(),
+            code: self.code(),
+        };
+        let mut response = (status, axum::Json(problem)).into_response();
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/problem+json"),
+        );
+        if let Some(retry_after) = self.retry_after() {
+            // Rounded up so clients don't come back too early
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+        }
+        response
+    }
+}
+
+/// Body of an error response. `type` is left at `about:blank`, so `title` is
+/// the status text; `code` tells failures sharing a status apart.
+#[derive(Serialize)]
+struct Problem {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: &'static str,
+    status: u16,
+    detail: String,
+    code: &'static str,
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        tracing::error!(error = %self, code = self.code(), "Request failed");
+        self.problem()
+    }
+}
+
+impl From<reqwest::Error> for AppError {
+    /// Sort network failures by cause, going by the errors they wrap
+    fn from(e: reqwest::Error) -> Self {
+        let mut causes = Vec::new();
+        let mut next = e.source();
+        while let Some(cause) = next {
+            causes.push(cause);
+            next = cause.source();
+        }
+        let io_kind = |kind| {
+            causes.iter().any(|cause| {
+                cause.downcast_ref::<std::io::Error>().is_some_and(|io| io.kind() == kind)
+            })
+        };
+        let detail = || {
+            let mut detail = e.to_string();
+            for cause in &causes {
+                detail.push_str(&format!(": {}", cause));
+            }
+            detail
         };
 
-        tracing::error!(error = %self, "Request failed");
-        (status, self.to_string()).into_response()
+        if e.is_timeout() || io_kind(ErrorKind::TimedOut) {
+            AppError::UpstreamTimeout(detail())
+        } else if causes.iter().any(|cause| is_tls(*cause)) {
+            AppError::UpstreamTls(detail())
+        } else if io_kind(ErrorKind::ConnectionRefused) {
+            AppError::UpstreamRefused(detail())
+        } else if causes.iter().any(|cause| cause.to_string() == "dns error") {
+            AppError::UpstreamDns(detail())
+        } else {
+            AppError::Upstream(e)
+        }
+    }
+}
+
+/// Whether an error is rustls', maybe wrapped in io::Errors, whose source
+/// skips the error they wrap
+fn is_tls(mut error: &(dyn std::error::Error + 'static)) -> bool {
+    loop {
+        if error.is::<rustls::Error>() {
+            return true;
+        }
+        match error.downcast_ref::<std::io::Error>().and_then(|io| io.get_ref()) {
+            Some(inner) => error = inner,
+            None => return false,
+        }
     }
 }
 
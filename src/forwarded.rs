@@ -0,0 +1,131 @@
+use crate::config::Config;
+use crate::handlers::AppState;
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::HeaderMap;
+use axum::middleware::Next;
+use axum::response::Response;
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+/// Addresses of reverse proxies and load balancers whose forwarding headers
+/// are believed
+#[derive(Debug, Default)]
+pub struct TrustedProxies {
+    networks: Vec<IpNet>,
+}
+
+impl TrustedProxies {
+    /// Parse `trusted_proxies`, where `trust_x_forwarded_for` trusts everyone
+    pub fn from_config(config: &Config) -> anyhow::Result<Self> {
+        let mut networks = config
+            .trusted_proxies
+            .iter()
+            .map(|entry| {
+                entry
+                    .parse::<IpNet>()
+                    .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| anyhow::anyhow!("Invalid trusted proxy {:?}", entry))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if config.trust_x_forwarded_for {
+            networks.extend(["0.0.0.0/0", "::/0"].map(|net| net.parse::<IpNet>().expect("valid")));
+        }
+        Ok(Self { networks })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Client address of a request from `peer`. Forwarded addresses are
+    /// followed back from the nearest one for as long as they are trusted
+    /// proxies themselves; Unix socket peers are trusted whenever any proxy is.
+    pub fn resolve(&self, headers: &HeaderMap, peer: Option<IpAddr>) -> Option<IpAddr> {
+        let peer = peer.map(|ip| ip.to_canonical());
+        let trusted = match peer {
+            Some(ip) => self.contains(ip),
+            None => !self.networks.is_empty(),
+        };
+        if !trusted {
+            return peer;
+        }
+        let mut client = peer;
+        for hop in forwarded_for(headers).into_iter().rev() {
+            // Obfuscated or unknown hops end the chain
+            let Some(ip) = hop.map(|ip| ip.to_canonical()) else {
+                break;
+            };
+            client = Some(ip);
+            if !self.contains(ip) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+/// Address a request was resolved to come from, None over a Unix socket
+/// without a trusted forwarded address
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+/// Middleware recording the client address for rate limiting and logging
+pub async fn resolve_client_ip(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let peer = request.extensions().get::<ConnectInfo<SocketAddr>>().map(|info| info.0.ip());
+    let client = state.trusted_proxies.load().resolve(request.headers(), peer);
+    request.extensions_mut().insert(ClientIp(client));
+    next.run(request).await
+}
+
+/// The address `resolve_client_ip` found for a request
+pub fn client_ip(request: &Request) -> Option<IpAddr> {
+    request.extensions().get::<ClientIp>().and_then(|client| client.0)
+}
+
+/// Addresses a request passed through, client first, from the standard
+/// `Forwarded` header or else `X-Forwarded-For`. Entries that aren't
+/// addresses are None.
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .collect::<Vec<_>>()
+    };
+    let forwarded = values("forwarded");
+    if !forwarded.is_empty() {
+        return forwarded
+            .into_iter()
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.trim().split_once('='))
+                    .find(|(name, _)| name.eq_ignore_ascii_case("for"))
+                    .and_then(|(_, node)| parse_node(node))
+            })
+            .collect();
+    }
+    values("x-forwarded-for").into_iter().map(parse_node).collect()
+}
+
+/// Address of a node such as `192.0.2.1`, `192.0.2.1:4711` or
+/// `"[2001:db8::1]:4711"`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    node.parse::<SocketAddr>().ok().map(|addr| addr.ip())
+}
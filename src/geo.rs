@@ -0,0 +1,149 @@
+use crate::error::{AppError, Result};
+use crate::types::TileKey;
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+use std::str::FromStr;
+
+/// Deepest zoom level addressable by a `TileKey`
+pub const MAX_ZOOM: u8 = 31;
+
+/// Latitude limit of the Web Mercator projection
+pub const MAX_LATITUDE: f64 = 85.051_128_78;
+
+/// Convert a longitude to the tile column containing it at zoom `z`
+pub fn lon_to_tile_x(lon: f64, z: u8) -> u32 {
+    let n = (1u64 << z) as f64;
+    lon_to_x(lon, z).floor().clamp(0.0, n - 1.0) as u32
+}
+
+/// Convert a latitude to the tile row containing it at zoom `z`
+pub fn lat_to_tile_y(lat: f64, z: u8) -> u32 {
+    let n = (1u64 << z) as f64;
+    lat_to_y(lat, z).floor().clamp(0.0, n - 1.0) as u32
+}
+
+/// Fractional tile column of a longitude at zoom `z`
+pub fn lon_to_x(lon: f64, z: u8) -> f64 {
+    (lon + 180.0) / 360.0 * (1u64 << z) as f64
+}
+
+/// Fractional tile row of a latitude at zoom `z`
+pub fn lat_to_y(lat: f64, z: u8) -> f64 {
+    let lat = lat.clamp(-MAX_LATITUDE, MAX_LATITUDE).to_radians();
+    (1.0 - (lat.tan() + 1.0 / lat.cos()).ln() / PI) / 2.0 * (1u64 << z) as f64
+}
+
+/// Longitude of a fractional tile column at zoom `z`
+pub fn x_to_lon(x: f64, z: u8) -> f64 {
+    x / (1u64 << z) as f64 * 360.0 - 180.0
+}
+
+/// Latitude of a fractional tile row at zoom `z`
+pub fn y_to_lat(y: f64, z: u8) -> f64 {
+    let n = PI * (1.0 - 2.0 * y / (1u64 << z) as f64);
+    n.sinh().atan().to_degrees()
+}
+
+/// Geographic bounding box in WGS84 degrees
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BoundingBox {
+    pub min_lon: f64,
+    pub min_lat: f64,
+    pub max_lon: f64,
+    pub max_lat: f64,
+}
+
+impl BoundingBox {
+    pub fn is_valid(&self) -> bool {
+        (-180.0..=180.0).contains(&self.min_lon)
+            && (-180.0..=180.0).contains(&self.max_lon)
+            && (-90.0..=90.0).contains(&self.min_lat)
+            && (-90.0..=90.0).contains(&self.max_lat)
+            && self.min_lon <= self.max_lon
+            && self.min_lat <= self.max_lat
+    }
+
+    /// Inclusive tile column and row ranges covering the box at zoom `z`
+    fn tile_ranges(&self, z: u8) -> ((u32, u32), (u32, u32)) {
+        let xs = (lon_to_tile_x(self.min_lon, z), lon_to_tile_x(self.max_lon, z));
+        // Tile rows grow southwards, so the northern edge gives the smaller y
+        let ys = (lat_to_tile_y(self.max_lat, z), lat_to_tile_y(self.min_lat, z));
+        (xs, ys)
+    }
+
+    /// Number of tiles covering the box at zoom `z`
+    pub fn tile_count(&self, z: u8) -> u64 {
+        let ((x0, x1), (y0, y1)) = self.tile_ranges(z);
+        (x1 - x0 + 1) as u64 * (y1 - y0 + 1) as u64
+    }
+
+    /// Iterate over every tile covering the box at zoom `z`
+    pub fn tiles(&self, z: u8) -> impl Iterator<Item = TileKey> {
+        let ((x0, x1), (y0, y1)) = self.tile_ranges(z);
+        (x0..=x1).flat_map(move |x| (y0..=y1).map(move |y| TileKey::new(z, x, y)))
+    }
+
+    /// Whether a tile overlaps the box
+    pub fn contains(&self, key: &TileKey) -> bool {
+        let ((x0, x1), (y0, y1)) = self.tile_ranges(key.z);
+        (x0..=x1).contains(&key.x) && (y0..=y1).contains(&key.y)
+    }
+}
+
+/// Parse `min_lon,min_lat,max_lon,max_lat`
+impl FromStr for BoundingBox {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<f64>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        let [min_lon, min_lat, max_lon, max_lat] = values[..] else {
+            return Err("expected min_lon,min_lat,max_lon,max_lat".to_string());
+        };
+        let bbox = Self { min_lon, min_lat, max_lon, max_lat };
+        if !bbox.is_valid() {
+            return Err("invalid bounding box".to_string());
+        }
+        Ok(bbox)
+    }
+}
+
+/// A bounding box over an inclusive zoom range
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TileRange {
+    pub bbox: BoundingBox,
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+}
+
+impl TileRange {
+    pub fn validate(&self, max_zoom: u8) -> Result<()> {
+        if !self.bbox.is_valid() {
+            return Err(AppError::BadRequest("invalid bounding box".to_string()));
+        }
+        if self.min_zoom > self.max_zoom || self.max_zoom > max_zoom {
+            return Err(AppError::BadRequest(format!(
+                "zoom range must satisfy min_zoom <= max_zoom <= {}",
+                max_zoom
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn tile_count(&self) -> u64 {
+        (self.min_zoom..=self.max_zoom)
+            .map(|z| self.bbox.tile_count(z))
+            .sum()
+    }
+
+    pub fn tiles(&self) -> impl Iterator<Item = TileKey> + '_ {
+        (self.min_zoom..=self.max_zoom).flat_map(|z| self.bbox.tiles(z))
+    }
+
+    pub fn contains(&self, key: &TileKey) -> bool {
+        (self.min_zoom..=self.max_zoom).contains(&key.z) && self.bbox.contains(key)
+    }
+}
@@ -0,0 +1,353 @@
+//! gRPC service for `grpc_bind_addr`, serving the same tiles as the HTTP
+//! routes. The schema lives in `proto/tiles.proto`; build.rs generates the
+//! service stubs and the messages below mirror it.
+
+use crate::error::AppError;
+use crate::geo::{self, MAX_ZOOM};
+use crate::handlers::batch::{self, BatchTile, BATCH_CONCURRENCY};
+use crate::handlers::tile::tile_response;
+use crate::handlers::{has_admin_token, AppState};
+use crate::seed::{self, JobState, JobStatus};
+use crate::types::{TileFormat, TileKey, TileScheme};
+use axum::http::{header, HeaderMap, StatusCode, Uri};
+use axum::response::Response;
+use axum::Router;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use std::sync::Arc;
+use tonic::{Code, Request, Status};
+
+mod pb {
+    include!(concat!(env!("OUT_DIR"), "/maptile.Tiles.rs"));
+}
+
+use pb::tiles_server::{Tiles, TilesServer};
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TileRequest {
+    #[prost(uint32, tag = "1")]
+    pub z: u32,
+    #[prost(uint32, tag = "2")]
+    pub x: u32,
+    #[prost(uint32, tag = "3")]
+    pub y: u32,
+    #[prost(string, tag = "4")]
+    pub format: String,
+    #[prost(bool, tag = "5")]
+    pub retina: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TileReply {
+    #[prost(uint32, tag = "1")]
+    pub z: u32,
+    #[prost(uint32, tag = "2")]
+    pub x: u32,
+    #[prost(uint32, tag = "3")]
+    pub y: u32,
+    #[prost(uint32, tag = "4")]
+    pub status: u32,
+    #[prost(string, tag = "5")]
+    pub content_type: String,
+    #[prost(string, tag = "6")]
+    pub content_encoding: String,
+    #[prost(string, tag = "7")]
+    pub etag: String,
+    #[prost(bytes = "bytes", tag = "8")]
+    pub data: bytes::Bytes,
+    #[prost(string, tag = "9")]
+    pub error: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TileCoord {
+    #[prost(uint32, tag = "1")]
+    pub z: u32,
+    #[prost(uint32, tag = "2")]
+    pub x: u32,
+    #[prost(uint32, tag = "3")]
+    pub y: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct BoundingBox {
+    #[prost(double, tag = "1")]
+    pub min_lon: f64,
+    #[prost(double, tag = "2")]
+    pub min_lat: f64,
+    #[prost(double, tag = "3")]
+    pub max_lon: f64,
+    #[prost(double, tag = "4")]
+    pub max_lat: f64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TileRange {
+    #[prost(message, optional, tag = "1")]
+    pub bbox: Option<BoundingBox>,
+    #[prost(uint32, tag = "2")]
+    pub min_zoom: u32,
+    #[prost(uint32, tag = "3")]
+    pub max_zoom: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct BatchRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub tiles: Vec<TileCoord>,
+    #[prost(message, optional, tag = "2")]
+    pub range: Option<TileRange>,
+    #[prost(string, tag = "3")]
+    pub format: String,
+    #[prost(bool, tag = "4")]
+    pub retina: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SeedRequest {
+    #[prost(message, optional, tag = "1")]
+    pub range: Option<TileRange>,
+    #[prost(string, tag = "2")]
+    pub format: String,
+    #[prost(bool, tag = "3")]
+    pub retina: bool,
+    #[prost(uint32, tag = "4")]
+    pub concurrency: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SeedProgress {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(bool, tag = "2")]
+    pub completed: bool,
+    #[prost(uint64, tag = "3")]
+    pub total: u64,
+    #[prost(uint64, tag = "4")]
+    pub fetched: u64,
+    #[prost(uint64, tag = "5")]
+    pub skipped: u64,
+    #[prost(uint64, tag = "6")]
+    pub failed: u64,
+}
+
+/// Router serving the gRPC service over HTTP/2
+pub fn router(state: Arc<AppState>) -> Router {
+    tonic::service::Routes::new(TilesServer::new(TileService { state })).into_axum_router()
+}
+
+struct TileService {
+    state: Arc<AppState>,
+}
+
+impl TileService {
+    /// Count `tiles` against the caller's API key, taken from an
+    /// `authorization: Bearer` entry in the call's metadata
+    fn consume(&self, headers: &HeaderMap, tiles: u64) -> Result<(), Status> {
+        match &self.state.api_keys {
+            Some(api_keys) => {
+                let uri = Uri::from_static("/");
+                let layer = &self.state.wmts_layer;
+                api_keys.consume(&uri, headers, layer, tiles).map_err(error_status)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Tiles for TileService {
+    async fn get_tile(
+        &self,
+        request: Request<TileRequest>,
+    ) -> Result<tonic::Response<TileReply>, Status> {
+        let headers = request.metadata().clone().into_headers();
+        let request = request.into_inner();
+        let z = u8::try_from(request.z).map_err(|_| error_status(AppError::InvalidCoordinates))?;
+        let key = TileKey::new(z, request.x, request.y)
+            .with_format(parse_format(&request.format)?)
+            .with_scale(if request.retina { 2 } else { 1 });
+        self.consume(&headers, 1)?;
+
+        let reply = load_reply(&self.state, key).await;
+        if reply.status != u32::from(StatusCode::OK.as_u16()) {
+            let status = StatusCode::from_u16(reply.status as u16)
+                .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+            return Err(Status::new(code(status), reply.error));
+        }
+        Ok(tonic::Response::new(reply))
+    }
+
+    type GetTileBatchStream = BoxStream<'static, Result<TileReply, Status>>;
+
+    /// Tiles are loaded a few at a time ahead of the client, and only as fast
+    /// as it reads them
+    async fn get_tile_batch(
+        &self,
+        request: Request<BatchRequest>,
+    ) -> Result<tonic::Response<Self::GetTileBatchStream>, Status> {
+        let headers = request.metadata().clone().into_headers();
+        let request = request.into_inner();
+        let tiles = request
+            .tiles
+            .iter()
+            .map(|tile| {
+                let z = u8::try_from(tile.z).map_err(|_| AppError::InvalidCoordinates)?;
+                Ok(BatchTile {
+                    z,
+                    x: tile.x,
+                    y: tile.y,
+                })
+            })
+            .collect::<Result<_, AppError>>()
+            .map_err(error_status)?;
+        let batch = batch::BatchRequest {
+            tiles,
+            range: request.range.map(tile_range).transpose()?,
+            format: parse_format(&request.format)?,
+            retina: request.retina,
+        };
+        let keys = batch
+            .keys(self.state.config.load().max_batch_tiles)
+            .map_err(error_status)?;
+        self.consume(&headers, keys.len() as u64)?;
+
+        let state = self.state.clone();
+        let replies = stream::iter(keys)
+            .map(move |key| {
+                let state = state.clone();
+                async move { Ok(load_reply(&state, key).await) }
+            })
+            .buffered(BATCH_CONCURRENCY);
+        Ok(tonic::Response::new(replies.boxed()))
+    }
+
+    type SeedRegionStream = BoxStream<'static, Result<SeedProgress, Status>>;
+
+    async fn seed_region(
+        &self,
+        request: Request<SeedRequest>,
+    ) -> Result<tonic::Response<Self::SeedRegionStream>, Status> {
+        if !has_admin_token(&self.state, &request.metadata().clone().into_headers()) {
+            return Err(Status::unauthenticated("Missing or invalid admin token"));
+        }
+        let request = request.into_inner();
+        let range = request
+            .range
+            .ok_or_else(|| Status::invalid_argument("a seed request needs a range"))?;
+        let request = seed::SeedRequest {
+            range: tile_range(range)?,
+            format: parse_format(&request.format)?,
+            retina: request.retina,
+            concurrency: (request.concurrency > 0).then_some(request.concurrency as usize),
+        };
+        request.validate().map_err(error_status)?;
+
+        let job = self.state.jobs.create(request, self.state.seed_concurrency);
+        tokio::spawn(seed::run_seed(self.state.clone(), job.clone()));
+
+        let progress = job.progress().map(|status| Ok(seed_progress(status)));
+        Ok(tonic::Response::new(progress.boxed()))
+    }
+}
+
+/// Serve one tile as the HTTP routes would, carrying its status and headers
+/// over into the reply
+async fn load_reply(state: &Arc<AppState>, key: TileKey) -> TileReply {
+    let filename = key.file_name();
+    let response =
+        tile_response(state, key.z, key.x, &filename, TileScheme::Xyz, &HeaderMap::new()).await;
+    tile_reply(&key, response).await
+}
+
+async fn tile_reply(key: &TileKey, response: Response) -> TileReply {
+    let (parts, body) = response.into_parts();
+    let header = |name: header::HeaderName| {
+        parts
+            .headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string()
+    };
+    let body = axum::body::to_bytes(body, usize::MAX).await.unwrap_or_default();
+    let mut reply = TileReply {
+        z: key.z.into(),
+        x: key.x,
+        y: key.y,
+        status: parts.status.as_u16().into(),
+        content_type: header(header::CONTENT_TYPE),
+        content_encoding: header(header::CONTENT_ENCODING),
+        etag: header(header::ETAG),
+        ..Default::default()
+    };
+    if parts.status.is_success() {
+        reply.data = body;
+    } else {
+        // Error responses are problem+json, whose detail is the message
+        reply.error = serde_json::from_slice::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|problem| Some(problem.get("detail")?.as_str()?.to_string()))
+            .unwrap_or_else(|| String::from_utf8_lossy(&body).into_owned());
+    }
+    reply
+}
+
+/// Tile format from a file extension, PNG when empty
+fn parse_format(extension: &str) -> Result<TileFormat, Status> {
+    if extension.is_empty() {
+        return Ok(TileFormat::default());
+    }
+    TileFormat::from_extension(extension)
+        .ok_or_else(|| Status::invalid_argument(format!("unknown tile format {:?}", extension)))
+}
+
+fn tile_range(range: TileRange) -> Result<geo::TileRange, Status> {
+    let bbox = range
+        .bbox
+        .ok_or_else(|| Status::invalid_argument("a tile range needs a bbox"))?;
+    let zoom = |zoom: u32| {
+        u8::try_from(zoom).ok().filter(|z| *z <= MAX_ZOOM).ok_or_else(|| {
+            Status::invalid_argument(format!("zoom must be at most {}", MAX_ZOOM))
+        })
+    };
+    Ok(geo::TileRange {
+        bbox: geo::BoundingBox {
+            min_lon: bbox.min_lon,
+            min_lat: bbox.min_lat,
+            max_lon: bbox.max_lon,
+            max_lat: bbox.max_lat,
+        },
+        min_zoom: zoom(range.min_zoom)?,
+        max_zoom: zoom(range.max_zoom)?,
+    })
+}
+
+fn seed_progress(status: JobStatus) -> SeedProgress {
+    SeedProgress {
+        id: status.id,
+        completed: matches!(status.state, JobState::Completed),
+        total: status.total,
+        fetched: status.fetched,
+        skipped: status.skipped,
+        failed: status.failed,
+    }
+}
+
+fn error_status(e: AppError) -> Status {
+    Status::new(code(e.status()), e.to_string())
+}
+
+/// gRPC code closest to the HTTP status the same failure gets
+fn code(status: StatusCode) -> Code {
+    match status {
+        StatusCode::BAD_REQUEST => Code::InvalidArgument,
+        StatusCode::UNAUTHORIZED => Code::Unauthenticated,
+        StatusCode::FORBIDDEN => Code::PermissionDenied,
+        StatusCode::NOT_FOUND | StatusCode::GONE => Code::NotFound,
+        StatusCode::TOO_MANY_REQUESTS => Code::ResourceExhausted,
+        StatusCode::GATEWAY_TIMEOUT => Code::DeadlineExceeded,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE => Code::Unavailable,
+        status if status.is_server_error() => Code::Internal,
+        _ => Code::Unknown,
+    }
+}
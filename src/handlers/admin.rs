@@ -0,0 +1,654 @@
+use crate::api_keys::{KeyUsage, TenantUsage};
+use crate::cache::{DiskTileInfo, DiskUsage};
+use crate::config::Freshness;
+use crate::error::{AppError, Result};
+use crate::geo::{TileRange, MAX_ZOOM};
+use crate::handlers::AppState;
+use crate::mbtiles::{self, ExportSummary};
+use crate::metrics;
+use crate::popularity::{Heatmap, PopularTile};
+use crate::preload::{self, PreloadSummary};
+use crate::reload;
+use crate::seed::{self, JobState, JobStatus, SeedRequest};
+use crate::types::{TileFormat, TileKey, TilePath};
+use axum::extract::{Path, Query, Request, State};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures_util::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+/// Middleware rejecting admin requests without the configured bearer token
+pub async fn require_admin_token(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if has_admin_token(&state, request.headers()) {
+        return next.run(request).await;
+    }
+    let mut response = AppError::AdminUnauthorized.problem();
+    response
+        .headers_mut()
+        .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+    response
+}
+
+/// Whether a request presents the admin bearer token, always true when none is set
+pub(crate) fn has_admin_token(state: &AppState, headers: &HeaderMap) -> bool {
+    let Some(token) = &state.admin_token else {
+        return true;
+    };
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|presented| constant_time_eq(presented.as_bytes(), token.as_bytes()))
+}
+
+/// Compare secrets without exiting early on the first differing byte
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Re-read the config, applying upstream, cache lifetime, rate limit and CORS
+/// changes without restarting
+pub async fn post_reload(State(state): State<Arc<AppState>>) -> Result<StatusCode> {
+    reload::reload(&state).map_err(|e| AppError::BadRequest(e.to_string()))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Start a background job pre-warming the cache for a bbox and zoom range
+pub async fn post_seed(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<SeedRequest>,
+) -> Result<(StatusCode, Json<JobStatus>)> {
+    request.validate()?;
+
+    let job = state.jobs.create(request, state.seed_concurrency);
+    tokio::spawn(seed::run_seed(state.clone(), job.clone()));
+
+    Ok((StatusCode::ACCEPTED, Json(job.status())))
+}
+
+/// Status of every seed job, oldest first
+pub async fn get_jobs(State(state): State<Arc<AppState>>) -> Json<Vec<JobStatus>> {
+    Json(state.jobs.all().iter().map(|job| job.status()).collect())
+}
+
+pub async fn get_job(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+) -> Result<Json<JobStatus>> {
+    let job = state.jobs.get(id).ok_or(AppError::JobNotFound(id))?;
+    Ok(Json(job.status()))
+}
+
+/// Follow a seed job as Server-Sent Events: a `progress` event with its status
+/// every second while it runs, then a final `completed` one
+pub async fn get_job_events(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+) -> Result<Sse<impl Stream<Item = std::result::Result<Event, Infallible>>>> {
+    let job = state.jobs.get(id).ok_or(AppError::JobNotFound(id))?;
+    let events = job.progress().map(|status| {
+        let name = match status.state {
+            JobState::Running => "progress",
+            JobState::Completed => "completed",
+        };
+        Ok(Event::default()
+            .event(name)
+            .json_data(&status)
+            .expect("job status serializes to JSON"))
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// Number of entries removed from each cache tier
+#[derive(Debug, Default, Serialize)]
+pub struct PurgeResult {
+    pub memory: u64,
+    pub disk: u64,
+}
+
+impl PurgeResult {
+    /// Purge the tile at `key`'s coordinates in every format and scale
+    async fn purge(&mut self, state: &AppState, key: &TileKey) -> Result<()> {
+        let variants = TileFormat::ALL
+            .into_iter()
+            .flat_map(|format| TileKey::SCALES.map(|scale| key.with_format(format).with_scale(scale)));
+        for key in variants {
+            for tier in state.tiers.remove(&key).await? {
+                match tier {
+                    "memory" => self.memory += 1,
+                    "disk" => self.disk += 1,
+                    _ => {}
+                }
+            }
+            state.negative_cache.remove(&key).await;
+        }
+        Ok(())
+    }
+}
+
+/// Remove a single tile from every cache tier
+pub async fn delete_tile(
+    State(state): State<Arc<AppState>>,
+    Path((z, x, y)): Path<(u8, u32, u32)>,
+) -> Result<Json<PurgeResult>> {
+    let key = TileKey::new(z, x, y).with_generation(state.disk_cache.generation());
+    if !key.is_valid() {
+        return Err(AppError::InvalidCoordinates);
+    }
+
+    let mut result = PurgeResult::default();
+    result.purge(&state, &key).await?;
+    tracing::info!(key = %key, memory = result.memory, disk = result.disk, "Purged tile");
+
+    Ok(Json(result))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TileInfoQuery {
+    format: Option<TileFormat>,
+    scale: Option<u8>,
+}
+
+/// What one tier holds for a tile
+#[derive(Debug, Serialize)]
+pub struct TierPresence {
+    pub tier: &'static str,
+    pub present: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    /// Time since the copy was written or revalidated, where the tier knows it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub age_secs: Option<u64>,
+    /// Whether the copy would be served as is, while revalidated, or only
+    /// after a refetch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub freshness: Option<Freshness>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub synthesized: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Everything the cache knows about a single tile
+#[derive(Debug, Serialize)]
+pub struct TileInfo {
+    pub key: String,
+    pub generation: u32,
+    /// Each tier in lookup order
+    pub tiers: Vec<TierPresence>,
+    pub disk: DiskTileInfo,
+    /// Whether upstream's 404 is being remembered
+    pub negative: bool,
+    /// Freshness window for the tile's zoom, before any upstream max-age
+    pub freshness_window_secs: u64,
+    /// Recent requests, when popularity tracking is on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requests: Option<u64>,
+}
+
+/// Presence, age and on-disk details of a tile in every cache tier
+pub async fn get_tile_info(
+    State(state): State<Arc<AppState>>,
+    Path((z, x, y)): Path<(u8, u32, u32)>,
+    Query(query): Query<TileInfoQuery>,
+) -> Result<Json<TileInfo>> {
+    let scale = query.scale.unwrap_or(1);
+    if !TileKey::SCALES.contains(&scale) {
+        return Err(AppError::BadRequest(format!("Unsupported scale {}", scale)));
+    }
+    let key = TileKey::new(z, x, y)
+        .with_format(query.format.unwrap_or_default())
+        .with_scale(scale)
+        .with_generation(state.disk_cache.generation());
+    if !key.is_valid() {
+        return Err(AppError::InvalidCoordinates);
+    }
+
+    // Before probing the tiers, whose reads would update the last access time
+    let disk_cache = state.disk_cache.clone();
+    let disk = tokio::task::spawn_blocking(move || disk_cache.inspect(&key))
+        .await
+        .expect("tile inspection task panicked")?;
+
+    let policy = state.cache_policy.load();
+    let tiers = state
+        .tiers
+        .probe(&key)
+        .await
+        .into_iter()
+        .map(|(tier, result)| {
+            let mut presence = TierPresence {
+                tier,
+                present: false,
+                bytes: None,
+                etag: None,
+                age_secs: None,
+                freshness: None,
+                synthesized: None,
+                error: None,
+            };
+            match result {
+                Ok(Some(tile)) => {
+                    presence.present = true;
+                    presence.bytes = Some(tile.data.len());
+                    presence.etag = tile.etag.clone();
+                    presence.age_secs = tile.age().map(|age| age.as_secs());
+                    presence.freshness = tile.age().map(|_| policy.freshness(key.z, &tile));
+                    presence.synthesized = Some(tile.synthesized);
+                }
+                Ok(None) => {}
+                Err(e) => presence.error = Some(e.to_string()),
+            }
+            presence
+        })
+        .collect();
+
+    Ok(Json(TileInfo {
+        key: key.to_string(),
+        generation: key.generation,
+        tiers,
+        disk,
+        negative: state.negative_cache.contains(&key).await,
+        freshness_window_secs: policy.freshness_window(key.z, None).as_secs(),
+        requests: state.popularity.as_ref().map(|popularity| popularity.requests(&key)),
+    }))
+}
+
+/// Remove every tile covering a bbox and zoom range from every cache tier
+pub async fn purge_range(
+    State(state): State<Arc<AppState>>,
+    Json(range): Json<TileRange>,
+) -> Result<Json<PurgeResult>> {
+    Ok(Json(purge_tiles(&state, &range).await?))
+}
+
+/// Purge a range within the served zoom levels, refusing ones covering more
+/// than `max_purge_tiles`
+pub(crate) async fn purge_tiles(state: &AppState, range: &TileRange) -> Result<PurgeResult> {
+    range.validate(state.max_zoom)?;
+    let max_tiles = state.config.load().max_purge_tiles;
+    if range.tile_count() > max_tiles as u64 {
+        return Err(AppError::BadRequest(format!(
+            "a purge may cover at most {} tiles",
+            max_tiles
+        )));
+    }
+
+    let mut result = PurgeResult::default();
+    let generation = state.disk_cache.generation();
+    for key in range.tiles() {
+        result.purge(state, &key.with_generation(generation)).await?;
+    }
+    tracing::info!(
+        tiles = range.tile_count(),
+        memory = result.memory,
+        disk = result.disk,
+        "Purged tile range"
+    );
+    Ok(result)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportRequest {
+    /// File name, without extension, created in the export directory
+    pub name: String,
+    /// Limit the export to a bbox and zoom range, defaults to the whole cache
+    pub range: Option<TileRange>,
+    /// Tile format to export, defaults to PNG
+    #[serde(default)]
+    pub format: TileFormat,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportResponse {
+    pub path: PathBuf,
+    #[serde(flatten)]
+    pub summary: ExportSummary,
+}
+
+/// Package cached tiles into an MBTiles file in the export directory
+pub async fn post_export(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<ExportRequest>,
+) -> Result<Json<ExportResponse>> {
+    Ok(Json(export_tiles(&state, request).await?))
+}
+
+pub(crate) async fn export_tiles(
+    state: &AppState,
+    request: ExportRequest,
+) -> Result<ExportResponse> {
+    let valid_name = !request.name.is_empty()
+        && request
+            .name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid_name {
+        return Err(AppError::BadRequest(
+            "name may only contain letters, digits, '-' and '_'".to_string(),
+        ));
+    }
+    if let Some(range) = &request.range {
+        range.validate(MAX_ZOOM)?;
+    }
+
+    std::fs::create_dir_all(&state.export_dir)?;
+    let path = state.export_dir.join(format!("{}.mbtiles", request.name));
+
+    let disk_cache = state.disk_cache.clone();
+    let export_path = path.clone();
+    let summary = tokio::task::spawn_blocking(move || {
+        mbtiles::export(
+            &disk_cache,
+            &export_path,
+            &request.name,
+            request.format,
+            request.range.as_ref(),
+        )
+    })
+    .await
+    .expect("export task panicked")?;
+
+    tracing::info!(path = ?path, tiles = summary.tiles, "Exported MBTiles");
+    Ok(ExportResponse { path, summary })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PreloadRequest {
+    /// MBTiles file or `z/x/y.ext` directory tree on the server
+    pub path: PathBuf,
+}
+
+/// Copy tiles from an MBTiles file or tile directory into the disk cache
+pub async fn post_preload(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<PreloadRequest>,
+) -> Result<Json<PreloadSummary>> {
+    if !request.path.exists() {
+        return Err(AppError::BadRequest(format!("{:?} does not exist", request.path)));
+    }
+    let disk_cache = state.disk_cache.clone();
+    let summary = tokio::task::spawn_blocking(move || preload::preload(&disk_cache, &request.path))
+        .await
+        .expect("preload task panicked")?;
+    Ok(Json(summary))
+}
+
+/// Most tiles a single warm request may name or cover
+const MAX_WARM_TILES: u64 = 100_000;
+
+#[derive(Debug, Deserialize)]
+pub struct WarmRequest {
+    /// Tiles as displayed, e.g. `12/2048/1361@2x.png`
+    #[serde(default)]
+    pub tiles: Vec<String>,
+    /// Also every tile covering a bbox and zoom range
+    pub range: Option<TileRange>,
+    /// Format of the range's tiles, defaults to PNG
+    #[serde(default)]
+    pub format: TileFormat,
+    /// Take the range's `@2x` tiles instead of standard ones
+    #[serde(default)]
+    pub retina: bool,
+}
+
+/// What became of the tiles of a warm request
+#[derive(Debug, Default, Serialize)]
+pub struct WarmResult {
+    /// Loaded from disk into memory
+    pub loaded: u64,
+    /// Already in memory
+    pub cached: u64,
+    /// Not on disk, left to be fetched when first requested
+    pub missing: u64,
+    /// Over `memory_max_tile_bytes`
+    pub too_large: u64,
+}
+
+/// Load tiles from disk into the memory cache ahead of expected traffic,
+/// without fetching any from upstream
+pub async fn post_warm(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<WarmRequest>,
+) -> Result<Json<WarmResult>> {
+    let mut keys = request
+        .tiles
+        .iter()
+        .map(|tile| {
+            TilePath::parse(tile)
+                .map(TilePath::key)
+                .ok_or_else(|| AppError::BadRequest(format!("invalid tile {:?}", tile)))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if let Some(range) = &request.range {
+        range.validate(MAX_ZOOM)?;
+    }
+    let count = keys.len() as u64 + request.range.map_or(0, |range| range.tile_count());
+    if count > MAX_WARM_TILES {
+        return Err(AppError::BadRequest(format!(
+            "at most {} tiles can be warmed at once",
+            MAX_WARM_TILES
+        )));
+    }
+    if let Some(range) = &request.range {
+        let scale = if request.retina { 2 } else { 1 };
+        keys.extend(range.tiles().map(|key| key.with_format(request.format).with_scale(scale)));
+    }
+
+    let mut result = WarmResult::default();
+    let generation = state.disk_cache.generation();
+    for key in keys {
+        let key = key.with_generation(generation);
+        if state.memory_cache.get(&key).await.is_some() {
+            result.cached += 1;
+            continue;
+        }
+        let Some(tile) = state.disk_cache.load(&key).await else {
+            result.missing += 1;
+            continue;
+        };
+        if state.memory_cache.insert_tile(key, tile).await {
+            result.loaded += 1;
+        } else {
+            result.too_large += 1;
+        }
+    }
+    tracing::info!(
+        loaded = result.loaded,
+        cached = result.cached,
+        missing = result.missing,
+        too_large = result.too_large,
+        "Warmed memory cache"
+    );
+    Ok(Json(result))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OfflineMode {
+    pub offline: bool,
+}
+
+pub async fn get_offline(State(state): State<Arc<AppState>>) -> Json<OfflineMode> {
+    Json(OfflineMode {
+        offline: state.is_offline(),
+    })
+}
+
+/// Switch offline mode on or off without a restart
+pub async fn put_offline(
+    State(state): State<Arc<AppState>>,
+    Json(mode): Json<OfflineMode>,
+) -> Json<OfflineMode> {
+    state.offline.store(mode.offline, Ordering::Relaxed);
+    tracing::info!(offline = mode.offline, "Offline mode changed");
+    Json(mode)
+}
+
+#[derive(Debug, Serialize)]
+pub struct CacheGeneration {
+    pub generation: u32,
+}
+
+pub async fn get_generation(State(state): State<Arc<AppState>>) -> Json<CacheGeneration> {
+    Json(CacheGeneration {
+        generation: state.disk_cache.generation(),
+    })
+}
+
+/// Start a new cache generation, invalidating every cached tile at once.
+/// Earlier generations are deleted from disk in the background.
+pub async fn post_generation(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<CacheGeneration>> {
+    let generation = state.disk_cache.bump_generation()?;
+    state.memory_cache.clear();
+    tracing::info!(generation, "Cache generation bumped");
+
+    let disk_cache = state.disk_cache.clone();
+    tokio::task::spawn_blocking(move || {
+        let removed = disk_cache.prune_generations();
+        tracing::info!(removed, "Removed old cache generations");
+    });
+
+    Ok(Json(CacheGeneration { generation }))
+}
+
+#[derive(Debug, Serialize)]
+pub struct TierStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Fraction of lookups served by the tier, absent before any lookup
+    pub hit_ratio: Option<f64>,
+}
+
+impl TierStats {
+    fn new(hits: u64, misses: u64) -> Self {
+        let lookups = hits + misses;
+        Self {
+            hits,
+            misses,
+            hit_ratio: (lookups > 0).then(|| hits as f64 / lookups as f64),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct UpstreamStats {
+    pub requests: u64,
+    pub errors: u64,
+}
+
+/// Cache and traffic counters since startup
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub memory_entries: u64,
+    pub disk: DiskUsage,
+    /// Lookup outcomes of the memory and disk tiers
+    pub lookups: BTreeMap<&'static str, TierStats>,
+    /// Tiles served by each tier
+    pub served_by: BTreeMap<String, u64>,
+    /// Tile requests per zoom level
+    pub requests_by_zoom: BTreeMap<u8, u64>,
+    pub upstream: UpstreamStats,
+    /// Tiles served per API key, when keys are required
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_keys: Option<BTreeMap<String, KeyUsage>>,
+    /// Combined usage of each tenant's keys, when tenants are configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenants: Option<BTreeMap<String, TenantUsage>>,
+}
+
+/// Tiles listed when `limit` isn't given
+const DEFAULT_POPULAR_LIMIT: usize = 100;
+/// Most tiles listed at once
+const MAX_POPULAR_LIMIT: usize = 10_000;
+
+#[derive(Debug, Deserialize)]
+pub struct PopularQuery {
+    /// Only list tiles of this zoom level
+    zoom: Option<u8>,
+    limit: Option<usize>,
+}
+
+impl PopularQuery {
+    fn top(&self, state: &AppState) -> Result<Vec<PopularTile>> {
+        let popularity = state.popularity.as_ref().ok_or_else(|| {
+            AppError::BadRequest("popularity_tracking is disabled".to_string())
+        })?;
+        let limit = self.limit.unwrap_or(DEFAULT_POPULAR_LIMIT);
+        if limit > MAX_POPULAR_LIMIT {
+            return Err(AppError::BadRequest(format!(
+                "limit {} exceeds {}",
+                limit, MAX_POPULAR_LIMIT
+            )));
+        }
+        Ok(popularity.top(self.zoom, limit))
+    }
+}
+
+/// The most requested tiles recently, most popular first
+pub async fn get_popular(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<PopularQuery>,
+) -> Result<Json<Vec<PopularTile>>> {
+    Ok(Json(query.top(&state)?))
+}
+
+/// The most requested tiles as GeoJSON points for a heatmap
+pub async fn get_popular_heatmap(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<PopularQuery>,
+) -> Result<impl IntoResponse> {
+    let heatmap = Heatmap::new(query.top(&state)?);
+    Ok(([(header::CONTENT_TYPE, "application/geo+json")], Json(heatmap)))
+}
+
+pub async fn get_stats(State(state): State<Arc<AppState>>) -> Json<Stats> {
+    let metrics = &state.metrics;
+    let hits = metrics::counter_totals(&metrics.cache_hits);
+    let misses = metrics::counter_totals(&metrics.cache_misses);
+    let tier = |name: &str| {
+        TierStats::new(
+            hits.get(name).copied().unwrap_or_default(),
+            misses.get(name).copied().unwrap_or_default(),
+        )
+    };
+    let upstream = metrics::histogram_counts(&metrics.upstream_latency);
+
+    // Until the startup sweep has indexed it, this walks the disk cache
+    let disk_cache = state.disk_cache.clone();
+    let disk = tokio::task::spawn_blocking(move || disk_cache.usage())
+        .await
+        .expect("disk usage task panicked");
+
+    Json(Stats {
+        memory_entries: state.memory_cache.entry_count(),
+        disk,
+        lookups: ["memory", "disk"].into_iter().map(|name| (name, tier(name))).collect(),
+        requests_by_zoom: metrics::counter_totals(&metrics.requests_by_zoom)
+            .into_iter()
+            .filter_map(|(zoom, count)| Some((zoom.parse().ok()?, count)))
+            .collect(),
+        served_by: hits,
+        upstream: UpstreamStats {
+            requests: upstream.values().sum(),
+            errors: upstream.get("error").copied().unwrap_or_default(),
+        },
+        api_keys: state.api_keys.as_ref().map(|keys| keys.usage()),
+        tenants: state.api_keys.as_ref().and_then(|keys| keys.tenant_usage()),
+    })
+}
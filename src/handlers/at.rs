@@ -0,0 +1,54 @@
+use crate::error::AppError;
+use crate::geo::{self, MAX_ZOOM};
+use crate::handlers::tile::tile_response;
+use crate::handlers::AppState;
+use crate::types::{TileFormat, TileScheme};
+use axum::extract::{Path, Query, State};
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Redirect, Response};
+use serde::Deserialize;
+use std::sync::Arc;
+
+#[derive(Debug, Deserialize)]
+pub struct AtQuery {
+    /// Redirect to the tile's own URL instead of serving it
+    #[serde(default)]
+    redirect: bool,
+}
+
+/// The tile containing a WGS84 point, e.g. `/at/15/48.8584/2.2945.png`,
+/// served as its `/{z}/{x}/{y}.png` URL would be. The longitude takes the
+/// tile's suffix, so `@2x` and every tile format work as usual.
+pub async fn get_tile_at(
+    State(state): State<Arc<AppState>>,
+    Path((z, lat, filename)): Path<(u8, f64, String)>,
+    Query(query): Query<AtQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let Some((lon, suffix)) = split_lon(&filename) else {
+        return AppError::InvalidCoordinates.into_response();
+    };
+    if z > MAX_ZOOM || !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+        return AppError::InvalidCoordinates.into_response();
+    }
+    let x = geo::lon_to_tile_x(lon, z);
+    let y = geo::lat_to_tile_y(lat, z);
+    let tile = format!("{}{}", y, suffix);
+    if query.redirect {
+        // Relative, so it stays under whatever prefix the proxy is served at
+        return Redirect::temporary(&format!("../../../{}/{}/{}", z, x, tile)).into_response();
+    }
+    tile_response(&state, z, x, &tile, TileScheme::Xyz, &headers).await
+}
+
+/// Split `2.2945@2x.png` into the longitude and the rest of a tile filename,
+/// `@2x.png`
+fn split_lon(filename: &str) -> Option<(f64, &str)> {
+    // The extension starts at the first dot that leaves a known one
+    let (dot, _) = filename
+        .match_indices('.')
+        .find(|(dot, _)| TileFormat::from_extension(&filename[dot + 1..]).is_some())?;
+    let stem = &filename[..dot];
+    let lon = stem.strip_suffix("@2x").unwrap_or(stem);
+    Some((lon.parse().ok()?, &filename[lon.len()..]))
+}
@@ -0,0 +1,147 @@
+use crate::error::{AppError, Result};
+use crate::geo::{TileRange, MAX_ZOOM};
+use crate::handlers::tile::tile_response;
+use crate::handlers::AppState;
+use crate::types::{TileFormat, TileKey, TileScheme};
+use axum::body::{Body, Bytes};
+use axum::extract::State;
+use axum::http::{header, HeaderMap, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures_util::stream::{self, StreamExt};
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::sync::Arc;
+
+/// Tiles of a batch loaded at once; the stream still yields them in order
+pub(crate) const BATCH_CONCURRENCY: usize = 8;
+
+/// Response headers of each tile copied into its part
+const PART_HEADERS: [header::HeaderName; 5] = [
+    header::CONTENT_TYPE,
+    header::CONTENT_ENCODING,
+    header::ETAG,
+    header::LAST_MODIFIED,
+    header::CACHE_CONTROL,
+];
+
+/// Tiles to return from `POST /tiles/batch`, as XYZ coordinates
+#[derive(Debug, Deserialize)]
+pub struct BatchRequest {
+    #[serde(default)]
+    pub tiles: Vec<BatchTile>,
+    /// Every tile of a bbox and zoom range, after the listed ones
+    pub range: Option<TileRange>,
+    /// Defaults to PNG
+    #[serde(default)]
+    pub format: TileFormat,
+    /// Return `@2x` retina tiles
+    #[serde(default)]
+    pub retina: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchTile {
+    pub z: u8,
+    pub x: u32,
+    pub y: u32,
+}
+
+impl BatchRequest {
+    /// Keys of the requested tiles, rejecting batches over `max_tiles`
+    pub(crate) fn keys(&self, max_tiles: usize) -> Result<Vec<TileKey>> {
+        let range_count = match &self.range {
+            Some(range) => {
+                range.validate(MAX_ZOOM)?;
+                range.tile_count()
+            }
+            None => 0,
+        };
+        if self.tiles.len() as u64 + range_count > max_tiles as u64 {
+            return Err(AppError::BadRequest(format!(
+                "a batch may hold at most {} tiles",
+                max_tiles
+            )));
+        }
+        let scale = if self.retina { 2 } else { 1 };
+        let listed = self.tiles.iter().map(|tile| TileKey::new(tile.z, tile.x, tile.y));
+        let ranged = self.range.iter().flat_map(|range| range.tiles());
+        Ok(listed
+            .chain(ranged)
+            .map(|key| key.with_format(self.format).with_scale(scale))
+            .collect())
+    }
+}
+
+/// Return many tiles in one `multipart/mixed` response, streamed in request
+/// order as they're loaded from cache or upstream. Each part carries the
+/// tile's path in `Content-Location` and its status in `X-Tile-Status`; tiles
+/// that failed have the error message as their body.
+pub async fn post_batch(
+    State(state): State<Arc<AppState>>,
+    uri: Uri,
+    headers: HeaderMap,
+    Json(batch): Json<BatchRequest>,
+) -> Result<Response> {
+    let keys = batch.keys(state.config.load().max_batch_tiles)?;
+    if let Some(api_keys) = &state.api_keys {
+        // Each tile counts against the quota as a request of its own would
+        api_keys.consume(&uri, &headers, &state.wmts_layer, keys.len() as u64)?;
+    }
+
+    // Only format negotiation carries over; conditional headers don't apply
+    let mut tile_headers = HeaderMap::new();
+    if let Some(accept) = headers.get(header::ACCEPT) {
+        tile_headers.insert(header::ACCEPT, accept.clone());
+    }
+    let boundary = format!("{:032x}", rand::random::<u128>());
+
+    let part_boundary = boundary.clone();
+    let parts = stream::iter(keys)
+        .map(move |key| {
+            let state = state.clone();
+            let headers = tile_headers.clone();
+            let boundary = part_boundary.clone();
+            async move {
+                let filename = key.file_name();
+                let response =
+                    tile_response(&state, key.z, key.x, &filename, TileScheme::Xyz, &headers).await;
+                Ok::<_, Infallible>(encode_part(&boundary, &key, response).await)
+            }
+        })
+        .buffered(BATCH_CONCURRENCY);
+    let closing = stream::iter([Ok(Bytes::from(format!("--{}--\r\n", boundary)))]);
+
+    Ok((
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, format!("multipart/mixed; boundary={}", boundary))],
+        Body::from_stream(parts.chain(closing)),
+    )
+        .into_response())
+}
+
+/// One tile response as a multipart body part, with its delimiter
+async fn encode_part(boundary: &str, key: &TileKey, response: Response) -> Bytes {
+    let (parts, body) = response.into_parts();
+    let mut head = format!(
+        "--{}\r\nContent-Location: /{}/{}/{}\r\nX-Tile-Status: {}\r\n",
+        boundary,
+        key.z,
+        key.x,
+        key.file_name(),
+        parts.status.as_u16()
+    );
+    for name in PART_HEADERS {
+        if let Some(value) = parts.headers.get(&name).and_then(|v| v.to_str().ok()) {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    head.push_str("\r\n");
+
+    let body = axum::body::to_bytes(body, usize::MAX).await.unwrap_or_default();
+    let mut part = Vec::with_capacity(head.len() + body.len() + 2);
+    part.extend_from_slice(head.as_bytes());
+    part.extend_from_slice(&body);
+    part.extend_from_slice(b"\r\n");
+    Bytes::from(part)
+}
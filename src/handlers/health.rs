@@ -0,0 +1,88 @@
+use crate::handlers::AppState;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long readiness waits for an upstream mirror to answer
+const UPSTREAM_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize)]
+pub struct BuildInfo {
+    pub name: &'static str,
+    pub version: &'static str,
+}
+
+const BUILD_INFO: BuildInfo = BuildInfo {
+    name: env!("CARGO_PKG_NAME"),
+    version: env!("CARGO_PKG_VERSION"),
+};
+
+#[derive(Debug, Serialize)]
+pub struct Health {
+    pub status: &'static str,
+    #[serde(flatten)]
+    pub build: BuildInfo,
+}
+
+/// Liveness: the process is up and serving requests
+pub async fn get_healthz() -> Json<Health> {
+    Json(Health {
+        status: "ok",
+        build: BUILD_INFO,
+    })
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub status: &'static str,
+    /// Failure of each check, `null` when it passed or was skipped
+    pub disk: Option<String>,
+    pub upstream: Option<String>,
+    pub offline: bool,
+    #[serde(flatten)]
+    pub build: BuildInfo,
+}
+
+/// Readiness: the disk cache is writable and upstream is reachable
+pub async fn get_readyz(State(state): State<Arc<AppState>>) -> (StatusCode, Json<Readiness>) {
+    let disk_cache = state.disk_cache.clone();
+    let disk = tokio::task::spawn_blocking(move || disk_cache.check_writable())
+        .await
+        .expect("disk check task panicked")
+        .err()
+        .map(|e| e.to_string());
+
+    // Offline deployments never talk to upstream, so don't wait on it
+    let offline = state.is_offline();
+    let upstream = if offline {
+        None
+    } else {
+        state
+            .fetcher
+            .load()
+            .probe(UPSTREAM_PROBE_TIMEOUT)
+            .await
+            .err()
+            .map(|e| e.to_string())
+    };
+
+    let ready = disk.is_none() && upstream.is_none();
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(Readiness {
+            status: if ready { "ok" } else { "unavailable" },
+            disk,
+            upstream,
+            offline,
+            build: BUILD_INFO,
+        }),
+    )
+}
@@ -0,0 +1,113 @@
+use crate::error::{AppError, Result};
+use crate::geo::{lat_to_y, lon_to_x};
+use crate::handlers::tile::{make_response, observe_request, request_key, CacheTier, TileQuery};
+use crate::handlers::AppState;
+use crate::processing::terrain;
+use crate::types::TileKey;
+use axum::extract::{Path, Query, State};
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Instant;
+
+pub async fn get_layer_tile(
+    State(state): State<Arc<AppState>>,
+    Path((name, z, x, filename)): Path<(String, u8, u32, String)>,
+    Query(query): Query<TileQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let started = Instant::now();
+    let response = serve_layer_tile(&state, &name, z, x, &filename, query, &headers)
+        .await
+        .unwrap_or_else(IntoResponse::into_response);
+    // Unknown names would make a series each
+    if state.composite_layers.contains_key(&name) || state.terrain_layers.contains_key(&name) {
+        observe_request(&state, &name, z, started, &response);
+    }
+    response
+}
+
+async fn serve_layer_tile(
+    state: &Arc<AppState>,
+    name: &str,
+    z: u8,
+    x: u32,
+    filename: &str,
+    query: TileQuery,
+    headers: &HeaderMap,
+) -> Result<Response> {
+    let scheme = query.scheme.unwrap_or(state.tile_scheme);
+    let key = request_key(state, z, x, filename, scheme)?;
+    if !key.format.is_raster() {
+        return Err(AppError::BadRequest("layers are raster only".to_string()));
+    }
+
+    let loaded = if let Some(layer) = state.composite_layers.get(name) {
+        layer.load(state, key).await?
+    } else if let Some(layer) = state.terrain_layers.get(name) {
+        layer.load(state, key).await?
+    } else {
+        return Err(AppError::NotFound);
+    };
+    let max_age = state.cache_policy.load().max_age(key.z).as_secs();
+    let mut response = make_response(&loaded.tile, key.format, headers, max_age)?;
+    response.extensions_mut().insert(CacheTier(loaded.tier));
+    Ok(response)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ElevationQuery {
+    lat: f64,
+    lon: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Elevation {
+    lat: f64,
+    lon: f64,
+    /// Meters above sea level
+    elevation: f64,
+}
+
+/// Height at a coordinate within a terrain tile, decoded from the tile
+pub async fn get_elevation(
+    State(state): State<Arc<AppState>>,
+    Path((name, z, x, y)): Path<(String, u8, u32, u32)>,
+    Query(query): Query<ElevationQuery>,
+) -> Result<Json<Elevation>> {
+    let layer = state.terrain_layers.get(&name).ok_or(AppError::NotFound)?;
+    let key = TileKey::new(z, x, y).with_generation(state.disk_cache.generation());
+    if !key.is_valid() || z < state.min_zoom || z > state.max_zoom {
+        return Err(AppError::InvalidCoordinates);
+    }
+    if !state.in_bounds(&key) {
+        return Err(AppError::NotFound);
+    }
+    // Position within the tile, from 0 to 1 across and down
+    let fx = lon_to_x(query.lon, z) - f64::from(x);
+    let fy = lat_to_y(query.lat, z) - f64::from(y);
+    let inside = (-90.0..=90.0).contains(&query.lat)
+        && (0.0..=1.0).contains(&fx)
+        && (0.0..=1.0).contains(&fy);
+    if !inside {
+        return Err(AppError::BadRequest(format!(
+            "{},{} is outside tile {}/{}/{}",
+            query.lat, query.lon, z, x, y
+        )));
+    }
+
+    let tile = layer.load(&state, key).await?.tile;
+    let encoding = layer.encoding();
+    let elevation = tokio::task::spawn_blocking(move || {
+        terrain::elevation_at(&tile.data, encoding, fx, fy)
+    })
+    .await
+    .expect("elevation task panicked")?;
+    Ok(Json(Elevation {
+        lat: query.lat,
+        lon: query.lon,
+        elevation: (elevation * 100.0).round() / 100.0,
+    }))
+}
@@ -1,3 +1,26 @@
+pub mod admin;
+pub mod at;
+pub mod batch;
+pub mod health;
+pub mod layer;
+pub mod metrics;
+pub mod preview;
+pub mod staticmap;
 pub mod tile;
+pub mod wmts;
 
-pub use tile::{get_tile, AppState};
+pub use admin::{
+    delete_tile, get_generation, get_job, get_job_events, get_jobs, get_offline, get_popular,
+    get_popular_heatmap, get_stats, get_tile_info, post_export, post_generation, post_preload,
+    post_reload, post_seed, post_warm, purge_range, put_offline, require_admin_token,
+};
+pub(crate) use admin::has_admin_token;
+pub use at::get_tile_at;
+pub use batch::post_batch;
+pub use health::{get_healthz, get_readyz};
+pub use layer::{get_elevation, get_layer_tile};
+pub use metrics::get_metrics;
+pub use preview::get_preview;
+pub use staticmap::get_static_map;
+pub use tile::{get_tile, head_tile, AppState, CacheTier};
+pub use wmts::{get_wmts_capabilities, get_wmts_kvp, get_wmts_tile};
@@ -0,0 +1,74 @@
+use crate::error::{AppError, Result};
+use crate::handlers::wmts::xml_escape;
+use crate::handlers::AppState;
+use axum::extract::{Path, State};
+use axum::response::Html;
+use std::sync::Arc;
+
+/// Leaflet page showing one layer; `{placeholders}` are filled in per request
+const PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+  <meta charset="utf-8">
+  <meta name="viewport" content="width=device-width, initial-scale=1">
+  <title>{layer} preview</title>
+  <link rel="stylesheet" href="https://unpkg.com/leaflet@1.9.4/dist/leaflet.css">
+  <script src="https://unpkg.com/leaflet@1.9.4/dist/leaflet.js"></script>
+  <style>
+    html, body, #map { height: 100%; margin: 0; }
+    #info { position: absolute; top: 10px; right: 10px; z-index: 1000; padding: 6px 10px;
+            background: rgba(255, 255, 255, 0.9); font: 12px sans-serif; border-radius: 4px; }
+  </style>
+</head>
+<body>
+  <div id="map"></div>
+  <div id="info"><b>{layer}</b> ({kind}), zoom <span id="zoom"></span> of {min_zoom}-{max_zoom}</div>
+  <script>
+    // Tile URLs are relative so the page works behind any prefix; an API key
+    // in this page's query string is passed on
+    var url = new URL("{tile_path}", window.location.href).href + window.location.search;
+    var map = L.map("map", { minZoom: {min_zoom}, maxZoom: {max_zoom} });
+    L.tileLayer(decodeURI(url), { minZoom: {min_zoom}, maxZoom: {max_zoom} }).addTo(map);
+    var bounds = {bounds};
+    if (bounds) {
+      map.fitBounds(bounds);
+    } else {
+      map.setView([0, 0], {min_zoom});
+    }
+    var zoom = document.getElementById("zoom");
+    map.on("zoomend", function () { zoom.textContent = map.getZoom(); });
+    zoom.textContent = map.getZoom();
+  </script>
+</body>
+</html>
+"#;
+
+/// Map page for checking a layer's tiles by eye: the proxy's own layer under
+/// its WMTS name, or a composite or terrain layer
+pub async fn get_preview(
+    State(state): State<Arc<AppState>>,
+    Path(layer): Path<String>,
+) -> Result<Html<String>> {
+    let (kind, tile_path) = if layer == state.wmts_layer {
+        ("base", "../{z}/{x}/{y}.png".to_string())
+    } else if state.composite_layers.contains_key(&layer) {
+        ("composite", format!("../layers/{}/{{z}}/{{x}}/{{y}}.png", layer))
+    } else if state.terrain_layers.contains_key(&layer) {
+        ("terrain", format!("../layers/{}/{{z}}/{{x}}/{{y}}.png", layer))
+    } else {
+        return Err(AppError::NotFound);
+    };
+    let bounds = match state.bounds {
+        Some(b) => format!("[[{}, {}], [{}, {}]]", b.min_lat, b.min_lon, b.max_lat, b.max_lon),
+        None => "null".to_string(),
+    };
+
+    Ok(Html(
+        PAGE.replace("{layer}", &xml_escape(&layer))
+            .replace("{kind}", kind)
+            .replace("{tile_path}", &tile_path)
+            .replace("{min_zoom}", &state.min_zoom.to_string())
+            .replace("{max_zoom}", &state.max_zoom.to_string())
+            .replace("{bounds}", &bounds),
+    ))
+}
@@ -0,0 +1,152 @@
+use crate::error::{AppError, Result};
+use crate::geo;
+use crate::handlers::batch::BATCH_CONCURRENCY;
+use crate::handlers::tile::tile_response;
+use crate::handlers::AppState;
+use crate::processing::composite;
+use crate::types::{TileKey, TileScheme};
+use axum::extract::{Query, State};
+use axum::http::{header, HeaderMap, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use bytes::Bytes;
+use futures_util::stream::{self, StreamExt, TryStreamExt};
+use serde::Deserialize;
+use std::sync::Arc;
+
+/// Largest width or height of a static map, in pixels
+const MAX_SIZE: u32 = 2048;
+/// Pixels across a tile, and `@2x` tiles across two
+const TILE_SIZE: u32 = 256;
+
+#[derive(Debug, Deserialize)]
+pub struct StaticMapQuery {
+    /// `lat,lon` of the map's center
+    center: String,
+    zoom: u8,
+    /// `WIDTHxHEIGHT` in pixels
+    size: String,
+    /// Draw from `@2x` tiles at twice the size, for high-DPI screens
+    #[serde(default)]
+    retina: bool,
+}
+
+/// A PNG of the map around a point, e.g.
+/// `/staticmap?center=48.8584,2.2945&zoom=15&size=600x400`, stitched from the
+/// proxy's own tiles as they would be served. Tiles outside the served region
+/// are left transparent, and each counts against the caller's API key.
+pub async fn get_static_map(
+    State(state): State<Arc<AppState>>,
+    uri: Uri,
+    headers: HeaderMap,
+    Query(query): Query<StaticMapQuery>,
+) -> Result<Response> {
+    let (lat, lon) = parse_pair(&query.center, ',')
+        .filter(|(lat, lon)| (-90.0..=90.0).contains(lat) && (-180.0..=180.0).contains(lon))
+        .ok_or_else(|| AppError::BadRequest("center must be lat,lon in degrees".into()))?;
+    let (width, height) = parse_pair(&query.size, 'x')
+        .filter(|&(width, height)| [width, height].iter().all(|n| (1..=MAX_SIZE).contains(n)))
+        .ok_or_else(|| {
+            AppError::BadRequest(format!("size must be WIDTHxHEIGHT, each 1 to {}", MAX_SIZE))
+        })?;
+    let zoom = query.zoom;
+    if zoom < state.min_zoom || zoom > state.max_zoom {
+        return Err(AppError::InvalidCoordinates);
+    }
+
+    let scale = if query.retina { 2 } else { 1 };
+    let placements = placements(lat, lon, zoom, width, height, scale);
+    if let Some(api_keys) = &state.api_keys {
+        api_keys.consume(&uri, &headers, &state.wmts_layer, placements.len() as u64)?;
+    }
+
+    let tiles = stream::iter(placements)
+        .map(|(key, left, top)| {
+            let state = state.clone();
+            async move {
+                let data = load(&state, key).await?;
+                Ok::<_, Response>(data.map(|data| (left, top, data)))
+            }
+        })
+        .buffered(BATCH_CONCURRENCY)
+        .try_collect::<Vec<_>>()
+        .await;
+    let tiles: Vec<_> = match tiles {
+        Ok(tiles) => tiles.into_iter().flatten().collect(),
+        Err(response) => return Ok(response),
+    };
+
+    let scale = u32::from(scale);
+    let (width, height) = (width * scale, height * scale);
+    let tile_size = TILE_SIZE * scale;
+    let png = tokio::task::spawn_blocking(move || {
+        composite::stitch(&tiles, width, height, tile_size)
+    })
+    .await
+    .expect("static map task panicked")?;
+
+    let max_age = state.cache_policy.load().max_age(zoom).as_secs();
+    Ok((
+        [
+            (header::CONTENT_TYPE, "image/png".to_string()),
+            (header::CACHE_CONTROL, format!("public, max-age={}", max_age)),
+        ],
+        png,
+    )
+        .into_response())
+}
+
+/// Tiles covering a `width` x `height` view centered on a point, with the
+/// pixel offset of each from the view's top-left corner. Columns wrap around
+/// the antimeridian; rows past the poles are left out.
+fn placements(
+    lat: f64,
+    lon: f64,
+    zoom: u8,
+    width: u32,
+    height: u32,
+    scale: u8,
+) -> Vec<(TileKey, i64, i64)> {
+    let n = 1i64 << zoom;
+    let tile_size = i64::from(TILE_SIZE);
+    let pixel = |tiles: f64| (tiles * tile_size as f64).round() as i64;
+    let left = pixel(geo::lon_to_x(lon, zoom)) - i64::from(width / 2);
+    let top = pixel(geo::lat_to_y(lat, zoom)) - i64::from(height / 2);
+    let last = |origin: i64, len: u32| (origin + i64::from(len) - 1).div_euclid(tile_size);
+    let columns = left.div_euclid(tile_size)..=last(left, width);
+    let rows = top.div_euclid(tile_size).max(0)..=last(top, height).min(n - 1);
+
+    let scale_i64 = i64::from(scale);
+    rows.flat_map(|row| {
+        columns.clone().map(move |column| {
+            let key =
+                TileKey::new(zoom, column.rem_euclid(n) as u32, row as u32).with_scale(scale);
+            let offset = |tile: i64, origin: i64| (tile * tile_size - origin) * scale_i64;
+            (key, offset(column, left), offset(row, top))
+        })
+    })
+    .collect()
+}
+
+/// A tile's image as it would be served, None where there's none to draw. A
+/// tile that fails fails the whole map with its response, so the client gets
+/// its status and headers, such as `Retry-After` for a rate-limited upstream.
+async fn load(state: &Arc<AppState>, key: TileKey) -> std::result::Result<Option<Bytes>, Response> {
+    let filename = key.file_name();
+    let response =
+        tile_response(state, key.z, key.x, &filename, TileScheme::Xyz, &HeaderMap::new()).await;
+    match response.status() {
+        StatusCode::OK => {}
+        StatusCode::NOT_FOUND => return Ok(None),
+        _ => return Err(response),
+    }
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .map_err(|e| AppError::from(std::io::Error::other(e)).into_response())?;
+    Ok(Some(body))
+}
+
+/// Two numbers separated by `separator`, e.g. `48.85,2.29` or `600x400`
+fn parse_pair<T: std::str::FromStr>(value: &str, separator: char) -> Option<(T, T)> {
+    let (first, second) = value.split_once(separator)?;
+    Some((first.trim().parse().ok()?, second.trim().parse().ok()?))
+}
@@ -1,166 +1,1228 @@
-use crate::cache::coalescing::CoalesceResult;
-use crate::cache::{DiskCache, MemoryCache, RequestCoalescer};
+use crate::api_keys::ApiKeys;
+use crate::cache::coalescing::{CoalesceResult, WaitOutcome};
+use crate::cache::{DiskCache, MemoryCache, NegativeCache, RequestCoalescer, TierChain};
+use crate::client_limit::ClientRateLimiter;
+use crate::composite::CompositeLayer;
+use crate::config::{CachePolicy, Config, Freshness};
 use crate::error::{AppError, Result};
-use crate::types::TileKey;
-use crate::upstream::{FetchResult, OsmFetcher};
+use crate::forwarded::TrustedProxies;
+use crate::geo::BoundingBox;
+use crate::metrics::Metrics;
+use crate::popularity::Popularity;
+use crate::prefetch::Prefetcher;
+use crate::processing::adjust::{self, Adjustments};
+use crate::processing::watermark::Watermark;
+use crate::processing::{raster_format, resample, transcode};
+use crate::refresh::{self, Refresh};
+use crate::seed::JobManager;
+use crate::terrain::TerrainLayer;
+use crate::types::{TileData, TileFormat, TileKey, TilePath, TileScheme, Validators};
+use crate::upstream::{scheduler, FetchResult, MetatileFetcher, OsmFetcher};
+use arc_swap::{ArcSwap, ArcSwapOption};
 use axum::body::Body;
-use axum::extract::{Path, State};
-use axum::http::{header, HeaderMap, StatusCode};
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
 use axum::response::{IntoResponse, Response};
+use bytes::Bytes;
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 pub struct AppState {
+    /// Also reachable through `tiers`, for variants and stand-ins kept out of the chain
     pub memory_cache: MemoryCache,
     pub disk_cache: DiskCache,
+    /// Caches and archives consulted, in order, before upstream
+    pub tiers: TierChain,
+    pub negative_cache: NegativeCache,
     pub coalescer: RequestCoalescer,
-    pub fetcher: OsmFetcher,
-    pub cache_max_age_secs: u64,
+    /// How long a request waits on another's fetch before acting on its own
+    pub coalescer_wait_timeout: Duration,
+    /// Refetch after the wait timeout rather than answering 504
+    pub coalescer_takeover: bool,
+    /// Rebuilt when the config is reloaded
+    pub fetcher: ArcSwap<OsmFetcher>,
+    /// Fetches uncached raster tiles a metatile at a time, if configured
+    pub metatiles: Option<MetatileFetcher>,
+    /// Deduplicates metatile fetches, keyed by their top-left tile
+    pub metatile_coalescer: RequestCoalescer,
+    /// Deduplicates transcodes, keyed by the variant being derived
+    pub derived_coalescer: RequestCoalescer,
+    /// Holds a subdirectory of recolored tiles per set of adjustments
+    pub adjusted_cache: DiskCache,
+    /// Attribution stamped on raster tiles, if configured
+    pub watermark: Option<Arc<Watermark>>,
+    /// Stamped copies of served tiles
+    pub watermarked_cache: DiskCache,
+    /// Layers blended from this one and overlay upstreams, by name
+    pub composite_layers: HashMap<String, CompositeLayer>,
+    /// Elevation layers passed through from their own upstreams, by name
+    pub terrain_layers: HashMap<String, TerrainLayer>,
+    pub cache_policy: ArcSwap<CachePolicy>,
+    pub metrics: Metrics,
+    pub jobs: JobManager,
+    pub seed_concurrency: usize,
+    pub export_dir: PathBuf,
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+    /// Region outside which tiles are refused, if restricted
+    pub bounds: Option<BoundingBox>,
+    pub max_native_zoom: u8,
+    /// Serve PNG tiles as WebP to clients that accept it
+    pub webp_transcoding: bool,
+    /// Row numbering used when the request doesn't specify one
+    pub tile_scheme: TileScheme,
+    /// Layer identifier advertised over WMTS
+    pub wmts_layer: String,
+    /// Externally visible base URL, derived from the Host header when unset
+    pub public_url: Option<String>,
+    /// Serve only cached and archived tiles, toggled through the admin API
+    pub offline: AtomicBool,
+    /// Placeholder served for raster tiles that can't be loaded
+    pub fallback_tile: Option<Arc<TileData>>,
+    pub fallback_max_age_secs: u64,
+    /// Ancestor levels searched when synthesizing a missing raster tile
+    pub parent_fallback_levels: u8,
+    /// Queue of neighbors to fetch after a miss, if prefetching is enabled
+    pub prefetcher: Option<Prefetcher>,
+    /// Request counts per tile, if tracking is enabled
+    pub popularity: Option<Arc<Popularity>>,
+    /// Bearer token required on admin routes, if set
+    pub admin_token: Option<String>,
+    /// Keys required for tile requests, if any are configured
+    pub api_keys: Option<ApiKeys>,
+    /// Per-client request rate limit, if enabled
+    pub client_limiter: ArcSwapOption<ClientRateLimiter>,
+    /// Proxies whose forwarded headers name the client
+    pub trusted_proxies: ArcSwap<TrustedProxies>,
+    /// Current settings; only some take effect on reload, the rest are copied
+    /// into the fields above at startup
+    pub config: ArcSwap<Config>,
+    /// File the config was loaded from, re-read on reload
+    pub config_path: Option<PathBuf>,
+}
+
+impl AppState {
+    pub fn is_offline(&self) -> bool {
+        self.offline.load(Ordering::Relaxed)
+    }
+
+    /// Whether a tile overlaps the served region
+    pub fn in_bounds(&self, key: &TileKey) -> bool {
+        self.bounds.is_none_or(|bounds| bounds.contains(key))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TileQuery {
+    pub(crate) scheme: Option<TileScheme>,
 }
 
 pub async fn get_tile(
     State(state): State<Arc<AppState>>,
     Path((z, x, filename)): Path<(u8, u32, String)>,
+    Query(query): Query<TileQuery>,
+    Query(adjustments): Query<Adjustments>,
     headers: HeaderMap,
+) -> Response {
+    let scheme = query.scheme.unwrap_or(state.tile_scheme);
+    if adjustments.is_empty() || !state.config.load().tile_adjustments {
+        return tile_response(&state, z, x, &filename, scheme, &headers).await;
+    }
+    let started = Instant::now();
+    let result = serve_adjusted_tile(&state, z, x, &filename, scheme, &adjustments, &headers).await;
+    count_response(&state, z, started, result)
+}
+
+/// HEAD for a tile, answered from a cached copy's metadata without reading
+/// its data or going upstream. Tiles that aren't cached, or whose response
+/// depends on more than the stored copy, are served as for GET.
+pub async fn head_tile(
+    State(state): State<Arc<AppState>>,
+    Path((z, x, filename)): Path<(u8, u32, String)>,
+    Query(query): Query<TileQuery>,
+    Query(adjustments): Query<Adjustments>,
+    headers: HeaderMap,
+) -> Response {
+    let scheme = query.scheme.unwrap_or(state.tile_scheme);
+    if adjustments.is_empty() || !state.config.load().tile_adjustments {
+        let started = Instant::now();
+        if let Some(result) = head_cached(&state, z, x, &filename, scheme, &headers).await {
+            return count_response(&state, z, started, result);
+        }
+    }
+    get_tile(State(state), Path((z, x, filename)), Query(query), Query(adjustments), headers).await
+}
+
+/// Headers of a cached tile, None when they can't be told from the memory or
+/// disk copy's metadata
+async fn head_cached(
+    state: &AppState,
+    z: u8,
+    x: u32,
+    filename: &str,
+    scheme: TileScheme,
+    headers: &HeaderMap,
+) -> Option<Result<Response>> {
+    let key = match request_key(state, z, x, filename, scheme) {
+        Ok(key) => key,
+        Err(e) => return Some(Err(e)),
+    };
+    // Transcoded and watermarked tiles are derived as they're served
+    let transcoded =
+        state.webp_transcoding && matches!(key.format, TileFormat::Png | TileFormat::Webp);
+    if transcoded || (state.watermark.is_some() && key.format.is_raster()) {
+        return None;
+    }
+
+    let (tile, len, tier) = match state.memory_cache.get(&key).await {
+        Some(tile) => {
+            let len = tile.data.len() as u64;
+            (tile, len, "memory")
+        }
+        None => {
+            let disk_cache = state.disk_cache.clone();
+            let (tile, len) = tokio::task::spawn_blocking(move || disk_cache.head(&key))
+                .await
+                .expect("disk head task panicked")?;
+            (Arc::new(tile), len, "disk")
+        }
+    };
+    // Expired tiles wait for upstream, and a stored encoding the client can't
+    // take is decoded, changing the length
+    let expired = state.cache_policy.load().freshness(key.z, &tile) == Freshness::Expired;
+    let decoded = tile
+        .content_encoding
+        .as_deref()
+        .is_some_and(|encoding| !accepts_encoding(headers, encoding));
+    if expired || decoded {
+        return None;
+    }
+
+    let max_age = if tile.synthesized {
+        state.fallback_max_age_secs
+    } else {
+        state.cache_policy.load().max_age(key.z).as_secs()
+    };
+    let result = make_response(&tile, key.format, headers, max_age).map(|mut response| {
+        if response.status() == StatusCode::OK {
+            response.headers_mut().insert(header::CONTENT_LENGTH, HeaderValue::from(len));
+        }
+        response.extensions_mut().insert(CacheTier(tier));
+        response
+    });
+    Some(result)
+}
+
+/// Serve a tile, turning errors into responses and recording the status
+pub(crate) async fn tile_response(
+    state: &Arc<AppState>,
+    z: u8,
+    x: u32,
+    filename: &str,
+    scheme: TileScheme,
+    headers: &HeaderMap,
+) -> Response {
+    let started = Instant::now();
+    count_response(state, z, started, serve_tile(state, z, x, filename, scheme, headers).await)
+}
+
+/// Turn the result of serving a tile into a response, recording the status
+fn count_response(
+    state: &AppState,
+    z: u8,
+    started: Instant,
+    result: Result<Response>,
+) -> Response {
+    state
+        .metrics
+        .requests_by_zoom
+        .with_label_values(&[&z.to_string()])
+        .inc();
+
+    let response = result.unwrap_or_else(IntoResponse::into_response);
+
+    state
+        .metrics
+        .responses
+        .with_label_values(&[response.status().as_str()])
+        .inc();
+    observe_request(state, &state.wmts_layer, z, started, &response);
+
+    response
+}
+
+/// Record a tile request of `layer` by the tier its response came from
+pub(crate) fn observe_request(
+    state: &AppState,
+    layer: &str,
+    z: u8,
+    started: Instant,
+    response: &Response,
+) {
+    let tier = response.extensions().get::<CacheTier>().map_or("none", |tier| tier.0);
+    state.metrics.observe_request(layer, z, tier, started.elapsed());
+}
+
+async fn serve_tile(
+    state: &Arc<AppState>,
+    z: u8,
+    x: u32,
+    filename: &str,
+    scheme: TileScheme,
+    headers: &HeaderMap,
 ) -> Result<Response> {
-    // Parse y from filename (e.g., "5461.png" -> 5461)
-    let y: u32 = filename
-        .strip_suffix(".png")
-        .ok_or(AppError::InvalidCoordinates)?
-        .parse()
-        .map_err(|_| AppError::InvalidCoordinates)?;
+    let key = request_key(state, z, x, filename, scheme)?;
+    let format = key.format;
+    if let Some(popularity) = &state.popularity {
+        popularity.record(&key);
+    }
+    // A derived WebP tile is refreshed through its PNG
+    let source_key = if state.webp_transcoding && format == TileFormat::Webp {
+        key.with_format(TileFormat::Png)
+    } else {
+        key
+    };
+    refresh_requested(state, source_key, headers).await;
+
+    let negotiable = state.webp_transcoding && format == TileFormat::Png;
+    let loaded = if negotiable && accepts_webp(headers) {
+        load_webp(state, key).await
+    } else if state.webp_transcoding && format == TileFormat::Webp {
+        // Derived from the PNG, sharing its fetch, rather than fetched separately
+        load_webp(state, key.with_format(TileFormat::Png)).await
+    } else {
+        load_tile(state, key).await
+    };
+    let loaded = match (loaded, &state.fallback_tile) {
+        (Ok(loaded), _) => loaded,
+        // Vector and grid clients can't use a raster placeholder
+        (Err(e), Some(fallback))
+            if format.is_raster() && (e.is_transient() || state.is_offline()) =>
+        {
+            tracing::debug!(key = %key, error = %e, "Serving fallback tile");
+            state.metrics.cache_hits.with_label_values(&["fallback"]).inc();
+            let mut response =
+                make_response(fallback, format, headers, state.fallback_max_age_secs)?;
+            response
+                .headers_mut()
+                .insert("x-cache", HeaderValue::from_static("fallback"));
+            response.extensions_mut().insert(CacheTier("fallback"));
+            return Ok(response);
+        }
+        (Err(e), _) => return Err(e),
+    };
+    let loaded = match &state.watermark {
+        Some(watermark) if format.is_raster() => {
+            load_watermarked(state, watermark.clone(), key, loaded).await?
+        }
+        _ => loaded,
+    };
+
+    // Synthesized tiles are short-lived so clients come back for the real one
+    let max_age = if loaded.tile.synthesized {
+        state.fallback_max_age_secs
+    } else {
+        state.cache_policy.load().max_age(key.z).as_secs()
+    };
+    let mut response = make_response(&loaded.tile, format, headers, max_age)?;
+    response.extensions_mut().insert(CacheTier(loaded.tier));
+    let response_headers = response.headers_mut();
+    if loaded.tile.synthesized {
+        response_headers.insert("x-cache", HeaderValue::from_static("synthesized"));
+    }
+    if loaded.stale {
+        response_headers.insert(
+            header::WARNING,
+            HeaderValue::from_static("111 - \"Revalidation Failed\""),
+        );
+        response_headers.insert("x-cache", HeaderValue::from_static("stale"));
+    }
+    if negotiable {
+        response_headers.append(header::VARY, HeaderValue::from_static("Accept"));
+    }
+
+    Ok(response)
+}
+
+/// Serve a raster tile recolored by the request's adjustments, from the
+/// variant's cache or derived from the tile
+async fn serve_adjusted_tile(
+    state: &Arc<AppState>,
+    z: u8,
+    x: u32,
+    filename: &str,
+    scheme: TileScheme,
+    adjustments: &Adjustments,
+    headers: &HeaderMap,
+) -> Result<Response> {
+    let key = request_key(state, z, x, filename, scheme)?;
+    if !key.format.is_raster() {
+        return Err(AppError::BadRequest("adjustments apply to raster tiles only".to_string()));
+    }
+    if let Some(popularity) = &state.popularity {
+        popularity.record(&key);
+    }
+    adjustments.validate()?;
+    // The variant is adjusted again once its source is newer
+    refresh_requested(state, key, headers).await;
 
-    let key = TileKey::new(z, x, y);
+    let loaded = load_adjusted(state, key, adjustments).await?;
+    let max_age = if loaded.tile.synthesized {
+        state.fallback_max_age_secs
+    } else {
+        state.cache_policy.load().max_age(key.z).as_secs()
+    };
+    let mut response = make_response(&loaded.tile, key.format, headers, max_age)?;
+    response.extensions_mut().insert(CacheTier(loaded.tier));
+    if loaded.stale {
+        let response_headers = response.headers_mut();
+        response_headers.insert(
+            header::WARNING,
+            HeaderValue::from_static("111 - \"Revalidation Failed\""),
+        );
+        response_headers.insert("x-cache", HeaderValue::from_static("stale"));
+    }
+    Ok(response)
+}
+
+/// Load a recolored tile, adjusting it again when the cached variant is
+/// older than its source
+async fn load_adjusted(
+    state: &Arc<AppState>,
+    key: TileKey,
+    adjustments: &Adjustments,
+) -> Result<LoadedTile> {
+    // Variants are cached stamped, so each watermark gets its own
+    let variant = match &state.watermark {
+        Some(watermark) => format!("{}-wm{}", adjustments.id(), watermark.fingerprint()),
+        None => adjustments.id(),
+    };
+    let cache = state.adjusted_cache.subdirectory(&variant)?;
+    let outdated = match (cache.tile_age(&key).await, state.disk_cache.tile_age(&key).await) {
+        (Some(variant_age), Some(source_age)) => variant_age > source_age,
+        _ => false,
+    };
+    if !outdated {
+        let cached = cache.load(&key).await;
+        if let Some(tile) = cached.filter(|tile| is_fresh(state, &key, tile)) {
+            return Ok(LoadedTile::hit(tile, "adjusted"));
+        }
+    }
+
+    let source = load_tile(state, key).await?;
+    let tile = source.tile.clone();
+    let stale = source.stale;
+    let adjustments = adjustments.clone();
+    let watermark = state.watermark.clone();
+    let adjusted = tokio::task::spawn_blocking(move || -> Result<TileData> {
+        let mut adjusted = adjust::apply(&tile, &adjustments, key.format)?;
+        if let Some(watermark) = watermark {
+            adjusted = watermark.apply(&adjusted)?;
+        }
+        // Not worth caching a variant of a stand-in or stale copy
+        if tile.synthesized || stale {
+            adjusted.synthesized = tile.synthesized;
+        } else {
+            cache.store(&key, &adjusted)?;
+        }
+        Ok(adjusted)
+    })
+    .await
+    .expect("adjust task panicked")?;
+    tracing::debug!(key = %key, size = adjusted.data.len(), "Adjusted tile");
 
-    // Validate coordinates
-    let max_coord = 1u32 << z;
-    if x >= max_coord || y >= max_coord {
+    Ok(LoadedTile {
+        tile: Arc::new(adjusted),
+        stale: source.stale,
+        tier: source.tier,
+    })
+}
+
+/// Stamp the watermark onto a loaded raster tile, reusing the stamped copy
+/// made from the same version of it
+async fn load_watermarked(
+    state: &Arc<AppState>,
+    watermark: Arc<Watermark>,
+    key: TileKey,
+    loaded: LoadedTile,
+) -> Result<LoadedTile> {
+    // Negotiated WebP and its PNG fallback are stamped and cached apart
+    let stamped_key = key.with_format(raster_format(&loaded.tile.data));
+    let etag = loaded.tile.etag.as_deref().map(|e| watermark.etag(e));
+    if let Some(etag) = &etag {
+        if let Some(tile) = state.watermarked_cache.load(&stamped_key).await {
+            if tile.etag.as_ref() == Some(etag) {
+                return Ok(LoadedTile { tile, ..loaded });
+            }
+        }
+    }
+
+    let source = loaded.tile.clone();
+    let cache = state.watermarked_cache.clone();
+    let tile = tokio::task::spawn_blocking(move || -> Result<TileData> {
+        let stamped = watermark.apply(&source)?;
+        // Stand-ins and tiles without a validator can't be matched up later
+        if stamped.etag.is_some() && !stamped.synthesized {
+            cache.store(&stamped_key, &stamped)?;
+        }
+        Ok(stamped)
+    })
+    .await
+    .expect("watermark task panicked")?;
+    Ok(LoadedTile {
+        tile: Arc::new(tile),
+        ..loaded
+    })
+}
+
+/// Key of a requested tile in XYZ numbering, refusing ones outside the
+/// served zoom levels and region
+pub(crate) fn request_key(
+    state: &AppState,
+    z: u8,
+    x: u32,
+    filename: &str,
+    scheme: TileScheme,
+) -> Result<TileKey> {
+    // Parse y, scale and format from filename (e.g., "5461@2x.png" -> 5461, 2, Png)
+    let mut key = TilePath::from_filename(z, x, filename)
+        .ok_or(AppError::InvalidCoordinates)?
+        .key()
+        .with_generation(state.disk_cache.generation());
+
+    if z < state.min_zoom || z > state.max_zoom {
         return Err(AppError::InvalidCoordinates);
     }
+    key.y = scheme.to_xyz(z, key.y).ok_or(AppError::InvalidCoordinates)?;
+    if !state.in_bounds(&key) {
+        return Err(AppError::NotFound);
+    }
+    Ok(key)
+}
+
+/// Cache tier a tile response came from, for the access log
+#[derive(Debug, Clone, Copy)]
+pub struct CacheTier(pub &'static str);
+
+pub(crate) struct LoadedTile {
+    pub(crate) tile: Arc<TileData>,
+    /// Served from cache because upstream failed
+    stale: bool,
+    pub(crate) tier: &'static str,
+}
+
+impl LoadedTile {
+    pub(crate) fn hit(tile: Arc<TileData>, tier: &'static str) -> Self {
+        Self {
+            tile,
+            stale: false,
+            tier,
+        }
+    }
 
-    // Check client's If-None-Match
-    let client_etag = headers
-        .get(header::IF_NONE_MATCH)
-        .and_then(|v| v.to_str().ok());
+    /// A cached copy served because refreshing it failed
+    pub(crate) fn stale(tile: Arc<TileData>) -> Self {
+        Self {
+            tile,
+            stale: true,
+            tier: "stale",
+        }
+    }
+}
 
-    // 1. Check memory cache
-    if let Some(tile) = state.memory_cache.get(&key).await {
-        tracing::trace!(key = %key, "Memory cache hit");
-        return make_response(&tile.data, tile.etag.as_deref(), client_etag, state.cache_max_age_secs);
+/// Find a tile in the cache tiers, falling back to an upstream fetch
+pub(crate) async fn load_tile(state: &Arc<AppState>, key: TileKey) -> Result<LoadedTile> {
+    if key.z > state.max_native_zoom {
+        return Box::pin(load_overzoomed(state, key)).await;
     }
 
-    // 2. Check disk cache
-    if let Some(tile) = state.disk_cache.get(&key) {
-        tracing::trace!(key = %key, "Disk cache hit");
-        // Promote to memory cache
+    // 1. Walk the cache tiers, where a synthesized tile only stands in until a real fetch works
+    let mut synthesized = None;
+    let mut expired = None;
+    let mut from = 0;
+    while let Some(hit) = state.tiers.lookup(&key, from).await {
+        count_misses(state, from..hit.tier);
+        from = hit.tier + 1;
+        let tier = state.tiers.name(hit.tier);
+        if hit.tile.synthesized {
+            synthesized = Some(hit.tile);
+            continue;
+        }
+
+        match state.cache_policy.load().freshness(key.z, &hit.tile) {
+            Freshness::Fresh => state.tiers.promote(&key, &hit).await,
+            Freshness::Stale => {
+                // Serve the stale copy now and keep it out of faster tiers until refreshed
+                tracing::debug!(key = %key, tier, "Serving stale tile while revalidating");
+                tokio::spawn(scheduler::background(revalidate(state.clone(), key)));
+            }
+            Freshness::Expired => {
+                // Too old to serve unless upstream can't be reached
+                tracing::debug!(key = %key, tier, "Cached tile expired, refetching");
+                expired = Some(hit.tile);
+                break;
+            }
+        }
+        tracing::trace!(key = %key, tier, "Cache hit");
+        state.metrics.cache_hits.with_label_values(&[tier]).inc();
+        return Ok(LoadedTile::hit(hit.tile, tier));
+    }
+    if expired.is_none() {
+        count_misses(state, from..state.tiers.len());
+    }
+
+    // 2. Fetch from upstream with request coalescing
+    match fetch_with_coalescing(state, key).await {
+        Ok(tile) => {
+            state.metrics.cache_hits.with_label_values(&["upstream"]).inc();
+            if let Some(prefetcher) = &state.prefetcher {
+                let dropped = prefetcher.enqueue_around(&key, state.max_native_zoom);
+                state.metrics.prefetches.with_label_values(&["dropped"]).inc_by(dropped as u64);
+            }
+            Ok(LoadedTile::hit(tile, "upstream"))
+        }
+        Err(e) if e.is_transient() || state.is_offline() => {
+            // Fall back to any cached copy, however old, rather than failing
+            let cached = match expired {
+                Some(tile) => Some(tile),
+                None => state.disk_cache.load(&key).await,
+            };
+            if let Some(tile) = cached {
+                tracing::warn!(key = %key, error = %e, "Upstream failed, serving stale tile");
+                state.metrics.cache_hits.with_label_values(&["stale"]).inc();
+                return Ok(LoadedTile::stale(tile));
+            }
+            let synthesized = match synthesized {
+                Some(tile) => Some(tile),
+                None => synthesize_from_ancestor(state, key).await,
+            };
+            let Some(tile) = synthesized else {
+                return Err(e);
+            };
+            tracing::debug!(key = %key, error = %e, "Serving tile synthesized from an ancestor");
+            state.metrics.cache_hits.with_label_values(&["synthesized"]).inc();
+            Ok(LoadedTile::hit(tile, "synthesized"))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether a cached variant can be served without going back to its source
+fn is_fresh(state: &AppState, key: &TileKey, tile: &TileData) -> bool {
+    state.cache_policy.load().freshness(key.z, tile) == Freshness::Fresh
+}
+
+fn count_misses(state: &AppState, tiers: std::ops::Range<usize>) {
+    for tier in tiers {
+        state.metrics.cache_misses.with_label_values(&[state.tiers.name(tier)]).inc();
+    }
+}
+
+/// Serve a PNG tile as WebP, transcoding and caching the variant on first use
+async fn load_webp(state: &Arc<AppState>, key: TileKey) -> Result<LoadedTile> {
+    let webp_key = key.with_format(TileFormat::Webp);
+
+    // Variants past their source's freshness are rebuilt through it, so it
+    // gets revalidated
+    let cached = state.memory_cache.get(&webp_key).await;
+    if let Some(tile) = cached.filter(|tile| is_fresh(state, &key, tile)) {
+        return Ok(LoadedTile::hit(tile, "memory"));
+    }
+    // A variant older than its source was transcoded before the last revalidation
+    let ages = (
+        state.disk_cache.tile_age(&webp_key).await,
+        state.disk_cache.tile_age(&key).await,
+    );
+    let outdated = match ages {
+        (Some(variant_age), Some(source_age)) => variant_age > source_age,
+        _ => false,
+    };
+    if !outdated {
+        let cached = state.disk_cache.load(&webp_key).await;
+        if let Some(tile) = cached.filter(|tile| is_fresh(state, &key, tile)) {
+            state.memory_cache.insert_tile(webp_key, tile.clone()).await;
+            return Ok(LoadedTile::hit(tile, "disk"));
+        }
+    }
+
+    // Requests for the same variant wait on one transcode, and every format
+    // of a tile on the one fetch of its PNG
+    let guard = match state.derived_coalescer.try_acquire(webp_key) {
+        CoalesceResult::Acquired(guard) => guard,
+        CoalesceResult::Wait(waiter) => {
+            let outcome = state
+                .derived_coalescer
+                .wait(webp_key, waiter, state.coalescer_wait_timeout, false)
+                .await;
+            return match outcome {
+                WaitOutcome::Done(result) => result.map(|tile| LoadedTile::hit(tile, "coalesced")),
+                // The other request went away or stalled, so derive our own
+                _ => transcode_webp(state, key).await,
+            };
+        }
+    };
+    match transcode_webp(state, key).await {
+        Ok(loaded) => {
+            let _ = guard.complete(Ok(loaded.tile.clone()));
+            Ok(loaded)
+        }
+        // Waiters get the error as well
+        Err(e) => guard.complete(Err(e)).map(|tile| LoadedTile::hit(tile, "coalesced")),
+    }
+}
+
+/// Load the PNG tile and transcode it, falling back to the PNG on failure
+async fn transcode_webp(state: &Arc<AppState>, key: TileKey) -> Result<LoadedTile> {
+    let webp_key = key.with_format(TileFormat::Webp);
+    let source = load_tile(state, key).await?;
+    if source.tile.synthesized {
+        // Not worth caching a variant of a stand-in
+        return Ok(source);
+    }
+    let png = source.tile.clone();
+    let transcoded = tokio::task::spawn_blocking(move || transcode::to_webp(&png))
+        .await
+        .expect("transcode task panicked");
+
+    match transcoded {
+        Ok(webp) => {
+            tracing::debug!(
+                key = %key,
+                png_size = source.tile.data.len(),
+                webp_size = webp.data.len(),
+                "Transcoded tile to WebP"
+            );
+            let tile = store_tile(state, webp_key, webp).await;
+            Ok(LoadedTile {
+                tile,
+                stale: source.stale,
+                tier: source.tier,
+            })
+        }
+        Err(e) => {
+            tracing::warn!(key = %key, error = %e, "WebP transcoding failed, serving original");
+            Ok(source)
+        }
+    }
+}
+
+/// Cut a tile above the native zoom out of its ancestor at the native zoom
+async fn load_overzoomed(state: &Arc<AppState>, key: TileKey) -> Result<LoadedTile> {
+    if !key.format.is_raster() {
+        // Vector tiles are overzoomed by the client, grids can't be
+        return Err(AppError::NotFound);
+    }
+    let cached = state.memory_cache.get(&key).await;
+    if let Some(tile) = cached.filter(|tile| is_fresh(state, &key, tile)) {
+        return Ok(LoadedTile::hit(tile, "memory"));
+    }
+
+    let levels = key.z - state.max_native_zoom;
+    let parent_key = TileKey {
+        z: state.max_native_zoom,
+        x: key.x >> levels,
+        y: key.y >> levels,
+        ..key
+    };
+    let parent = load_tile(state, parent_key).await?;
+
+    let source = parent.tile.clone();
+    let mut tile =
+        tokio::task::spawn_blocking(move || resample::from_ancestor(&source, levels, &key))
+            .await
+            .expect("resample task panicked")?;
+    // Only as provisional, and as old, as the tile it was cut from
+    tile.synthesized = parent.tile.synthesized;
+    tile.fetched_at = parent.tile.fetched_at;
+    tile.upstream_headers = parent.tile.upstream_headers.clone();
+    let tile = Arc::new(tile);
+    if !parent.stale && !tile.synthesized {
         state.memory_cache.insert_tile(key, tile.clone()).await;
-        return make_response(&tile.data, tile.etag.as_deref(), client_etag, state.cache_max_age_secs);
     }
+    Ok(LoadedTile {
+        tile,
+        stale: parent.stale,
+        tier: parent.tier,
+    })
+}
+
+/// Upscale part of the nearest cached ancestor, keeping the result in memory only
+async fn synthesize_from_ancestor(state: &AppState, key: TileKey) -> Option<Arc<TileData>> {
+    if !key.format.is_raster() {
+        return None;
+    }
+    for levels in 1..=state.parent_fallback_levels.min(key.z) {
+        let ancestor_key = TileKey {
+            z: key.z - levels,
+            x: key.x >> levels,
+            y: key.y >> levels,
+            ..key
+        };
+        let ancestor = match state.memory_cache.get(&ancestor_key).await {
+            Some(tile) => tile,
+            None => match state.disk_cache.load(&ancestor_key).await {
+                Some(tile) => tile,
+                None => continue,
+            },
+        };
 
-    // 3. Fetch from upstream with request coalescing
-    let tile = fetch_with_coalescing(&state, key).await?;
+        let resampled =
+            tokio::task::spawn_blocking(move || resample::from_ancestor(&ancestor, levels, &key))
+                .await
+                .expect("resample task panicked");
+        match resampled {
+            Ok(tile) => {
+                let tile = Arc::new(tile);
+                state.memory_cache.insert_tile(key, tile.clone()).await;
+                return Some(tile);
+            }
+            Err(e) => {
+                tracing::warn!(
+                    key = %key,
+                    ancestor = %ancestor_key,
+                    error = %e,
+                    "Failed to resample ancestor tile"
+                );
+                return None;
+            }
+        }
+    }
+    None
+}
 
-    make_response(&tile.data, tile.etag.as_deref(), client_etag, state.cache_max_age_secs)
+fn accepts_webp(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|item| item.split(';').next().unwrap_or_default().trim() == "image/webp")
 }
 
-async fn fetch_with_coalescing(
+pub(crate) async fn fetch_with_coalescing(
     state: &Arc<AppState>,
     key: TileKey,
-) -> Result<Arc<crate::types::TileData>> {
-    loop {
+) -> Result<Arc<TileData>> {
+    let guard = loop {
+        // Also catches a 404 just recorded by the request we waited on
+        if state.negative_cache.contains(&key).await {
+            tracing::trace!(key = %key, "Negative cache hit");
+            state.metrics.cache_hits.with_label_values(&["negative"]).inc();
+            return Err(AppError::NotFound);
+        }
+
         match state.coalescer.try_acquire(key) {
-            CoalesceResult::Acquired(guard) => {
-                // We're responsible for fetching
-                let stored_etag = state.disk_cache.get_etag(&key);
+            CoalesceResult::Acquired(guard) => break guard,
+            CoalesceResult::Wait(waiter) => {
+                // Take the other request's tile or error as our own
+                state.metrics.coalescer_waits.inc();
+                let started = Instant::now();
+                let outcome = state
+                    .coalescer
+                    .wait(key, waiter, state.coalescer_wait_timeout, state.coalescer_takeover)
+                    .await;
+                let label = match &outcome {
+                    WaitOutcome::Done(_) => "done",
+                    WaitOutcome::Abandoned => "abandoned",
+                    WaitOutcome::TimedOut => "timed_out",
+                    WaitOutcome::TookOver(_) => "took_over",
+                };
+                state
+                    .metrics
+                    .coalescer_wait_duration
+                    .with_label_values(&[label])
+                    .observe(started.elapsed().as_secs_f64());
 
-                let result = state.fetcher.fetch(&key, stored_etag.as_deref()).await;
+                match outcome {
+                    WaitOutcome::Done(result) => return result,
+                    // The other request was cancelled before finishing, try again
+                    WaitOutcome::Abandoned => {}
+                    WaitOutcome::TimedOut => return Err(AppError::GatewayTimeout),
+                    WaitOutcome::TookOver(guard) => {
+                        tracing::debug!(key = %key, "In-flight fetch stalled, fetching as well");
+                        break guard;
+                    }
+                }
+            }
+        }
+    };
 
-                // Complete guard before processing result to unblock waiters
-                guard.complete();
+    // We're responsible for fetching
+    if state.is_offline() {
+        tracing::trace!(key = %key, "Offline, not contacting upstream");
+        return guard.complete(Err(AppError::NotFound));
+    }
 
-                match result {
-                    Ok(FetchResult::Data(tile)) => {
-                        let data = tile.data.clone();
-                        let etag = tile.etag.clone();
+    let result = fetch_and_store(state, key).await;
+    guard.complete(result)
+}
 
-                        // Store to caches
-                        if let Err(e) = state.disk_cache.store(&key, &data, etag.as_deref()) {
-                            tracing::warn!(key = %key, error = %e, "Failed to store to disk cache");
-                        }
-                        state.memory_cache.insert(key, data.clone(), etag.clone()).await;
+/// Conditionally fetch a tile from upstream and store it in both cache tiers
+async fn fetch_and_store(state: &AppState, key: TileKey) -> Result<Arc<TileData>> {
+    let validators = state.disk_cache.load_validators(&key).await;
+    // A tile never fetched before comes with the rest of its metatile
+    if validators.etag.is_none() && validators.last_modified.is_none() {
+        if let Some(metatiles) = state.metatiles.as_ref().filter(|m| m.covers(&key)) {
+            if let Some(tile) = fetch_metatile(state, metatiles, key).await {
+                return Ok(tile);
+            }
+        }
+    }
+    fetch_upstream(state, key, validators).await
+}
 
-                        return Ok(Arc::new(tile));
-                    }
-                    Ok(FetchResult::NotModified) => {
-                        // Re-read from disk cache (should exist since we had an etag)
-                        if let Some(tile) = state.disk_cache.get(&key) {
-                            state.memory_cache.insert_tile(key, tile.clone()).await;
-                            return Ok(tile);
-                        }
-                        // Fallback: fetch without etag
-                        match state.fetcher.fetch(&key, None).await? {
-                            FetchResult::Data(tile) => {
-                                let data = tile.data.clone();
-                                let etag = tile.etag.clone();
-                                if let Err(e) = state.disk_cache.store(&key, &data, etag.as_deref()) {
-                                    tracing::warn!(key = %key, error = %e, "Failed to store to disk cache");
-                                }
-                                state.memory_cache.insert(key, data, etag).await;
-                                return Ok(Arc::new(tile));
-                            }
-                            FetchResult::NotModified => {
-                                return Err(AppError::NotFound);
-                            }
-                        }
-                    }
-                    Err(e) => return Err(e),
+/// Fetch a single tile with `validators`, storing it in every tier, or
+/// refreshing the cached copy when upstream reports it unchanged
+async fn fetch_upstream(
+    state: &AppState,
+    key: TileKey,
+    validators: Validators,
+) -> Result<Arc<TileData>> {
+    let started = Instant::now();
+    let fetcher = state.fetcher.load_full();
+    let result = fetcher.fetch(&key, &validators).await;
+    let outcome = match &result {
+        Ok(FetchResult::Data(_)) => "ok",
+        Ok(FetchResult::NotModified) => "not_modified",
+        Err(_) => "error",
+    };
+    state
+        .metrics
+        .upstream_latency
+        .with_label_values(&[outcome])
+        .observe(started.elapsed().as_secs_f64());
+    state.metrics.observe_upstream(&state.wmts_layer, key.z, started.elapsed());
+
+    if let Err(AppError::NotFound) = result {
+        state.negative_cache.insert(key).await;
+    }
+
+    match result? {
+        FetchResult::Data(mut tile) => {
+            tile.ensure_etag();
+            Ok(store_tile(state, key, tile).await)
+        }
+        FetchResult::NotModified => {
+            // Re-read from disk cache (should exist since we had validators),
+            // once touched so it's read back as just fetched
+            if let Err(e) = state.disk_cache.mark_revalidated(&key).await {
+                tracing::warn!(key = %key, error = %e, "Failed to refresh disk cache timestamp");
+            }
+            if let Some(tile) = state.disk_cache.load(&key).await {
+                state.memory_cache.insert_tile(key, tile.clone()).await;
+                return Ok(tile);
+            }
+            // Fallback: fetch unconditionally
+            match fetcher.fetch(&key, &Validators::default()).await? {
+                FetchResult::Data(mut tile) => {
+                    tile.ensure_etag();
+                    Ok(store_tile(state, key, tile).await)
                 }
+                FetchResult::NotModified => Err(AppError::NotFound),
             }
-            CoalesceResult::Wait(notify) => {
-                // Wait for the other request to complete
-                notify.notified().await;
+        }
+    }
+}
 
-                // Check caches again
-                if let Some(tile) = state.memory_cache.get(&key).await {
-                    return Ok(tile);
-                }
-                if let Some(tile) = state.disk_cache.get(&key) {
-                    state.memory_cache.insert_tile(key, tile.clone()).await;
-                    return Ok(tile);
+/// Fetch and store the metatile holding `key`, or wait for the request
+/// already fetching it. None when the tile should be fetched on its own.
+async fn fetch_metatile(
+    state: &AppState,
+    metatiles: &MetatileFetcher,
+    key: TileKey,
+) -> Option<Arc<TileData>> {
+    let origin = metatiles.origin(&key);
+    let guard = match state.metatile_coalescer.try_acquire(origin) {
+        CoalesceResult::Acquired(guard) => guard,
+        CoalesceResult::Wait(waiter) => {
+            let outcome = state
+                .metatile_coalescer
+                .wait(origin, waiter, state.coalescer_wait_timeout, false)
+                .await;
+            // The other request stored every tile of the metatile
+            return match outcome {
+                WaitOutcome::Done(Ok(_)) => {
+                    state.tiers.lookup(&key, 0).await.map(|hit| hit.tile)
                 }
+                _ => None,
+            };
+        }
+    };
 
-                // Still not in cache, loop and try again
-                // (this handles the case where the other request failed)
-            }
+    let started = Instant::now();
+    let result = metatiles.fetch(&origin).await;
+    let outcome = if result.is_ok() { "metatile" } else { "metatile_error" };
+    state
+        .metrics
+        .upstream_latency
+        .with_label_values(&[outcome])
+        .observe(started.elapsed().as_secs_f64());
+    state.metrics.observe_upstream(&state.wmts_layer, key.z, started.elapsed());
+
+    let tiles = match result {
+        Ok(tiles) => tiles,
+        Err(e) => {
+            tracing::debug!(key = %key, error = %e, "Metatile fetch failed, fetching tile alone");
+            let _ = guard.complete(Err(e));
+            return None;
+        }
+    };
+    tracing::debug!(origin = %origin, tiles = tiles.len(), "Fetched metatile");
+    let mut own = None;
+    for (sub_key, mut tile) in tiles {
+        tile.ensure_etag();
+        let tile = store_tile(state, sub_key, tile).await;
+        if sub_key == key {
+            own = Some(tile);
         }
     }
+    guard.complete(own.ok_or(AppError::NotFound)).ok()
 }
 
-fn make_response(
-    data: &[u8],
-    etag: Option<&str>,
-    client_etag: Option<&str>,
-    cache_max_age_secs: u64,
-) -> Result<Response> {
-    // Check if client's etag matches (304 Not Modified)
-    if let (Some(server_etag), Some(client_etag)) = (etag, client_etag) {
-        if server_etag == client_etag {
-            return Ok(StatusCode::NOT_MODIFIED.into_response());
+/// Write a tile through every writable cache tier
+async fn store_tile(state: &AppState, key: TileKey, tile: TileData) -> Arc<TileData> {
+    let tile = Arc::new(tile);
+    if key.format == TileFormat::Png {
+        // Drop the transcoded variant so it gets rebuilt from the new source
+        state.memory_cache.remove(&key.with_format(TileFormat::Webp)).await;
+    }
+    state.tiers.store(&key, &tile).await;
+    tile
+}
+
+/// Fetch and store a tile no request is waiting on, or None when a fetch of
+/// it is already in flight
+pub(crate) async fn fetch_unless_in_flight(
+    state: &AppState,
+    key: TileKey,
+) -> Option<Result<Arc<TileData>>> {
+    let CoalesceResult::Acquired(guard) = state.coalescer.try_acquire(key) else {
+        return None;
+    };
+    Some(guard.complete(fetch_and_store(state, key).await))
+}
+
+/// Revalidate or refetch a native tile ahead of serving it, when the request
+/// asks to and is allowed to, so the caches hold upstream's current copy
+async fn refresh_requested(state: &AppState, key: TileKey, headers: &HeaderMap) {
+    let Some(refresh) = refresh::requested(state, headers) else {
+        return;
+    };
+    if key.z > state.max_native_zoom || state.is_offline() {
+        return;
+    }
+    // A fetch already in flight is as fresh as ours would be
+    let CoalesceResult::Acquired(guard) = state.coalescer.try_acquire(key) else {
+        return;
+    };
+    tracing::info!(key = %key, refresh = refresh.as_str(), "Refreshing tile on request");
+    // Upstream may have the tile by now
+    state.negative_cache.remove(&key).await;
+    let validators = match refresh {
+        Refresh::Revalidate => state.disk_cache.load_validators(&key).await,
+        Refresh::Refetch => Validators::default(),
+    };
+    if let Err(e) = guard.complete(fetch_upstream(state, key, validators).await) {
+        tracing::warn!(key = %key, error = %e, "Failed to refresh tile on request");
+    }
+}
+
+/// Refresh a stale tile in the background, unless a fetch is already in
+/// flight, returning whether it was refreshed
+pub(crate) async fn revalidate(state: Arc<AppState>, key: TileKey) -> bool {
+    if state.is_offline() {
+        return false;
+    }
+    let Some(result) = fetch_unless_in_flight(&state, key).await else {
+        return false;
+    };
+
+    let refreshed = match result {
+        Ok(_) => {
+            tracing::debug!(key = %key, "Revalidated stale tile");
+            true
+        }
+        Err(e) => {
+            tracing::warn!(key = %key, error = %e, "Failed to revalidate stale tile");
+            false
         }
+    };
+    let outcome = if refreshed { "ok" } else { "error" };
+    state.metrics.revalidations.with_label_values(&[outcome]).inc();
+    refreshed
+}
+
+/// Whether any `If-None-Match` entry matches the tile's etag, using the weak
+/// comparison RFC 9110 prescribes for GET: `W/` prefixes are ignored and `*`
+/// matches any current representation
+fn etag_matches(request_headers: &HeaderMap, etag: Option<&str>) -> bool {
+    let opaque = |tag: &str| {
+        let tag = tag.trim();
+        tag.strip_prefix("W/").unwrap_or(tag).to_string()
+    };
+    let etag = etag.map(opaque);
+    request_headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .any(|tag| tag == "*" || etag.as_deref() == Some(opaque(tag).as_str()))
+}
+
+/// Whether the client's `If-Modified-Since` date is no older than the tile
+fn not_modified_since(request_headers: &HeaderMap, tile: &TileData) -> bool {
+    let since = request_headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok());
+    let modified = tile
+        .last_modified
+        .as_deref()
+        .and_then(|v| httpdate::parse_http_date(v).ok());
+    matches!((since, modified), (Some(since), Some(modified)) if modified <= since)
+}
+
+/// Send the upstream's allow-listed headers as stored, in place of any this
+/// proxy set under the same names
+fn replay_upstream_headers(headers: &mut HeaderMap, upstream_headers: &[(String, String)]) {
+    let parsed = upstream_headers.iter().filter_map(|(name, value)| {
+        Some((
+            header::HeaderName::from_bytes(name.as_bytes()).ok()?,
+            header::HeaderValue::from_str(value).ok()?,
+        ))
+    });
+    for (name, _) in parsed.clone() {
+        headers.remove(&name);
     }
+    for (name, value) in parsed {
+        headers.append(name, value);
+    }
+}
 
+pub(crate) fn make_response(
+    tile: &TileData,
+    format: TileFormat,
+    request_headers: &HeaderMap,
+    cache_max_age_secs: u64,
+) -> Result<Response> {
+    // Check if client's etag matches (304 Not Modified); If-None-Match takes
+    // precedence over If-Modified-Since when both are sent
+    let not_modified = if request_headers.contains_key(header::IF_NONE_MATCH) {
+        etag_matches(request_headers, tile.etag.as_deref())
+    } else {
+        not_modified_since(request_headers, tile)
+    };
+
+    // Freshness counts from when the tile was fetched, not from now, so a
+    // tile that has sat in the cache is that much closer to expiring downstream
+    let fetched_at = tile.fetched_at.unwrap_or_else(SystemTime::now);
+    let expires = fetched_at + Duration::from_secs(cache_max_age_secs);
     let mut builder = Response::builder()
-        .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, "image/png")
         .header(
             header::CACHE_CONTROL,
             format!("public, max-age={}", cache_max_age_secs),
-        );
-
-    if let Some(etag) = etag {
+        )
+        .header(header::EXPIRES, httpdate::fmt_http_date(expires));
+    if let Some(age) = tile.age() {
+        builder = builder.header(header::AGE, age.as_secs());
+    }
+    if let Some(etag) = &tile.etag {
         builder = builder.header(header::ETAG, etag);
     }
+    if tile.content_encoding.is_some() {
+        builder = builder.header(header::VARY, "Accept-Encoding");
+    }
+    if not_modified {
+        // A 304 carries the caching headers the 200 would (RFC 9110 §15.4.5)
+        if let Some(headers) = builder.headers_mut() {
+            replay_upstream_headers(headers, &tile.upstream_headers);
+        }
+        return Ok(builder
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .expect("valid response"));
+    }
+
+    let content_type = tile
+        .content_type
+        .as_deref()
+        .unwrap_or_else(|| format.content_type());
+    builder = builder
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type);
+    if let Some(last_modified) = &tile.last_modified {
+        builder = builder.header(header::LAST_MODIFIED, last_modified);
+    }
 
+    let mut body = tile.data.clone();
+    if let Some(encoding) = &tile.content_encoding {
+        if accepts_encoding(request_headers, encoding) {
+            builder = builder.header(header::CONTENT_ENCODING, encoding);
+        } else if tile.is_gzipped() {
+            // Client can't take gzip, decode it on its behalf
+            let mut decoded = Vec::new();
+            GzDecoder::new(&tile.data[..]).read_to_end(&mut decoded)?;
+            body = Bytes::from(decoded);
+        } else {
+            builder = builder.header(header::CONTENT_ENCODING, encoding);
+        }
+    }
+
+    if body.len() >= STREAM_MIN_LEN {
+        // A streamed body has no length of its own, so it's given here
+        builder = builder.header(header::CONTENT_LENGTH, body.len());
+    }
+    if let Some(headers) = builder.headers_mut() {
+        replay_upstream_headers(headers, &tile.upstream_headers);
+    }
     Ok(builder
-        .body(Body::from(data.to_vec()))
+        .body(tile_body(body))
         .expect("valid response"))
 }
+
+/// Tiles from this size, like hi-res satellite imagery, are sent as a stream
+const STREAM_MIN_LEN: usize = 256 * 1024;
+/// Size of each streamed slice
+const STREAM_CHUNK_LEN: usize = 64 * 1024;
+
+/// Body for tile data. Large tiles go out in slices of the same buffer, so
+/// one mapped from disk is paged in as it's written rather than in one go,
+/// and nothing is copied either way.
+fn tile_body(data: Bytes) -> Body {
+    if data.len() < STREAM_MIN_LEN {
+        return Body::from(data);
+    }
+    let len = data.len();
+    let chunks = (0..len).step_by(STREAM_CHUNK_LEN).map(move |start| {
+        Ok::<_, Infallible>(data.slice(start..len.min(start + STREAM_CHUNK_LEN)))
+    });
+    Body::from_stream(futures_util::stream::iter(chunks))
+}
+
+/// Whether the client's Accept-Encoding allows `encoding`
+fn accepts_encoding(headers: &HeaderMap, encoding: &str) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            let name = parts.next().unwrap_or_default();
+            let rejected = parts.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            (name.eq_ignore_ascii_case(encoding) || name == "*") && !rejected
+        })
+}
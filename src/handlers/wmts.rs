@@ -0,0 +1,185 @@
+use crate::error::{AppError, Result};
+use crate::handlers::tile::tile_response;
+use crate::handlers::AppState;
+use crate::types::TileScheme;
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap};
+use axum::response::{IntoResponse, Response};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Arc;
+
+/// Well-known Web Mercator tile matrix set, matching slippy map tiles
+const TILE_MATRIX_SET: &str = "GoogleMapsCompatible";
+
+/// Scale denominator of zoom level 0 for 256px tiles at 0.28mm per pixel
+const ZOOM_0_SCALE_DENOMINATOR: f64 = 559_082_264.028_717_8;
+
+/// Half the width of the Web Mercator plane, in metres
+const MERCATOR_EXTENT: f64 = 20_037_508.342_789_2;
+
+pub async fn get_wmts_tile(
+    State(state): State<Arc<AppState>>,
+    Path((layer, matrix_set, z, x, filename)): Path<(String, String, u8, u32, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = check_layer(&state, &layer, &matrix_set) {
+        return e.into_response();
+    }
+    tile_response(&state, z, x, &filename, TileScheme::Xyz, &headers).await
+}
+
+pub async fn get_wmts_capabilities(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Response {
+    capabilities_response(&state, &headers)
+}
+
+/// Key-value-pair binding: `/wmts?SERVICE=WMTS&REQUEST=GetCapabilities` or `GetTile`
+pub async fn get_wmts_kvp(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Response {
+    // Parameter names are case-insensitive in OGC services
+    let params: HashMap<String, String> = params
+        .into_iter()
+        .map(|(k, v)| (k.to_ascii_lowercase(), v))
+        .collect();
+    let param = |name: &str| params.get(name).map(String::as_str).unwrap_or_default();
+
+    if !param("service").eq_ignore_ascii_case("wmts") {
+        return AppError::BadRequest("SERVICE must be WMTS".to_string()).into_response();
+    }
+
+    match param("request") {
+        r if r.eq_ignore_ascii_case("getcapabilities") => capabilities_response(&state, &headers),
+        r if r.eq_ignore_ascii_case("gettile") => {
+            if let Err(e) = check_layer(&state, param("layer"), param("tilematrixset")) {
+                return e.into_response();
+            }
+            let (Ok(z), Ok(x), Ok(y)) = (
+                param("tilematrix").parse::<u8>(),
+                param("tilecol").parse::<u32>(),
+                param("tilerow").parse::<u32>(),
+            ) else {
+                return AppError::InvalidCoordinates.into_response();
+            };
+            let extension = match param("format") {
+                "" | "image/png" => "png",
+                "image/jpeg" => "jpg",
+                "image/webp" => "webp",
+                other => {
+                    return AppError::BadRequest(format!("unsupported format {}", other))
+                        .into_response()
+                }
+            };
+            let filename = format!("{}.{}", y, extension);
+            tile_response(&state, z, x, &filename, TileScheme::Xyz, &headers).await
+        }
+        other => AppError::BadRequest(format!("unsupported WMTS request {:?}", other)).into_response(),
+    }
+}
+
+fn check_layer(state: &AppState, layer: &str, matrix_set: &str) -> Result<()> {
+    if layer != state.wmts_layer || matrix_set != TILE_MATRIX_SET {
+        return Err(AppError::NotFound);
+    }
+    Ok(())
+}
+
+fn capabilities_response(state: &AppState, headers: &HeaderMap) -> Response {
+    let base_url = match &state.public_url {
+        Some(url) => url.trim_end_matches('/').to_string(),
+        None => {
+            let host = headers
+                .get(header::HOST)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("localhost");
+            format!("http://{}", host)
+        }
+    };
+
+    (
+        [(header::CONTENT_TYPE, "application/xml")],
+        capabilities(state, &base_url),
+    )
+        .into_response()
+}
+
+/// OGC WMTS 1.0.0 capabilities document describing the single proxied layer
+fn capabilities(state: &AppState, base_url: &str) -> String {
+    let layer = xml_escape(&state.wmts_layer);
+    let base_url = xml_escape(base_url);
+
+    let mut matrices = String::new();
+    for z in state.min_zoom..=state.max_zoom {
+        let size = 1u64 << z;
+        // Writing to a String cannot fail
+        let _ = write!(
+            matrices,
+            r#"
+      <TileMatrix>
+        <ows:Identifier>{z}</ows:Identifier>
+        <ScaleDenominator>{scale}</ScaleDenominator>
+        <TopLeftCorner>-{extent} {extent}</TopLeftCorner>
+        <TileWidth>256</TileWidth>
+        <TileHeight>256</TileHeight>
+        <MatrixWidth>{size}</MatrixWidth>
+        <MatrixHeight>{size}</MatrixHeight>
+      </TileMatrix>"#,
+            scale = ZOOM_0_SCALE_DENOMINATOR / size as f64,
+            extent = MERCATOR_EXTENT,
+        );
+    }
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<Capabilities xmlns="http://www.opengis.net/wmts/1.0" xmlns:ows="http://www.opengis.net/ows/1.1" xmlns:xlink="http://www.w3.org/1999/xlink" version="1.0.0">
+  <ows:ServiceIdentification>
+    <ows:Title>maptile_cacher</ows:Title>
+    <ows:ServiceType>OGC WMTS</ows:ServiceType>
+    <ows:ServiceTypeVersion>1.0.0</ows:ServiceTypeVersion>
+  </ows:ServiceIdentification>
+  <ows:OperationsMetadata>
+    <ows:Operation name="GetCapabilities">
+      <ows:DCP><ows:HTTP><ows:Get xlink:href="{base_url}/wmts?"/></ows:HTTP></ows:DCP>
+    </ows:Operation>
+    <ows:Operation name="GetTile">
+      <ows:DCP><ows:HTTP><ows:Get xlink:href="{base_url}/wmts?"/></ows:HTTP></ows:DCP>
+    </ows:Operation>
+  </ows:OperationsMetadata>
+  <Contents>
+    <Layer>
+      <ows:Title>{layer}</ows:Title>
+      <ows:Identifier>{layer}</ows:Identifier>
+      <ows:WGS84BoundingBox>
+        <ows:LowerCorner>-180 -85.0511287798</ows:LowerCorner>
+        <ows:UpperCorner>180 85.0511287798</ows:UpperCorner>
+      </ows:WGS84BoundingBox>
+      <Style isDefault="true"><ows:Identifier>default</ows:Identifier></Style>
+      <Format>image/png</Format>
+      <TileMatrixSetLink><TileMatrixSet>{set}</TileMatrixSet></TileMatrixSetLink>
+      <ResourceURL format="image/png" resourceType="tile" template="{base_url}/wmts/1.0.0/{layer}/default/{{TileMatrixSet}}/{{TileMatrix}}/{{TileCol}}/{{TileRow}}.png"/>
+    </Layer>
+    <TileMatrixSet>
+      <ows:Identifier>{set}</ows:Identifier>
+      <ows:SupportedCRS>urn:ogc:def:crs:EPSG::3857</ows:SupportedCRS>
+      <WellKnownScaleSet>urn:ogc:def:wkss:OGC:1.0:GoogleMapsCompatible</WellKnownScaleSet>{matrices}
+    </TileMatrixSet>
+  </Contents>
+  <ServiceMetadataURL xlink:href="{base_url}/wmts/1.0.0/WMTSCapabilities.xml"/>
+</Capabilities>
+"#,
+        set = TILE_MATRIX_SET,
+    )
+}
+
+pub(crate) fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
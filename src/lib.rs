@@ -0,0 +1,49 @@
+//! Caching proxy for XYZ map tiles, usable as a standalone server or embedded
+//! in another Axum app through [`TileProxy`]:
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use maptile_cacher::{config::Config, TileProxy};
+//!
+//! let proxy = TileProxy::builder().config(Config::default()).build().await?;
+//! let app = axum::Router::new().nest("/tiles", proxy.router());
+//! # Ok(())
+//! # }
+//! ```
+
+mod api_keys;
+pub mod cache;
+mod client_limit;
+mod composite;
+mod forwarded;
+pub mod config;
+pub mod error;
+pub mod geo;
+mod grpc;
+mod handlers;
+pub mod logging;
+mod mbtiles;
+mod metrics;
+mod popularity;
+mod prefetch;
+mod preload;
+mod processing;
+mod proxy;
+pub mod proxy_protocol;
+mod referer;
+mod refresh;
+mod reload;
+mod revalidation;
+mod seed;
+mod terrain;
+#[cfg(feature = "test-support")]
+pub mod testing;
+pub mod tls;
+pub mod types;
+pub mod upstream;
+
+pub use handlers::admin::{ExportRequest, ExportResponse, PurgeResult};
+pub use mbtiles::ExportSummary;
+pub use preload::PreloadSummary;
+pub use proxy::{TileProxy, TileProxyBuilder};
+pub use seed::{JobState, JobStatus, SeedRequest};
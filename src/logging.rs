@@ -0,0 +1,78 @@
+use crate::forwarded::client_ip;
+use crate::handlers::CacheTier;
+use axum::body::HttpBody;
+use axum::extract::Request;
+use axum::http::header;
+use axum::middleware::Next;
+use axum::response::Response;
+use serde::Deserialize;
+use std::str::FromStr;
+use std::time::Instant;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+/// Output format of log lines
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable, colored when writing to a terminal
+    #[default]
+    Text,
+    /// One JSON object per line, for log shippers
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Install the global subscriber, filtered by `RUST_LOG` when set
+pub fn init(format: LogFormat) {
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| "maptile_cacher=debug,tower_http=debug".into());
+    let registry = tracing_subscriber::registry().with(filter);
+    // Logs go to stderr, leaving stdout to command output
+    let layer = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+    match format {
+        LogFormat::Text => registry.with(layer).init(),
+        LogFormat::Json => registry.with(layer.json().flatten_event(true)).init(),
+    }
+}
+
+/// Middleware logging one line per request at info level
+pub async fn access_log(request: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let method = request.method().clone();
+    // The query is left out as it may carry an API key
+    let path = request.uri().path().to_string();
+    let client = client_ip(&request).map_or_else(|| "unix".to_string(), |ip| ip.to_string());
+
+    let response = next.run(request).await;
+
+    let bytes = response.body().size_hint().exact().or_else(|| {
+        response
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+    });
+    let tier = response.extensions().get::<CacheTier>().map(|tier| tier.0);
+    tracing::info!(
+        method = %method,
+        path = %path,
+        status = response.status().as_u16(),
+        cache = tier.unwrap_or("-"),
+        bytes = bytes.unwrap_or(0),
+        duration_ms = started.elapsed().as_secs_f64() * 1000.0,
+        client = %client,
+        "Request served"
+    );
+    response
+}
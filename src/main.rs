@@ -1,66 +1,399 @@
-mod cache;
-mod config;
-mod error;
-mod handlers;
-mod types;
-mod upstream;
-
-use axum::{routing::get, Router};
-use std::sync::Arc;
-use tower_http::cors::{Any, CorsLayer};
-use tower_http::trace::TraceLayer;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-
-use cache::{DiskCache, MemoryCache, RequestCoalescer};
-use config::Config;
-use handlers::{get_tile, AppState};
-use upstream::OsmFetcher;
+use axum::extract::ConnectInfo;
+use axum::serve::IncomingStream;
+use axum::{Extension, Router};
+use axum_server::tls_rustls::RustlsAcceptor;
+use clap::{Args, Parser, Subcommand};
+use serde::Serialize;
+use std::convert::Infallible;
+use std::future::IntoFuture;
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tokio::net::UnixListener;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tower::{service_fn, Layer};
+
+use maptile_cacher::cache::{DiskCache, DiskLayout};
+use maptile_cacher::config::Config;
+use maptile_cacher::geo::{BoundingBox, TileRange};
+use maptile_cacher::logging;
+use maptile_cacher::proxy_protocol::{ProxyProtocolAcceptor, ProxyProtocolListener};
+use maptile_cacher::tls::Tls;
+use maptile_cacher::types::TileFormat;
+use maptile_cacher::{ExportRequest, SeedRequest, TileProxy};
+
+/// Caching proxy for XYZ map tiles
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// TOML config file; environment variables take precedence over it
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the HTTP server (the default)
+    Serve,
+    /// Fetch every tile of a region not yet on disk, then exit
+    Seed {
+        #[command(flatten)]
+        range: RangeArgs,
+        #[arg(long, default_value = "png", value_parser = parse_format)]
+        format: TileFormat,
+        /// Fetch @2x tiles
+        #[arg(long)]
+        retina: bool,
+        /// Parallel upstream fetches, defaults to seed_concurrency
+        #[arg(long)]
+        concurrency: Option<usize>,
+    },
+    /// Remove a region's tiles from the disk cache
+    ///
+    /// A running server keeps serving its in-memory copies until they expire.
+    Purge {
+        #[command(flatten)]
+        range: RangeArgs,
+    },
+    /// Print the size of the disk cache
+    Stats,
+    /// Package cached tiles into an MBTiles file in the export directory
+    ExportMbtiles {
+        /// File name, without extension
+        name: String,
+        /// Limit the export to a region, e.g. `--bbox 5.9,45.8,10.5,47.8`
+        #[arg(long, requires = "zoom", value_parser = parse_bbox)]
+        bbox: Option<BoundingBox>,
+        /// Zoom range of that region
+        #[arg(long, requires = "bbox", value_parser = parse_zoom)]
+        zoom: Option<(u8, u8)>,
+        #[arg(long, default_value = "png", value_parser = parse_format)]
+        format: TileFormat,
+    },
+    /// Move the disk cache from another layout to the configured one
+    MigrateLayout {
+        /// Layout the cache is currently in, "flat" or "sharded"
+        #[arg(long, value_parser = parse_layout)]
+        from: DiskLayout,
+    },
+}
+
+/// A bbox and zoom range selecting tiles
+#[derive(Args)]
+struct RangeArgs {
+    /// min_lon,min_lat,max_lon,max_lat in degrees
+    #[arg(long, value_parser = parse_bbox)]
+    bbox: BoundingBox,
+    /// A zoom level, or an inclusive range like `0-12`
+    #[arg(long, value_parser = parse_zoom)]
+    zoom: (u8, u8),
+}
+
+impl RangeArgs {
+    fn range(&self) -> TileRange {
+        TileRange {
+            bbox: self.bbox,
+            min_zoom: self.zoom.0,
+            max_zoom: self.zoom.1,
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    // Initialize tracing
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "maptile_cacher=debug,tower_http=debug".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+    let cli = Cli::parse();
+    let config = match &cli.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+
+    // Initialize tracing, in the format the config asks for
+    logging::init(config.log_format);
+    if let Some(path) = &cli.config {
+        tracing::info!(path = ?path, "Loaded config file");
+    }
 
-    let config = Config::default();
+    let command = cli.command.unwrap_or(Command::Serve);
+    if let Command::MigrateLayout { from } = command {
+        tracing::info!(from = ?from, to = ?config.disk_layout, "Migrating disk cache layout");
+        let moved = DiskCache::new(&config)?.migrate_from(from)?;
+        tracing::info!(moved, "Disk cache migration complete");
+        return Ok(());
+    }
+    if let Command::Serve = command {
+        return run_server(config, cli.config).await;
+    }
 
+    // One-off commands work on the cache directly, no server needed, and
+    // leave the server's memory snapshot alone
+    let proxy = TileProxy::builder()
+        .config(Config {
+            memory_warm_start: false,
+            ..config.clone()
+        })
+        .build()
+        .await?;
+    match command {
+        Command::Seed { range, format, retina, concurrency } => {
+            let request = SeedRequest {
+                range: range.range(),
+                format,
+                retina,
+                concurrency,
+            };
+            print_json(&proxy.seed(request).await?)?;
+        }
+        Command::Purge { range } => print_json(&proxy.purge(&range.range()).await?)?,
+        Command::Stats => print_json(&proxy.disk_usage().await)?,
+        Command::ExportMbtiles { name, bbox, zoom, format } => {
+            let range = bbox.zip(zoom).map(|(bbox, (min_zoom, max_zoom))| TileRange {
+                bbox,
+                min_zoom,
+                max_zoom,
+            });
+            let request = ExportRequest { name, range, format };
+            print_json(&proxy.export_mbtiles(request).await?)?;
+        }
+        Command::Serve | Command::MigrateLayout { .. } => unreachable!("handled above"),
+    }
+    // Seeded tiles may still be queued for disk
+    proxy.shutdown(config.shutdown_timeout).await;
+    Ok(())
+}
+
+/// Serve HTTP until SIGINT or SIGTERM, then drain requests and disk writes
+async fn run_server(config: Config, config_path: Option<PathBuf>) -> anyhow::Result<()> {
     tracing::info!(bind_addr = %config.bind_addr, "Starting OSM tile caching proxy");
     tracing::info!(cache_dir = ?config.cache_dir, "Disk cache directory");
     tracing::info!(memory_cache_size = config.memory_cache_size, "Memory cache max entries");
+    tracing::info!(disk_cache_max_bytes = config.disk_cache_max_bytes, "Disk cache max bytes");
+
+    let mut builder = TileProxy::builder()
+        .config(config.clone())
+        .reload_on_sighup(true)
+        .resume_seed_jobs(true);
+    if let Some(path) = config_path {
+        builder = builder.config_path(path);
+    }
+    let proxy = builder.build().await?;
+    // Admin routes get their own listener when configured, keeping them off
+    // the public port
+    let app = proxy.router();
+    let admin = proxy.admin_router();
+    let grpc = proxy.grpc_router();
+
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(Tls::load(cert, key).await?),
+        (None, None) => None,
+        _ => anyhow::bail!("tls_cert and tls_key must be set together"),
+    };
+    if let Some(tls) = &tls {
+        if !config.tls_reload_interval.is_zero() {
+            tokio::spawn(tls.clone().watch(config.tls_reload_interval));
+        }
+    }
+    let socket_mode = u32::from_str_radix(&config.unix_socket_mode, 8)
+        .map_err(|_| anyhow::anyhow!("Invalid unix_socket_mode {:?}", config.unix_socket_mode))?;
 
-    // Initialize components
-    let memory_cache = MemoryCache::new(config.memory_cache_size);
-    let disk_cache = DiskCache::new(&config)?;
-    let coalescer = RequestCoalescer::new();
-    let fetcher = OsmFetcher::new(&config)?;
-
-    let state = Arc::new(AppState {
-        memory_cache,
-        disk_cache,
-        coalescer,
-        fetcher,
-        cache_max_age_secs: config.cache_max_age.as_secs(),
+    // Stop accepting connections on a signal, then let open requests finish
+    let (shutdown, shutdown_rx) = watch::channel(false);
+    let mut servers = JoinSet::new();
+    let listener = Listener {
+        tls: tls.clone(),
+        socket_mode,
+        proxy_protocol: config.proxy_protocol,
+    };
+    serve(&mut servers, &config.bind_addr, app, &listener, shutdown_rx.clone()).await?;
+    tracing::info!(
+        tls = tls.is_some(),
+        proxy_protocol = config.proxy_protocol,
+        "Listening on {}",
+        config.bind_addr
+    );
+    if let (Some(addr), Some(admin)) = (&config.admin_bind_addr, admin) {
+        // Only the public port sits behind the load balancer
+        let listener = Listener {
+            proxy_protocol: false,
+            ..listener
+        };
+        serve(&mut servers, addr, admin, &listener, shutdown_rx.clone()).await?;
+        tracing::info!(tls = tls.is_some(), "Admin API listening on {}", addr);
+    }
+    if let (Some(addr), Some(grpc)) = (&config.grpc_bind_addr, grpc) {
+        let listener = Listener {
+            tls: tls.clone(),
+            socket_mode,
+            proxy_protocol: false,
+        };
+        serve(&mut servers, addr, grpc, &listener, shutdown_rx).await?;
+        tracing::info!(tls = tls.is_some(), "gRPC service listening on {}", addr);
+    }
+
+    tokio::select! {
+        Some(result) = servers.join_next() => return Ok(result??),
+        _ = shutdown_signal() => shutdown.send_replace(true),
+    };
+    let drained = async {
+        while let Some(result) = servers.join_next().await {
+            result??;
+        }
+        anyhow::Ok(())
+    };
+    match tokio::time::timeout(config.shutdown_timeout, drained).await {
+        Ok(result) => result?,
+        Err(_) => tracing::warn!("Timed out draining requests"),
+    }
+
+    proxy.shutdown(config.shutdown_timeout).await;
+    let addrs = [&config.admin_bind_addr, &config.grpc_bind_addr];
+    for addr in std::iter::once(&config.bind_addr).chain(addrs.into_iter().flatten()) {
+        if let Some(path) = unix_socket_path(addr) {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+    tracing::info!("Shutdown complete");
+
+    Ok(())
+}
+
+/// How a listener accepts connections
+struct Listener {
+    tls: Option<Tls>,
+    /// Permissions of a Unix socket
+    socket_mode: u32,
+    /// Read the client address from a PROXY protocol header, on TCP only
+    proxy_protocol: bool,
+}
+
+/// Bind `addr`, either `host:port` or `unix:/path`, and serve `app` on it, over
+/// TLS if configured, until `shutdown` turns true, then finish open requests
+async fn serve(
+    servers: &mut JoinSet<std::io::Result<()>>,
+    addr: &str,
+    app: Router,
+    listener: &Listener,
+    mut shutdown: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let stopped = async move {
+        let _ = shutdown.wait_for(|stop| *stop).await;
+    };
+
+    if let Some(path) = unix_socket_path(addr) {
+        if listener.tls.is_some() {
+            anyhow::bail!("TLS is not supported on Unix socket {:?}", path);
+        }
+        let listener = bind_unix(path, listener.socket_mode)?;
+        servers.spawn(
+            axum::serve(listener, app.into_make_service())
+                .with_graceful_shutdown(stopped)
+                .into_future(),
+        );
+        return Ok(());
+    }
+
+    let tcp = tokio::net::TcpListener::bind(addr).await?;
+    let Some(tls) = &listener.tls else {
+        if !listener.proxy_protocol {
+            let app = app.into_make_service_with_connect_info::<SocketAddr>();
+            servers.spawn(axum::serve(tcp, app).with_graceful_shutdown(stopped).into_future());
+            return Ok(());
+        }
+        // Each connection is served under the address from its header
+        let app = service_fn(move |incoming: IncomingStream<'_, ProxyProtocolListener>| {
+            let client = ConnectInfo(*incoming.remote_addr());
+            std::future::ready(Ok::<_, Infallible>(Extension(client).layer(app.clone())))
+        });
+        let tcp = ProxyProtocolListener::new(tcp)?;
+        servers.spawn(axum::serve(tcp, app).with_graceful_shutdown(stopped).into_future());
+        return Ok(());
+    };
+    let handle = axum_server::Handle::new();
+    let server = axum_server::from_tcp(tcp.into_std()?)?.handle(handle.clone());
+    let acceptor = RustlsAcceptor::new(tls.config.clone());
+    tokio::spawn(async move {
+        stopped.await;
+        handle.graceful_shutdown(None);
     });
+    if listener.proxy_protocol {
+        // The acceptor provides the client address itself
+        let server = server.acceptor(acceptor.acceptor(ProxyProtocolAcceptor));
+        servers.spawn(server.serve(app.into_make_service()));
+    } else {
+        let app = app.into_make_service_with_connect_info::<SocketAddr>();
+        servers.spawn(server.acceptor(acceptor).serve(app));
+    }
+    Ok(())
+}
 
-    // Build router
-    let app = Router::new()
-        .route("/{z}/{x}/{filename}", get(get_tile))
-        .layer(CorsLayer::new()
-            .allow_origin(Any)
-            .allow_methods(Any)
-            .allow_headers(Any))
-        .layer(TraceLayer::new_for_http())
-        .with_state(state);
+/// Path of a `unix:/path` bind address
+fn unix_socket_path(addr: &str) -> Option<&Path> {
+    addr.strip_prefix("unix:").map(Path::new)
+}
 
-    let listener = tokio::net::TcpListener::bind(&config.bind_addr).await?;
-    tracing::info!("Listening on {}", config.bind_addr);
+/// Listen on a Unix socket with the given permissions, replacing one left
+/// behind by an earlier run
+fn bind_unix(path: &Path, mode: u32) -> anyhow::Result<UnixListener> {
+    if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)
+        .map_err(|e| anyhow::anyhow!("Failed to bind Unix socket {:?}: {}", path, e))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
 
-    axum::serve(listener, app).await?;
+/// Resolves on SIGINT or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!(error = %e, "Failed to listen for SIGINT");
+            std::future::pending::<()>().await;
+        }
+    };
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
 
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+    tracing::info!("Shutdown signal received, draining requests");
+}
+
+fn print_json(value: &impl Serialize) -> anyhow::Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
     Ok(())
 }
+
+/// Parse `min_lon,min_lat,max_lon,max_lat`
+fn parse_bbox(s: &str) -> Result<BoundingBox, String> {
+    s.parse()
+}
+
+/// Parse `z` or `min-max`
+fn parse_zoom(s: &str) -> Result<(u8, u8), String> {
+    let (min, max) = s.split_once('-').unwrap_or((s, s));
+    let min = min.trim().parse::<u8>().map_err(|e| e.to_string())?;
+    let max = max.trim().parse::<u8>().map_err(|e| e.to_string())?;
+    Ok((min, max))
+}
+
+fn parse_format(s: &str) -> Result<TileFormat, String> {
+    TileFormat::from_extension(s).ok_or_else(|| format!("unknown tile format {:?}", s))
+}
+
+fn parse_layout(s: &str) -> Result<DiskLayout, String> {
+    s.parse().map_err(|_| format!("unknown disk layout {:?}", s))
+}
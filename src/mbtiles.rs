@@ -0,0 +1,101 @@
+use crate::cache::DiskCache;
+use crate::error::Result;
+use crate::geo::{TileRange, MAX_LATITUDE};
+use crate::types::TileFormat;
+use rusqlite::{params, Connection};
+use std::fs;
+use std::path::Path;
+
+const SCHEMA: &str = "
+    CREATE TABLE metadata (name TEXT, value TEXT);
+    CREATE TABLE tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_data BLOB);
+    CREATE UNIQUE INDEX tile_index ON tiles (zoom_level, tile_column, tile_row);
+";
+
+/// Summary of a finished export
+#[derive(Debug, serde::Serialize)]
+pub struct ExportSummary {
+    pub tiles: u64,
+    pub min_zoom: Option<u8>,
+    pub max_zoom: Option<u8>,
+}
+
+/// Write cached tiles of one format, optionally limited to a range, into a new MBTiles file
+pub fn export(
+    disk_cache: &DiskCache,
+    path: &Path,
+    name: &str,
+    format: TileFormat,
+    range: Option<&TileRange>,
+) -> Result<ExportSummary> {
+    // Build next to the destination and rename into place once complete
+    let tmp_path = path.with_extension("mbtiles.tmp");
+    if tmp_path.exists() {
+        fs::remove_file(&tmp_path)?;
+    }
+
+    let mut conn = Connection::open(&tmp_path)?;
+    conn.execute_batch(SCHEMA)?;
+
+    let tx = conn.transaction()?;
+    let mut summary = ExportSummary {
+        tiles: 0,
+        min_zoom: None,
+        max_zoom: None,
+    };
+    {
+        let mut insert = tx.prepare(
+            "INSERT INTO tiles (zoom_level, tile_column, tile_row, tile_data) VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        for key in disk_cache.keys() {
+            // MBTiles has no notion of pixel density, export standard tiles only
+            if key.format != format || key.scale != 1 {
+                continue;
+            }
+            if range.is_some_and(|range| !range.contains(&key)) {
+                continue;
+            }
+            let Some(tile) = disk_cache.get(&key) else {
+                continue;
+            };
+
+            // MBTiles rows follow the TMS scheme, counting from the south
+            let tms_row = (1u32 << key.z) - 1 - key.y;
+            insert.execute(params![key.z, key.x, tms_row, &tile.data[..]])?;
+
+            summary.tiles += 1;
+            summary.min_zoom = Some(summary.min_zoom.map_or(key.z, |z| z.min(key.z)));
+            summary.max_zoom = Some(summary.max_zoom.map_or(key.z, |z| z.max(key.z)));
+        }
+
+        let bounds = match range {
+            Some(range) => format!(
+                "{},{},{},{}",
+                range.bbox.min_lon, range.bbox.min_lat, range.bbox.max_lon, range.bbox.max_lat
+            ),
+            None => format!("-180,{},180,{}", -MAX_LATITUDE, MAX_LATITUDE),
+        };
+        let mut metadata = vec![
+            ("name", name.to_string()),
+            ("format", format.extension().to_string()),
+            ("type", "baselayer".to_string()),
+            ("version", "1".to_string()),
+            ("bounds", bounds),
+            ("attribution", "© OpenStreetMap contributors".to_string()),
+        ];
+        if let (Some(min_zoom), Some(max_zoom)) = (summary.min_zoom, summary.max_zoom) {
+            metadata.push(("minzoom", min_zoom.to_string()));
+            metadata.push(("maxzoom", max_zoom.to_string()));
+        }
+
+        let mut insert = tx.prepare("INSERT INTO metadata (name, value) VALUES (?1, ?2)")?;
+        for (name, value) in metadata {
+            insert.execute(params![name, value])?;
+        }
+    }
+    tx.commit()?;
+    conn.close().map_err(|(_, e)| e)?;
+
+    fs::rename(&tmp_path, path)?;
+    Ok(summary)
+}
@@ -0,0 +1,264 @@
+use prometheus::core::Collector;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Prometheus metrics for the tile proxy
+pub struct Metrics {
+    registry: Registry,
+    /// Tiles served per tier (memory/disk/redis/s3/mbtiles/pmtiles/upstream/
+    /// stale/negative/synthesized/fallback)
+    pub cache_hits: IntCounterVec,
+    /// Lookups that fell through a tier (memory/disk)
+    pub cache_misses: IntCounterVec,
+    /// Upstream fetch latency by outcome
+    pub upstream_latency: HistogramVec,
+    /// Background refreshes of stale disk tiles by outcome
+    pub revalidations: IntCounterVec,
+    /// Speculative fetches of tiles next to a miss by outcome
+    pub prefetches: IntCounterVec,
+    /// Requests that waited on another in-flight fetch
+    pub coalescer_waits: IntCounter,
+    /// Time requests spent parked behind another fetch, by how the wait ended
+    pub coalescer_wait_duration: HistogramVec,
+    /// Responses by HTTP status code
+    pub responses: IntCounterVec,
+    /// Requests rejected with 503 at the concurrency limit
+    pub requests_shed: IntCounter,
+    /// Tile requests answered with 504 after `request_timeout`
+    pub requests_timed_out: IntCounter,
+    /// Requests rejected with 429 by the per-client rate limit
+    pub requests_rate_limited: IntCounter,
+    /// Tiles not persisted because the disk write queue was full
+    pub disk_writes_dropped: IntCounter,
+    /// Stored PNG tiles recompressed in the background, by outcome
+    /// (optimized/unchanged/failed/dropped)
+    pub png_optimizations: IntCounterVec,
+    /// Disk space recovered by PNG recompression
+    pub png_bytes_saved: IntCounter,
+    /// Tile requests by zoom level
+    pub requests_by_zoom: IntCounterVec,
+    /// Tile requests by layer, zoom bucket and the tier that served them
+    /// ("none" for errors)
+    pub layer_requests: IntCounterVec,
+    /// Time to answer tile requests, by layer and zoom bucket
+    pub layer_request_duration: HistogramVec,
+    /// Upstream fetch latency by layer and zoom bucket, counting the fetches
+    /// that misses cause
+    pub layer_upstream_latency: HistogramVec,
+    pub memory_cache_entries: IntGauge,
+    /// First zoom level of each bucket, ascending from 0
+    zoom_buckets: Vec<u8>,
+}
+
+impl Metrics {
+    /// Metrics grouping zoom levels into buckets starting at `zoom_buckets`
+    pub fn new(zoom_buckets: &[u8]) -> prometheus::Result<Self> {
+        let mut zoom_buckets = zoom_buckets.to_vec();
+        zoom_buckets.push(0);
+        zoom_buckets.sort_unstable();
+        zoom_buckets.dedup();
+        let registry = Registry::new_custom(Some("maptile_cacher".to_string()), None)?;
+
+        let cache_hits = IntCounterVec::new(
+            Opts::new("cache_hits_total", "Tiles served, by cache tier"),
+            &["tier"],
+        )?;
+        let cache_misses = IntCounterVec::new(
+            Opts::new("cache_misses_total", "Cache lookups that missed, by cache tier"),
+            &["tier"],
+        )?;
+        let upstream_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "upstream_request_duration_seconds",
+                "Upstream tile fetch latency",
+            )
+            .buckets(vec![0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]),
+            &["result"],
+        )?;
+        let revalidations = IntCounterVec::new(
+            Opts::new("revalidations_total", "Background revalidations of stale tiles"),
+            &["result"],
+        )?;
+        let prefetches = IntCounterVec::new(
+            Opts::new("prefetches_total", "Background fetches of tiles next to a cache miss"),
+            &["result"],
+        )?;
+        let coalescer_waits = IntCounter::new(
+            "coalescer_waits_total",
+            "Requests that waited on an in-flight fetch for the same tile",
+        )?;
+        let coalescer_wait_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "coalescer_wait_duration_seconds",
+                "Time spent waiting on an in-flight fetch for the same tile",
+            )
+            .buckets(vec![0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]),
+            &["outcome"],
+        )?;
+        let requests_shed = IntCounter::new(
+            "requests_shed_total",
+            "Requests rejected because the server was at its concurrency limit",
+        )?;
+        let requests_timed_out = IntCounter::new(
+            "requests_timed_out_total",
+            "Tile requests abandoned because they exceeded the request timeout",
+        )?;
+        let requests_rate_limited = IntCounter::new(
+            "requests_rate_limited_total",
+            "Requests rejected because the client exceeded its rate limit",
+        )?;
+        let responses = IntCounterVec::new(
+            Opts::new("responses_total", "Tile responses, by HTTP status"),
+            &["status"],
+        )?;
+        let disk_writes_dropped = IntCounter::new(
+            "disk_writes_dropped_total",
+            "Tiles not written to disk because the write queue was full",
+        )?;
+        let png_optimizations = IntCounterVec::new(
+            Opts::new("png_optimizations_total", "Stored PNG tiles recompressed, by outcome"),
+            &["result"],
+        )?;
+        let png_bytes_saved = IntCounter::new(
+            "png_optimization_bytes_saved_total",
+            "Bytes of disk space saved by recompressing PNG tiles",
+        )?;
+        let requests_by_zoom = IntCounterVec::new(
+            Opts::new("requests_by_zoom_total", "Tile requests, by zoom level"),
+            &["zoom"],
+        )?;
+        let layer_requests = IntCounterVec::new(
+            Opts::new(
+                "layer_requests_total",
+                "Tile requests, by layer, zoom bucket and serving cache tier",
+            ),
+            &["layer", "zoom", "tier"],
+        )?;
+        let layer_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "layer_request_duration_seconds",
+                "Time to answer tile requests, by layer and zoom bucket",
+            )
+            .buckets(vec![0.001, 0.005, 0.025, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]),
+            &["layer", "zoom"],
+        )?;
+        let layer_upstream_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "layer_upstream_request_duration_seconds",
+                "Upstream tile fetch latency, by layer and zoom bucket",
+            )
+            .buckets(vec![0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]),
+            &["layer", "zoom"],
+        )?;
+        let memory_cache_entries =
+            IntGauge::new("memory_cache_entries", "Entries in the memory cache")?;
+
+        registry.register(Box::new(cache_hits.clone()))?;
+        registry.register(Box::new(cache_misses.clone()))?;
+        registry.register(Box::new(upstream_latency.clone()))?;
+        registry.register(Box::new(revalidations.clone()))?;
+        registry.register(Box::new(prefetches.clone()))?;
+        registry.register(Box::new(coalescer_waits.clone()))?;
+        registry.register(Box::new(coalescer_wait_duration.clone()))?;
+        registry.register(Box::new(requests_shed.clone()))?;
+        registry.register(Box::new(requests_timed_out.clone()))?;
+        registry.register(Box::new(requests_rate_limited.clone()))?;
+        registry.register(Box::new(responses.clone()))?;
+        registry.register(Box::new(disk_writes_dropped.clone()))?;
+        registry.register(Box::new(png_optimizations.clone()))?;
+        registry.register(Box::new(png_bytes_saved.clone()))?;
+        registry.register(Box::new(requests_by_zoom.clone()))?;
+        registry.register(Box::new(layer_requests.clone()))?;
+        registry.register(Box::new(layer_request_duration.clone()))?;
+        registry.register(Box::new(layer_upstream_latency.clone()))?;
+        registry.register(Box::new(memory_cache_entries.clone()))?;
+
+        Ok(Self {
+            registry,
+            cache_hits,
+            cache_misses,
+            upstream_latency,
+            revalidations,
+            prefetches,
+            coalescer_waits,
+            coalescer_wait_duration,
+            requests_shed,
+            requests_timed_out,
+            requests_rate_limited,
+            responses,
+            disk_writes_dropped,
+            png_optimizations,
+            png_bytes_saved,
+            requests_by_zoom,
+            layer_requests,
+            layer_request_duration,
+            layer_upstream_latency,
+            memory_cache_entries,
+            zoom_buckets,
+        })
+    }
+
+    /// Record a tile request of `layer`, served by `tier`
+    pub fn observe_request(&self, layer: &str, z: u8, tier: &str, elapsed: Duration) {
+        let zoom = self.zoom_bucket(z);
+        self.layer_requests.with_label_values(&[layer, &zoom, tier]).inc();
+        self.layer_request_duration
+            .with_label_values(&[layer, &zoom])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Record a fetch of one of `layer`'s tiles from its upstream
+    pub fn observe_upstream(&self, layer: &str, z: u8, elapsed: Duration) {
+        self.layer_upstream_latency
+            .with_label_values(&[layer, &self.zoom_bucket(z)])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Label of the bucket holding zoom `z`, like `11-14` or `17+`
+    fn zoom_bucket(&self, z: u8) -> String {
+        let index = self.zoom_buckets.partition_point(|&start| start <= z) - 1;
+        let start = self.zoom_buckets[index];
+        match self.zoom_buckets.get(index + 1) {
+            Some(&next) if next == start + 1 => start.to_string(),
+            Some(&next) => format!("{}-{}", start, next - 1),
+            None => format!("{}+", start),
+        }
+    }
+
+    /// Encode all metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        let encoder = TextEncoder::new();
+        if let Err(e) = encoder.encode(&self.registry.gather(), &mut buffer) {
+            tracing::warn!(error = %e, "Failed to encode metrics");
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+/// Current value of each series of a single-label counter, keyed by label value
+pub fn counter_totals(counter: &IntCounterVec) -> BTreeMap<String, u64> {
+    series(counter)
+        .map(|(label, metric)| (label, metric.get_counter().get_value() as u64))
+        .collect()
+}
+
+/// Observation count of each series of a single-label histogram
+pub fn histogram_counts(histogram: &HistogramVec) -> BTreeMap<String, u64> {
+    series(histogram)
+        .map(|(label, metric)| (label, metric.get_histogram().get_sample_count()))
+        .collect()
+}
+
+fn series(collector: &impl Collector) -> impl Iterator<Item = (String, prometheus::proto::Metric)> {
+    collector.collect().into_iter().flat_map(|family| {
+        family.get_metric().to_vec().into_iter().filter_map(|metric| {
+            let label = metric.get_label().first()?.value().to_string();
+            Some((label, metric))
+        })
+    })
+}
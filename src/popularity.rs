@@ -0,0 +1,120 @@
+use crate::config::Config;
+use crate::geo::{x_to_lon, y_to_lat};
+use crate::types::TileKey;
+use dashmap::DashMap;
+use serde::Serialize;
+
+/// Approximate request counts per tile, halved every half-life so they follow
+/// current demand, for finding the regions worth seeding or keeping
+pub struct Popularity {
+    /// Keyed by coordinates, so every format and scale of a tile counts once
+    counts: DashMap<(u8, u32, u32), u64>,
+    max_tiles: usize,
+}
+
+/// A tile and its recent request count
+#[derive(Debug, Serialize)]
+pub struct PopularTile {
+    pub z: u8,
+    pub x: u32,
+    pub y: u32,
+    pub requests: u64,
+}
+
+impl Popularity {
+    /// Tracker for the configured limit, None when tracking is off
+    pub fn from_config(config: &Config) -> Option<Self> {
+        config.popularity_tracking.then(|| Self {
+            counts: DashMap::new(),
+            max_tiles: config.popularity_max_tiles,
+        })
+    }
+
+    /// Count a request for a tile
+    pub fn record(&self, key: &TileKey) {
+        let coords = (key.z, key.x, key.y);
+        if let Some(mut count) = self.counts.get_mut(&coords) {
+            *count += 1;
+            return;
+        }
+        // Once full, only tracked tiles count until a decay makes room
+        if self.counts.len() < self.max_tiles {
+            *self.counts.entry(coords).or_insert(0) += 1;
+        }
+    }
+
+    /// Recent requests for a tile, 0 for one that isn't tracked
+    pub fn requests(&self, key: &TileKey) -> u64 {
+        self.counts.get(&(key.z, key.x, key.y)).map_or(0, |count| *count)
+    }
+
+    /// Halve every count, forgetting tiles that drop to zero
+    pub fn decay(&self) {
+        self.counts.retain(|_, count| {
+            *count /= 2;
+            *count > 0
+        });
+    }
+
+    /// The most requested tiles, at one zoom level or all of them
+    pub fn top(&self, zoom: Option<u8>, limit: usize) -> Vec<PopularTile> {
+        let mut tiles: Vec<_> = self
+            .counts
+            .iter()
+            .filter(|entry| zoom.is_none_or(|z| entry.key().0 == z))
+            .map(|entry| {
+                let (z, x, y) = *entry.key();
+                PopularTile { z, x, y, requests: *entry.value() }
+            })
+            .collect();
+        tiles.sort_unstable_by(|a, b| {
+            b.requests.cmp(&a.requests).then_with(|| (a.z, a.x, a.y).cmp(&(b.z, b.x, b.y)))
+        });
+        tiles.truncate(limit);
+        tiles
+    }
+}
+
+/// GeoJSON points at tile centers weighted by `requests`, for heatmap layers
+#[derive(Debug, Serialize)]
+pub struct Heatmap {
+    r#type: &'static str,
+    features: Vec<Feature>,
+}
+
+#[derive(Debug, Serialize)]
+struct Feature {
+    r#type: &'static str,
+    geometry: Point,
+    properties: PopularTile,
+}
+
+#[derive(Debug, Serialize)]
+struct Point {
+    r#type: &'static str,
+    /// Longitude, latitude
+    coordinates: [f64; 2],
+}
+
+impl Heatmap {
+    pub fn new(tiles: Vec<PopularTile>) -> Self {
+        let features = tiles
+            .into_iter()
+            .map(|tile| Feature {
+                r#type: "Feature",
+                geometry: Point {
+                    r#type: "Point",
+                    coordinates: [
+                        x_to_lon(f64::from(tile.x) + 0.5, tile.z),
+                        y_to_lat(f64::from(tile.y) + 0.5, tile.z),
+                    ],
+                },
+                properties: tile,
+            })
+            .collect();
+        Self {
+            r#type: "FeatureCollection",
+            features,
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use crate::handlers::tile::fetch_unless_in_flight;
+use crate::handlers::AppState;
+use crate::types::TileKey;
+use crate::upstream::rate_limit::TokenBucket;
+use crate::upstream::scheduler;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+/// Queue of tiles a client is likely to ask for next, fetched in the
+/// background at a limited rate
+pub struct Prefetcher {
+    sender: mpsc::Sender<TileKey>,
+}
+
+impl Prefetcher {
+    /// A queue holding up to `capacity` tiles, and its receiving end for `run`
+    pub fn new(capacity: usize) -> (Self, mpsc::Receiver<TileKey>) {
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+        (Self { sender }, receiver)
+    }
+
+    /// Queue the 8 neighbors of a tile just fetched from upstream, and its 4
+    /// children up to `max_zoom`, returning how many didn't fit in the queue
+    pub fn enqueue_around(&self, key: &TileKey, max_zoom: u8) -> usize {
+        let size = 1i64 << key.z;
+        let neighbors = (-1i64..=1)
+            .flat_map(|dx| (-1i64..=1).map(move |dy| (dx, dy)))
+            .filter(|&offset| offset != (0, 0))
+            .filter_map(|(dx, dy)| {
+                let y = i64::from(key.y) + dy;
+                // Columns wrap around the antimeridian, rows stop at the poles
+                (0..size).contains(&y).then(|| TileKey {
+                    x: (i64::from(key.x) + dx).rem_euclid(size) as u32,
+                    y: y as u32,
+                    ..*key
+                })
+            });
+        let children = (key.z < max_zoom)
+            .then(|| {
+                (0..4).map(|i| TileKey {
+                    z: key.z + 1,
+                    x: key.x * 2 + i % 2,
+                    y: key.y * 2 + i / 2,
+                    ..*key
+                })
+            })
+            .into_iter()
+            .flatten();
+        neighbors
+            .chain(children)
+            .filter(|key| self.sender.try_send(*key).is_err())
+            .count()
+    }
+}
+
+/// Fetch queued tiles that aren't cached yet, at most `rate` per second, or
+/// as fast as upstream allows when 0
+pub async fn run(state: Arc<AppState>, mut receiver: mpsc::Receiver<TileKey>, rate: f64) {
+    let bucket = (rate > 0.0).then(|| TokenBucket::new(rate));
+    while let Some(key) = receiver.recv().await {
+        let cached = state.memory_cache.get(&key).await.is_some()
+            || state.disk_cache.contains(&key).await
+            || state.negative_cache.contains(&key).await;
+        if cached || state.is_offline() || !state.in_bounds(&key) {
+            state.metrics.prefetches.with_label_values(&["skipped"]).inc();
+            continue;
+        }
+        if let Some(bucket) = &bucket {
+            bucket.acquire().await;
+        }
+        let outcome = match scheduler::background(fetch_unless_in_flight(&state, key)).await {
+            Some(Ok(_)) => "ok",
+            Some(Err(e)) => {
+                tracing::debug!(key = %key, error = %e, "Prefetch failed");
+                "error"
+            }
+            // A request is already fetching it
+            None => "skipped",
+        };
+        state.metrics.prefetches.with_label_values(&[outcome]).inc();
+    }
+}
@@ -0,0 +1,124 @@
+use crate::cache::DiskCache;
+use crate::error::{AppError, Result};
+use crate::types::{TileData, TileFormat, TileKey, TilePath};
+use bytes::Bytes;
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+
+/// Tiles between progress log lines
+const PROGRESS_INTERVAL: u64 = 10_000;
+
+/// Summary of a finished pre-load
+#[derive(Debug, Default, Serialize)]
+pub struct PreloadSummary {
+    /// Tiles written to the disk cache
+    pub tiles: u64,
+    /// Tiles left alone because the cache already had them
+    pub skipped: u64,
+}
+
+impl PreloadSummary {
+    fn add(&mut self, disk_cache: &DiskCache, key: TileKey, data: Vec<u8>) -> Result<()> {
+        let key = key.with_generation(disk_cache.generation());
+        if !key.is_valid() || disk_cache.exists(&key) {
+            self.skipped += 1;
+            return Ok(());
+        }
+        let mut tile = TileData::new(Bytes::from(data), None);
+        tile.content_type = Some(key.format.content_type().to_string());
+        if tile.data.starts_with(&[0x1f, 0x8b]) {
+            // Vector tiles are usually stored gzipped
+            tile.content_encoding = Some("gzip".to_string());
+        }
+        tile.ensure_etag();
+        disk_cache.store(&key, &tile)?;
+
+        self.tiles += 1;
+        if self.tiles.is_multiple_of(PROGRESS_INTERVAL) {
+            tracing::info!(tiles = self.tiles, skipped = self.skipped, "Pre-loading tiles");
+        }
+        Ok(())
+    }
+}
+
+/// Copy the tiles of an MBTiles file, or a `z/x/y.ext` directory tree such
+/// as a mod_tile or TileCache export, into the disk cache. Tiles already
+/// cached are kept.
+pub fn preload(disk_cache: &DiskCache, path: &Path) -> Result<PreloadSummary> {
+    tracing::info!(path = ?path, "Pre-loading tiles into the disk cache");
+    let summary = if path.is_dir() {
+        preload_dir(disk_cache, path)?
+    } else {
+        preload_mbtiles(disk_cache, path)?
+    };
+    tracing::info!(
+        path = ?path,
+        tiles = summary.tiles,
+        skipped = summary.skipped,
+        "Pre-load complete"
+    );
+    Ok(summary)
+}
+
+fn preload_dir(disk_cache: &DiskCache, root: &Path) -> Result<PreloadSummary> {
+    let mut summary = PreloadSummary::default();
+    for (z, z_dir) in numbered_entries::<u8>(root) {
+        for (x, x_dir) in numbered_entries::<u32>(&z_dir) {
+            for entry in fs::read_dir(&x_dir)?.flatten() {
+                let name = entry.file_name();
+                let path = name.to_str().and_then(|name| TilePath::from_filename(z, x, name));
+                let Some(key) = path.map(TilePath::key) else {
+                    continue;
+                };
+                summary.add(disk_cache, key, fs::read(entry.path())?)?;
+            }
+        }
+    }
+    Ok(summary)
+}
+
+fn preload_mbtiles(disk_cache: &DiskCache, path: &Path) -> Result<PreloadSummary> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let format = conn
+        .query_row("SELECT value FROM metadata WHERE name = 'format'", [], |row| {
+            row.get::<_, String>(0)
+        })
+        .optional()?;
+    let format = match format {
+        Some(format) => TileFormat::from_extension(&format)
+            .ok_or_else(|| AppError::Archive(format!("unsupported tile format {:?}", format)))?,
+        None => TileFormat::Png,
+    };
+
+    let mut summary = PreloadSummary::default();
+    let mut select =
+        conn.prepare("SELECT zoom_level, tile_column, tile_row, tile_data FROM tiles")?;
+    let mut rows = select.query([])?;
+    while let Some(row) = rows.next()? {
+        let z: u8 = row.get(0)?;
+        let x: u32 = row.get(1)?;
+        let tms_row: u32 = row.get(2)?;
+        // MBTiles rows follow the TMS scheme, counting from the south
+        let Some(y) = (1u32 << z.min(31)).checked_sub(1).and_then(|max| max.checked_sub(tms_row))
+        else {
+            summary.skipped += 1;
+            continue;
+        };
+        let key = TileKey::new(z, x, y).with_format(format);
+        summary.add(disk_cache, key, row.get(3)?)?;
+    }
+    Ok(summary)
+}
+
+/// Subdirectories named by a number, e.g. zoom or column levels
+fn numbered_entries<T: std::str::FromStr>(dir: &Path) -> Vec<(T, std::path::PathBuf)> {
+    fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_dir()))
+        .filter_map(|entry| Some((entry.file_name().to_str()?.parse().ok()?, entry.path())))
+        .collect()
+}
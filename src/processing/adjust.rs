@@ -0,0 +1,128 @@
+use crate::error::{AppError, Result};
+use crate::processing::encode;
+use crate::types::{TileData, TileFormat};
+use bytes::Bytes;
+use image::{DynamicImage, ImageResult, Rgba};
+use serde::{Deserialize, Deserializer};
+
+/// Largest brightness, contrast and saturation factor accepted
+const MAX_FACTOR: f32 = 10.0;
+
+/// Color adjustments requested through tile query parameters, after CSS
+/// filters: `?grayscale=1&invert=1&brightness=0.8&contrast=1.2&saturation=0.5&hue_rotate=180`.
+/// Factors are 1 for no change and are rounded to two decimals, so
+/// near-identical requests share a cached variant.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Adjustments {
+    #[serde(default, deserialize_with = "flag")]
+    pub grayscale: bool,
+    #[serde(default, deserialize_with = "flag")]
+    pub invert: bool,
+    pub brightness: Option<f32>,
+    pub contrast: Option<f32>,
+    pub saturation: Option<f32>,
+    /// Degrees around the color wheel
+    pub hue_rotate: Option<i32>,
+}
+
+/// `1` or `true`
+fn flag<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<bool, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    Ok(value == "1" || value.eq_ignore_ascii_case("true"))
+}
+
+impl Adjustments {
+    pub fn is_empty(&self) -> bool {
+        self.id().is_empty()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let factors = [self.brightness, self.contrast, self.saturation];
+        if factors.into_iter().flatten().any(|f| !(0.0..=MAX_FACTOR).contains(&f)) {
+            return Err(AppError::BadRequest(format!(
+                "brightness, contrast and saturation must be between 0 and {}",
+                MAX_FACTOR
+            )));
+        }
+        if self.hue_rotate.is_some_and(|degrees| !(-360..=360).contains(&degrees)) {
+            return Err(AppError::BadRequest("hue_rotate must be between -360 and 360".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Canonical name of the variant, empty when nothing changes, e.g.
+    /// `grayscale-b0.8`
+    pub fn id(&self) -> String {
+        let factor = |name: &str, value: Option<f32>| {
+            value
+                .map(round)
+                .filter(|&v| v != 1.0)
+                .map(|v| format!("{}{}", name, v))
+        };
+        [
+            self.grayscale.then(|| "grayscale".to_string()),
+            factor("s", self.saturation),
+            self.hue_rotate.filter(|&d| d % 360 != 0).map(|d| format!("h{}", d)),
+            self.invert.then(|| "invert".to_string()),
+            factor("b", self.brightness),
+            factor("c", self.contrast),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join("-")
+    }
+}
+
+fn round(factor: f32) -> f32 {
+    (factor * 100.0).round() / 100.0
+}
+
+/// Apply the adjustments to a raster tile, in the order grayscale,
+/// saturation, hue rotation, inversion, brightness, contrast
+pub fn apply(tile: &TileData, adjustments: &Adjustments, format: TileFormat) -> ImageResult<TileData> {
+    let mut image = image::load_from_memory(&tile.data)?;
+    if adjustments.grayscale {
+        image = DynamicImage::ImageLumaA8(image.to_luma_alpha8());
+    }
+    let mut image = image.to_rgba8();
+    if let Some(saturation) = adjustments.saturation.map(round) {
+        for Rgba([r, g, b, _]) in image.pixels_mut() {
+            let luma = 0.2126 * f32::from(*r) + 0.7152 * f32::from(*g) + 0.0722 * f32::from(*b);
+            for channel in [r, g, b] {
+                *channel = to_channel(luma + (f32::from(*channel) - luma) * saturation);
+            }
+        }
+    }
+    if let Some(degrees) = adjustments.hue_rotate {
+        image = DynamicImage::ImageRgba8(image).huerotate(degrees).to_rgba8();
+    }
+    let brightness = adjustments.brightness.map(round).unwrap_or(1.0);
+    let contrast = adjustments.contrast.map(round).unwrap_or(1.0);
+    for Rgba([r, g, b, _]) in image.pixels_mut() {
+        for channel in [r, g, b] {
+            let mut value = f32::from(*channel);
+            if adjustments.invert {
+                value = 255.0 - value;
+            }
+            value *= brightness;
+            value = (value - 127.5) * contrast + 127.5;
+            *channel = to_channel(value);
+        }
+    }
+
+    let out = encode(image, format)?;
+
+    let mut adjusted = TileData::new(Bytes::from(out), None);
+    adjusted.content_type = Some(format.content_type().to_string());
+    adjusted.last_modified = tile.last_modified.clone();
+    adjusted.max_age = tile.max_age;
+    adjusted.fetched_at = tile.fetched_at;
+    adjusted.upstream_headers = tile.upstream_headers.clone();
+    adjusted.ensure_etag();
+    Ok(adjusted)
+}
+
+fn to_channel(value: f32) -> u8 {
+    value.round().clamp(0.0, 255.0) as u8
+}
@@ -0,0 +1,50 @@
+use crate::processing::encode;
+use crate::types::{TileData, TileFormat};
+use bytes::Bytes;
+use image::imageops::{self, FilterType};
+use image::{ImageError, ImageResult, RgbaImage};
+use std::sync::Arc;
+
+/// Alpha-blend raster tiles over one another, the first at the bottom. Layers
+/// of another size are scaled to the bottom one's.
+pub fn blend(layers: &[Arc<TileData>], format: TileFormat) -> ImageResult<TileData> {
+    let Some((bottom, overlays)) = layers.split_first() else {
+        return Err(ImageError::Limits(image::error::LimitError::from_kind(
+            image::error::LimitErrorKind::DimensionError,
+        )));
+    };
+    let mut canvas = image::load_from_memory(&bottom.data)?.to_rgba8();
+    for overlay in overlays {
+        let mut image = image::load_from_memory(&overlay.data)?.to_rgba8();
+        if image.dimensions() != canvas.dimensions() {
+            image = imageops::resize(&image, canvas.width(), canvas.height(), FilterType::Triangle);
+        }
+        imageops::overlay(&mut canvas, &image, 0, 0);
+    }
+
+    let out = encode(canvas, format)?;
+
+    let mut tile = TileData::new(Bytes::from(out), None);
+    tile.content_type = Some(format.content_type().to_string());
+    Ok(tile)
+}
+
+/// Draw raster tiles `tile_size` pixels across onto a transparent `width` x
+/// `height` canvas, each at its pixel offset, which may lie partly outside it,
+/// and encode the result as PNG
+pub fn stitch(
+    tiles: &[(i64, i64, Bytes)],
+    width: u32,
+    height: u32,
+    tile_size: u32,
+) -> ImageResult<Vec<u8>> {
+    let mut canvas = RgbaImage::new(width, height);
+    for (left, top, data) in tiles {
+        let mut image = image::load_from_memory(data)?.to_rgba8();
+        if image.dimensions() != (tile_size, tile_size) {
+            image = imageops::resize(&image, tile_size, tile_size, FilterType::Triangle);
+        }
+        imageops::overlay(&mut canvas, &image, *left, *top);
+    }
+    encode(canvas, TileFormat::Png)
+}
@@ -0,0 +1,32 @@
+use crate::processing::encode;
+use crate::types::{TileData, TileFormat};
+use bytes::Bytes;
+use image::error::{LimitError, LimitErrorKind};
+use image::{ImageError, ImageResult};
+
+/// Cut a metatile image `count` tiles across into its tiles, row by row,
+/// each encoded in `format` and carrying the metatile's upstream metadata
+pub fn split(metatile: &TileData, count: u32, format: TileFormat) -> ImageResult<Vec<TileData>> {
+    let image = image::load_from_memory(&metatile.data)?;
+    let (width, height) = (image.width() / count, image.height() / count);
+    if width == 0 || height == 0 {
+        return Err(ImageError::Limits(LimitError::from_kind(
+            LimitErrorKind::DimensionError,
+        )));
+    }
+
+    let mut tiles = Vec::with_capacity((count * count) as usize);
+    for row in 0..count {
+        for column in 0..count {
+            let region = image.crop_imm(column * width, row * height, width, height);
+            let mut tile = TileData::new(Bytes::from(encode(region.to_rgba8(), format)?), None);
+            tile.content_type = Some(format.content_type().to_string());
+            tile.last_modified = metatile.last_modified.clone();
+            tile.max_age = metatile.max_age;
+            tile.fetched_at = metatile.fetched_at;
+            tile.upstream_headers = metatile.upstream_headers.clone();
+            tiles.push(tile);
+        }
+    }
+    Ok(tiles)
+}
@@ -0,0 +1,46 @@
+use crate::types::TileFormat;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::{DynamicImage, ImageFormat, ImageResult, RgbaImage};
+
+pub mod adjust;
+pub mod composite;
+pub mod metatile;
+pub mod optimize;
+pub mod resample;
+pub mod terrain;
+pub mod transcode;
+pub mod watermark;
+
+/// Encode a raster tile in `format`, PNG standing in for non-image formats
+pub(crate) fn encode(image: RgbaImage, format: TileFormat) -> ImageResult<Vec<u8>> {
+    let mut out = Vec::new();
+    match format {
+        TileFormat::Jpeg => DynamicImage::ImageRgba8(image)
+            .to_rgb8()
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut out, 90))?,
+        TileFormat::Webp => image.write_with_encoder(WebPEncoder::new_lossless(&mut out))?,
+        TileFormat::Png | TileFormat::Mvt | TileFormat::Grid => {
+            image.write_with_encoder(PngEncoder::new(&mut out))?
+        }
+    }
+    Ok(out)
+}
+
+/// Format of encoded raster data, PNG when unrecognized
+pub(crate) fn raster_format(data: &[u8]) -> TileFormat {
+    match image::guess_format(data) {
+        Ok(ImageFormat::Jpeg) => TileFormat::Jpeg,
+        Ok(ImageFormat::WebP) => TileFormat::Webp,
+        _ => TileFormat::Png,
+    }
+}
+
+/// Derive a distinct validator for a variant of a tile
+fn variant_etag(etag: &str, variant: &str) -> String {
+    match etag.strip_suffix('"') {
+        Some(opaque) => format!("{}-{}\"", opaque, variant),
+        None => format!("{}-{}", etag, variant),
+    }
+}
@@ -0,0 +1,41 @@
+use color_quant::NeuQuant;
+use oxipng::{BitDepth, ColorType, Options, PngError, PngResult, RawImage};
+
+/// How hard to work at shrinking stored PNG tiles
+#[derive(Debug, Clone, Copy)]
+pub struct PngOptimization {
+    /// oxipng preset, 0 (fast) to 6 (smallest)
+    pub level: u8,
+    /// Reduce tiles to this many colors first, losing detail; 0 keeps them
+    /// pixel for pixel
+    pub colors: u16,
+}
+
+/// Recompress a PNG tile, None when that doesn't make it smaller
+pub fn optimize(data: &[u8], optimization: PngOptimization) -> PngResult<Option<Vec<u8>>> {
+    let options = Options::from_preset(optimization.level.min(6));
+    let out = if optimization.colors == 0 {
+        oxipng::optimize_from_memory(data, &options)?
+    } else {
+        let image = image::load_from_memory(data)
+            .map_err(|e| PngError::new(&e.to_string()))?
+            .to_rgba8();
+        let (width, height) = image.dimensions();
+        let pixels = quantize(image.into_raw(), usize::from(optimization.colors.min(256)));
+        // Reduced to a palette by oxipng once no more than 256 colors remain
+        RawImage::new(width, height, ColorType::RGBA, BitDepth::Eight, pixels)?
+            .create_optimized_png(&options)?
+    };
+    Ok((out.len() < data.len()).then_some(out))
+}
+
+/// Replace each RGBA pixel by the closest of `colors` representative colors
+fn quantize(mut pixels: Vec<u8>, colors: usize) -> Vec<u8> {
+    let quantizer = NeuQuant::new(10, colors, &pixels);
+    let palette = quantizer.color_map_rgba();
+    for pixel in pixels.chunks_exact_mut(4) {
+        let index = quantizer.index_of(pixel);
+        pixel.copy_from_slice(&palette[index * 4..index * 4 + 4]);
+    }
+    pixels
+}
@@ -0,0 +1,35 @@
+use crate::processing::encode;
+use crate::types::{TileData, TileKey};
+use bytes::Bytes;
+use image::imageops::{self, FilterType};
+use image::{ImageError, ImageResult};
+
+/// Build `key`'s tile by cropping the matching region of an ancestor tile
+/// `levels` zooms up and scaling it back to full size
+pub fn from_ancestor(ancestor: &TileData, levels: u8, key: &TileKey) -> ImageResult<TileData> {
+    let image = image::load_from_memory(&ancestor.data)?;
+    let (width, height) = (image.width(), image.height());
+    let (crop_width, crop_height) = (width >> levels, height >> levels);
+    if crop_width == 0 || crop_height == 0 {
+        return Err(ImageError::Limits(image::error::LimitError::from_kind(
+            image::error::LimitErrorKind::DimensionError,
+        )));
+    }
+
+    // Position of the tile within its ancestor, in descendant tiles
+    let mask = (1u32 << levels) - 1;
+    let region = image.crop_imm(
+        (key.x & mask) * crop_width,
+        (key.y & mask) * crop_height,
+        crop_width,
+        crop_height,
+    );
+    let scaled = imageops::resize(&region, width, height, FilterType::Triangle);
+
+    let out = encode(scaled, key.format)?;
+
+    let mut tile = TileData::new(Bytes::from(out), None);
+    tile.content_type = Some(key.format.content_type().to_string());
+    tile.synthesized = true;
+    Ok(tile)
+}
@@ -0,0 +1,42 @@
+use image::{ImageResult, Rgba};
+use serde::Deserialize;
+
+/// How elevations are packed into the color channels of terrain tiles
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TerrainEncoding {
+    /// Mapbox Terrain-RGB: tenths of a meter above -10000 m
+    #[default]
+    Mapbox,
+    /// Mapzen Terrarium: meters above -32768 m, with 1/256 m in blue
+    Terrarium,
+}
+
+impl TerrainEncoding {
+    /// Elevation in meters of a pixel
+    pub fn decode(self, Rgba([r, g, b, _]): Rgba<u8>) -> f64 {
+        let (r, g, b) = (f64::from(r), f64::from(g), f64::from(b));
+        match self {
+            TerrainEncoding::Mapbox => -10000.0 + (r * 65536.0 + g * 256.0 + b) * 0.1,
+            TerrainEncoding::Terrarium => r * 256.0 + g + b / 256.0 - 32768.0,
+        }
+    }
+}
+
+/// Elevation at a point of a terrain tile, given as fractions of its width
+/// and height, interpolated between the four nearest pixels
+pub fn elevation_at(data: &[u8], encoding: TerrainEncoding, fx: f64, fy: f64) -> ImageResult<f64> {
+    let image = image::load_from_memory(data)?.to_rgba8();
+    let (width, height) = image.dimensions();
+    // Pixel centers sit half a pixel in
+    let px = (fx * f64::from(width) - 0.5).clamp(0.0, f64::from(width - 1));
+    let py = (fy * f64::from(height) - 0.5).clamp(0.0, f64::from(height - 1));
+    let (x0, y0) = (px.floor() as u32, py.floor() as u32);
+    let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+    let (tx, ty) = (px - f64::from(x0), py - f64::from(y0));
+
+    let at = |x, y| encoding.decode(*image.get_pixel(x, y));
+    let top = at(x0, y0) * (1.0 - tx) + at(x1, y0) * tx;
+    let bottom = at(x0, y1) * (1.0 - tx) + at(x1, y1) * tx;
+    Ok(top * (1.0 - ty) + bottom * ty)
+}
@@ -0,0 +1,22 @@
+use crate::processing::variant_etag;
+use crate::types::TileData;
+use bytes::Bytes;
+use image::codecs::webp::WebPEncoder;
+use image::ImageResult;
+
+/// Re-encode a raster tile as lossless WebP
+pub fn to_webp(tile: &TileData) -> ImageResult<TileData> {
+    let image = image::load_from_memory(&tile.data)?;
+
+    let mut out = Vec::new();
+    image.write_with_encoder(WebPEncoder::new_lossless(&mut out))?;
+
+    let etag = tile.etag.as_deref().map(|e| variant_etag(e, "webp"));
+    let mut webp = TileData::new(Bytes::from(out), etag);
+    webp.content_type = Some("image/webp".to_string());
+    webp.last_modified = tile.last_modified.clone();
+    webp.max_age = tile.max_age;
+    webp.fetched_at = tile.fetched_at;
+    webp.upstream_headers = tile.upstream_headers.clone();
+    Ok(webp)
+}
@@ -0,0 +1,196 @@
+use crate::config::Config;
+use crate::processing::{encode, raster_format, variant_etag};
+use crate::types::TileData;
+use ab_glyph::{point, Font, FontVec, PxScale, ScaleFont};
+use anyhow::Context;
+use bytes::Bytes;
+use image::imageops;
+use image::{ImageResult, Rgba, RgbaImage};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::str::FromStr;
+
+/// Gap between the stamp and the tile's edges, and around the text
+const MARGIN: u32 = 2;
+
+/// Corner of the tile the watermark is stamped in
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    #[default]
+    BottomRight,
+}
+
+impl FromStr for WatermarkPosition {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "top_left" => Ok(Self::TopLeft),
+            "top_right" => Ok(Self::TopRight),
+            "bottom_left" => Ok(Self::BottomLeft),
+            "bottom_right" => Ok(Self::BottomRight),
+            _ => Err(format!("unknown watermark position {:?}", s)),
+        }
+    }
+}
+
+/// Attribution text and/or logo stamped onto raster tiles, rendered once
+pub struct Watermark {
+    stamp: RgbaImage,
+    position: WatermarkPosition,
+    /// Identifies the stamp, so tiles stamped with an earlier one aren't reused
+    fingerprint: String,
+}
+
+impl Watermark {
+    /// The configured watermark, if any
+    pub fn from_config(config: &Config) -> anyhow::Result<Option<Self>> {
+        let logo = match &config.watermark_image {
+            Some(path) => Some(
+                image::open(path)
+                    .with_context(|| format!("Failed to read watermark image {:?}", path))?
+                    .to_rgba8(),
+            ),
+            None => None,
+        };
+        let text = match &config.watermark_text {
+            Some(text) if !text.is_empty() => {
+                let path = config
+                    .watermark_font
+                    .as_ref()
+                    .context("watermark_text needs a watermark_font to render it with")?;
+                let data = std::fs::read(path)
+                    .with_context(|| format!("Failed to read watermark font {:?}", path))?;
+                let font = FontVec::try_from_vec(data)
+                    .with_context(|| format!("Invalid watermark font {:?}", path))?;
+                Some(render_text(&font, text, config.watermark_font_size))
+            }
+            _ => None,
+        };
+        let mut stamp = match (logo, text) {
+            (Some(logo), Some(text)) => side_by_side(&logo, &text),
+            (Some(stamp), None) | (None, Some(stamp)) => stamp,
+            (None, None) => return Ok(None),
+        };
+        let opacity = config.watermark_opacity.clamp(0.0, 1.0);
+        for Rgba([_, _, _, alpha]) in stamp.pixels_mut() {
+            *alpha = (f32::from(*alpha) * opacity).round() as u8;
+        }
+
+        let mut hasher = Sha256::new();
+        hasher.update(stamp.width().to_be_bytes());
+        hasher.update(format!("{:?}", config.watermark_position));
+        hasher.update(stamp.as_raw());
+        let fingerprint = hasher.finalize()[..4].iter().map(|b| format!("{:02x}", b)).collect();
+        Ok(Some(Self {
+            stamp,
+            position: config.watermark_position,
+            fingerprint,
+        }))
+    }
+
+    /// Short hash of the stamp and its placement
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    /// Validator of a tile's stamped copy
+    pub fn etag(&self, etag: &str) -> String {
+        variant_etag(etag, &format!("wm{}", self.fingerprint))
+    }
+
+    /// Stamp a raster tile, keeping its format
+    pub fn apply(&self, tile: &TileData) -> ImageResult<TileData> {
+        let format = raster_format(&tile.data);
+        let mut image = image::load_from_memory(&tile.data)?.to_rgba8();
+        let (x, y) = self.origin(image.width(), image.height());
+        imageops::overlay(&mut image, &self.stamp, x, y);
+
+        let out = encode(image, format)?;
+
+        let etag = tile.etag.as_deref().map(|e| self.etag(e));
+        let mut stamped = TileData::new(Bytes::from(out), etag);
+        stamped.content_type = Some(format.content_type().to_string());
+        stamped.last_modified = tile.last_modified.clone();
+        stamped.max_age = tile.max_age;
+        stamped.fetched_at = tile.fetched_at;
+        stamped.upstream_headers = tile.upstream_headers.clone();
+        stamped.synthesized = tile.synthesized;
+        Ok(stamped)
+    }
+
+    /// Top-left corner of the stamp on a tile of this size
+    fn origin(&self, width: u32, height: u32) -> (i64, i64) {
+        let left = i64::from(MARGIN);
+        let top = i64::from(MARGIN);
+        let right = i64::from(width) - i64::from(self.stamp.width() + MARGIN);
+        let bottom = i64::from(height) - i64::from(self.stamp.height() + MARGIN);
+        match self.position {
+            WatermarkPosition::TopLeft => (left, top),
+            WatermarkPosition::TopRight => (right, top),
+            WatermarkPosition::BottomLeft => (left, bottom),
+            WatermarkPosition::BottomRight => (right, bottom),
+        }
+    }
+}
+
+/// Dark text on a light translucent box, after web map attribution controls
+fn render_text(font: &FontVec, text: &str, size: f32) -> RgbaImage {
+    let font = font.as_scaled(PxScale::from(size));
+    let pad = MARGIN as f32;
+    let mut glyphs = Vec::new();
+    let mut caret = pad;
+    let mut previous = None;
+    for c in text.chars() {
+        let id = font.glyph_id(c);
+        if let Some(previous) = previous {
+            caret += font.kern(previous, id);
+        }
+        glyphs.push(id.with_scale_and_position(size, point(caret, pad + font.ascent())));
+        caret += font.h_advance(id);
+        previous = Some(id);
+    }
+
+    let width = (caret + pad).ceil() as u32;
+    let height = (font.height() + 2.0 * pad).ceil() as u32;
+    let mut stamp = RgbaImage::from_pixel(width, height, Rgba([255, 255, 255, 180]));
+    for glyph in glyphs {
+        let Some(outline) = font.outline_glyph(glyph) else {
+            continue;
+        };
+        let bounds = outline.px_bounds();
+        outline.draw(|x, y, coverage| {
+            let x = bounds.min.x as i64 + i64::from(x);
+            let y = bounds.min.y as i64 + i64::from(y);
+            let (Ok(x), Ok(y)) = (u32::try_from(x), u32::try_from(y)) else {
+                return;
+            };
+            if let Some(pixel) = stamp.get_pixel_mut_checked(x, y) {
+                let coverage = coverage.clamp(0.0, 1.0);
+                for channel in &mut pixel.0[..3] {
+                    *channel = (f32::from(*channel) * (1.0 - coverage) + 51.0 * coverage) as u8;
+                }
+                pixel.0[3] = pixel.0[3].max((coverage * 255.0) as u8);
+            }
+        });
+    }
+    stamp
+}
+
+/// The logo with the text to its right, both vertically centered
+fn side_by_side(logo: &RgbaImage, text: &RgbaImage) -> RgbaImage {
+    let height = logo.height().max(text.height());
+    let mut stamp = RgbaImage::new(logo.width() + text.width(), height);
+    imageops::overlay(&mut stamp, logo, 0, i64::from((height - logo.height()) / 2));
+    imageops::overlay(
+        &mut stamp,
+        text,
+        i64::from(logo.width()),
+        i64::from((height - text.height()) / 2),
+    );
+    stamp
+}
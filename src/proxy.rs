@@ -0,0 +1,650 @@
+use crate::api_keys::{require_api_key, ApiKeys};
+use crate::cache::{
+    snapshot, DiskCache, DiskUsage, DiskWriter, Evictor, MemoryCache, MemorySnapshot, NegativeCache,
+    PngOptimizer, RedisCache, RequestCoalescer, S3Store, TierChain, TileStore,
+};
+use crate::client_limit::{limit_clients, ClientRateLimiter};
+use crate::composite::CompositeLayer;
+use crate::config::{CachePolicy, Config};
+use crate::error::{AppError, Result};
+use crate::forwarded::{resolve_client_ip, TrustedProxies};
+use crate::geo::TileRange;
+use crate::grpc;
+use crate::handlers::admin::{
+    export_tiles, purge_tiles, ExportRequest, ExportResponse, PurgeResult,
+};
+use crate::handlers::{
+    delete_tile, get_elevation, get_generation, get_healthz, get_job, get_job_events, get_jobs,
+    get_layer_tile, get_metrics, get_offline, get_popular, get_popular_heatmap, get_preview,
+    get_readyz, get_static_map, get_stats, get_tile, get_tile_at, get_tile_info,
+    get_wmts_capabilities, get_wmts_kvp, get_wmts_tile, head_tile, post_batch, post_export,
+    post_generation, post_preload, post_reload, post_seed, post_warm, purge_range, put_offline,
+    require_admin_token, AppState,
+};
+use crate::logging;
+use crate::metrics::Metrics;
+use crate::popularity::Popularity;
+use crate::prefetch::{self, Prefetcher};
+use crate::preload;
+use crate::processing::optimize::PngOptimization;
+use crate::processing::watermark::Watermark;
+use crate::referer::require_allowed_referer;
+use crate::reload;
+use crate::revalidation;
+use crate::seed::{self, JobManager, JobStatus, SeedRequest};
+use crate::terrain::TerrainLayer;
+use crate::types::{TileData, TileFormat};
+use crate::upstream::{MbtilesSource, MetatileFetcher, OsmFetcher, PmtilesSource};
+use arc_swap::{ArcSwap, ArcSwapOption};
+use axum::error_handling::HandleErrorLayer;
+use axum::http::{header, HeaderValue};
+use axum::middleware;
+use axum::response::Response;
+use axum::routing::{delete, get, post};
+use axum::{BoxError, Router};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
+use tower::limit::GlobalConcurrencyLimitLayer;
+use tower::ServiceBuilder;
+use tower_http::compression::predicate::{DefaultPredicate, NotForContentType, Predicate};
+use tower_http::compression::CompressionLayer;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+use tower_http::trace::TraceLayer;
+
+/// The caching stack (memory and disk caches, upstream fetcher, archives) and
+/// the routes serving it
+pub struct TileProxy {
+    state: Arc<AppState>,
+}
+
+/// Options for [`TileProxy`]
+#[derive(Default)]
+pub struct TileProxyBuilder {
+    config: Option<Config>,
+    config_path: Option<PathBuf>,
+    reload_on_sighup: bool,
+    resume_seed_jobs: bool,
+}
+
+impl TileProxy {
+    pub fn builder() -> TileProxyBuilder {
+        TileProxyBuilder::default()
+    }
+
+    /// Settings the proxy was built with
+    pub fn config(&self) -> Arc<Config> {
+        self.state.config.load_full()
+    }
+
+    /// Tile, WMTS, health and metrics routes, plus the admin routes unless
+    /// `admin_bind_addr` puts them on their own listener. Nest it under a path
+    /// with [`Router::nest`]; set `public_url` to match so WMTS documents link
+    /// back correctly.
+    pub fn router(&self) -> Router {
+        let state = &self.state;
+        let config = state.config.load();
+
+        // Tile routes count against API keys when those are configured, and are
+        // refused to web pages outside `allowed_referers`
+        let mut tiles = Router::new()
+            .route("/wmts", get(get_wmts_kvp))
+            .route("/wmts/1.0.0/WMTSCapabilities.xml", get(get_wmts_capabilities))
+            .route(
+                "/wmts/1.0.0/{layer}/default/{matrix_set}/{z}/{x}/{filename}",
+                get(get_wmts_tile),
+            )
+            // HEAD is answered from the cached copy's metadata; the other `get`
+            // routes answer it through their GET handler, dropping the body
+            .route("/{z}/{x}/{filename}", get(get_tile).head(head_tile))
+            .route("/at/{z}/{lat}/{filename}", get(get_tile_at))
+            .route("/layers/{name}/{z}/{x}/{filename}", get(get_layer_tile))
+            // Beside the tile routes, which take a file name as their third segment
+            .route("/{layer}/elevation/{z}/{x}/{y}", get(get_elevation))
+            .route_layer(middleware::from_fn_with_state(state.clone(), require_api_key))
+            // These check API keys themselves, counting every tile they draw on
+            .route("/tiles/batch", post(post_batch))
+            .route("/staticmap", get(get_static_map))
+            .route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                require_allowed_referer,
+            ));
+        if !config.request_timeout.is_zero() {
+            // Dropping a timed-out handler releases its coalescer slot, and
+            // requests waiting on its fetch take it over
+            let state = state.clone();
+            tiles = tiles.route_layer(
+                ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(move |_: BoxError| {
+                        let state = state.clone();
+                        async move { timed_out(&state) }
+                    }))
+                    .timeout(config.request_timeout),
+            );
+        }
+        let mut app = Router::new()
+            .route("/healthz", get(get_healthz))
+            .route("/readyz", get(get_readyz))
+            .route("/metrics", get(get_metrics))
+            .route("/preview/{layer}", get(get_preview))
+            .merge(tiles);
+        if config.admin_bind_addr.is_none() {
+            app = app.merge(self.admin_routes());
+        }
+        if config.max_concurrent_requests > 0 {
+            // One semaphore shared by every route; requests beyond it are turned away
+            let state = state.clone();
+            let retry_after = config.overload_retry_after.as_secs();
+            app = app.layer(
+                ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(move |_: BoxError| {
+                        let state = state.clone();
+                        async move { overloaded(&state, retry_after) }
+                    }))
+                    .load_shed()
+                    .layer(GlobalConcurrencyLimitLayer::new(config.max_concurrent_requests)),
+            );
+        }
+        let app = with_compression(app, &config)
+            .layer(middleware::from_fn_with_state(state.clone(), limit_clients))
+            .layer(CorsLayer::new()
+                .allow_origin(cors_origin(state.clone()))
+                .allow_methods(Any)
+                .allow_headers(Any))
+            .layer(TraceLayer::new_for_http());
+        with_access_log(app, &config, state).with_state(state.clone())
+    }
+
+    /// Admin routes, when `admin_bind_addr` keeps them off the public router
+    pub fn admin_router(&self) -> Option<Router> {
+        let config = self.state.config.load();
+        config.admin_bind_addr.as_ref()?;
+        let admin =
+            with_compression(self.admin_routes(), &config).layer(TraceLayer::new_for_http());
+        Some(with_access_log(admin, &config, &self.state).with_state(self.state.clone()))
+    }
+
+    /// gRPC tile service, when `grpc_bind_addr` is set
+    pub fn grpc_router(&self) -> Option<Router> {
+        self.state.config.load().grpc_bind_addr.as_ref()?;
+        Some(grpc::router(self.state.clone()).layer(TraceLayer::new_for_http()))
+    }
+
+    fn admin_routes(&self) -> Router<Arc<AppState>> {
+        Router::new()
+            .route("/admin/seed", post(post_seed))
+            .route("/admin/jobs", get(get_jobs))
+            .route("/admin/jobs/{id}", get(get_job))
+            .route("/admin/jobs/{id}/events", get(get_job_events))
+            .route("/admin/tiles/{z}/{x}/{y}", delete(delete_tile))
+            .route("/admin/tiles/{z}/{x}/{y}/info", get(get_tile_info))
+            .route("/admin/purge", post(purge_range))
+            .route("/admin/export", post(post_export))
+            .route("/admin/preload", post(post_preload))
+            .route("/admin/warm", post(post_warm))
+            .route("/admin/stats", get(get_stats))
+            .route("/admin/popular", get(get_popular))
+            .route("/admin/popular.geojson", get(get_popular_heatmap))
+            .route("/admin/offline", get(get_offline).put(put_offline))
+            .route("/admin/generation", get(get_generation).post(post_generation))
+            .route("/admin/reload", post(post_reload))
+            .route_layer(middleware::from_fn_with_state(
+                self.state.clone(),
+                require_admin_token,
+            ))
+    }
+
+    /// Fetch every tile of the request that isn't on disk yet, returning once
+    /// all have been tried
+    pub async fn seed(&self, request: SeedRequest) -> Result<JobStatus> {
+        request.validate()?;
+        let job = self.state.jobs.create(request, self.state.seed_concurrency);
+        seed::run_seed(self.state.clone(), job.clone()).await;
+        Ok(job.status())
+    }
+
+    /// Remove every tile of a range from the caches, in every format and scale
+    pub async fn purge(&self, range: &TileRange) -> Result<PurgeResult> {
+        purge_tiles(&self.state, range).await
+    }
+
+    /// Package cached tiles into an MBTiles file in the export directory
+    pub async fn export_mbtiles(&self, request: ExportRequest) -> Result<ExportResponse> {
+        export_tiles(&self.state, request).await
+    }
+
+    /// Size of the disk cache, walking every tile unless a sweep has indexed it
+    pub async fn disk_usage(&self) -> DiskUsage {
+        let disk_cache = self.state.disk_cache.clone();
+        tokio::task::spawn_blocking(move || disk_cache.usage())
+            .await
+            .expect("disk usage task panicked")
+    }
+
+    /// Re-read the config, as on SIGHUP or `POST /admin/reload`
+    pub fn reload(&self) -> anyhow::Result<()> {
+        reload::reload(&self.state)
+    }
+
+    /// Wait up to `timeout` each for upstream fetches still in flight and
+    /// queued disk writes, once the routers have stopped taking requests
+    pub async fn shutdown(&self, timeout: Duration) {
+        let state = &self.state;
+        // Background revalidations and seed fetches still hold coalescer slots
+        let in_flight = state.coalescer.in_flight();
+        if in_flight > 0 {
+            tracing::info!(in_flight, "Waiting for upstream fetches to finish");
+            if !state.coalescer.drain(timeout).await {
+                tracing::warn!(
+                    in_flight = state.coalescer.in_flight(),
+                    "Shutting down with upstream fetches in flight"
+                );
+            }
+        }
+        // Running seed jobs pick up from here on the next start
+        state.jobs.save_checkpoints().await;
+        if !state.tiers.flush(timeout).await {
+            tracing::warn!("Shutting down with cache writes pending");
+        }
+        let config = state.config.load();
+        if config.memory_warm_start {
+            let snapshot = MemorySnapshot::new(&config.cache_dir);
+            match snapshot.save(&state.memory_cache, state.disk_cache.generation()) {
+                Ok(saved) => tracing::info!(saved, "Saved memory cache snapshot"),
+                Err(e) => tracing::warn!(error = %e, "Failed to save memory cache snapshot"),
+            }
+        }
+    }
+}
+
+impl TileProxyBuilder {
+    /// Settings to use, `Config::default()` (environment variables) when unset
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// File the config came from, re-read on reload
+    pub fn config_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config_path = Some(path.into());
+        self
+    }
+
+    /// Reload the config whenever the process receives SIGHUP
+    pub fn reload_on_sighup(mut self, enabled: bool) -> Self {
+        self.reload_on_sighup = enabled;
+        self
+    }
+
+    /// Save the progress of seed jobs under the cache directory, and carry on
+    /// with those an earlier run left unfinished
+    pub fn resume_seed_jobs(mut self, enabled: bool) -> Self {
+        self.resume_seed_jobs = enabled;
+        self
+    }
+
+    /// Open the caches and archives and start background maintenance tasks;
+    /// must be called within a Tokio runtime
+    pub async fn build(self) -> anyhow::Result<TileProxy> {
+        let config = match (self.config, &self.config_path) {
+            (Some(config), _) => config,
+            (None, Some(path)) => Config::load(path)?,
+            (None, None) => Config::default(),
+        };
+
+        if config.bounds.is_some_and(|bounds| !bounds.is_valid()) {
+            anyhow::bail!("Invalid bounds {:?}", config.bounds);
+        }
+
+        let memory_cache = MemoryCache::new(&config);
+        let disk_cache = DiskCache::new(&config)?;
+        let negative_cache = NegativeCache::new(
+            config.memory_cache_size,
+            config.negative_cache_ttl,
+            config.negative_cache_disk.then(|| disk_cache.clone()),
+        );
+        let coalescer = RequestCoalescer::new();
+        let fetcher = OsmFetcher::new(&config)?;
+        let metrics = Metrics::new(&config.metrics_zoom_buckets)?;
+        let popularity = Popularity::from_config(&config).map(Arc::new);
+        let evictor = config.disk_eviction_policy.evictor(popularity.as_ref())?;
+        let png_optimizer = config.png_optimization.then(|| {
+            PngOptimizer::new(
+                disk_cache.clone(),
+                PngOptimization {
+                    level: config.png_optimization_level,
+                    colors: config.png_quantize_colors,
+                },
+                config.png_optimization_queue_size,
+                metrics.png_optimizations.clone(),
+                metrics.png_bytes_saved.clone(),
+            )
+        });
+        let disk_writer = DiskWriter::new(
+            disk_cache.clone(),
+            config.disk_write_queue_size,
+            metrics.disk_writes_dropped.clone(),
+            png_optimizer,
+        );
+        let tiers = build_tiers(&config, &memory_cache, disk_writer, &fetcher).await?;
+        let (prefetcher, prefetch_queue) = Prefetcher::new(config.prefetch_queue_size);
+        let composite_layers = CompositeLayer::from_config(&config, &disk_cache)?;
+        let terrain_layers = TerrainLayer::from_config(&config, &disk_cache)?;
+        let adjusted_cache = disk_cache.subdirectory(ADJUSTED_DIR)?;
+        let watermarked_cache = disk_cache.subdirectory(WATERMARKED_DIR)?;
+        let watermark = Watermark::from_config(&config)?.map(Arc::new);
+        let fallback_tile = match &config.fallback_tile {
+            Some(path) => Some(Arc::new(load_fallback_tile(path)?)),
+            None => None,
+        };
+
+        let state = Arc::new(AppState {
+            memory_cache,
+            disk_cache,
+            tiers,
+            negative_cache,
+            coalescer,
+            coalescer_wait_timeout: config.coalescer_wait_timeout,
+            coalescer_takeover: config.coalescer_takeover,
+            fetcher: ArcSwap::from_pointee(fetcher),
+            metatiles: MetatileFetcher::new(&config)?,
+            metatile_coalescer: RequestCoalescer::new(),
+            derived_coalescer: RequestCoalescer::new(),
+            adjusted_cache,
+            watermark,
+            watermarked_cache,
+            composite_layers,
+            terrain_layers,
+            cache_policy: ArcSwap::from_pointee(CachePolicy::new(&config)),
+            metrics,
+            jobs: JobManager::new(
+                config.seed_upstream_budget,
+                self.resume_seed_jobs.then(|| config.cache_dir.join(seed::CHECKPOINT_DIR)),
+            ),
+            seed_concurrency: config.seed_concurrency,
+            export_dir: config.export_dir.clone(),
+            min_zoom: config.min_zoom,
+            max_zoom: config.max_zoom,
+            bounds: config.bounds,
+            max_native_zoom: config.max_native_zoom,
+            webp_transcoding: config.webp_transcoding,
+            tile_scheme: config.tile_scheme,
+            wmts_layer: config.wmts_layer.clone(),
+            public_url: config.public_url.clone(),
+            offline: AtomicBool::new(config.offline),
+            fallback_tile,
+            fallback_max_age_secs: config.fallback_max_age.as_secs(),
+            parent_fallback_levels: config.parent_fallback_levels,
+            prefetcher: config.prefetch_neighbors.then_some(prefetcher),
+            popularity,
+            admin_token: config.admin_token.clone(),
+            api_keys: ApiKeys::load(&config)?,
+            client_limiter: ArcSwapOption::from_pointee(ClientRateLimiter::from_config(&config)),
+            trusted_proxies: ArcSwap::from_pointee(TrustedProxies::from_config(&config)?),
+            config: ArcSwap::from_pointee(config.clone()),
+            config_path: self.config_path,
+        });
+
+        tokio::spawn(sweep_disk_cache(state.disk_cache.clone(), config.disk_sweep_interval));
+        tokio::spawn(evict_disk_tiles(state.clone(), evictor, config.disk_sweep_interval));
+        for layer in state.composite_layers.values() {
+            tokio::spawn(sweep_disk_cache(layer.cache().clone(), config.disk_sweep_interval));
+        }
+        for layer in state.terrain_layers.values() {
+            tokio::spawn(sweep_disk_cache(layer.cache().clone(), config.disk_sweep_interval));
+        }
+        if state.watermark.is_some() {
+            tokio::spawn(sweep_disk_cache(
+                state.watermarked_cache.clone(),
+                config.disk_sweep_interval,
+            ));
+        }
+        tokio::spawn(sweep_adjusted_tiles(
+            state.adjusted_cache.clone(),
+            config.disk_sweep_interval,
+        ));
+        tokio::spawn(prune_client_limits(state.clone()));
+        if state.popularity.is_some() {
+            tokio::spawn(decay_popularity(state.clone(), config.popularity_half_life));
+        }
+        if !config.preload_paths.is_empty() {
+            tokio::spawn(preload_tiles(state.disk_cache.clone(), config.preload_paths.clone()));
+        }
+        tokio::spawn(revalidation::sweep_stale_tiles(state.clone()));
+        if config.prefetch_neighbors {
+            tokio::spawn(prefetch::run(state.clone(), prefetch_queue, config.prefetch_rate));
+        }
+        if config.memory_warm_start {
+            tokio::spawn(snapshot::warm_start(
+                MemorySnapshot::new(&config.cache_dir),
+                state.memory_cache.clone(),
+                state.disk_cache.clone(),
+                config.memory_snapshot_interval,
+            ));
+        }
+        if self.reload_on_sighup {
+            tokio::spawn(reload::reload_on_sighup(state.clone()));
+        }
+        for job in state.jobs.resume(state.seed_concurrency) {
+            tracing::info!(job = job.id, "Resuming seed job");
+            tokio::spawn(seed::run_seed(state.clone(), job));
+        }
+
+        Ok(TileProxy { state })
+    }
+}
+
+/// Assemble the tiers named in `cache_tiers`, skipping ones with nothing configured
+async fn build_tiers(
+    config: &Config,
+    memory_cache: &MemoryCache,
+    disk_writer: DiskWriter,
+    fetcher: &OsmFetcher,
+) -> anyhow::Result<TierChain> {
+    let mut tiers: Vec<Arc<dyn TileStore>> = Vec::new();
+    for name in &config.cache_tiers {
+        if config.cache_tiers.iter().filter(|other| *other == name).count() > 1 {
+            anyhow::bail!("Cache tier {:?} is listed more than once", name);
+        }
+        match name.as_str() {
+            "memory" => tiers.push(Arc::new(memory_cache.clone())),
+            "disk" => tiers.push(Arc::new(disk_writer.clone())),
+            "mbtiles" if !config.mbtiles_sources.is_empty() => {
+                tiers.push(Arc::new(MbtilesSource::open(&config.mbtiles_sources)?))
+            }
+            "pmtiles" if !config.pmtiles_sources.is_empty() => {
+                let source = PmtilesSource::open(&config.pmtiles_sources, fetcher.client()).await?;
+                tiers.push(Arc::new(source))
+            }
+            "redis" => {
+                if let Some(cache) = RedisCache::connect(config).await? {
+                    tiers.push(Arc::new(cache));
+                }
+            }
+            "s3" => {
+                if let Some(store) = S3Store::new(config)? {
+                    tiers.push(Arc::new(store));
+                }
+            }
+            "mbtiles" | "pmtiles" => {}
+            other => anyhow::bail!("Unknown cache tier {:?}", other),
+        }
+    }
+    let chain = TierChain::new(tiers);
+    tracing::info!(tiers = ?chain.names(), "Cache tiers");
+    Ok(chain)
+}
+
+/// Temp files younger than this may belong to a write still in progress
+const TMP_FILE_MIN_AGE: Duration = Duration::from_secs(60);
+
+/// Cache subdirectory holding a directory of recolored tiles per variant
+const ADJUSTED_DIR: &str = "adjusted";
+
+/// Cache subdirectory holding watermarked copies of served tiles
+const WATERMARKED_DIR: &str = "watermarked";
+
+/// Clean the disk cache at startup and then periodically
+async fn sweep_disk_cache(disk_cache: DiskCache, interval: Duration) {
+    loop {
+        sweep_once(disk_cache.clone()).await;
+
+        if interval.is_zero() {
+            return;
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Trim the disk cache back under `disk_cache_max_bytes` at startup and then
+/// on every sweep interval
+async fn evict_disk_tiles(state: Arc<AppState>, evictor: Arc<dyn Evictor>, interval: Duration) {
+    loop {
+        let max_bytes = state.config.load().disk_cache_max_bytes;
+        if max_bytes > 0 {
+            let cache = state.disk_cache.clone();
+            let evictor = evictor.clone();
+            let summary = tokio::task::spawn_blocking(move || cache.evict(max_bytes, &*evictor))
+                .await
+                .expect("disk eviction task panicked");
+            if summary.tiles > 0 {
+                tracing::info!(tiles = summary.tiles, bytes = summary.bytes, "Evicted disk tiles");
+            }
+        }
+
+        if interval.is_zero() {
+            return;
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Clean each adjusted variant's cache, including ones created since the
+/// last sweep
+async fn sweep_adjusted_tiles(adjusted_cache: DiskCache, interval: Duration) {
+    loop {
+        for cache in adjusted_cache.subdirectories() {
+            sweep_once(cache).await;
+        }
+
+        if interval.is_zero() {
+            return;
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+async fn sweep_once(cache: DiskCache) {
+    let summary = tokio::task::spawn_blocking(move || cache.sweep(TMP_FILE_MIN_AGE))
+        .await
+        .expect("disk sweep task panicked");
+    tracing::info!(
+        tmp_files = summary.tmp_files,
+        invalid_tiles = summary.invalid_tiles,
+        orphaned_blobs = summary.orphaned_blobs,
+        old_generations = summary.old_generations,
+        empty_dirs = summary.empty_dirs,
+        "Swept disk cache"
+    );
+}
+
+/// Ingest the configured MBTiles files and tile directories in the background
+async fn preload_tiles(disk_cache: DiskCache, paths: Vec<PathBuf>) {
+    for path in paths {
+        let cache = disk_cache.clone();
+        let result = tokio::task::spawn_blocking(move || preload::preload(&cache, &path))
+            .await
+            .expect("preload task panicked");
+        if let Err(e) = result {
+            tracing::error!(error = %e, "Failed to pre-load tiles");
+        }
+    }
+}
+
+/// Drop idle per-client rate limit buckets so the table doesn't grow unbounded
+async fn prune_client_limits(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        if let Some(limiter) = &*state.client_limiter.load() {
+            limiter.prune();
+        }
+    }
+}
+
+/// Halve tile request counts every half-life so they reflect recent demand
+async fn decay_popularity(state: Arc<AppState>, half_life: Duration) {
+    let mut interval = tokio::time::interval(half_life);
+    // The first tick completes immediately
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if let Some(popularity) = &state.popularity {
+            popularity.decay();
+        }
+    }
+}
+
+/// Compress responses when enabled, for clients sending a matching
+/// `Accept-Encoding`. Images, event streams and responses with their own
+/// `Content-Encoding` pass through, as do batches, which are mostly images.
+fn with_compression(router: Router<Arc<AppState>>, config: &Config) -> Router<Arc<AppState>> {
+    if !config.compress_responses {
+        return router;
+    }
+    let predicate = DefaultPredicate::new().and(NotForContentType::const_new("multipart/mixed"));
+    router.layer(CompressionLayer::new().compress_when(predicate))
+}
+
+/// Wrap a router in the access log when enabled, outermost so rejected
+/// requests are logged too, with the client address resolved before either
+fn with_access_log(
+    router: Router<Arc<AppState>>,
+    config: &Config,
+    state: &Arc<AppState>,
+) -> Router<Arc<AppState>> {
+    let router = if config.access_log {
+        router.layer(middleware::from_fn(logging::access_log))
+    } else {
+        router
+    };
+    router.layer(middleware::from_fn_with_state(state.clone(), resolve_client_ip))
+}
+
+/// Origins allowed by the current config, which can change on reload
+fn cors_origin(state: Arc<AppState>) -> AllowOrigin {
+    AllowOrigin::predicate(move |origin: &HeaderValue, _| {
+        let config = state.config.load();
+        config.cors_origins.is_empty()
+            || config.cors_origins.iter().any(|allowed| allowed.as_bytes() == origin.as_bytes())
+    })
+}
+
+/// Response for a request shed because the server is at its concurrency limit
+fn overloaded(state: &AppState, retry_after: u64) -> Response {
+    state.metrics.requests_shed.inc();
+    let mut response = AppError::Overloaded.problem();
+    response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+    response
+}
+
+/// Response for a tile request that took longer than `request_timeout`
+fn timed_out(state: &AppState) -> Response {
+    state.metrics.requests_timed_out.inc();
+    AppError::RequestTimeout.problem()
+}
+
+/// Read the placeholder image, typing it by its extension
+fn load_fallback_tile(path: &Path) -> anyhow::Result<TileData> {
+    let data = std::fs::read(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read fallback tile {:?}: {}", path, e))?;
+    let format = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(TileFormat::from_extension)
+        .unwrap_or_default();
+    let mut tile = TileData::new(data.into(), None);
+    tile.content_type = Some(format.content_type().to_string());
+    Ok(tile)
+}
@@ -0,0 +1,190 @@
+use axum::extract::ConnectInfo;
+use axum::middleware::AddExtension;
+use axum::Extension;
+use axum_server::accept::Accept;
+use futures_util::future::BoxFuture;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tower::Layer;
+
+/// How long a new connection may take to send its header before it is dropped
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+/// Start of every version 2 header
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest version 1 header, including the CRLF
+const V1_MAX_LEN: usize = 107;
+
+/// Listener for connections from a load balancer that prefixes each with a
+/// PROXY protocol header, accepting them under the client address it names.
+/// Headers are read off the accept loop, so a slow peer holds up no one else.
+pub struct ProxyProtocolListener {
+    connections: mpsc::Receiver<(TcpStream, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl ProxyProtocolListener {
+    pub fn new(listener: TcpListener) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (sender, connections) = mpsc::channel(64);
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, peer) = tokio::select! {
+                    // The server is done with the listener
+                    _ = sender.closed() => return,
+                    accepted = listener.accept() => match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            tracing::warn!(error = %e, "Failed to accept connection");
+                            tokio::time::sleep(Duration::from_secs(1)).await;
+                            continue;
+                        }
+                    },
+                };
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    match read_header_within(&mut stream, peer).await {
+                        Ok(client) => {
+                            let _ = sender.send((stream, client)).await;
+                        }
+                        Err(e) => {
+                            tracing::debug!(peer = %peer, error = %e, "Rejected connection")
+                        }
+                    }
+                });
+            }
+        });
+        Ok(Self { connections, local_addr })
+    }
+}
+
+impl axum::serve::Listener for ProxyProtocolListener {
+    type Io = TcpStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.connections.recv().await {
+            Some(connection) => connection,
+            // The accept loop only stops once this listener is dropped
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+/// TLS server counterpart of [`ProxyProtocolListener`], run before the
+/// handshake. It supplies the `ConnectInfo` itself, so the app is served
+/// without one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProxyProtocolAcceptor;
+
+impl<S: Send + 'static> Accept<TcpStream, S> for ProxyProtocolAcceptor {
+    type Stream = TcpStream;
+    type Service = AddExtension<S, ConnectInfo<SocketAddr>>;
+    type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, mut stream: TcpStream, service: S) -> Self::Future {
+        Box::pin(async move {
+            let peer = stream.peer_addr()?;
+            let client = read_header_within(&mut stream, peer).await?;
+            Ok((stream, Extension(ConnectInfo(client)).layer(service)))
+        })
+    }
+}
+
+/// Client address of a connection from `peer`, which is used when the header
+/// names none, as for the load balancer's own health checks
+async fn read_header_within(stream: &mut TcpStream, peer: SocketAddr) -> io::Result<SocketAddr> {
+    let client = tokio::time::timeout(HEADER_TIMEOUT, read_header(stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no PROXY protocol header"))??;
+    Ok(client.unwrap_or(peer))
+}
+
+/// Source address from the version 1 or 2 header starting a connection,
+/// reading exactly the header so the rest is left for HTTP
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    // Shorter than any valid header of either version
+    let mut start = [0; 12];
+    stream.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, &start).await
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+/// Binary header after the signature: version and command, address family,
+/// then the length of the addresses that follow
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut fixed = [0; 4];
+    stream.read_exact(&mut fixed).await?;
+    let [version_command, family, len_high, len_low] = fixed;
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    let mut addresses = vec![0; usize::from(u16::from_be_bytes([len_high, len_low]))];
+    stream.read_exact(&mut addresses).await?;
+
+    match version_command & 0x0f {
+        // LOCAL: the proxy's own connection
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("unknown PROXY protocol command")),
+    }
+    let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+    Ok(match family >> 4 {
+        1 if addresses.len() >= 12 => {
+            let ip: [u8; 4] = addresses[..4].try_into().expect("4 bytes");
+            Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(ip)), port(8)))
+        }
+        2 if addresses.len() >= 36 => {
+            let ip: [u8; 16] = addresses[..16].try_into().expect("16 bytes");
+            Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port(32)))
+        }
+        1 | 2 => return Err(invalid("truncated PROXY protocol addresses")),
+        // Unix sockets and unspecified families carry no IP
+        _ => None,
+    })
+}
+
+/// Text header, e.g. `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n`
+async fn read_v1<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    start: &[u8],
+) -> io::Result<Option<SocketAddr>> {
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY protocol header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY protocol header is not text"))?;
+
+    let mut fields = line.split(' ').skip(1);
+    match fields.next() {
+        Some("TCP4" | "TCP6") => {}
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(invalid("unknown PROXY protocol family")),
+    }
+    let source = fields.next().and_then(|ip| ip.parse::<IpAddr>().ok());
+    let source_port = fields.nth(1).and_then(|port| port.parse::<u16>().ok());
+    match source.zip(source_port) {
+        Some((ip, port)) => Ok(Some(SocketAddr::new(ip, port))),
+        None => Err(invalid("malformed PROXY protocol header")),
+    }
+}
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
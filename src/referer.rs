@@ -0,0 +1,58 @@
+use crate::error::AppError;
+use crate::handlers::AppState;
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderMap};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use reqwest::Url;
+use std::sync::Arc;
+
+/// Middleware rejecting tile requests made from web pages whose host isn't
+/// in `allowed_referers`, to stop other sites hotlinking the proxy
+pub async fn require_allowed_referer(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let config = state.config.load();
+    if config.allowed_referers.is_empty() {
+        return next.run(request).await;
+    }
+    let allowed = match page_host(request.headers()) {
+        Some(host) => config
+            .allowed_referers
+            .iter()
+            .any(|pattern| host_matches(pattern, &host)),
+        // Native apps and scripts send neither header
+        None => !has_page_header(request.headers()) && config.allow_missing_referer,
+    };
+    drop(config);
+    if !allowed {
+        return AppError::Forbidden.into_response();
+    }
+    next.run(request).await
+}
+
+/// Host of the page making the request, from `Origin`, else `Referer`
+fn page_host(headers: &HeaderMap) -> Option<String> {
+    [header::ORIGIN, header::REFERER]
+        .into_iter()
+        .filter_map(|name| headers.get(name)?.to_str().ok())
+        .find_map(|value| Some(Url::parse(value).ok()?.host_str()?.to_ascii_lowercase()))
+}
+
+/// Whether the request has an `Origin` or `Referer`, parseable or not
+fn has_page_header(headers: &HeaderMap) -> bool {
+    headers.contains_key(header::ORIGIN) || headers.contains_key(header::REFERER)
+}
+
+/// Match a host against `example.com`, or `*.example.com` for its subdomains
+fn host_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.trim().to_ascii_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(domain)
+            .is_some_and(|subdomain| subdomain.ends_with('.')),
+        None => host == pattern,
+    }
+}
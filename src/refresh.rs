@@ -0,0 +1,75 @@
+use crate::handlers::{has_admin_token, AppState};
+use axum::http::{header, HeaderMap};
+use serde::Deserialize;
+use std::str::FromStr;
+
+/// Who may force a tile to be refreshed from upstream with a request header
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientRefresh {
+    /// Refresh headers are ignored
+    #[default]
+    Off,
+    /// Requests carrying the admin token, or all when none is set
+    Admin,
+    /// Every client, so a browser's hard reload reaches upstream
+    Anyone,
+}
+
+impl FromStr for ClientRefresh {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(ClientRefresh::Off),
+            "admin" => Ok(ClientRefresh::Admin),
+            "anyone" => Ok(ClientRefresh::Anyone),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How far a requested refresh goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refresh {
+    /// Ask upstream whether the cached copy is current, as `Cache-Control: no-cache` does
+    Revalidate,
+    /// Download the tile again regardless, replacing a corrupted copy
+    Refetch,
+}
+
+impl Refresh {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Refresh::Revalidate => "revalidate",
+            Refresh::Refetch => "refetch",
+        }
+    }
+}
+
+/// Refresh asked for by a request's `X-Refresh: 1` or `Cache-Control:
+/// no-cache` header, if it is allowed to
+pub fn requested(state: &AppState, headers: &HeaderMap) -> Option<Refresh> {
+    let refresh = if headers.get("x-refresh").is_some_and(|v| v == "1") {
+        Refresh::Refetch
+    } else if cache_control_no_cache(headers) {
+        Refresh::Revalidate
+    } else {
+        return None;
+    };
+    let allowed = match state.config.load().client_refresh {
+        ClientRefresh::Off => false,
+        ClientRefresh::Admin => has_admin_token(state, headers),
+        ClientRefresh::Anyone => true,
+    };
+    allowed.then_some(refresh)
+}
+
+fn cache_control_no_cache(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-cache"))
+}
@@ -0,0 +1,89 @@
+use crate::client_limit::ClientRateLimiter;
+use crate::config::{CachePolicy, Config};
+use crate::forwarded::TrustedProxies;
+use crate::handlers::AppState;
+use crate::upstream::OsmFetcher;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Re-read the config file and environment, swapping in the upstreams, cache
+/// lifetimes, client rate limit, trusted proxies, CORS origins and
+/// allowed referers. Caches are kept, and other
+/// settings keep their startup values until a restart.
+pub fn reload(state: &AppState) -> anyhow::Result<()> {
+    let config = match &state.config_path {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    // Build everything before swapping, so a bad config changes nothing
+    let fetcher = OsmFetcher::new(&config)?;
+    let trusted_proxies = TrustedProxies::from_config(&config)?;
+
+    let ignored = restart_only_changes(&state.config.load(), &config);
+    if !ignored.is_empty() {
+        tracing::warn!(settings = ?ignored, "Changed settings take effect after a restart");
+    }
+
+    state.fetcher.store(Arc::new(fetcher));
+    state.cache_policy.store(Arc::new(CachePolicy::new(&config)));
+    state.client_limiter.store(ClientRateLimiter::from_config(&config).map(Arc::new));
+    state.trusted_proxies.store(Arc::new(trusted_proxies));
+    state.config.store(Arc::new(config));
+    tracing::info!("Reloaded configuration");
+    Ok(())
+}
+
+/// Reload whenever the process receives SIGHUP
+pub async fn reload_on_sighup(state: Arc<AppState>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to listen for SIGHUP");
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        if let Err(e) = reload(&state) {
+            tracing::error!(error = %e, "Failed to reload configuration");
+        }
+    }
+}
+
+/// Settings that differ but are only read at startup
+fn restart_only_changes(old: &Config, new: &Config) -> Vec<&'static str> {
+    [
+        ("bind_addr", old.bind_addr != new.bind_addr),
+        ("admin_bind_addr", old.admin_bind_addr != new.admin_bind_addr),
+        ("grpc_bind_addr", old.grpc_bind_addr != new.grpc_bind_addr),
+        ("proxy_protocol", old.proxy_protocol != new.proxy_protocol),
+        ("compress_responses", old.compress_responses != new.compress_responses),
+        ("metrics_zoom_buckets", old.metrics_zoom_buckets != new.metrics_zoom_buckets),
+        ("disk_eviction_policy", old.disk_eviction_policy != new.disk_eviction_policy),
+        ("popularity_tracking", old.popularity_tracking != new.popularity_tracking),
+        ("popularity_max_tiles", old.popularity_max_tiles != new.popularity_max_tiles),
+        ("popularity_half_life_secs", old.popularity_half_life != new.popularity_half_life),
+        ("cache_dir", old.cache_dir != new.cache_dir),
+        ("seed_upstream_budget", old.seed_upstream_budget != new.seed_upstream_budget),
+        ("cache_generation", old.cache_generation != new.cache_generation),
+        ("memory_cache_size", old.memory_cache_size != new.memory_cache_size),
+        ("memory_warm_start", old.memory_warm_start != new.memory_warm_start),
+        ("memory_ttl_secs", old.memory_ttl != new.memory_ttl),
+        ("memory_tti_secs", old.memory_tti != new.memory_tti),
+        ("memory_max_tile_bytes", old.memory_max_tile_bytes != new.memory_max_tile_bytes),
+        ("mbtiles_sources", old.mbtiles_sources != new.mbtiles_sources),
+        ("pmtiles_sources", old.pmtiles_sources != new.pmtiles_sources),
+        ("bounds", old.bounds != new.bounds),
+        ("cache_tiers", old.cache_tiers != new.cache_tiers),
+        ("metatile_size", old.metatile_size != new.metatile_size),
+        ("composite_layers", old.composite_layers != new.composite_layers),
+        ("terrain_layers", old.terrain_layers != new.terrain_layers),
+        ("watermark_text", old.watermark_text != new.watermark_text),
+        ("watermark_image", old.watermark_image != new.watermark_image),
+        ("metatile_upstreams", old.metatile_upstreams != new.metatile_upstreams),
+        ("prefetch_neighbors", old.prefetch_neighbors != new.prefetch_neighbors),
+        ("prefetch_rate", old.prefetch_rate != new.prefetch_rate),
+    ]
+    .into_iter()
+    .filter_map(|(name, changed)| changed.then_some(name))
+    .collect()
+}
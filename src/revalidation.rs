@@ -0,0 +1,93 @@
+use crate::config::Config;
+use crate::handlers::tile::revalidate;
+use crate::handlers::AppState;
+use crate::types::{TileFormat, TileKey};
+use crate::upstream::scheduler;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How often a disabled or out-of-hours sweep checks whether it may run
+const IDLE_CHECK: Duration = Duration::from_secs(60);
+
+/// Periodically refresh disk cache tiles past their freshness window during
+/// off-peak hours, so popular areas stay current without requests waiting on
+/// upstream. Settings are read afresh each round, so reloads apply.
+pub async fn sweep_stale_tiles(state: Arc<AppState>) {
+    loop {
+        let config = state.config.load_full();
+        if config.revalidation_sweep_interval.is_zero() || !in_off_peak_hours(&config) {
+            tokio::time::sleep(IDLE_CHECK).await;
+            continue;
+        }
+        sweep(&state).await;
+        tokio::time::sleep(config.revalidation_sweep_interval).await;
+    }
+}
+
+async fn sweep(state: &Arc<AppState>) {
+    let stale = {
+        let state = state.clone();
+        tokio::task::spawn_blocking(move || stale_tiles(&state))
+            .await
+            .expect("stale tile scan panicked")
+    };
+    tracing::info!(stale = stale.len(), "Starting revalidation sweep");
+
+    let (mut refreshed, mut skipped) = (0u64, 0u64);
+    let mut ticker: Option<tokio::time::Interval> = None;
+    for (done, key) in stale.iter().enumerate() {
+        let config = state.config.load_full();
+        if state.is_offline() || !in_off_peak_hours(&config) {
+            tracing::info!(
+                refreshed,
+                remaining = stale.len() - done,
+                "Revalidation sweep stopped outside off-peak hours"
+            );
+            return;
+        }
+        // Rebuilt when the rate changes on reload
+        let period = Duration::from_secs_f64(1.0 / config.revalidation_sweep_rate.max(0.01));
+        if ticker.as_ref().is_none_or(|ticker| ticker.period() != period) {
+            ticker = Some(tokio::time::interval(period));
+        }
+        if let Some(ticker) = &mut ticker {
+            ticker.tick().await;
+        }
+        if scheduler::background(revalidate(state.clone(), *key)).await {
+            refreshed += 1;
+        } else {
+            skipped += 1;
+        }
+    }
+    tracing::info!(refreshed, skipped, "Revalidation sweep finished");
+}
+
+/// Disk cache tiles older than the freshness window for their zoom. Walks the
+/// whole cache, so it's run on the blocking pool.
+fn stale_tiles(state: &AppState) -> Vec<TileKey> {
+    let policy = state.cache_policy.load();
+    state
+        .disk_cache
+        .keys()
+        .filter(|key| key.z <= state.max_native_zoom)
+        // Transcoded variants are rebuilt from their source instead
+        .filter(|key| !(state.webp_transcoding && key.format == TileFormat::Webp))
+        .filter(|key| {
+            let window = policy.freshness_window(key.z, None);
+            state.disk_cache.age(key).is_some_and(|age| age > window)
+        })
+        .collect()
+}
+
+fn in_off_peak_hours(config: &Config) -> bool {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let hour = (secs / 3600 % 24) as u8;
+    let (start, end) = (config.revalidation_sweep_start_hour, config.revalidation_sweep_end_hour);
+    if start == end {
+        true
+    } else if start < end {
+        (start..end).contains(&hour)
+    } else {
+        hour >= start || hour < end
+    }
+}
@@ -0,0 +1,413 @@
+use crate::error::{AppError, Result};
+use crate::geo::TileRange;
+use crate::handlers::tile::fetch_with_coalescing;
+use crate::handlers::AppState;
+use crate::types::{TileFormat, TileKey};
+use crate::upstream::scheduler;
+use dashmap::DashMap;
+use futures_util::stream::{self, Stream};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+
+/// Highest zoom level a seed job may request
+pub const MAX_SEED_ZOOM: u8 = 19;
+
+/// How often a followed job reports its progress while running
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Directory under the cache directory holding checkpoints of running jobs
+pub const CHECKPOINT_DIR: &str = "seed-jobs";
+
+/// How often a running job saves its checkpoint
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SeedRequest {
+    #[serde(flatten)]
+    pub range: TileRange,
+    /// Tile format to fetch, defaults to PNG
+    #[serde(default)]
+    pub format: TileFormat,
+    /// Fetch `@2x` retina tiles instead of standard ones
+    #[serde(default)]
+    pub retina: bool,
+    /// Parallel upstream fetches, defaults to the configured seed concurrency
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub concurrency: Option<usize>,
+}
+
+impl SeedRequest {
+    pub fn validate(&self) -> Result<()> {
+        self.range.validate(MAX_SEED_ZOOM)?;
+        if self.concurrency == Some(0) {
+            return Err(AppError::BadRequest("concurrency must be at least 1".to_string()));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Running,
+    Completed,
+}
+
+/// Snapshot of a seed job's progress
+#[derive(Debug, Serialize)]
+pub struct JobStatus {
+    pub id: u64,
+    pub state: JobState,
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+    pub total: u64,
+    pub fetched: u64,
+    pub skipped: u64,
+    pub failed: u64,
+    pub elapsed_secs: u64,
+    /// Estimated time left from the pace so far, until the first tile is done
+    /// unknown
+    pub eta_secs: Option<u64>,
+    /// Most recent fetch failure
+    pub last_error: Option<String>,
+}
+
+/// A background job pre-warming the cache for a region
+pub struct SeedJob {
+    pub id: u64,
+    request: SeedRequest,
+    concurrency: usize,
+    total: u64,
+    fetched: AtomicU64,
+    skipped: AtomicU64,
+    failed: AtomicU64,
+    last_error: Mutex<Option<String>>,
+    started: Instant,
+    /// Tiles already done when this run started, from a checkpoint
+    resumed: u64,
+    /// Index in the range's tile order of the next tile to start
+    next_index: AtomicU64,
+    /// Indices of tiles being seeded now
+    in_flight: Mutex<BTreeSet<u64>>,
+    /// File the job's progress is saved to, when it resumes after a restart
+    checkpoint: Option<PathBuf>,
+    finished: AtomicBool,
+}
+
+/// Progress of a job saved to disk, so it can carry on after a restart
+#[derive(Debug, Serialize, Deserialize)]
+struct Checkpoint {
+    id: u64,
+    request: SeedRequest,
+    /// Every tile before this index in the range's order is done
+    resume_index: u64,
+    fetched: u64,
+    skipped: u64,
+    failed: u64,
+}
+
+impl SeedJob {
+    pub fn status(&self) -> JobStatus {
+        let state = if self.finished.load(Ordering::Acquire) {
+            JobState::Completed
+        } else {
+            JobState::Running
+        };
+
+        let fetched = self.fetched.load(Ordering::Relaxed);
+        let skipped = self.skipped.load(Ordering::Relaxed);
+        let failed = self.failed.load(Ordering::Relaxed);
+        let done = fetched + skipped + failed;
+        let elapsed = self.started.elapsed();
+        // Only tiles done since a resume say anything about the current pace
+        let done_now = done.saturating_sub(self.resumed);
+        let eta_secs = match state {
+            JobState::Completed => Some(0),
+            JobState::Running if done_now == 0 => None,
+            JobState::Running => {
+                let left = self.total.saturating_sub(done) as f64;
+                Some((elapsed.as_secs_f64() * left / done_now as f64).ceil() as u64)
+            }
+        };
+
+        JobStatus {
+            id: self.id,
+            state,
+            min_zoom: self.request.range.min_zoom,
+            max_zoom: self.request.range.max_zoom,
+            total: self.total,
+            fetched,
+            skipped,
+            failed,
+            elapsed_secs: elapsed.as_secs(),
+            eta_secs,
+            last_error: self.last_error.lock().expect("seed error lock poisoned").clone(),
+        }
+    }
+
+    /// The job's status now and then every [`PROGRESS_INTERVAL`], ending with
+    /// the status it completed with
+    pub fn progress(self: Arc<Self>) -> impl Stream<Item = JobStatus> + Send + 'static {
+        stream::unfold((Some(self), false), |(job, wait)| async move {
+            let job = job?;
+            if wait {
+                tokio::time::sleep(PROGRESS_INTERVAL).await;
+            }
+            let status = job.status();
+            let job = matches!(status.state, JobState::Running).then_some(job);
+            Some((status, (job, true)))
+        })
+    }
+
+    /// Index from which the job would pick up again: its first tile still in
+    /// flight, or the next one to start
+    fn resume_index(&self) -> u64 {
+        // Tiles are added under the lock before `next_index` moves past them
+        let in_flight = self.in_flight.lock().expect("seed progress lock poisoned");
+        in_flight
+            .first()
+            .copied()
+            .unwrap_or_else(|| self.next_index.load(Ordering::Relaxed))
+    }
+
+    /// Save the job's progress, if it is kept across restarts. Tiles finished
+    /// past the resume index are seeded again after a restart, and counted twice.
+    pub async fn save_checkpoint(&self) {
+        let Some(path) = &self.checkpoint else {
+            return;
+        };
+        if self.finished.load(Ordering::Acquire) {
+            return;
+        }
+        let checkpoint = Checkpoint {
+            id: self.id,
+            request: self.request.clone(),
+            resume_index: self.resume_index(),
+            fetched: self.fetched.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+        };
+        let json = serde_json::to_vec(&checkpoint).expect("checkpoint serializes to JSON");
+        let tmp = path.with_extension("json.tmp");
+        let saved = async {
+            tokio::fs::write(&tmp, json).await?;
+            tokio::fs::rename(&tmp, path).await
+        };
+        if let Err(e) = saved.await {
+            tracing::warn!(job = self.id, error = %e, "Failed to save seed job checkpoint");
+        }
+    }
+}
+
+/// Registry of seed jobs started since the process came up, or resumed from
+/// their checkpoints
+pub struct JobManager {
+    jobs: DashMap<u64, Arc<SeedJob>>,
+    next_id: AtomicU64,
+    /// Upstream fetches all jobs together may have in flight, unlimited when unset
+    budget: Option<Arc<Semaphore>>,
+    /// Directory job checkpoints are kept in, unset when jobs aren't resumed
+    checkpoint_dir: Option<PathBuf>,
+}
+
+impl JobManager {
+    pub fn new(upstream_budget: usize, checkpoint_dir: Option<PathBuf>) -> Self {
+        Self {
+            jobs: DashMap::new(),
+            next_id: AtomicU64::new(1),
+            budget: (upstream_budget > 0).then(|| Arc::new(Semaphore::new(upstream_budget))),
+            checkpoint_dir,
+        }
+    }
+
+    pub fn create(&self, request: SeedRequest, default_concurrency: usize) -> Arc<SeedJob> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let checkpoint = Checkpoint {
+            id,
+            request,
+            resume_index: 0,
+            fetched: 0,
+            skipped: 0,
+            failed: 0,
+        };
+        self.insert(checkpoint, default_concurrency)
+    }
+
+    fn insert(&self, checkpoint: Checkpoint, default_concurrency: usize) -> Arc<SeedJob> {
+        let request = checkpoint.request;
+        let job = Arc::new(SeedJob {
+            id: checkpoint.id,
+            concurrency: request.concurrency.unwrap_or(default_concurrency).max(1),
+            total: request.range.tile_count(),
+            request,
+            fetched: AtomicU64::new(checkpoint.fetched),
+            skipped: AtomicU64::new(checkpoint.skipped),
+            failed: AtomicU64::new(checkpoint.failed),
+            last_error: Mutex::new(None),
+            started: Instant::now(),
+            resumed: checkpoint.fetched + checkpoint.skipped + checkpoint.failed,
+            next_index: AtomicU64::new(checkpoint.resume_index),
+            in_flight: Mutex::new(BTreeSet::new()),
+            checkpoint: self
+                .checkpoint_dir
+                .as_ref()
+                .map(|dir| dir.join(format!("{}.json", checkpoint.id))),
+            finished: AtomicBool::new(false),
+        });
+        self.jobs.insert(job.id, job.clone());
+        job
+    }
+
+    pub fn get(&self, id: u64) -> Option<Arc<SeedJob>> {
+        self.jobs.get(&id).map(|job| job.clone())
+    }
+
+    /// Every job, oldest first
+    pub fn all(&self) -> Vec<Arc<SeedJob>> {
+        let mut jobs: Vec<_> = self.jobs.iter().map(|job| job.clone()).collect();
+        jobs.sort_by_key(|job| job.id);
+        jobs
+    }
+
+    /// Register the jobs left running by an earlier process, to be started
+    /// again with [`run_seed`]
+    pub fn resume(&self, default_concurrency: usize) -> Vec<Arc<SeedJob>> {
+        let Some(dir) = &self.checkpoint_dir else {
+            return Vec::new();
+        };
+        if let Err(e) = std::fs::create_dir_all(dir) {
+            tracing::warn!(dir = ?dir, error = %e, "Failed to create seed checkpoint directory");
+            return Vec::new();
+        }
+        let mut jobs = Vec::new();
+        for entry in std::fs::read_dir(dir).into_iter().flatten().flatten() {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            match read_checkpoint(&path) {
+                Ok(checkpoint) => {
+                    self.next_id.fetch_max(checkpoint.id + 1, Ordering::Relaxed);
+                    jobs.push(self.insert(checkpoint, default_concurrency));
+                }
+                Err(e) => {
+                    tracing::warn!(path = ?path, error = %e, "Skipping unreadable seed checkpoint")
+                }
+            }
+        }
+        jobs.sort_by_key(|job| job.id);
+        jobs
+    }
+
+    /// Save the checkpoint of every running job, as on shutdown
+    pub async fn save_checkpoints(&self) {
+        for job in self.all() {
+            job.save_checkpoint().await;
+        }
+    }
+
+    /// Wait for room in the upstream budget shared by all jobs
+    async fn upstream_permit(&self) -> Option<OwnedSemaphorePermit> {
+        let budget = self.budget.clone()?;
+        Some(budget.acquire_owned().await.expect("seed budget semaphore is never closed"))
+    }
+}
+
+fn read_checkpoint(path: &Path) -> anyhow::Result<Checkpoint> {
+    let checkpoint: Checkpoint = serde_json::from_slice(&std::fs::read(path)?)?;
+    checkpoint.request.validate()?;
+    Ok(checkpoint)
+}
+
+/// Fetch every tile of a seed job through the coalescer, bounded by the job's concurrency
+pub async fn run_seed(state: Arc<AppState>, job: Arc<SeedJob>) {
+    let start = job.next_index.load(Ordering::Relaxed);
+    tracing::info!(job = job.id, total = job.total, start, "Seed job started");
+
+    let semaphore = Arc::new(Semaphore::new(job.concurrency));
+    let mut tasks = JoinSet::new();
+    let mut saved_at = Instant::now();
+    job.save_checkpoint().await;
+
+    for (index, key) in (start..).zip(job.request.range.tiles().skip(start as usize)) {
+        let key = key
+            .with_format(job.request.format)
+            .with_scale(if job.request.retina { 2 } else { 1 })
+            .with_generation(state.disk_cache.generation());
+        let permit = semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("seed semaphore is never closed");
+        {
+            let mut in_flight = job.in_flight.lock().expect("seed progress lock poisoned");
+            in_flight.insert(index);
+            job.next_index.store(index + 1, Ordering::Relaxed);
+        }
+        let state = state.clone();
+        let tile_job = job.clone();
+
+        tasks.spawn(async move {
+            let _permit = permit;
+            scheduler::background(seed_tile(&state, &tile_job, key)).await;
+            tile_job.in_flight.lock().expect("seed progress lock poisoned").remove(&index);
+        });
+
+        // Reap finished tasks so the set doesn't grow with the region size
+        while tasks.try_join_next().is_some() {}
+        if saved_at.elapsed() >= CHECKPOINT_INTERVAL {
+            job.save_checkpoint().await;
+            saved_at = Instant::now();
+        }
+    }
+
+    while tasks.join_next().await.is_some() {}
+
+    job.finished.store(true, Ordering::Release);
+    if let Some(path) = &job.checkpoint {
+        if let Err(e) = tokio::fs::remove_file(path).await {
+            tracing::warn!(job = job.id, error = %e, "Failed to remove seed job checkpoint");
+        }
+    }
+    let status = job.status();
+    tracing::info!(
+        job = job.id,
+        fetched = status.fetched,
+        skipped = status.skipped,
+        failed = status.failed,
+        "Seed job completed"
+    );
+}
+
+async fn seed_tile(state: &Arc<AppState>, job: &SeedJob, key: TileKey) {
+    if state.disk_cache.contains(&key).await {
+        job.skipped.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    // Copy from a shared tier or archive rather than asking upstream
+    if let Some(hit) = state.tiers.lookup(&key, 0).await {
+        if !hit.tile.synthesized {
+            state.tiers.promote(&key, &hit).await;
+            job.fetched.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    }
+
+    let _budget = state.jobs.upstream_permit().await;
+    match fetch_with_coalescing(state, key).await {
+        Ok(_) => {
+            job.fetched.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(e) => {
+            tracing::debug!(job = job.id, key = %key, error = %e, "Seed fetch failed");
+            *job.last_error.lock().expect("seed error lock poisoned") = Some(e.to_string());
+            job.failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use crate::cache::{DiskCache, RequestCoalescer};
+use crate::composite::{check_layer_name, load_layer_tile, LAYERS_DIR};
+use crate::config::{Config, TerrainLayerConfig};
+use crate::error::{AppError, Result};
+use crate::handlers::tile::LoadedTile;
+use crate::handlers::AppState;
+use crate::processing::terrain::TerrainEncoding;
+use crate::types::{TileData, TileKey};
+use crate::upstream::{FetchResult, OsmFetcher};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// A layer of elevation tiles from its own upstream, passed through and
+/// cached on their own, whose heights can be looked up by coordinate
+pub struct TerrainLayer {
+    name: String,
+    fetcher: OsmFetcher,
+    encoding: TerrainEncoding,
+    cache: DiskCache,
+    /// Deduplicates fetches of the same tile
+    coalescer: RequestCoalescer,
+}
+
+impl TerrainLayer {
+    /// Layers of the config, by name
+    pub fn from_config(
+        config: &Config,
+        disk_cache: &DiskCache,
+    ) -> anyhow::Result<HashMap<String, Self>> {
+        let layers_cache = disk_cache.subdirectory(LAYERS_DIR)?;
+        let mut layers = HashMap::new();
+        for layer in &config.terrain_layers {
+            check_layer_name(&layer.name)?;
+            let taken = config.composite_layers.iter().any(|c| c.name == layer.name);
+            let terrain = Self::new(config, layer, layers_cache.subdirectory(&layer.name)?)?;
+            if taken || layers.insert(layer.name.clone(), terrain).is_some() {
+                anyhow::bail!("Layer {:?} is configured more than once", layer.name);
+            }
+        }
+        Ok(layers)
+    }
+
+    fn new(config: &Config, layer: &TerrainLayerConfig, cache: DiskCache) -> anyhow::Result<Self> {
+        Ok(Self {
+            name: layer.name.clone(),
+            fetcher: OsmFetcher::new(&Config {
+                upstreams: vec![layer.upstream.clone()],
+                passthrough_headers: layer.passthrough_headers.clone(),
+                ..config.clone()
+            })?,
+            encoding: layer.encoding,
+            cache,
+            coalescer: RequestCoalescer::new(),
+        })
+    }
+
+    /// Disk cache of the layer's tiles
+    pub fn cache(&self) -> &DiskCache {
+        &self.cache
+    }
+
+    pub fn encoding(&self) -> TerrainEncoding {
+        self.encoding
+    }
+
+    /// A terrain tile from the layer's cache, fetched again once older than
+    /// the freshness window
+    pub async fn load(&self, state: &Arc<AppState>, key: TileKey) -> Result<LoadedTile> {
+        let fetch = || self.fetch(state, key);
+        load_layer_tile(state, &self.cache, &self.coalescer, key, "upstream", fetch).await
+    }
+
+    /// Fetch a tile, or confirm the cached one, and store it
+    async fn fetch(&self, state: &Arc<AppState>, key: TileKey) -> Result<Arc<TileData>> {
+        if state.is_offline() {
+            return Err(AppError::NotFound);
+        }
+        let started = Instant::now();
+        let validators = self.cache.load_validators(&key).await;
+        let result = self.fetcher.fetch(&key, &validators).await;
+        state.metrics.observe_upstream(&self.name, key.z, started.elapsed());
+        match result? {
+            FetchResult::Data(mut tile) => {
+                tile.ensure_etag();
+                let cache = self.cache.clone();
+                let tile = tokio::task::spawn_blocking(move || -> Result<TileData> {
+                    cache.store(&key, &tile)?;
+                    Ok(tile)
+                })
+                .await
+                .expect("terrain store task panicked")?;
+                tracing::debug!(key = %key, size = tile.data.len(), "Fetched terrain tile");
+                Ok(Arc::new(tile))
+            }
+            FetchResult::NotModified => {
+                self.cache.mark_revalidated(&key).await?;
+                self.cache.load(&key).await.ok_or(AppError::NotFound)
+            }
+        }
+    }
+}
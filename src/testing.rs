@@ -0,0 +1,315 @@
+//! Building blocks for end-to-end tests, behind the `test-support` feature: a
+//! mock upstream whose answers, latency and failures a test sets, and the
+//! full proxy served against it on a local port.
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use maptile_cacher::testing::{MockTile, MockUpstream, TestProxy};
+//!
+//! let upstream = MockUpstream::start().await?;
+//! upstream.set("1/0/0.png", MockTile::png([0, 0, 255, 255]).with_etag("\"v1\""));
+//! let proxy = TestProxy::start(&upstream, |config| config.upstream_max_retries = 0).await?;
+//!
+//! let response = reqwest::get(proxy.url("/1/0/0.png")).await?;
+//! assert_eq!(response.status(), 200);
+//! assert_eq!(upstream.hits("1/0/0.png"), 1);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::config::Config;
+use crate::processing;
+use crate::proxy::TileProxy;
+use crate::types::TileFormat;
+use axum::extract::State;
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::Router;
+use bytes::Bytes;
+use image::RgbaImage;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+/// How long [`TestProxy::stop`] waits for fetches and disk writes
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How the mock upstream answers for a tile
+#[derive(Debug, Clone)]
+pub struct MockTile {
+    pub status: StatusCode,
+    pub body: Bytes,
+    /// Sent as `ETag`; a request whose `If-None-Match` matches gets a 304
+    pub etag: Option<String>,
+    pub headers: Vec<(String, String)>,
+    /// Wait before answering, on top of the upstream's latency
+    pub delay: Duration,
+}
+
+impl MockTile {
+    /// 200 with `body`, typed as a PNG
+    pub fn ok(body: impl Into<Bytes>) -> Self {
+        Self {
+            status: StatusCode::OK,
+            body: body.into(),
+            etag: None,
+            headers: vec![(header::CONTENT_TYPE.to_string(), "image/png".to_string())],
+            delay: Duration::ZERO,
+        }
+    }
+
+    /// 200 with a 256x256 PNG of a single RGBA color, for tests that decode tiles
+    pub fn png(color: [u8; 4]) -> Self {
+        let image = RgbaImage::from_pixel(256, 256, image::Rgba(color));
+        let png = processing::encode(image, TileFormat::Png).expect("PNG encoding failed");
+        Self::ok(png)
+    }
+
+    /// An empty response with `status`, e.g. 404 or 503
+    pub fn status(status: StatusCode) -> Self {
+        Self {
+            status,
+            body: Bytes::new(),
+            etag: None,
+            headers: Vec::new(),
+            delay: Duration::ZERO,
+        }
+    }
+
+    pub fn with_etag(mut self, etag: &str) -> Self {
+        self.etag = Some(etag.to_string());
+        self
+    }
+
+    /// Add a response header, e.g. `Cache-Control` or `Retry-After`
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+/// A request the mock upstream received
+#[derive(Debug, Clone)]
+pub struct MockRequest {
+    /// Path without the leading slash, e.g. `1/0/0.png`
+    pub path: String,
+    pub if_none_match: Option<String>,
+    pub if_modified_since: Option<String>,
+}
+
+#[derive(Default)]
+struct MockState {
+    tiles: Mutex<HashMap<String, MockTile>>,
+    /// Answer for paths without their own, 404 when unset
+    fallback: Mutex<Option<MockTile>>,
+    /// Statuses the next requests get, whatever they ask for
+    failures: Mutex<VecDeque<StatusCode>>,
+    latency: Mutex<Duration>,
+    requests: Mutex<Vec<MockRequest>>,
+}
+
+/// Tile server on a local port answering as a test tells it to, recording
+/// every request it gets
+pub struct MockUpstream {
+    addr: SocketAddr,
+    state: Arc<MockState>,
+    server: JoinHandle<()>,
+}
+
+impl MockUpstream {
+    pub async fn start() -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(MockState::default());
+        let app = Router::new().fallback(answer).with_state(state.clone());
+        let server = tokio::spawn(async move {
+            let _ = axum::serve(listener, app).await;
+        });
+        Ok(Self {
+            addr,
+            state,
+            server,
+        })
+    }
+
+    /// Template to configure as an upstream, serving `z/x/y.ext` paths
+    pub fn url_template(&self) -> String {
+        format!("http://{}/{{z}}/{{x}}/{{y}}{{r}}.{{ext}}", self.addr)
+    }
+
+    /// Answer requests for `path`, e.g. `1/0/0.png` or `3/2/1@2x.webp`
+    pub fn set(&self, path: &str, tile: MockTile) {
+        lock(&self.state.tiles).insert(path.to_string(), tile);
+    }
+
+    /// Go back to answering `path` with the fallback
+    pub fn remove(&self, path: &str) {
+        lock(&self.state.tiles).remove(path);
+    }
+
+    /// Answer paths without their own tile with `tile` instead of a 404
+    pub fn set_fallback(&self, tile: MockTile) {
+        *lock(&self.state.fallback) = Some(tile);
+    }
+
+    /// Answer the next `count` requests with `status`, whatever they ask for
+    pub fn fail_next(&self, count: usize, status: StatusCode) {
+        lock(&self.state.failures).extend(std::iter::repeat_n(status, count));
+    }
+
+    /// Wait this long before answering every request
+    pub fn set_latency(&self, latency: Duration) {
+        *lock(&self.state.latency) = latency;
+    }
+
+    /// Every request received so far, oldest first
+    pub fn requests(&self) -> Vec<MockRequest> {
+        lock(&self.state.requests).clone()
+    }
+
+    /// Requests received for `path`
+    pub fn hits(&self, path: &str) -> usize {
+        lock(&self.state.requests).iter().filter(|request| request.path == path).count()
+    }
+
+    pub fn clear_requests(&self) {
+        lock(&self.state.requests).clear();
+    }
+}
+
+impl Drop for MockUpstream {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+async fn answer(State(state): State<Arc<MockState>>, uri: Uri, headers: HeaderMap) -> Response {
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+    let request = MockRequest {
+        path: uri.path().trim_start_matches('/').to_string(),
+        if_none_match: header(header::IF_NONE_MATCH),
+        if_modified_since: header(header::IF_MODIFIED_SINCE),
+    };
+    lock(&state.requests).push(request.clone());
+
+    let failure = lock(&state.failures).pop_front();
+    let tile = lock(&state.tiles)
+        .get(&request.path)
+        .cloned()
+        .or_else(|| lock(&state.fallback).clone());
+    let latency = *lock(&state.latency);
+    tokio::time::sleep(latency + tile.as_ref().map_or(Duration::ZERO, |tile| tile.delay)).await;
+
+    if let Some(status) = failure {
+        return status.into_response();
+    }
+    let Some(tile) = tile else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let mut response = if tile.etag.is_some() && request.if_none_match == tile.etag {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        let mut response = (tile.status, tile.body).into_response();
+        for (name, value) in &tile.headers {
+            let (Ok(name), Ok(value)) = (HeaderName::try_from(name), HeaderValue::try_from(value))
+            else {
+                continue;
+            };
+            response.headers_mut().insert(name, value);
+        }
+        response
+    };
+    if let Some(etag) = tile.etag.and_then(|etag| HeaderValue::try_from(etag).ok()) {
+        response.headers_mut().insert(header::ETAG, etag);
+    }
+    response
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().expect("mock upstream lock poisoned")
+}
+
+/// The proxy's routes, admin ones included, served on a local port against a
+/// mock upstream, caching to a fresh directory that is removed on drop
+pub struct TestProxy {
+    proxy: TileProxy,
+    addr: SocketAddr,
+    cache_dir: PathBuf,
+    server: JoinHandle<()>,
+}
+
+impl TestProxy {
+    /// Serve a proxy of `upstream`, configured with the built-in defaults,
+    /// ignoring the environment, as adjusted by `configure`
+    pub async fn start(
+        upstream: &MockUpstream,
+        configure: impl FnOnce(&mut Config),
+    ) -> anyhow::Result<Self> {
+        static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+        let cache_dir = std::env::temp_dir().join(format!(
+            "maptile_cacher-test-{}-{}",
+            std::process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let mut config = Config::builtin();
+        config.upstreams = vec![upstream.url_template().into()];
+        config.cache_dir = cache_dir.clone();
+        configure(&mut config);
+
+        let proxy = TileProxy::builder().config(config).build().await?;
+        let mut app = proxy.router();
+        if let Some(admin) = proxy.admin_router() {
+            app = app.merge(admin);
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let app = app.into_make_service_with_connect_info::<SocketAddr>();
+        let server = tokio::spawn(async move {
+            let _ = axum::serve(listener, app).await;
+        });
+        Ok(Self {
+            proxy,
+            addr,
+            cache_dir,
+            server,
+        })
+    }
+
+    /// URL of `path` on the proxy, e.g. `/1/0/0.png`
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    pub fn proxy(&self) -> &TileProxy {
+        &self.proxy
+    }
+
+    pub fn cache_dir(&self) -> &Path {
+        &self.cache_dir
+    }
+
+    /// Stop taking requests and wait for upstream fetches and disk writes to
+    /// finish, as a server shutting down does
+    pub async fn stop(&self) {
+        self.server.abort();
+        self.proxy.shutdown(STOP_TIMEOUT).await;
+    }
+}
+
+impl Drop for TestProxy {
+    fn drop(&mut self) {
+        self.server.abort();
+        let _ = std::fs::remove_dir_all(&self.cache_dir);
+    }
+}
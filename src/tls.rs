@@ -0,0 +1,54 @@
+use axum_server::tls_rustls::RustlsConfig;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Certificate and key for HTTPS, reloadable while serving
+#[derive(Clone)]
+pub struct Tls {
+    pub config: RustlsConfig,
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+impl Tls {
+    /// Load the PEM certificate chain and private key
+    pub async fn load(cert: &Path, key: &Path) -> anyhow::Result<Self> {
+        // reqwest brings its own provider; the server uses the process default
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let config = RustlsConfig::from_pem_file(cert, key).await.map_err(|e| {
+            anyhow::anyhow!("Failed to load TLS certificate {:?} and key {:?}: {}", cert, key, e)
+        })?;
+        Ok(Self {
+            config,
+            cert: cert.to_path_buf(),
+            key: key.to_path_buf(),
+        })
+    }
+
+    /// Reload the certificate whenever either file changes, checking every `interval`
+    pub async fn watch(self, interval: Duration) {
+        let mut loaded = self.modified();
+        loop {
+            tokio::time::sleep(interval).await;
+            let modified = self.modified();
+            if modified == loaded {
+                continue;
+            }
+            match self.config.reload_from_pem_file(&self.cert, &self.key).await {
+                Ok(()) => {
+                    tracing::info!(cert = ?self.cert, "Reloaded TLS certificate");
+                    loaded = modified;
+                }
+                // Rotation may have replaced only one file so far; retry next time
+                Err(e) => {
+                    tracing::warn!(cert = ?self.cert, error = %e, "Failed to reload TLS certificate");
+                }
+            }
+        }
+    }
+
+    fn modified(&self) -> (Option<SystemTime>, Option<SystemTime>) {
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        (modified(&self.cert), modified(&self.key))
+    }
+}
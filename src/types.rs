@@ -1,20 +1,79 @@
 use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::hash::{Hash, Hasher};
+use std::time::{Duration, SystemTime};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct TileKey {
     pub z: u8,
     pub x: u32,
     pub y: u32,
+    pub format: TileFormat,
+    /// Pixel density, 2 for `@2x` retina tiles
+    pub scale: u8,
+    /// Cache generation the tile is stored under; bumping it leaves every
+    /// earlier tile behind
+    pub generation: u32,
 }
 
 impl TileKey {
+    /// Pixel densities a tile can be requested at
+    pub const SCALES: [u8; 2] = [1, 2];
+
+    /// Key for a standard-resolution PNG tile
     pub fn new(z: u8, x: u32, y: u32) -> Self {
-        Self { z, x, y }
+        Self {
+            z,
+            x,
+            y,
+            format: TileFormat::default(),
+            scale: 1,
+            generation: 0,
+        }
+    }
+
+    pub fn with_format(self, format: TileFormat) -> Self {
+        Self { format, ..self }
+    }
+
+    pub fn with_scale(self, scale: u8) -> Self {
+        Self { scale, ..self }
+    }
+
+    pub fn with_generation(self, generation: u32) -> Self {
+        Self { generation, ..self }
+    }
+
+    /// `z/x/file` path of the tile in shared stores, under its generation's directory
+    pub fn store_path(&self) -> String {
+        let path = format!("{}/{}/{}", self.z, self.x, self.file_name());
+        match generation_dir(self.generation) {
+            Some(dir) => format!("{}/{}", dir, path),
+            None => path,
+        }
+    }
+
+    /// Marker inserted before the extension, e.g. `@2x`
+    pub fn scale_suffix(&self) -> &'static str {
+        match self.scale {
+            2 => "@2x",
+            _ => "",
+        }
+    }
+
+    /// Name of the tile file within its `z/x` directory
+    pub fn file_name(&self) -> String {
+        format!("{}{}.{}", self.y, self.scale_suffix(), self.format.extension())
     }
 
-    pub fn to_path(&self) -> String {
-        format!("{}/{}/{}.png", self.z, self.x, self.y)
+    /// Check that x and y fall within the tile grid at this zoom level
+    pub fn is_valid(&self) -> bool {
+        if self.z > 31 {
+            return false;
+        }
+        let max_coord = 1u32 << self.z;
+        self.x < max_coord && self.y < max_coord
     }
 }
 
@@ -23,12 +82,188 @@ impl Hash for TileKey {
         state.write_u8(self.z);
         state.write_u32(self.x);
         state.write_u32(self.y);
+        self.format.hash(state);
+        state.write_u8(self.scale);
+        state.write_u32(self.generation);
     }
 }
 
 impl std::fmt::Display for TileKey {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}/{}/{}", self.z, self.x, self.y)
+        write!(f, "{}/{}/{}", self.z, self.x, self.file_name())
+    }
+}
+
+/// A tile named by a `z/x/y[@2x].ext` path, parsed strictly: coordinates in
+/// plain decimal within the zoom's grid and a known extension, so a path that
+/// parses can only ever name a tile, and prints back exactly as given
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TilePath(TileKey);
+
+impl TilePath {
+    /// Path of a key, None when it falls outside the grid
+    pub fn new(key: TileKey) -> Option<Self> {
+        (key.is_valid() && TileKey::SCALES.contains(&key.scale)).then_some(Self(key))
+    }
+
+    /// Parse the filename within a `z/x` directory, e.g. `5461.png` or `5461@2x.png`
+    pub fn from_filename(z: u8, x: u32, filename: &str) -> Option<Self> {
+        let (stem, ext) = filename.split_once('.')?;
+        let format = TileFormat::from_extension(ext)?;
+        let (y, scale) = match stem.strip_suffix("@2x") {
+            Some(y) => (y, 2),
+            None => (stem, 1),
+        };
+        Self::new(TileKey::new(z, x, parse_coordinate(y)?).with_format(format).with_scale(scale))
+    }
+
+    /// Parse a whole `z/x/filename` path, e.g. `12/2048/1361@2x.png`
+    pub fn parse(path: &str) -> Option<Self> {
+        let mut parts = path.splitn(3, '/');
+        let z = parse_coordinate(parts.next()?)?.try_into().ok()?;
+        let x = parse_coordinate(parts.next()?)?;
+        Self::from_filename(z, x, parts.next()?)
+    }
+
+    pub fn key(self) -> TileKey {
+        self.0
+    }
+}
+
+impl std::fmt::Display for TilePath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Whether `name` can stand as a single file or directory name: not empty,
+/// `.` or `..`, and only letters, digits, `-`, `_`, `.` and `@`
+pub fn is_path_component(name: &str) -> bool {
+    !matches!(name, "" | "." | "..")
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '@'))
+}
+
+/// A coordinate in plain decimal, without sign or leading zeros
+fn parse_coordinate(digits: &str) -> Option<u32> {
+    let canonical = !digits.is_empty()
+        && digits.bytes().all(|b| b.is_ascii_digit())
+        && (digits == "0" || !digits.starts_with('0'));
+    canonical.then(|| digits.parse().ok()).flatten()
+}
+
+/// Directory a generation's tiles are kept under, e.g. `gen-3`; the first
+/// generation has none so caches from before generations stay valid
+pub fn generation_dir(generation: u32) -> Option<String> {
+    (generation > 0).then(|| format!("gen-{}", generation))
+}
+
+/// Row numbering used by a client
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TileScheme {
+    /// Slippy map rows counted from the north, as used internally
+    #[default]
+    Xyz,
+    /// Rows counted from the south
+    Tms,
+}
+
+impl TileScheme {
+    /// Convert a row in this scheme to XYZ numbering; the conversion is its own inverse
+    pub fn to_xyz(self, z: u8, y: u32) -> Option<u32> {
+        match self {
+            TileScheme::Xyz => Some(y),
+            TileScheme::Tms => 1u32.checked_shl(z.into())?.checked_sub(1)?.checked_sub(y),
+        }
+    }
+}
+
+impl std::str::FromStr for TileScheme {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "xyz" => Ok(TileScheme::Xyz),
+            "tms" => Ok(TileScheme::Tms),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Tile encoding requested by the client, derived from the filename extension
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TileFormat {
+    #[default]
+    Png,
+    /// Requested as `.jpg` or `.jpeg`
+    Jpeg,
+    Webp,
+    /// Mapbox vector tile, requested as `.pbf` or `.mvt`
+    Mvt,
+    /// UTFGrid interactivity grid, requested as `.grid.json`
+    Grid,
+}
+
+impl TileFormat {
+    pub const ALL: [TileFormat; 5] = [
+        TileFormat::Png,
+        TileFormat::Jpeg,
+        TileFormat::Webp,
+        TileFormat::Mvt,
+        TileFormat::Grid,
+    ];
+
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        match ext {
+            "png" => Some(TileFormat::Png),
+            "jpg" | "jpeg" => Some(TileFormat::Jpeg),
+            "webp" => Some(TileFormat::Webp),
+            "pbf" | "mvt" => Some(TileFormat::Mvt),
+            "grid.json" => Some(TileFormat::Grid),
+            _ => None,
+        }
+    }
+
+    /// Canonical extension, used for cache paths and upstream URLs
+    pub fn extension(self) -> &'static str {
+        match self {
+            TileFormat::Png => "png",
+            TileFormat::Jpeg => "jpg",
+            TileFormat::Webp => "webp",
+            TileFormat::Mvt => "pbf",
+            TileFormat::Grid => "grid.json",
+        }
+    }
+
+    /// Content type used when upstream didn't provide one
+    pub fn content_type(self) -> &'static str {
+        match self {
+            TileFormat::Png => "image/png",
+            TileFormat::Jpeg => "image/jpeg",
+            TileFormat::Webp => "image/webp",
+            TileFormat::Mvt => "application/vnd.mapbox-vector-tile",
+            TileFormat::Grid => "application/json",
+        }
+    }
+
+    /// Whether tiles are images, which can be resampled, transcoded and stamped
+    pub fn is_raster(self) -> bool {
+        matches!(self, TileFormat::Png | TileFormat::Jpeg | TileFormat::Webp)
+    }
+
+    /// Whether a file's leading bytes could be a complete tile of this format.
+    /// Raster tiles accept any image signature, since upstreams don't always
+    /// match the extension they're requested with.
+    pub fn plausible_header(self, head: &[u8]) -> bool {
+        match self {
+            TileFormat::Mvt | TileFormat::Grid => !head.is_empty(),
+            TileFormat::Png | TileFormat::Jpeg | TileFormat::Webp => {
+                head.starts_with(b"\x89PNG")
+                    || head.starts_with(&[0xff, 0xd8, 0xff])
+                    || (head.starts_with(b"RIFF") && head.get(8..12) == Some(b"WEBP"))
+            }
+        }
     }
 }
 
@@ -36,10 +271,136 @@ impl std::fmt::Display for TileKey {
 pub struct TileData {
     pub data: Bytes,
     pub etag: Option<String>,
+    pub content_type: Option<String>,
+    /// Encoding of `data` as stored, e.g. `gzip` for most vector tiles
+    pub content_encoding: Option<String>,
+    /// Upstream `Last-Modified` date, as sent
+    pub last_modified: Option<String>,
+    /// Freshness lifetime granted by upstream `Cache-Control` or `Expires`
+    pub max_age: Option<Duration>,
+    /// Built from an ancestor tile; replaced once the real tile is fetched
+    pub synthesized: bool,
+    /// When the tile was fetched from upstream or last revalidated, if known
+    pub fetched_at: Option<SystemTime>,
+    /// Upstream headers on the `passthrough_headers` allow-list, replayed
+    /// with the tile
+    pub upstream_headers: Vec<(String, String)>,
 }
 
 impl TileData {
     pub fn new(data: Bytes, etag: Option<String>) -> Self {
-        Self { data, etag }
+        Self {
+            data,
+            etag,
+            content_type: None,
+            content_encoding: None,
+            last_modified: None,
+            max_age: None,
+            synthesized: false,
+            fetched_at: None,
+            upstream_headers: Vec::new(),
+        }
+    }
+
+    /// Time since the tile was fetched or last revalidated, if known
+    pub fn age(&self) -> Option<Duration> {
+        self.fetched_at?.elapsed().ok()
+    }
+
+    pub fn is_gzipped(&self) -> bool {
+        self.content_encoding
+            .as_deref()
+            .is_some_and(|e| e.eq_ignore_ascii_case("gzip"))
+    }
+
+    /// Give tiles from upstreams that send no ETag a strong validator derived
+    /// from their bytes, so clients can still revalidate
+    pub fn ensure_etag(&mut self) {
+        if self.etag.is_none() {
+            let digest = Sha256::digest(&self.data);
+            let hash: String = digest[..16].iter().map(|b| format!("{:02x}", b)).collect();
+            self.etag = Some(format!("{}{}\"", GENERATED_ETAG_PREFIX, hash));
+        }
+    }
+}
+
+/// Marks etags computed by `TileData::ensure_etag` rather than sent upstream
+const GENERATED_ETAG_PREFIX: &str = "\"mtc-";
+
+/// Whether an etag was computed locally and so means nothing to the upstream
+pub fn is_generated_etag(etag: &str) -> bool {
+    etag.starts_with(GENERATED_ETAG_PREFIX)
+}
+
+/// Stored validators for a conditional upstream request
+#[derive(Debug, Default, Clone)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// A key inside the grid, in any format and scale
+    fn tile_key() -> impl Strategy<Value = TileKey> {
+        (0u8..=31, any::<u32>(), any::<u32>(), 0..TileFormat::ALL.len(), any::<bool>()).prop_map(
+            |(z, x, y, format, retina)| {
+                let size = 1u64 << z;
+                TileKey::new(z, (x as u64 % size) as u32, (y as u64 % size) as u32)
+                    .with_format(TileFormat::ALL[format])
+                    .with_scale(if retina { 2 } else { 1 })
+            },
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn tile_path_round_trips(key in tile_key()) {
+            let path = TilePath::new(key).unwrap();
+            let printed = path.to_string();
+            prop_assert_eq!(TilePath::parse(&printed), Some(path));
+            prop_assert_eq!(TilePath::parse(&printed).unwrap().to_string(), printed);
+        }
+
+        #[test]
+        fn tile_path_rejects_signs(key in tile_key(), sign in "[+-]", part in 0..3usize) {
+            let mut parts: Vec<String> =
+                key.to_string().splitn(3, '/').map(str::to_string).collect();
+            parts[part].insert_str(0, &sign);
+            prop_assert_eq!(TilePath::parse(&parts.join("/")), None);
+        }
+
+        #[test]
+        fn tile_path_rejects_leading_zeros(key in tile_key(), zeros in "0{1,3}", part in 0..3usize) {
+            let mut parts: Vec<String> =
+                key.to_string().splitn(3, '/').map(str::to_string).collect();
+            parts[part].insert_str(0, &zeros);
+            prop_assert_eq!(TilePath::parse(&parts.join("/")), None);
+        }
+
+        #[test]
+        fn tile_path_rejects_parent_components(key in tile_key(), part in 0..4usize) {
+            let mut parts: Vec<String> =
+                key.to_string().splitn(3, '/').map(str::to_string).collect();
+            parts.insert(part, "..".to_string());
+            prop_assert_eq!(TilePath::parse(&parts.join("/")), None);
+            let printed = key.to_string();
+            prop_assert_eq!(TilePath::parse(&printed.replace('/', "/../")), None);
+        }
+
+        #[test]
+        fn tile_path_rejects_oversized_coordinates(z in 0u8..=31, over in 0u64..1 << 33) {
+            let size = 1u64 << z;
+            let outside = size + over % (u32::MAX as u64 + 1 - size).max(1);
+            prop_assert_eq!(TilePath::parse(&format!("{}/{}/0.png", z, outside)), None);
+            prop_assert_eq!(TilePath::parse(&format!("{}/0/{}.png", z, outside)), None);
+            prop_assert_eq!(TilePath::parse(&format!("{}/0/0.png", 32 + over % 224)), None);
+            // Past u32 and u8, where a wrapping parse would land back inside the grid
+            prop_assert_eq!(TilePath::parse(&format!("{}/{}/0.png", z, (1u64 << 32) + over)), None);
+            prop_assert_eq!(TilePath::parse(&format!("{}/0/0.png", 256 + over)), None);
+        }
     }
 }
@@ -0,0 +1,308 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Longest a mirror stays quarantined after repeated failed probes
+const MAX_QUARANTINE: Duration = Duration::from_secs(10 * 60);
+
+/// Successful requests whose latency the median is taken over
+const LATENCY_WINDOW: usize = 64;
+
+/// Weight of the newest outcome in the error rate moving average
+const ERROR_RATE_ALPHA: f64 = 0.1;
+
+/// Per-mirror circuit breaker.
+///
+/// After `failure_threshold` consecutive transient failures a mirror is
+/// quarantined. Once the quarantine expires a single probe request is let
+/// through: success closes the breaker, failure re-opens it for twice as long.
+/// A mirror asking to be left alone with `Retry-After` is paused for that
+/// long instead, without counting against its health.
+pub struct MirrorHealth {
+    failure_threshold: u32,
+    quarantine: Duration,
+    state: Mutex<HealthState>,
+}
+
+#[derive(Default)]
+struct HealthState {
+    consecutive_failures: u32,
+    /// Times the breaker re-opened without a successful request in between
+    trips: u32,
+    open_until: Option<Instant>,
+    probing: bool,
+    /// Until when the mirror asked not to be sent requests
+    paused_until: Option<Instant>,
+    /// Latencies of the most recent successful requests
+    latencies: VecDeque<Duration>,
+    /// Moving average of the share of requests failing
+    error_rate: f64,
+}
+
+impl MirrorHealth {
+    pub fn new(failure_threshold: u32, quarantine: Duration) -> Self {
+        Self {
+            failure_threshold: failure_threshold.max(1),
+            quarantine,
+            state: Mutex::new(HealthState::default()),
+        }
+    }
+
+    /// Leave to send a request to this mirror now, claiming the probe slot if
+    /// the quarantine has just expired
+    pub fn try_acquire(&self) -> Option<Admission<'_>> {
+        let mut state = self.state.lock().expect("mirror health lock poisoned");
+        if state.paused_until.is_some_and(|until| Instant::now() < until) {
+            return None;
+        }
+        let probe = match state.open_until {
+            None => false,
+            Some(until) if Instant::now() >= until && !state.probing => {
+                state.probing = true;
+                true
+            }
+            Some(_) => return None,
+        };
+        Some(Admission {
+            health: self,
+            probe,
+        })
+    }
+
+    /// Leave to send a request whatever the mirror's state, for when every
+    /// mirror is quarantined
+    pub fn admit(&self) -> Admission<'_> {
+        Admission {
+            health: self,
+            probe: false,
+        }
+    }
+
+    /// Record a successful request, returning whether it closed an open breaker
+    fn record_success(&self, latency: Duration) -> bool {
+        let mut state = self.state.lock().expect("mirror health lock poisoned");
+        let recovered = state.open_until.is_some();
+        state.consecutive_failures = 0;
+        state.trips = 0;
+        state.open_until = None;
+        state.probing = false;
+        if state.latencies.len() == LATENCY_WINDOW {
+            state.latencies.pop_front();
+        }
+        state.latencies.push_back(latency);
+        state.error_rate *= 1.0 - ERROR_RATE_ALPHA;
+        recovered
+    }
+
+    /// Record a transient failure, returning the quarantine duration if the
+    /// breaker opened. Only the failure opening a closed breaker and failed
+    /// probes lengthen the quarantine; failures of requests already in flight,
+    /// or let through while every mirror is quarantined, leave it as it is.
+    fn record_failure(&self, probe: bool) -> Option<Duration> {
+        let mut state = self.state.lock().expect("mirror health lock poisoned");
+        state.consecutive_failures += 1;
+        state.error_rate = state.error_rate * (1.0 - ERROR_RATE_ALPHA) + ERROR_RATE_ALPHA;
+
+        let opens = state.open_until.is_none()
+            && state.consecutive_failures >= self.failure_threshold;
+        if !probe && !opens {
+            return None;
+        }
+
+        let quarantine = self
+            .quarantine
+            .saturating_mul(1 << state.trips.min(16))
+            .min(MAX_QUARANTINE);
+        state.trips += 1;
+        state.probing = false;
+        state.open_until = Some(Instant::now() + quarantine);
+        Some(quarantine)
+    }
+
+    /// Hold off requests to this mirror for as long as it asked, up to the
+    /// longest quarantine, returning the pause applied. An open breaker is
+    /// probed again once the pause is over.
+    fn pause(&self, duration: Duration) -> Duration {
+        let duration = duration.min(MAX_QUARANTINE);
+        let until = Instant::now() + duration;
+        let mut state = self.state.lock().expect("mirror health lock poisoned");
+        state.paused_until = Some(state.paused_until.map_or(until, |paused| paused.max(until)));
+        if let Some(open_until) = &mut state.open_until {
+            *open_until = (*open_until).max(until);
+        }
+        state.probing = false;
+        duration
+    }
+
+    /// Time left before the mirror may be sent requests again, zero when it isn't paused
+    pub fn paused_for(&self) -> Duration {
+        let state = self.state.lock().expect("mirror health lock poisoned");
+        state
+            .paused_until
+            .map_or(Duration::ZERO, |until| until.saturating_duration_since(Instant::now()))
+    }
+
+    /// Median latency of recent successful requests
+    pub fn latency(&self) -> Option<Duration> {
+        let state = self.state.lock().expect("mirror health lock poisoned");
+        let mut latencies: Vec<_> = state.latencies.iter().copied().collect();
+        latencies.sort_unstable();
+        latencies.get(latencies.len() / 2).copied()
+    }
+
+    /// Recent share of requests that failed, from 0 to 1
+    pub fn error_rate(&self) -> f64 {
+        self.state.lock().expect("mirror health lock poisoned").error_rate
+    }
+}
+
+/// Leave to send one request to a mirror, through which its outcome is
+/// recorded. Dropped unrecorded, e.g. when the request is cancelled, it gives
+/// back the probe slot it may hold, so the mirror gets probed again.
+#[must_use]
+pub struct Admission<'a> {
+    health: &'a MirrorHealth,
+    /// Holds the half-open breaker's single probe slot
+    probe: bool,
+}
+
+impl Admission<'_> {
+    /// Record a successful request, returning whether it closed an open breaker
+    pub fn record_success(mut self, latency: Duration) -> bool {
+        self.probe = false;
+        self.health.record_success(latency)
+    }
+
+    /// Record a transient failure, returning the quarantine duration if the breaker opened
+    pub fn record_failure(mut self) -> Option<Duration> {
+        let probe = std::mem::take(&mut self.probe);
+        self.health.record_failure(probe)
+    }
+
+    /// Record that the mirror asked to be left alone for `duration`,
+    /// returning the pause applied
+    pub fn pause(mut self, duration: Duration) -> Duration {
+        self.probe = false;
+        self.health.pause(duration)
+    }
+}
+
+impl Drop for Admission<'_> {
+    fn drop(&mut self) {
+        if self.probe {
+            self.health.state.lock().expect("mirror health lock poisoned").probing = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUARANTINE: Duration = Duration::from_millis(20);
+
+    fn fail(health: &MirrorHealth) -> Option<Duration> {
+        health.try_acquire().expect("mirror should be admitted").record_failure()
+    }
+
+    /// A breaker opened by `threshold` failures whose quarantine has run out
+    fn expired(threshold: u32) -> MirrorHealth {
+        let health = MirrorHealth::new(threshold, QUARANTINE);
+        for _ in 0..threshold {
+            fail(&health);
+        }
+        std::thread::sleep(QUARANTINE);
+        health
+    }
+
+    #[test]
+    fn opens_after_threshold() {
+        let health = MirrorHealth::new(3, QUARANTINE);
+        assert_eq!(fail(&health), None);
+        assert_eq!(fail(&health), None);
+        assert_eq!(fail(&health), Some(QUARANTINE));
+        assert!(health.try_acquire().is_none());
+    }
+
+    #[test]
+    fn failures_while_open_leave_quarantine_alone() {
+        let health = MirrorHealth::new(2, QUARANTINE);
+        let opened: Vec<_> = (0..8).map(|_| health.admit().record_failure()).collect();
+        assert_eq!(opened[..2], [None, Some(QUARANTINE)]);
+        assert!(opened[2..].iter().all(Option::is_none));
+        // Still the first quarantine, and the probe after it only the first doubling
+        std::thread::sleep(QUARANTINE);
+        assert_eq!(health.try_acquire().unwrap().record_failure(), Some(QUARANTINE * 2));
+    }
+
+    #[test]
+    fn success_resets_failure_count() {
+        let health = MirrorHealth::new(2, QUARANTINE);
+        assert_eq!(fail(&health), None);
+        assert!(!health.try_acquire().unwrap().record_success(Duration::from_millis(5)));
+        assert_eq!(fail(&health), None);
+        assert!(health.try_acquire().is_some());
+    }
+
+    #[test]
+    fn lets_a_single_probe_through() {
+        let health = expired(1);
+        let probe = health.try_acquire().expect("probe should be admitted");
+        assert!(health.try_acquire().is_none());
+        assert!(probe.record_failure().is_some());
+        assert!(health.try_acquire().is_none());
+    }
+
+    #[test]
+    fn failed_probe_doubles_quarantine() {
+        let health = expired(1);
+        assert_eq!(health.try_acquire().unwrap().record_failure(), Some(QUARANTINE * 2));
+    }
+
+    #[test]
+    fn successful_probe_closes_breaker() {
+        let health = expired(1);
+        assert!(health.try_acquire().unwrap().record_success(Duration::from_millis(5)));
+        assert!(health.try_acquire().is_some());
+        assert!(health.try_acquire().is_some());
+        assert_eq!(health.latency(), Some(Duration::from_millis(5)));
+    }
+
+    #[test]
+    fn pause_holds_off_requests() {
+        let health = MirrorHealth::new(3, QUARANTINE);
+        assert_eq!(health.try_acquire().unwrap().pause(QUARANTINE), QUARANTINE);
+        assert!(health.try_acquire().is_none());
+        assert!(!health.paused_for().is_zero());
+        std::thread::sleep(QUARANTINE);
+        assert!(health.paused_for().is_zero());
+        assert!(health.try_acquire().is_some());
+    }
+
+    #[test]
+    fn probe_answered_with_pause_is_probed_again_after_it() {
+        let health = expired(1);
+        let probe = health.try_acquire().expect("probe should be admitted");
+        probe.pause(QUARANTINE * 2);
+        assert!(health.try_acquire().is_none());
+        std::thread::sleep(QUARANTINE);
+        // The pause outlasts the quarantine it interrupted
+        assert!(health.try_acquire().is_none());
+        std::thread::sleep(QUARANTINE);
+        let probe = health.try_acquire().expect("mirror should be probed again");
+        assert!(health.try_acquire().is_none());
+        assert!(probe.record_success(Duration::from_millis(5)));
+        assert!(health.try_acquire().is_some());
+    }
+
+    #[test]
+    fn dropped_probe_releases_slot() {
+        let health = expired(1);
+        let probe = health.try_acquire().expect("probe should be admitted");
+        assert!(health.try_acquire().is_none());
+        drop(probe);
+        let probe = health.try_acquire().expect("slot should be free again");
+        assert!(health.try_acquire().is_none());
+        assert!(probe.record_success(Duration::from_millis(5)));
+    }
+}
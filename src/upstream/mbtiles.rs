@@ -0,0 +1,123 @@
+use crate::cache::TileStore;
+use crate::error::Result;
+use crate::types::{TileData, TileKey};
+use async_trait::async_trait;
+use bytes::Bytes;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Read-only tile source backed by one or more MBTiles archives, checked in order
+#[derive(Clone)]
+pub struct MbtilesSource {
+    archives: Arc<[Archive]>,
+}
+
+struct Archive {
+    path: PathBuf,
+    conn: Mutex<Connection>,
+}
+
+impl Archive {
+    fn open(path: &Path) -> Result<Self> {
+        let conn = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn get(&self, key: &TileKey) -> Result<Option<Bytes>> {
+        // MBTiles rows follow the TMS scheme, counting from the south
+        let tms_row = (1u32 << key.z) - 1 - key.y;
+        let conn = self.conn.lock().expect("mbtiles connection lock poisoned");
+        let data = conn
+            .query_row(
+                "SELECT tile_data FROM tiles WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3",
+                params![key.z, key.x, tms_row],
+                |row| row.get::<_, Vec<u8>>(0),
+            )
+            .optional()?;
+        Ok(data.map(Bytes::from))
+    }
+}
+
+impl MbtilesSource {
+    pub fn open(paths: &[PathBuf]) -> Result<Self> {
+        let archives = paths
+            .iter()
+            .map(|path| {
+                let archive = Archive::open(path)?;
+                tracing::info!(path = ?path, "Mounted MBTiles archive");
+                Ok(archive)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            archives: archives.into(),
+        })
+    }
+
+    /// Look a tile up in each archive, returning the first match
+    pub async fn get(&self, key: &TileKey) -> Option<TileData> {
+        if self.archives.is_empty() {
+            return None;
+        }
+
+        let archives = self.archives.clone();
+        let key = *key;
+        tokio::task::spawn_blocking(move || {
+            archives.iter().find_map(|archive| match archive.get(&key) {
+                Ok(data) => data,
+                Err(e) => {
+                    tracing::warn!(path = ?archive.path, key = %key, error = %e, "MBTiles lookup failed");
+                    None
+                }
+            })
+        })
+        .await
+        .expect("mbtiles lookup panicked")
+        .map(|data| {
+            let mut tile = TileData::new(data, None);
+            // Vector tile archives usually hold gzip-compressed blobs
+            if tile.data.starts_with(&[0x1f, 0x8b]) {
+                tile.content_encoding = Some("gzip".to_string());
+            }
+            tile
+        })
+    }
+}
+
+/// A read-only tier; archives only hold standard-resolution tiles
+#[async_trait]
+impl TileStore for MbtilesSource {
+    fn name(&self) -> &'static str {
+        "mbtiles"
+    }
+
+    async fn get(&self, key: &TileKey) -> Result<Option<Arc<TileData>>> {
+        if key.scale != 1 {
+            return Ok(None);
+        }
+        let Some(mut tile) = MbtilesSource::get(self, key).await else {
+            return Ok(None);
+        };
+        tile.ensure_etag();
+        Ok(Some(Arc::new(tile)))
+    }
+
+    async fn put(&self, _key: &TileKey, _tile: Arc<TileData>) -> Result<()> {
+        Ok(())
+    }
+
+    async fn delete(&self, _key: &TileKey) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn writable(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,87 @@
+use crate::config::{Config, UpstreamConfig};
+use crate::error::{AppError, Result};
+use crate::processing::metatile;
+use crate::types::{TileData, TileKey, Validators};
+use crate::upstream::{FetchResult, OsmFetcher};
+
+/// Largest metatile accepted, in tiles across
+const MAX_METATILE_SIZE: u32 = 16;
+
+/// Fetches square blocks of raster tiles as one image from an upstream that
+/// renders metatiles, and cuts them into tiles
+pub struct MetatileFetcher {
+    fetcher: OsmFetcher,
+    /// Tiles across a metatile
+    size: u32,
+}
+
+impl MetatileFetcher {
+    /// Fetcher for the configured metatile upstreams, None when metatiles are off
+    pub fn new(config: &Config) -> anyhow::Result<Option<Self>> {
+        if config.metatile_size <= 1 {
+            return Ok(None);
+        }
+        if config.metatile_size > MAX_METATILE_SIZE {
+            anyhow::bail!("metatile_size may be at most {}", MAX_METATILE_SIZE);
+        }
+        if config.metatile_upstreams.is_empty() {
+            anyhow::bail!("metatile_size needs metatile_upstreams");
+        }
+        let size = config.metatile_size;
+        let upstreams = config
+            .metatile_upstreams
+            .iter()
+            .map(|upstream| UpstreamConfig {
+                url: upstream.url.replace("{size}", &size.to_string()),
+                ..upstream.clone()
+            })
+            .collect();
+        let fetcher = OsmFetcher::new(&Config {
+            upstreams,
+            ..config.clone()
+        })?;
+        Ok(Some(Self { fetcher, size }))
+    }
+
+    /// Whether the tile is fetched as part of a metatile; only images can be cut
+    pub fn covers(&self, key: &TileKey) -> bool {
+        key.format.is_raster()
+    }
+
+    /// Top-left tile of the metatile holding `key`
+    pub fn origin(&self, key: &TileKey) -> TileKey {
+        TileKey {
+            x: key.x / self.size * self.size,
+            y: key.y / self.size * self.size,
+            ..*key
+        }
+    }
+
+    /// Fetch the metatile starting at `origin` and cut it into its tiles; at
+    /// low zooms it covers the whole world and holds fewer
+    pub async fn fetch(&self, origin: &TileKey) -> Result<Vec<(TileKey, TileData)>> {
+        let FetchResult::Data(image) = self.fetcher.fetch(origin, &Validators::default()).await?
+        else {
+            return Err(AppError::NotFound);
+        };
+        let count = self.size.min(1 << origin.z.min(31));
+        let format = origin.format;
+        let tiles = tokio::task::spawn_blocking(move || metatile::split(&image, count, format))
+            .await
+            .expect("metatile split task panicked")?;
+        let origin = *origin;
+        Ok(tiles
+            .into_iter()
+            .enumerate()
+            .map(|(i, tile)| {
+                let i = i as u32;
+                let key = TileKey {
+                    x: origin.x + i % count,
+                    y: origin.y + i / count,
+                    ..origin
+                };
+                (key, tile)
+            })
+            .collect())
+    }
+}
@@ -1,3 +1,14 @@
+pub mod health;
+pub mod mbtiles;
+pub mod metatile;
 pub mod osm;
+pub mod pmtiles;
+pub mod rate_limit;
+pub mod retry;
+pub mod scheduler;
 
+pub use mbtiles::MbtilesSource;
+pub use metatile::MetatileFetcher;
+pub(crate) use osm::client_builder;
 pub use osm::{FetchResult, OsmFetcher};
+pub use pmtiles::PmtilesSource;
@@ -1,84 +1,618 @@
-use crate::config::Config;
+use crate::config::{Config, UpstreamConfig};
 use crate::error::{AppError, Result};
-use crate::types::{TileData, TileKey};
-use reqwest::Client;
+use crate::types::{TileData, TileKey, Validators};
+use crate::upstream::health::{Admission, MirrorHealth};
+use crate::upstream::retry::RetryPolicy;
+use crate::upstream::scheduler::UpstreamScheduler;
+use bytes::Bytes;
+use memmap2::Mmap;
+use reqwest::header::{
+    HeaderMap, HeaderName, HeaderValue, AGE, CACHE_CONTROL, CONNECTION, CONTENT_ENCODING,
+    CONTENT_LENGTH, CONTENT_TYPE, DATE, ETAG, EXPIRES, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+    LAST_MODIFIED, RETRY_AFTER, TRANSFER_ENCODING, VARY,
+};
+use reqwest::{Client, ClientBuilder, NoProxy, Proxy, RequestBuilder, Response};
+use serde::Deserialize;
+use std::env;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::AsyncWriteExt;
+
+/// Error rate above which a mirror's share stops shrinking, so it keeps
+/// getting enough requests to notice when it recovers
+const MAX_ERROR_RATE: f64 = 0.95;
+
+/// Longest a fetch waits for a paused mirror before giving up, leaving the
+/// caller to serve stale or pass the wait on to the client
+const MAX_RETRY_WAIT: Duration = Duration::from_secs(5);
+
+/// Bodies growing past this are written to a spool file as they download
+/// and mapped back, rather than held in memory
+const SPOOL_MIN_LEN: usize = 1024 * 1024;
+/// Directory under the cache directory holding spool files
+const SPOOL_DIR: &str = "spool";
+
+/// Headers the proxy sets from the tile itself, which `passthrough_headers`
+/// can't override
+const MANAGED_HEADERS: [HeaderName; 9] = [
+    AGE,
+    CONNECTION,
+    CONTENT_ENCODING,
+    CONTENT_LENGTH,
+    CONTENT_TYPE,
+    ETAG,
+    LAST_MODIFIED,
+    TRANSFER_ENCODING,
+    VARY,
+];
+
+/// How a mirror is chosen for each upstream request
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamSelection {
+    /// Each mirror in turn
+    RoundRobin,
+    /// At random, favoring mirrors with a lower median latency and error
+    /// rate, unless upstreams have pinned weights
+    #[default]
+    Latency,
+}
+
+impl FromStr for UpstreamSelection {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "round_robin" => Ok(UpstreamSelection::RoundRobin),
+            "latency" => Ok(UpstreamSelection::Latency),
+            _ => Err(()),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct OsmFetcher {
     client: Client,
-    servers: Vec<&'static str>,
+    servers: Arc<[Upstream]>,
+    health: Arc<[MirrorHealth]>,
     current_server: Arc<AtomicUsize>,
+    selection: UpstreamSelection,
+    /// Caps parallel upstream downloads and their rate, live requests first
+    scheduler: Arc<UpstreamScheduler>,
+    retry: RetryPolicy,
+    /// Largest body accepted, 0 for no limit
+    max_tile_bytes: u64,
+    spool_dir: PathBuf,
+    /// Response headers kept with fetched tiles
+    passthrough_headers: Arc<[HeaderName]>,
 }
 
 impl OsmFetcher {
     pub fn new(config: &Config) -> Result<Self> {
-        let client = Client::builder()
-            .user_agent(&config.user_agent)
-            .timeout(config.upstream_timeout)
-            .pool_max_idle_per_host(10)
-            .pool_idle_timeout(std::time::Duration::from_secs(90))
+        if config.upstreams.is_empty() {
+            return Err(AppError::NoUpstreams);
+        }
+
+        let client = client_builder(config)?
             .build()
             .map_err(AppError::Upstream)?;
 
+        let servers = config
+            .upstreams
+            .iter()
+            .map(Upstream::new)
+            .collect::<Result<Vec<_>>>()?;
+
         Ok(Self {
             client,
-            servers: vec![
-                "a.tile.openstreetmap.org",
-                "b.tile.openstreetmap.org",
-                "c.tile.openstreetmap.org",
-            ],
+            servers: servers.into(),
+            health: config
+                .upstreams
+                .iter()
+                .map(|_| MirrorHealth::new(config.mirror_failure_threshold, config.mirror_quarantine))
+                .collect(),
             current_server: Arc::new(AtomicUsize::new(0)),
+            selection: config.upstream_selection,
+            scheduler: Arc::new(UpstreamScheduler::new(
+                config.upstream_max_concurrency,
+                config.upstream_rate_limit,
+            )),
+            retry: RetryPolicy::from_config(config),
+            max_tile_bytes: config.max_tile_bytes,
+            spool_dir: config.cache_dir.join(SPOOL_DIR),
+            passthrough_headers: passthrough_headers(&config.passthrough_headers)?.into(),
         })
     }
 
-    /// Get next server using round-robin
-    fn next_server(&self) -> &'static str {
-        let idx = self.current_server.fetch_add(1, Ordering::Relaxed) % self.servers.len();
-        self.servers[idx]
+    /// Shared HTTP client, reused by other remote sources
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Pick a healthy server by the configured strategy, falling back to
+    /// plain round-robin when every mirror is quarantined
+    fn next_server(&self) -> (usize, Admission<'_>) {
+        let start = self.current_server.fetch_add(1, Ordering::Relaxed);
+        let admit = |idx: usize| Some((idx, self.health[idx].try_acquire()?));
+        let healthy = match self.selection {
+            UpstreamSelection::RoundRobin => (0..self.servers.len())
+                .map(|offset| (start + offset) % self.servers.len())
+                .find_map(admit),
+            UpstreamSelection::Latency => {
+                let mut weights = self.weights();
+                // Redraw without any mirror found quarantined
+                std::iter::from_fn(|| {
+                    let idx = pick_weighted(&weights)?;
+                    weights[idx] = 0.0;
+                    Some(idx)
+                })
+                .find_map(admit)
+            }
+        };
+        healthy.unwrap_or_else(|| {
+            let idx = start % self.servers.len();
+            (idx, self.health[idx].admit())
+        })
+    }
+
+    /// Time until the first paused mirror may be asked again, zero while any
+    /// mirror isn't paused
+    fn shortest_pause(&self) -> Duration {
+        self.health.iter().map(MirrorHealth::paused_for).min().unwrap_or_default()
+    }
+
+    /// Share of requests for each mirror: its pinned weight when any mirror
+    /// has one, else its speed relative to the fastest mirror, reduced by its
+    /// error rate. Mirrors without measurements count as the fastest.
+    fn weights(&self) -> Vec<f64> {
+        if self.servers.iter().any(|upstream| upstream.weight.is_some()) {
+            return self
+                .servers
+                .iter()
+                .map(|upstream| upstream.weight.unwrap_or(1.0).max(0.0))
+                .collect();
+        }
+        let latencies: Vec<_> = self.health.iter().map(MirrorHealth::latency).collect();
+        let fastest = latencies.iter().flatten().min().copied();
+        latencies
+            .iter()
+            .zip(self.health.iter())
+            .map(|(latency, health)| {
+                let speed = match (fastest, latency) {
+                    (Some(fastest), Some(latency)) => {
+                        fastest.as_secs_f64().max(1e-3) / latency.as_secs_f64().max(1e-3)
+                    }
+                    _ => 1.0,
+                };
+                speed * (1.0 - health.error_rate().min(MAX_ERROR_RATE))
+            })
+            .collect()
+    }
+
+    fn tile_url(&self, server: usize, key: &TileKey) -> String {
+        self.servers[server]
+            .template
+            .replace("{z}", &key.z.to_string())
+            .replace("{x}", &key.x.to_string())
+            .replace("{y}", &key.y.to_string())
+            .replace("{ext}", key.format.extension())
+            .replace("{r}", key.scale_suffix())
+    }
+
+    /// Check that a mirror answers a HEAD request for the world tile in time
+    pub async fn probe(&self, timeout: Duration) -> Result<()> {
+        self.scheduler.acquire_token().await;
+        let (server, _admission) = self.next_server();
+        let url = self.tile_url(server, &TileKey::new(0, 0, 0));
+        let upstream = &self.servers[server];
+        let response = upstream
+            .authorize(self.client.head(&url))
+            .timeout(timeout)
+            .send()
+            .await
+            .map_err(|e| upstream.redact(e))?;
+        if response.status().is_server_error() {
+            return Err(AppError::UpstreamStatus(response.status().as_u16()));
+        }
+        Ok(())
     }
 
-    fn tile_url(&self, key: &TileKey) -> String {
-        let server = self.next_server();
-        format!("https://{}/{}/{}/{}.png", server, key.z, key.x, key.y)
+    /// Fetch a tile, retrying retryable failures against the next mirror. With
+    /// every mirror paused, waits for the first back unless that's too long.
+    pub async fn fetch(&self, key: &TileKey, validators: &Validators) -> Result<FetchResult> {
+        let mut attempt = 0;
+        loop {
+            match self.fetch_once(key, validators).await {
+                Err(e)
+                    if e.is_retryable()
+                        && attempt < self.retry.max_retries
+                        && self.shortest_pause() <= MAX_RETRY_WAIT =>
+                {
+                    let delay = self.retry.delay(attempt).max(self.shortest_pause());
+                    tracing::debug!(
+                        key = %key,
+                        attempt = attempt + 1,
+                        delay_ms = delay.as_millis() as u64,
+                        error = %e,
+                        "Retrying upstream fetch"
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
     }
 
-    pub async fn fetch(&self, key: &TileKey, etag: Option<&str>) -> Result<FetchResult> {
-        let url = self.tile_url(key);
+    async fn fetch_once(&self, key: &TileKey, validators: &Validators) -> Result<FetchResult> {
+        // Held until the body has been read
+        let _permit = self.scheduler.acquire().await;
+
+        let (server, admission) = self.next_server();
+        let health = &self.health[server];
+        // Only picked when every mirror is paused or quarantined
+        let paused = health.paused_for();
+        if !paused.is_zero() {
+            return Err(AppError::UpstreamRateLimited { retry_after: Some(paused) });
+        }
 
-        let mut request = self.client.get(&url);
+        let url = self.tile_url(server, key);
+        let started = Instant::now();
+        let result = self.request(server, &url, key, validators).await;
 
-        if let Some(etag) = etag {
-            request = request.header("If-None-Match", etag);
+        match &result {
+            Err(e) if e.retry_after().is_some() => {
+                let pause = admission.pause(e.retry_after().unwrap_or_default());
+                tracing::info!(
+                    server = %self.servers[server].template,
+                    pause_secs = pause.as_secs(),
+                    "Pausing upstream mirror as asked by Retry-After"
+                );
+            }
+            Err(e) if e.is_transient() => {
+                if let Some(quarantine) = admission.record_failure() {
+                    tracing::warn!(
+                        server = %self.servers[server].template,
+                        quarantine_secs = quarantine.as_secs(),
+                        p50_latency_ms = health.latency().map(|l| l.as_millis() as u64),
+                        "Quarantining unhealthy upstream mirror"
+                    );
+                }
+            }
+            _ => {
+                if admission.record_success(started.elapsed()) {
+                    tracing::info!(
+                        server = %self.servers[server].template,
+                        "Upstream mirror recovered"
+                    );
+                }
+            }
         }
 
-        let response = request.send().await?;
+        result
+    }
+
+    async fn request(
+        &self,
+        server: usize,
+        url: &str,
+        key: &TileKey,
+        validators: &Validators,
+    ) -> Result<FetchResult> {
+        let upstream = &self.servers[server];
+        let mut request = upstream.authorize(self.client.get(url));
+
+        if let Some(etag) = &validators.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+
+        let response = request.send().await.map_err(|e| upstream.redact(e))?;
         let status = response.status();
 
         match status.as_u16() {
             200 => {
-                let etag = response
-                    .headers()
-                    .get("etag")
-                    .and_then(|v| v.to_str().ok())
-                    .map(|s| s.to_string());
+                let header = |name| {
+                    response
+                        .headers()
+                        .get(name)
+                        .and_then(|v| v.to_str().ok())
+                        .map(|s| s.to_string())
+                };
+                let etag = header(ETAG);
+                let content_type = header(CONTENT_TYPE);
+                let content_encoding = header(CONTENT_ENCODING);
+                let last_modified = header(LAST_MODIFIED);
+                let max_age = freshness_lifetime(response.headers());
+                let upstream_headers = self
+                    .passthrough_headers
+                    .iter()
+                    .flat_map(|name| {
+                        response.headers().get_all(name).iter().filter_map(|value| {
+                            Some((name.to_string(), value.to_str().ok()?.to_string()))
+                        })
+                    })
+                    .collect();
 
-                let data = response.bytes().await?;
+                let data = self.read_body(response, upstream).await?;
                 tracing::debug!(key = %key, size = data.len(), "Fetched tile from upstream");
-                Ok(FetchResult::Data(TileData::new(data, etag)))
+                let mut tile = TileData::new(data, etag);
+                tile.content_type = content_type;
+                tile.content_encoding = content_encoding;
+                tile.last_modified = last_modified;
+                tile.max_age = max_age;
+                tile.fetched_at = Some(SystemTime::now());
+                tile.upstream_headers = upstream_headers;
+                Ok(FetchResult::Data(tile))
             }
             304 => {
                 tracing::debug!(key = %key, "Tile not modified (304)");
                 Ok(FetchResult::NotModified)
             }
             404 => Err(AppError::NotFound),
+            code @ (429 | 503) => match retry_after(response.headers()) {
+                // A 503 without Retry-After is an outage rather than backpressure
+                None if code == 503 => Err(AppError::UpstreamStatus(code)),
+                retry_after => Err(AppError::UpstreamRateLimited { retry_after }),
+            },
             code => Err(AppError::UpstreamStatus(code)),
         }
     }
+
+    /// Read a response body up to `max_tile_bytes`, giving up as soon as it
+    /// is known to be larger. Large bodies are spooled to disk and mapped.
+    async fn read_body(&self, mut response: Response, upstream: &Upstream) -> Result<Bytes> {
+        let too_large = |len: u64| self.max_tile_bytes > 0 && len > self.max_tile_bytes;
+        let expected = response.content_length();
+        if expected.is_some_and(too_large) {
+            return Err(AppError::TileTooLarge(self.max_tile_bytes));
+        }
+
+        let capacity = expected.unwrap_or(0).min(SPOOL_MIN_LEN as u64) as usize;
+        let mut buffer = Vec::with_capacity(capacity);
+        let mut spool: Option<tokio::fs::File> = None;
+        let mut len = 0;
+        while let Some(chunk) = response.chunk().await.map_err(|e| upstream.redact(e))? {
+            len += chunk.len() as u64;
+            if too_large(len) {
+                return Err(AppError::TileTooLarge(self.max_tile_bytes));
+            }
+            match &mut spool {
+                Some(file) => file.write_all(&chunk).await?,
+                None if buffer.len() + chunk.len() > SPOOL_MIN_LEN => {
+                    let mut file = self.spool_file().await?;
+                    file.write_all(&buffer).await?;
+                    file.write_all(&chunk).await?;
+                    buffer = Vec::new();
+                    spool = Some(file);
+                }
+                None => buffer.extend_from_slice(&chunk),
+            }
+        }
+
+        let Some(mut file) = spool else {
+            return Ok(Bytes::from(buffer));
+        };
+        file.flush().await?;
+        let file = file.into_std().await;
+        // Safety: the spool file was unlinked when created and nothing writes
+        // to it once mapped
+        let mmap = unsafe { Mmap::map(&file)? };
+        Ok(Bytes::from_owner(mmap))
+    }
+
+    /// New spool file, already unlinked so it goes away with its last handle,
+    /// even if the proxy dies mid-download
+    async fn spool_file(&self) -> Result<tokio::fs::File> {
+        tokio::fs::create_dir_all(&self.spool_dir).await?;
+        let path = self.spool_dir.join(format!("{:016x}.tmp", rand::random::<u64>()));
+        let file = tokio::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await?;
+        if let Err(e) = tokio::fs::remove_file(&path).await {
+            tracing::warn!(path = ?path, error = %e, "Failed to unlink spool file");
+        }
+        Ok(file)
+    }
+}
+
+/// HTTP client settings shared by everything contacting remote servers: the
+/// user agent, timeouts, connection tuning and outbound proxy. Without
+/// `upstream_proxy`, the `HTTPS_PROXY`, `HTTP_PROXY`, `ALL_PROXY` and
+/// `NO_PROXY` variables apply.
+pub(crate) fn client_builder(config: &Config) -> Result<ClientBuilder> {
+    let enabled = |duration: Duration| (!duration.is_zero()).then_some(duration);
+    let mut builder = Client::builder()
+        .user_agent(&config.user_agent)
+        .timeout(config.upstream_timeout)
+        .tcp_keepalive(enabled(config.upstream_tcp_keepalive))
+        .pool_max_idle_per_host(config.upstream_pool_max_idle_per_host)
+        .pool_idle_timeout(config.upstream_pool_idle_timeout);
+    if let Some(timeout) = enabled(config.upstream_connect_timeout) {
+        builder = builder.connect_timeout(timeout);
+    }
+    if config.upstream_http2_prior_knowledge {
+        builder = builder.http2_prior_knowledge();
+    }
+    for (host, addr) in &config.upstream_dns_overrides {
+        // The port comes from the URL
+        builder = builder.resolve(host, SocketAddr::new(*addr, 0));
+    }
+    if let Some(url) = &config.upstream_proxy {
+        // Credentials in the URL's userinfo are sent as Proxy-Authorization
+        let proxy = Proxy::all(url.as_str())
+            .map_err(|e| AppError::InvalidProxy(e.without_url().to_string()))?
+            .no_proxy(NoProxy::from_string(&config.upstream_no_proxy.join(",")));
+        builder = builder.proxy(proxy);
+    }
+    Ok(builder)
+}
+
+/// An upstream URL template with the credentials to send it
+struct Upstream {
+    template: String,
+    /// Marked sensitive, so they're redacted wherever requests are debug-printed
+    headers: HeaderMap,
+    query: Vec<(String, String)>,
+    weight: Option<f64>,
+}
+
+impl Upstream {
+    fn new(config: &UpstreamConfig) -> Result<Self> {
+        let invalid = |reason: String| AppError::InvalidUpstream {
+            url: config.url.clone(),
+            reason,
+        };
+        let mut headers = HeaderMap::new();
+        for (name, value) in &config.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| invalid(format!("invalid header name {:?}", name)))?;
+            let mut value = HeaderValue::from_str(&expand_env(value).map_err(invalid)?)
+                .map_err(|_| invalid(format!("invalid value for header {}", name)))?;
+            value.set_sensitive(true);
+            headers.insert(name, value);
+        }
+        let query = config
+            .query
+            .iter()
+            .map(|(name, value)| Ok((name.clone(), expand_env(value).map_err(invalid)?)))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            template: config.url.clone(),
+            headers,
+            query,
+            weight: config.weight,
+        })
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        request.headers(self.headers.clone()).query(&self.query)
+    }
+
+    /// Drop the URL from an error when it carries credentials, so it can be logged
+    fn redact(&self, error: reqwest::Error) -> AppError {
+        if self.query.is_empty() {
+            error.into()
+        } else {
+            error.without_url().into()
+        }
+    }
+}
+
+/// Draw an index at random in proportion to its weight, None when all are 0
+fn pick_weighted(weights: &[f64]) -> Option<usize> {
+    let total: f64 = weights.iter().sum();
+    if total <= 0.0 {
+        return None;
+    }
+    let mut target = rand::random::<f64>() * total;
+    for (idx, &weight) in weights.iter().enumerate() {
+        if weight > 0.0 && target < weight {
+            return Some(idx);
+        }
+        target -= weight;
+    }
+    // Rounding left a remainder
+    weights.iter().rposition(|&weight| weight > 0.0)
+}
+
+/// Substitute `${NAME}` references with environment variables
+fn expand_env(value: &str) -> std::result::Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let Some(len) = rest[start..].find('}') else {
+            break;
+        };
+        let name = &rest[start + 2..start + len];
+        let var = env::var(name).map_err(|_| format!("environment variable {} is not set", name))?;
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(&var);
+        rest = &rest[start + len + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
 }
 
 pub enum FetchResult {
     Data(TileData),
     NotModified,
 }
+
+/// Parse the `passthrough_headers` allow-list, refusing names the proxy
+/// manages itself
+fn passthrough_headers(names: &[String]) -> Result<Vec<HeaderName>> {
+    names
+        .iter()
+        .map(|name| {
+            HeaderName::from_bytes(name.as_bytes())
+                .ok()
+                .filter(|header| !MANAGED_HEADERS.contains(header))
+                .ok_or_else(|| AppError::InvalidPassthroughHeader(name.clone()))
+        })
+        .collect()
+}
+
+/// How long to wait before asking again, from `Retry-After` in seconds or as
+/// a date relative to `Date`
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let retry_at = httpdate::parse_http_date(value).ok()?;
+    let now = headers
+        .get(DATE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok())
+        .unwrap_or_else(SystemTime::now);
+    Some(retry_at.duration_since(now).unwrap_or(Duration::ZERO))
+}
+
+/// How long a response may be cached, from `Cache-Control` (`s-maxage` first, as
+/// a shared cache) or else `Expires` relative to `Date`
+fn freshness_lifetime(headers: &HeaderMap) -> Option<Duration> {
+    let mut max_age = None;
+    let mut shared_max_age = None;
+    for directive in headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+    {
+        let (name, value) = directive.trim().split_once('=').unwrap_or((directive.trim(), ""));
+        let seconds = || value.trim_matches('"').parse().ok().map(Duration::from_secs);
+        match name.to_ascii_lowercase().as_str() {
+            "no-cache" | "no-store" => return Some(Duration::ZERO),
+            "max-age" => max_age = seconds(),
+            "s-maxage" => shared_max_age = seconds(),
+            _ => {}
+        }
+    }
+    if let Some(lifetime) = shared_max_age.or(max_age) {
+        return Some(lifetime);
+    }
+
+    let date = |name| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| httpdate::parse_http_date(v).ok())
+    };
+    // An unparsable Expires, such as "0", means already expired
+    let expires = headers.get(EXPIRES)?;
+    let Some(expires) = date(EXPIRES) else {
+        return expires.to_str().is_ok().then_some(Duration::ZERO);
+    };
+    let now = date(DATE).unwrap_or_else(std::time::SystemTime::now);
+    Some(expires.duration_since(now).unwrap_or(Duration::ZERO))
+}
@@ -0,0 +1,419 @@
+use crate::cache::TileStore;
+use crate::error::{AppError, Result};
+use crate::types::{TileData, TileKey};
+use async_trait::async_trait;
+use bytes::{Buf, Bytes};
+use flate2::read::GzDecoder;
+use moka::future::Cache;
+use reqwest::header::RANGE;
+use reqwest::Client;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+const HEADER_LEN: usize = 127;
+
+/// Root directory, header and metadata all live in the first 16KiB
+const INITIAL_FETCH_LEN: u64 = 16_384;
+
+/// Directories nest at most three levels deep (root plus two leaf levels)
+const MAX_DIRECTORY_DEPTH: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Unsupported(u8),
+}
+
+impl Compression {
+    fn from_byte(byte: u8) -> Self {
+        match byte {
+            // 0 means unknown, treat it as uncompressed
+            0 | 1 => Compression::None,
+            2 => Compression::Gzip,
+            4 => Compression::Zstd,
+            other => Compression::Unsupported(other),
+        }
+    }
+
+    fn decompress(self, data: Bytes) -> Result<Bytes> {
+        match self {
+            Compression::None => Ok(data),
+            Compression::Gzip => {
+                let mut out = Vec::new();
+                GzDecoder::new(&data[..]).read_to_end(&mut out)?;
+                Ok(Bytes::from(out))
+            }
+            Compression::Zstd => Ok(Bytes::from(zstd::decode_all(&data[..])?)),
+            Compression::Unsupported(code) => Err(AppError::Archive(format!(
+                "unsupported PMTiles compression {}",
+                code
+            ))),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Header {
+    root_offset: u64,
+    root_length: u64,
+    leaf_offset: u64,
+    tile_data_offset: u64,
+    internal_compression: Compression,
+    tile_compression: Compression,
+    content_type: Option<&'static str>,
+    min_zoom: u8,
+    max_zoom: u8,
+}
+
+impl Header {
+    fn parse(mut buf: &[u8]) -> Result<Self> {
+        if buf.len() < HEADER_LEN || &buf[..7] != b"PMTiles" {
+            return Err(AppError::Archive("not a PMTiles archive".to_string()));
+        }
+        if buf[7] != 3 {
+            return Err(AppError::Archive(format!(
+                "unsupported PMTiles version {}",
+                buf[7]
+            )));
+        }
+
+        buf.advance(8);
+        let root_offset = buf.get_u64_le();
+        let root_length = buf.get_u64_le();
+        let _metadata_offset = buf.get_u64_le();
+        let _metadata_length = buf.get_u64_le();
+        let leaf_offset = buf.get_u64_le();
+        let _leaf_length = buf.get_u64_le();
+        let tile_data_offset = buf.get_u64_le();
+        // Tile data length, addressed tiles, tile entries, tile contents, clustered
+        buf.advance(8 * 4 + 1);
+        let internal_compression = Compression::from_byte(buf.get_u8());
+        let tile_compression = Compression::from_byte(buf.get_u8());
+        let content_type = match buf.get_u8() {
+            1 => Some("application/vnd.mapbox-vector-tile"),
+            2 => Some("image/png"),
+            3 => Some("image/jpeg"),
+            4 => Some("image/webp"),
+            5 => Some("image/avif"),
+            _ => None,
+        };
+        let min_zoom = buf.get_u8();
+        let max_zoom = buf.get_u8();
+
+        Ok(Self {
+            root_offset,
+            root_length,
+            leaf_offset,
+            tile_data_offset,
+            internal_compression,
+            tile_compression,
+            content_type,
+            min_zoom,
+            max_zoom,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    tile_id: u64,
+    offset: u64,
+    length: u32,
+    /// Zero marks a pointer to a leaf directory
+    run_length: u32,
+}
+
+fn read_varint(buf: &mut &[u8]) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        if !buf.has_remaining() {
+            break;
+        }
+        let byte = buf.get_u8();
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(AppError::Archive("malformed PMTiles varint".to_string()))
+}
+
+fn parse_directory(data: &[u8]) -> Result<Vec<Entry>> {
+    let mut buf = data;
+    let count = read_varint(&mut buf)? as usize;
+    // Every entry needs at least four bytes, guard against absurd counts
+    if count > data.len() {
+        return Err(AppError::Archive("malformed PMTiles directory".to_string()));
+    }
+
+    let mut entries = Vec::with_capacity(count);
+    let mut tile_id = 0;
+    for _ in 0..count {
+        tile_id += read_varint(&mut buf)?;
+        entries.push(Entry {
+            tile_id,
+            offset: 0,
+            length: 0,
+            run_length: 0,
+        });
+    }
+    for entry in &mut entries {
+        entry.run_length = read_varint(&mut buf)? as u32;
+    }
+    for entry in &mut entries {
+        entry.length = read_varint(&mut buf)? as u32;
+    }
+    for i in 0..count {
+        let value = read_varint(&mut buf)?;
+        entries[i].offset = if value == 0 && i > 0 {
+            // Contiguous with the previous entry
+            entries[i - 1].offset + u64::from(entries[i - 1].length)
+        } else {
+            value.saturating_sub(1)
+        };
+    }
+
+    Ok(entries)
+}
+
+/// Position of a tile along the Hilbert curve ordering used by PMTiles
+fn tile_id(key: &TileKey) -> u64 {
+    // Tiles at all lower zoom levels come first
+    let base = ((1u64 << (2 * u32::from(key.z))) - 1) / 3;
+    let (mut x, mut y) = (u64::from(key.x), u64::from(key.y));
+    let mut d = 0;
+    let mut s = (1u64 << key.z) >> 1;
+    while s > 0 {
+        let rx = u64::from(x & s > 0);
+        let ry = u64::from(y & s > 0);
+        d += s * s * ((3 * rx) ^ ry);
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - (x & (s - 1));
+                y = s - 1 - (y & (s - 1));
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s >>= 1;
+    }
+    base + d
+}
+
+/// Last entry whose run covers the tile, or the leaf pointer that may contain it
+fn find_entry(entries: &[Entry], tile_id: u64) -> Option<Entry> {
+    let idx = entries.partition_point(|e| e.tile_id <= tile_id).checked_sub(1)?;
+    let entry = entries[idx];
+    if entry.run_length == 0 || tile_id < entry.tile_id + u64::from(entry.run_length) {
+        Some(entry)
+    } else {
+        None
+    }
+}
+
+enum RangeReader {
+    File(Arc<File>),
+    Http { client: Client, url: String },
+}
+
+impl RangeReader {
+    async fn read(&self, offset: u64, length: u64) -> Result<Bytes> {
+        match self {
+            RangeReader::File(file) => {
+                let file = file.clone();
+                tokio::task::spawn_blocking(move || {
+                    let mut buf = vec![0; length as usize];
+                    let mut filled = 0;
+                    while filled < buf.len() {
+                        let read = file.read_at(&mut buf[filled..], offset + filled as u64)?;
+                        if read == 0 {
+                            break;
+                        }
+                        filled += read;
+                    }
+                    buf.truncate(filled);
+                    Ok(Bytes::from(buf))
+                })
+                .await
+                .expect("pmtiles read panicked")
+            }
+            RangeReader::Http { client, url } => {
+                let response = client
+                    .get(url)
+                    .header(RANGE, format!("bytes={}-{}", offset, offset + length - 1))
+                    .send()
+                    .await?;
+                let status = response.status();
+                if !status.is_success() {
+                    return Err(AppError::UpstreamStatus(status.as_u16()));
+                }
+                let data = response.bytes().await?;
+                // A server ignoring Range returns the whole archive
+                if status.as_u16() == 200 && data.len() as u64 > length {
+                    return Ok(data.slice(offset as usize..(offset + length) as usize));
+                }
+                Ok(data)
+            }
+        }
+    }
+}
+
+struct Archive {
+    name: String,
+    reader: RangeReader,
+    header: Header,
+    root: Vec<Entry>,
+    /// Parsed leaf directories keyed by their offset
+    leaves: Cache<u64, Arc<Vec<Entry>>>,
+}
+
+impl Archive {
+    async fn open(source: &str, client: &Client) -> Result<Self> {
+        let reader = if source.starts_with("http://") || source.starts_with("https://") {
+            RangeReader::Http {
+                client: client.clone(),
+                url: source.to_string(),
+            }
+        } else {
+            RangeReader::File(Arc::new(File::open(PathBuf::from(source))?))
+        };
+
+        let initial = reader.read(0, INITIAL_FETCH_LEN).await?;
+        let header = Header::parse(&initial)?;
+
+        let root_end = header.root_offset + header.root_length;
+        let root_data = if root_end <= initial.len() as u64 {
+            initial.slice(header.root_offset as usize..root_end as usize)
+        } else {
+            reader.read(header.root_offset, header.root_length).await?
+        };
+        let root = parse_directory(&header.internal_compression.decompress(root_data)?)?;
+
+        Ok(Self {
+            name: source.to_string(),
+            reader,
+            header,
+            root,
+            leaves: Cache::new(1024),
+        })
+    }
+
+    async fn leaf(&self, offset: u64, length: u32) -> Result<Arc<Vec<Entry>>> {
+        if let Some(entries) = self.leaves.get(&offset).await {
+            return Ok(entries);
+        }
+        let data = self
+            .reader
+            .read(self.header.leaf_offset + offset, u64::from(length))
+            .await?;
+        let entries = Arc::new(parse_directory(
+            &self.header.internal_compression.decompress(data)?,
+        )?);
+        self.leaves.insert(offset, entries.clone()).await;
+        Ok(entries)
+    }
+
+    async fn get(&self, key: &TileKey) -> Result<Option<Bytes>> {
+        if key.z < self.header.min_zoom || key.z > self.header.max_zoom {
+            return Ok(None);
+        }
+
+        let id = tile_id(key);
+        let mut entry = find_entry(&self.root, id);
+        for _ in 0..MAX_DIRECTORY_DEPTH {
+            let Some(found) = entry else {
+                return Ok(None);
+            };
+            if found.run_length > 0 {
+                let data = self
+                    .reader
+                    .read(self.header.tile_data_offset + found.offset, u64::from(found.length))
+                    .await?;
+                return self.header.tile_compression.decompress(data).map(Some);
+            }
+            let leaf = self.leaf(found.offset, found.length).await?;
+            entry = find_entry(&leaf, id);
+        }
+        Err(AppError::Archive("PMTiles directory nested too deeply".to_string()))
+    }
+}
+
+/// Read-only tile source backed by PMTiles v3 archives on disk or behind HTTP
+/// range requests, checked in order
+#[derive(Clone)]
+pub struct PmtilesSource {
+    archives: Arc<[Archive]>,
+}
+
+impl PmtilesSource {
+    pub async fn open(sources: &[String], client: &Client) -> Result<Self> {
+        let mut archives = Vec::with_capacity(sources.len());
+        for source in sources {
+            let archive = Archive::open(source, client).await?;
+            tracing::info!(
+                source = %source,
+                min_zoom = archive.header.min_zoom,
+                max_zoom = archive.header.max_zoom,
+                "Mounted PMTiles archive"
+            );
+            archives.push(archive);
+        }
+
+        Ok(Self {
+            archives: archives.into(),
+        })
+    }
+
+    /// Look a tile up in each archive, returning the first match
+    pub async fn get(&self, key: &TileKey) -> Option<TileData> {
+        for archive in self.archives.iter() {
+            match archive.get(key).await {
+                Ok(Some(data)) => {
+                    let mut tile = TileData::new(data, None);
+                    tile.content_type = archive.header.content_type.map(str::to_string);
+                    return Some(tile);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::warn!(source = %archive.name, key = %key, error = %e, "PMTiles lookup failed");
+                }
+            }
+        }
+        None
+    }
+}
+
+/// A read-only tier; archives only hold standard-resolution tiles
+#[async_trait]
+impl TileStore for PmtilesSource {
+    fn name(&self) -> &'static str {
+        "pmtiles"
+    }
+
+    async fn get(&self, key: &TileKey) -> Result<Option<Arc<TileData>>> {
+        if key.scale != 1 {
+            return Ok(None);
+        }
+        let Some(mut tile) = PmtilesSource::get(self, key).await else {
+            return Ok(None);
+        };
+        tile.ensure_etag();
+        Ok(Some(Arc::new(tile)))
+    }
+
+    async fn put(&self, _key: &TileKey, _tile: Arc<TileData>) -> Result<()> {
+        Ok(())
+    }
+
+    async fn delete(&self, _key: &TileKey) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn writable(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,71 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Token bucket limiting the rate of upstream requests
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Allow `rate` requests per second, bursting up to one second's worth
+    pub fn new(rate: f64) -> Self {
+        Self::with_burst(rate, rate)
+    }
+
+    /// Allow `rate` requests per second, bursting up to `burst`
+    pub fn with_burst(rate: f64, burst: f64) -> Self {
+        let capacity = burst.max(1.0);
+        Self {
+            rate,
+            capacity,
+            state: Mutex::new(BucketState {
+                tokens: capacity,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Wait until a token is available and take it
+    pub async fn acquire(&self) {
+        while let Err(wait) = self.try_acquire() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Take a token if one is available, returning the whole tokens left,
+    /// or else how long until the next one
+    pub fn try_acquire(&self) -> Result<u64, Duration> {
+        let mut state = self.refilled();
+        if state.tokens >= 1.0 {
+            state.tokens -= 1.0;
+            return Ok(state.tokens as u64);
+        }
+        Err(Duration::from_secs_f64((1.0 - state.tokens) / self.rate))
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity as u64
+    }
+
+    /// How long until the bucket is full again
+    pub fn time_to_full(&self) -> Duration {
+        let state = self.refilled();
+        Duration::from_secs_f64((self.capacity - state.tokens) / self.rate)
+    }
+
+    fn refilled(&self) -> std::sync::MutexGuard<'_, BucketState> {
+        let mut state = self.state.lock().expect("token bucket lock poisoned");
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.rate).min(self.capacity);
+        state.last_refill = now;
+        state
+    }
+}
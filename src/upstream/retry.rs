@@ -0,0 +1,29 @@
+use crate::config::Config;
+use std::time::Duration;
+
+/// Exponential backoff with jitter for transient upstream failures
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    /// Fraction of each delay randomized in either direction, 0 to 1
+    pub jitter: f64,
+}
+
+impl RetryPolicy {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            max_retries: config.upstream_max_retries,
+            base_delay: config.upstream_retry_base_delay,
+            jitter: config.upstream_retry_jitter.clamp(0.0, 1.0),
+        }
+    }
+
+    /// Delay before retry number `attempt` (starting at 0)
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self.base_delay.saturating_mul(1 << attempt.min(16));
+        let factor = 1.0 + self.jitter * (rand::random::<f64>() * 2.0 - 1.0);
+        backoff.mul_f64(factor)
+    }
+}
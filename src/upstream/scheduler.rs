@@ -0,0 +1,143 @@
+use crate::upstream::rate_limit::TokenBucket;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+
+tokio::task_local! {
+    static PRIORITY: Priority;
+}
+
+/// Who an upstream fetch is for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// A client waiting on the response
+    Interactive,
+    /// Seeding, prefetching and revalidation, which can wait
+    Background,
+}
+
+/// Run `work` with its upstream fetches at background priority
+pub async fn background<F: Future>(work: F) -> F::Output {
+    PRIORITY.scope(Priority::Background, work).await
+}
+
+/// Priority of the current task's fetches, interactive unless run through
+/// [`background`]
+pub fn current_priority() -> Priority {
+    PRIORITY.try_with(|priority| *priority).unwrap_or(Priority::Interactive)
+}
+
+/// Hands out upstream connection slots and rate-limit tokens, interactive
+/// fetches first. Background fetches only take a slot or token while no
+/// interactive fetch is waiting for one, so seeding can't slow the live map.
+/// A live request for a tile that a background fetch already has in flight
+/// still waits for that fetch.
+pub struct UpstreamScheduler {
+    permits: Arc<Semaphore>,
+    rate_limit: Option<TokenBucket>,
+    /// Interactive fetches waiting for a slot or token
+    interactive_waiting: AtomicUsize,
+    /// Woken when the last waiting interactive fetch gets through
+    interactive_done: Notify,
+}
+
+impl UpstreamScheduler {
+    /// Allow `max_concurrency` fetches at once and `rate` per second,
+    /// unlimited when 0
+    pub fn new(max_concurrency: usize, rate: f64) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(max_concurrency.max(1))),
+            rate_limit: (rate > 0.0).then(|| TokenBucket::new(rate)),
+            interactive_waiting: AtomicUsize::new(0),
+            interactive_done: Notify::new(),
+        }
+    }
+
+    /// Wait for a connection slot and a rate-limit token at the current
+    /// task's priority; the slot is held until the permit is dropped
+    pub async fn acquire(&self) -> OwnedSemaphorePermit {
+        match current_priority() {
+            Priority::Interactive => self.acquire_interactive().await,
+            Priority::Background => self.acquire_background().await,
+        }
+    }
+
+    /// Wait for a rate-limit token alone, as interactive work
+    pub async fn acquire_token(&self) {
+        if let Some(rate_limit) = &self.rate_limit {
+            let _waiting = self.wait_interactive();
+            rate_limit.acquire().await;
+        }
+    }
+
+    async fn acquire_interactive(&self) -> OwnedSemaphorePermit {
+        let _waiting = self.wait_interactive();
+        let permit = self.slot().await;
+        if let Some(rate_limit) = &self.rate_limit {
+            rate_limit.acquire().await;
+        }
+        permit
+    }
+
+    async fn acquire_background(&self) -> OwnedSemaphorePermit {
+        'retry: loop {
+            self.interactive_idle().await;
+            let permit = self.slot().await;
+            let Some(rate_limit) = &self.rate_limit else {
+                if self.interactive_waiting.load(Ordering::Acquire) > 0 {
+                    continue 'retry;
+                }
+                return permit;
+            };
+            loop {
+                // Give the slot back to a live request that queued meanwhile
+                if self.interactive_waiting.load(Ordering::Acquire) > 0 {
+                    continue 'retry;
+                }
+                match rate_limit.try_acquire() {
+                    Ok(_) => return permit,
+                    Err(wait) => tokio::time::sleep(wait).await,
+                }
+            }
+        }
+    }
+
+    async fn slot(&self) -> OwnedSemaphorePermit {
+        self.permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("upstream semaphore is never closed")
+    }
+
+    /// Count an interactive fetch as waiting until the guard is dropped
+    fn wait_interactive(&self) -> InteractiveWaiting<'_> {
+        self.interactive_waiting.fetch_add(1, Ordering::AcqRel);
+        InteractiveWaiting(self)
+    }
+
+    /// Wait until no interactive fetch is queued
+    async fn interactive_idle(&self) {
+        loop {
+            let done = self.interactive_done.notified();
+            tokio::pin!(done);
+            // Registered before the check so a wakeup in between isn't lost
+            done.as_mut().enable();
+            if self.interactive_waiting.load(Ordering::Acquire) == 0 {
+                return;
+            }
+            done.await;
+        }
+    }
+}
+
+struct InteractiveWaiting<'a>(&'a UpstreamScheduler);
+
+impl Drop for InteractiveWaiting<'_> {
+    fn drop(&mut self) {
+        if self.0.interactive_waiting.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.0.interactive_done.notify_waiters();
+        }
+    }
+}
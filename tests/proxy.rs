@@ -0,0 +1,226 @@
+//! End-to-end tests of the proxy against a mock upstream
+
+use futures_util::future::join_all;
+use maptile_cacher::config::TerrainLayerConfig;
+use maptile_cacher::testing::{MockTile, MockUpstream, TestProxy};
+use std::time::{Duration, Instant};
+
+/// A PNG-looking tile body of `bytes`, distinct per `seed`
+fn tile_body(seed: u32, bytes: usize) -> Vec<u8> {
+    let mut body = b"\x89PNG\r\n\x1a\n".to_vec();
+    body.extend(seed.to_be_bytes());
+    body.resize(bytes, 0);
+    body
+}
+
+/// Poll `check` until it holds, failing the test after a few seconds
+async fn eventually<F: std::future::Future<Output = bool>>(what: &str, check: impl Fn() -> F) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !check().await {
+        assert!(Instant::now() < deadline, "timed out waiting until {}", what);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+#[tokio::test]
+async fn concurrent_misses_fetch_once() -> anyhow::Result<()> {
+    let upstream = MockUpstream::start().await?;
+    upstream.set("3/2/1.png", MockTile::png([255, 0, 0, 255]));
+    upstream.set_latency(Duration::from_millis(200));
+    let proxy = TestProxy::start(&upstream, |_| {}).await?;
+
+    let client = reqwest::Client::new();
+    let responses = join_all((0..16).map(|_| client.get(proxy.url("/3/2/1.png")).send())).await;
+    for response in responses {
+        let response = response?;
+        assert_eq!(response.status(), 200);
+        assert!(!response.bytes().await?.is_empty());
+    }
+    assert_eq!(upstream.hits("3/2/1.png"), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn stale_tile_revalidates_with_its_etag() -> anyhow::Result<()> {
+    let upstream = MockUpstream::start().await?;
+    upstream.set("3/2/1.png", MockTile::png([0, 255, 0, 255]).with_etag("\"v1\""));
+    let proxy = TestProxy::start(&upstream, |config| config.freshness_window = Duration::ZERO)
+        .await?;
+
+    let first = reqwest::get(proxy.url("/3/2/1.png")).await?;
+    assert_eq!(first.status(), 200);
+    let body = first.bytes().await?;
+    eventually("the tile is on disk", || async { proxy.proxy().disk_usage().await.tiles == 1 })
+        .await;
+    assert_eq!(upstream.requests()[0].if_none_match, None);
+
+    // Any age is past a zero freshness window
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let second = reqwest::get(proxy.url("/3/2/1.png")).await?;
+    assert_eq!(second.status(), 200);
+    assert_eq!(second.bytes().await?, body);
+
+    eventually("the tile is revalidated", || async { upstream.requests().len() == 2 }).await;
+    assert_eq!(upstream.requests()[1].if_none_match.as_deref(), Some("\"v1\""));
+    // The mock answered 304, leaving the cached copy to be touched, not replaced
+    let not_modified = "upstream_request_duration_seconds_count{result=\"not_modified\"} 1";
+    eventually("the 304 is counted", || async {
+        let metrics = reqwest::get(proxy.url("/metrics")).await.unwrap();
+        metrics.text().await.unwrap().contains(not_modified)
+    })
+    .await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn eviction_brings_the_disk_cache_under_its_cap() -> anyhow::Result<()> {
+    const TILES: u32 = 8;
+    const TILE_BYTES: usize = 4096;
+    const MAX_BYTES: u64 = 10_000;
+
+    let upstream = MockUpstream::start().await?;
+    for x in 0..TILES {
+        upstream.set(&format!("4/{}/0.png", x), MockTile::ok(tile_body(x, TILE_BYTES)));
+    }
+    let proxy = TestProxy::start(&upstream, |config| {
+        config.disk_cache_max_bytes = MAX_BYTES;
+        config.disk_sweep_interval = Duration::from_secs(2);
+    })
+    .await?;
+
+    for x in 0..TILES {
+        let response = reqwest::get(proxy.url(&format!("/4/{}/0.png", x))).await?;
+        assert_eq!(response.status(), 200);
+    }
+    let usage = || async { proxy.proxy().disk_usage().await };
+    eventually("every tile is on disk", || async { usage().await.tiles == u64::from(TILES) })
+        .await;
+    assert!(usage().await.bytes > MAX_BYTES);
+
+    eventually("the cache is evicted", || async { usage().await.bytes <= MAX_BYTES }).await;
+    let usage = usage().await;
+    assert!(usage.tiles < u64::from(TILES));
+    assert!(usage.tiles > 0, "eviction stops once under the cap");
+    Ok(())
+}
+
+#[tokio::test]
+async fn head_answers_from_cache_metadata() -> anyhow::Result<()> {
+    let upstream = MockUpstream::start().await?;
+    upstream.set("3/2/1.png", MockTile::ok(tile_body(1, 3000)).with_etag("\"v1\""));
+    // Without a memory cache, HEAD goes to the disk copy's header
+    let proxy = TestProxy::start(&upstream, |config| config.memory_cache_size = 0).await?;
+
+    let get = reqwest::get(proxy.url("/3/2/1.png")).await?;
+    assert_eq!(get.status(), 200);
+    eventually("the tile is on disk", || async { proxy.proxy().disk_usage().await.tiles == 1 })
+        .await;
+
+    let head = reqwest::Client::new().head(proxy.url("/3/2/1.png")).send().await?;
+    assert_eq!(head.status(), 200);
+    let header = |name| head.headers().get(name).and_then(|v| v.to_str().ok());
+    assert_eq!(header("content-length"), Some("3000"));
+    assert_eq!(header("etag"), Some("\"v1\""));
+    assert_eq!(header("content-type"), Some("image/png"));
+    assert!(head.bytes().await?.is_empty());
+    assert_eq!(upstream.hits("3/2/1.png"), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn static_map_passes_on_a_rate_limited_tile() -> anyhow::Result<()> {
+    let upstream = MockUpstream::start().await?;
+    upstream.set(
+        "1/0/0.png",
+        MockTile::status(reqwest::StatusCode::TOO_MANY_REQUESTS).with_header("Retry-After", "30"),
+    );
+    let proxy = TestProxy::start(&upstream, |config| config.upstream_max_retries = 0).await?;
+
+    // A view inside tile 1/0/0
+    let url = proxy.url("/staticmap?center=66.5,-90&zoom=1&size=64x64");
+    let response = reqwest::get(url).await?;
+    assert_eq!(response.status(), 503);
+    let header = |name| response.headers().get(name).and_then(|v| v.to_str().ok());
+    assert_eq!(header("retry-after"), Some("30"));
+    assert_eq!(header("content-type"), Some("application/problem+json"));
+    let problem: serde_json::Value = serde_json::from_str(&response.text().await?)?;
+    assert_eq!(problem["code"], "upstream_rate_limited");
+    assert_eq!(upstream.hits("1/0/0.png"), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn not_modified_carries_the_caching_headers() -> anyhow::Result<()> {
+    let upstream = MockUpstream::start().await?;
+    upstream.set("3/2/1.png", MockTile::png([0, 0, 255, 255]).with_etag("\"v1\""));
+    let proxy = TestProxy::start(&upstream, |_| {}).await?;
+    let client = reqwest::Client::new();
+
+    let ok = client.get(proxy.url("/3/2/1.png")).send().await?;
+    assert_eq!(ok.status(), 200);
+    let not_modified = client
+        .get(proxy.url("/3/2/1.png"))
+        .header("If-None-Match", "\"v1\"")
+        .send()
+        .await?;
+    assert_eq!(not_modified.status(), 304);
+    for name in ["etag", "cache-control", "expires"] {
+        assert_eq!(not_modified.headers().get(name), ok.headers().get(name), "{}", name);
+    }
+    assert_eq!(not_modified.headers()["etag"], "\"v1\"");
+    Ok(())
+}
+
+#[tokio::test]
+async fn purge_refuses_oversized_ranges() -> anyhow::Result<()> {
+    let upstream = MockUpstream::start().await?;
+    let proxy = TestProxy::start(&upstream, |config| config.max_purge_tiles = 100).await?;
+    let client = reqwest::Client::new();
+    let purge = |min_zoom: u8, max_zoom: u8| {
+        let world = r#"{"min_lon":-180,"min_lat":-85,"max_lon":180,"max_lat":85}"#;
+        let body = format!(
+            r#"{{"bbox":{},"min_zoom":{},"max_zoom":{}}}"#,
+            world, min_zoom, max_zoom
+        );
+        client
+            .post(proxy.url("/admin/purge"))
+            .header("content-type", "application/json")
+            .body(body)
+            .send()
+    };
+
+    // 1 + 4 + 16 + 64 tiles
+    assert_eq!(purge(0, 3).await?.status(), 200);
+    assert_eq!(purge(0, 4).await?.status(), 400);
+    // Past the served zoom levels, whatever the tile count
+    assert_eq!(purge(0, 31).await?.status(), 400);
+    Ok(())
+}
+
+#[tokio::test]
+async fn elevation_is_served_beside_the_tile_routes() -> anyhow::Result<()> {
+    let upstream = MockUpstream::start().await?;
+    // Terrain-RGB for 100 m
+    upstream.set("1/0/0.png", MockTile::png([1, 138, 136, 255]));
+    let proxy = TestProxy::start(&upstream, |config| {
+        config.terrain_layers = vec![TerrainLayerConfig {
+            name: "terrain".to_string(),
+            upstream: upstream.url_template().into(),
+            encoding: Default::default(),
+            passthrough_headers: Vec::new(),
+        }];
+    })
+    .await?;
+
+    let response = reqwest::get(proxy.url("/terrain/elevation/1/0/0?lat=66.5&lon=-90")).await?;
+    assert_eq!(response.status(), 200);
+    let elevation: serde_json::Value = serde_json::from_str(&response.text().await?)?;
+    assert!((elevation["elevation"].as_f64().unwrap() - 100.0).abs() < 0.01);
+
+    // Tiles and admin routes of the same shape still reach their own handlers
+    assert_eq!(reqwest::get(proxy.url("/1/0/0.png")).await?.status(), 200);
+    assert_eq!(reqwest::get(proxy.url("/layers/terrain/1/0/0.png")).await?.status(), 200);
+    let missing = reqwest::get(proxy.url("/nowhere/elevation/1/0/0?lat=66.5&lon=-90")).await?;
+    assert_eq!(missing.status(), 404);
+    Ok(())
+}